@@ -22,6 +22,15 @@ mod types {
         pub images: Vec<String>,
         #[serde(default)]
         pub timestamp: i64,
+        /// assistant 消息发起的工具调用
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub tool_calls: Vec<ToolCall>,
+        /// tool 消息对应的调用 ID
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub tool_call_id: Option<String>,
+        /// 置顶保护，清空历史与自动清理时保留
+        #[serde(default)]
+        pub pinned: bool,
     }
 
     impl ChatMessage {
@@ -31,8 +40,42 @@ mod types {
                 content: content.to_string(),
                 images,
                 timestamp: chrono::Local::now().timestamp(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                pinned: false,
             }
         }
+
+        /// 是否为同一条消息 (角色、内容、图片与时间一致)，置顶状态不参与比较
+        pub fn same_as(&self, other: &ChatMessage) -> bool {
+            self.role == other.role
+                && self.timestamp == other.timestamp
+                && self.content == other.content
+                && self.images == other.images
+        }
+
+        pub fn tool_result(call_id: &str, content: &str) -> Self {
+            let mut m = Self::new("tool", content, vec![]);
+            m.tool_call_id = Some(call_id.to_string());
+            m
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ToolCall {
+        pub id: String,
+        pub name: String,
+        pub arguments: String,
+    }
+
+    /// 智能体可调用的工具 (function calling)
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct AgentTool {
+        pub name: String,
+        #[serde(default)]
+        pub description: String,
+        /// JSON Schema
+        pub parameters: serde_json::Value,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,14 +85,105 @@ mod types {
         pub description: String,
         pub model: String,
         pub system_prompt: String,
+        /// 公有历史，按群隔离 (私聊按用户)，键见 utils::public_bucket
         #[serde(default)]
-        pub public_history: Vec<ChatMessage>,
+        pub public_histories: HashMap<String, Vec<ChatMessage>>,
+        /// 旧版全局公有历史，加载后迁入 GLOBAL_BUCKET
+        #[serde(default, rename = "public_history", skip_serializing)]
+        pub legacy_public_history: Vec<ChatMessage>,
         #[serde(default)]
         pub private_histories: HashMap<String, Vec<ChatMessage>>,
+        /// 用户在私有会话中覆盖的提示词，键为用户 QQ
+        #[serde(default)]
+        pub private_prompts: HashMap<String, String>,
+        /// 智能体级生成 ID，重启、导入或清空全部历史时递增
         #[serde(default)]
         pub generation_id: u64,
+        /// 会话级生成 ID，键为 "private:用户" 或 "public:桶"，停止等操作只影响自己的会话
+        #[serde(default)]
+        pub session_generations: HashMap<String, u64>,
         #[serde(default)]
         pub created_at: i64,
+        #[serde(default)]
+        pub require_approval: bool,
+        #[serde(default)]
+        pub approvers: Vec<i64>,
+        #[serde(default)]
+        pub created_by: String,
+        #[serde(default)]
+        pub retention_policy: Option<RetentionPolicy>,
+        /// 公有历史的话题名，首轮回复后自动生成
+        #[serde(default)]
+        pub topic: Option<String>,
+        /// 系统参考图，随每次请求一起发送
+        #[serde(default)]
+        pub system_images: Vec<String>,
+        #[serde(default)]
+        pub tools: Vec<AgentTool>,
+        /// 请求超时秒数，未设置时使用全局 timeout_secs，0 为不限时
+        #[serde(default)]
+        pub timeout_secs: Option<u64>,
+        /// 停止词，非空时随请求发送
+        #[serde(default)]
+        pub stop: Vec<String>,
+        /// 固定随机种子，便于复现输出
+        #[serde(default)]
+        pub seed: Option<i64>,
+        /// 绘图模型的图片尺寸偏好，如 1024x1024
+        #[serde(default)]
+        pub image_size: Option<String>,
+        /// 绘图模型的图片质量偏好，如 high
+        #[serde(default)]
+        pub image_quality: Option<String>,
+        /// 单次请求的输入 token 预算，估算超出时不发送请求
+        #[serde(default)]
+        pub token_budget: Option<u32>,
+        /// 私有会话数量上限，达到后拒绝新用户开启私有会话
+        #[serde(default)]
+        pub max_private_users: Option<usize>,
+        /// 成功对话次数
+        #[serde(default)]
+        pub call_count: u64,
+        /// 最近一次成功对话的时间戳，0 表示从未使用
+        #[serde(default)]
+        pub last_used_at: i64,
+    }
+
+    /// 历史自动维护策略，0 表示不限制
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct RetentionPolicy {
+        #[serde(default)]
+        pub max_days: u32,
+        #[serde(default)]
+        pub max_messages: usize,
+        /// 清理前写入归档文件，而非直接丢弃
+        #[serde(default)]
+        pub archive: bool,
+    }
+
+    impl RetentionPolicy {
+        /// 按策略裁剪一段历史，返回被移除的消息；置顶消息始终保留
+        pub fn apply(&self, hist: &mut Vec<ChatMessage>, now: i64) -> Vec<ChatMessage> {
+            let cutoff = (self.max_days > 0).then(|| now - self.max_days as i64 * 86400);
+            let mut over = if self.max_messages > 0 {
+                hist.len().saturating_sub(self.max_messages)
+            } else {
+                0
+            };
+            let mut removed = Vec::new();
+            let mut kept = Vec::with_capacity(hist.len());
+            for m in hist.drain(..) {
+                let expired = cutoff.is_some_and(|c| m.timestamp < c);
+                if m.pinned || !(expired || over > 0) {
+                    kept.push(m);
+                    continue;
+                }
+                over = over.saturating_sub(1);
+                removed.push(m);
+            }
+            *hist = kept;
+            removed
+        }
     }
 
     impl Agent {
@@ -59,44 +193,174 @@ mod types {
                 description: desc.to_string(),
                 model: model.to_string(),
                 system_prompt: prompt.to_string(),
-                public_history: Vec::new(),
+                public_histories: HashMap::new(),
+                legacy_public_history: Vec::new(),
                 private_histories: HashMap::new(),
+                private_prompts: HashMap::new(),
                 generation_id: 0,
+                session_generations: HashMap::new(),
                 created_at: chrono::Local::now().timestamp(),
+                require_approval: false,
+                approvers: Vec::new(),
+                created_by: String::new(),
+                retention_policy: None,
+                topic: None,
+                system_images: Vec::new(),
+                tools: Vec::new(),
+                timeout_secs: None,
+                stop: Vec::new(),
+                seed: None,
+                image_size: None,
+                image_quality: None,
+                token_budget: None,
+                max_private_users: None,
+                call_count: 0,
+                last_used_at: 0,
             }
         }
 
-        pub fn history_mut(&mut self, private: bool, uid: &str) -> &mut Vec<ChatMessage> {
-            if private {
-                self.private_histories.entry(uid.to_string()).or_default()
-            } else {
-                &mut self.public_history
+        /// 复制出同配置的新智能体：只重置名称、历史、生成 ID 与使用统计
+        pub fn duplicate(&self, name: &str) -> Self {
+            let mut a = self.clone();
+            a.name = name.to_string();
+            a.public_histories.clear();
+            a.legacy_public_history.clear();
+            a.private_histories.clear();
+            a.generation_id = 0;
+            a.session_generations.clear();
+            a.topic = None;
+            a.call_count = 0;
+            a.last_used_at = 0;
+            a.created_at = chrono::Local::now().timestamp();
+            a
+        }
+
+        /// 全部会话历史及其 history_key (公有在前、私有在后)，用于批量快照
+        pub fn all_histories(&self) -> impl Iterator<Item = (String, &[ChatMessage])> {
+            let public = self
+                .public_histories
+                .iter()
+                .map(|(b, h)| (history_key(&self.name, false, b), h.as_slice()));
+            let private = self
+                .private_histories
+                .iter()
+                .map(|(u, h)| (history_key(&self.name, true, u), h.as_slice()));
+            public.chain(private)
+        }
+
+        /// 将旧版全局公有历史迁入 GLOBAL_BUCKET
+        pub fn migrate_legacy(&mut self) {
+            if !self.legacy_public_history.is_empty() {
+                let legacy = std::mem::take(&mut self.legacy_public_history);
+                self.public_histories
+                    .entry(GLOBAL_BUCKET.to_string())
+                    .or_default()
+                    .extend(legacy);
             }
         }
 
-        pub fn history(&self, private: bool, uid: &str) -> &[ChatMessage] {
-            if private {
-                self.private_histories
-                    .get(uid)
-                    .map(|v| v.as_slice())
-                    .unwrap_or(&[])
+        /// 将 GLOBAL_BUCKET 中的旧版历史并入指定桶 (排在该桶已有消息之前)，返回迁入条数
+        pub fn claim_global(&mut self, bucket: &str) -> usize {
+            let Some(legacy) = self
+                .public_histories
+                .remove(GLOBAL_BUCKET)
+                .filter(|h| !h.is_empty())
+            else {
+                return 0;
+            };
+            let n = legacy.len();
+            let h = self.public_histories.entry(bucket.to_string()).or_default();
+            let rest = std::mem::replace(h, legacy);
+            h.extend(rest);
+            n
+        }
+
+        /// owner: 私有历史为用户 QQ，公有历史为所在的桶
+        pub fn history_mut(&mut self, private: bool, owner: &str) -> &mut Vec<ChatMessage> {
+            let map = if private {
+                &mut self.private_histories
             } else {
-                &self.public_history
-            }
+                &mut self.public_histories
+            };
+            map.entry(owner.to_string()).or_default()
+        }
+
+        pub fn history(&self, private: bool, owner: &str) -> &[ChatMessage] {
+            let map = if private {
+                &self.private_histories
+            } else {
+                &self.public_histories
+            };
+            map.get(owner).map(|v| v.as_slice()).unwrap_or(&[])
         }
 
-        pub fn clear_history(&mut self, private: bool, uid: &str) {
+        /// 清空历史，置顶消息保留
+        pub fn clear_history(&mut self, private: bool, owner: &str) {
             if private {
-                if let Some(h) = self.private_histories.get_mut(uid) {
-                    h.clear();
+                if let Some(h) = self.private_histories.get_mut(owner) {
+                    h.retain(|m| m.pinned);
                 }
             } else {
-                self.public_history.clear();
+                if let Some(h) = self.public_histories.get_mut(owner) {
+                    h.retain(|m| m.pinned);
+                }
+                if self.public_histories.values().all(|h| h.is_empty()) {
+                    self.topic = None;
+                }
+            }
+        }
+
+        fn session_key(private: bool, owner: &str) -> String {
+            let scope = if private { "private" } else { "public" };
+            format!("{}:{}", scope, owner)
+        }
+
+        /// 会话当前的生成 ID：两个计数器都只增不减，任一递增都会使旧 ID 失效
+        pub fn session_generation(&self, private: bool, owner: &str) -> u64 {
+            let session = self
+                .session_generations
+                .get(&Self::session_key(private, owner))
+                .copied()
+                .unwrap_or(0);
+            self.generation_id.wrapping_add(session)
+        }
+
+        /// 记录一次成功对话
+        pub fn record_use(&mut self) {
+            self.call_count += 1;
+            self.last_used_at = chrono::Local::now().timestamp();
+        }
+
+        /// 仅使该会话进行中的生成失效，返回新的生成 ID
+        pub fn bump_generation(&mut self, private: bool, owner: &str) -> u64 {
+            let id = self
+                .session_generations
+                .entry(Self::session_key(private, owner))
+                .or_default();
+            *id = id.wrapping_add(1);
+            self.session_generation(private, owner)
+        }
+
+        /// 写回一轮对话：先从最新历史末尾移除重新生成时裁掉的消息 (trimmed，按时间顺序)，
+        /// 只有末尾仍是同一条消息时才移除，再追加本轮新消息
+        pub fn apply_turn(
+            &mut self,
+            private: bool,
+            owner: &str,
+            trimmed: &[ChatMessage],
+            new_msgs: Vec<ChatMessage>,
+        ) {
+            let live = self.history_mut(private, owner);
+            for m in trimmed.iter().rev() {
+                if live.last().is_some_and(|l| l.same_as(m)) {
+                    live.pop();
+                }
             }
+            live.extend(new_msgs);
         }
 
-        pub fn delete_at(&mut self, private: bool, uid: &str, indices: &[usize]) -> Vec<usize> {
-            let h = self.history_mut(private, uid);
+        pub fn delete_at(&mut self, private: bool, owner: &str, indices: &[usize]) -> Vec<usize> {
+            let h = self.history_mut(private, owner);
             let mut deleted = Vec::new();
             let mut sorted: Vec<usize> = indices.to_vec();
             // 降序排序，从后往前删除
@@ -113,61 +377,499 @@ mod types {
             deleted
         }
 
-        pub fn edit_at(&mut self, private: bool, uid: &str, idx: usize, content: &str) -> bool {
-            let h = self.history_mut(private, uid);
-            if idx > 0 && idx <= h.len() {
-                h[idx - 1].content = content.to_string();
+        /// 切换置顶状态，返回 (索引, 切换后是否置顶)，越界索引忽略
+        pub fn toggle_pin(
+            &mut self,
+            private: bool,
+            owner: &str,
+            indices: &[usize],
+        ) -> Vec<(usize, bool)> {
+            let h = self.history_mut(private, owner);
+            indices
+                .iter()
+                .filter_map(|&i| {
+                    let m = h.get_mut(i.checked_sub(1)?)?;
+                    m.pinned = !m.pinned;
+                    Some((i, m.pinned))
+                })
+                .collect()
+        }
+
+        /// 交换两条消息的位置 (1 起)，任一索引越界时不做修改
+        pub fn swap_at(&mut self, private: bool, owner: &str, i: usize, j: usize) -> bool {
+            let h = self.history_mut(private, owner);
+            if i == 0 || j == 0 || i > h.len() || j > h.len() {
+                return false;
+            }
+            h.swap(i - 1, j - 1);
+            true
+        }
+
+        /// 插入一条消息，使其成为第 idx 条 (1 起，可为末尾之后)
+        pub fn insert_at(
+            &mut self,
+            private: bool,
+            owner: &str,
+            idx: usize,
+            role: &str,
+            content: &str,
+        ) -> bool {
+            let h = self.history_mut(private, owner);
+            if idx > 0 && idx <= h.len() + 1 {
+                h.insert(idx - 1, ChatMessage::new(role, content, vec![]));
                 true
             } else {
                 false
             }
         }
+
+        pub fn edit_at(
+            &mut self,
+            private: bool,
+            owner: &str,
+            idx: usize,
+            content: &str,
+            mode: EditMode,
+        ) -> bool {
+            let h = self.history_mut(private, owner);
+            if idx == 0 || idx > h.len() {
+                return false;
+            }
+            let old = &mut h[idx - 1].content;
+            match mode {
+                EditMode::Replace => *old = content.to_string(),
+                EditMode::Append => old.push_str(content),
+                EditMode::Prepend => old.insert_str(0, content),
+            }
+            true
+        }
+    }
+
+    impl Agent {
+        /// 对一段历史的全部内容做查找替换，返回替换处数；正则非法时不做修改
+        pub fn replace_all(
+            &mut self,
+            private: bool,
+            owner: &str,
+            pattern: &str,
+            replacement: &str,
+            is_regex: bool,
+            ignore_case: bool,
+        ) -> Result<usize, String> {
+            if pattern.is_empty() {
+                return Err(super::messages::Msg::EmptyPattern.into());
+            }
+            // 忽略大小写时普通文本也转为转义后的正则匹配
+            let re = if is_regex || ignore_case {
+                let source = if is_regex {
+                    pattern.to_string()
+                } else {
+                    regex::escape(pattern)
+                };
+                Some(
+                    regex::RegexBuilder::new(&source)
+                        .case_insensitive(ignore_case)
+                        .build()
+                        .map_err(|e| e.to_string())?,
+                )
+            } else {
+                None
+            };
+            // 普通文本改用正则匹配时，替换内容不展开 $1 等捕获组引用
+            let replacement = if ignore_case && !is_regex {
+                replacement.replace('$', "$$")
+            } else {
+                replacement.to_string()
+            };
+            let replacement = replacement.as_str();
+            let mut count = 0;
+            for m in self.history_mut(private, owner).iter_mut() {
+                let n = match &re {
+                    Some(re) => re.find_iter(&m.content).count(),
+                    None => m.content.matches(pattern).count(),
+                };
+                if n == 0 {
+                    continue;
+                }
+                count += n;
+                m.content = match &re {
+                    Some(re) => re.replace_all(&m.content, replacement).into_owned(),
+                    None => m.content.replace(pattern, replacement),
+                };
+            }
+            Ok(count)
+        }
+    }
+
+    /// 编辑历史的方式：替换 ('1)、追加 ('1+)、前插 ('1^)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum EditMode {
+        Replace,
+        Append,
+        Prepend,
+    }
+
+    /// 可分享的智能体定义 (不含历史)
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct AgentDef {
+        pub name: String,
+        #[serde(default)]
+        pub description: String,
+        pub model: String,
+        #[serde(default)]
+        pub system_prompt: String,
+        #[serde(default)]
+        pub tools: Vec<AgentTool>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub system_images: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub timeout_secs: Option<u64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub stop: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub seed: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub image_size: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub image_quality: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub token_budget: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub max_private_users: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub retention_policy: Option<RetentionPolicy>,
+    }
+
+    impl From<&Agent> for AgentDef {
+        fn from(a: &Agent) -> Self {
+            Self {
+                name: a.name.clone(),
+                description: a.description.clone(),
+                model: a.model.clone(),
+                system_prompt: a.system_prompt.clone(),
+                tools: a.tools.clone(),
+                system_images: a.system_images.clone(),
+                timeout_secs: a.timeout_secs,
+                stop: a.stop.clone(),
+                seed: a.seed,
+                image_size: a.image_size.clone(),
+                image_quality: a.image_quality.clone(),
+                token_budget: a.token_budget,
+                max_private_users: a.max_private_users,
+                retention_policy: a.retention_policy.clone(),
+            }
+        }
+    }
+
+    impl AgentDef {
+        pub fn into_agent(self, name: &str) -> Agent {
+            let mut a = Agent::new(name, &self.model, &self.system_prompt, &self.description);
+            a.tools = self.tools;
+            a.system_images = self.system_images;
+            a.timeout_secs = self.timeout_secs;
+            a.stop = self.stop;
+            a.seed = self.seed;
+            a.image_size = self.image_size;
+            a.image_quality = self.image_quality;
+            a.token_budget = self.token_budget;
+            a.max_private_users = self.max_private_users;
+            a.retention_policy = self.retention_policy;
+            a
+        }
     }
 
+    /// 当前配置结构版本，新增需要迁移的改动时递增
+    pub const CONFIG_VERSION: u32 = 1;
+
     #[derive(Debug, Clone, Serialize, Deserialize, Default)]
     pub struct Config {
+        /// 配置结构版本，旧文件缺省为 0，加载时逐级迁移
+        #[serde(default)]
+        pub schema_version: u32,
         pub api_base: String,
         pub api_key: String,
         #[serde(default)]
         pub models: Vec<String>,
+        /// 接口返回的完整模型列表：修改过滤词时据此重新过滤，也用于解析未展示的模型
+        #[serde(default)]
+        pub all_models: Vec<String>,
+        /// 模型过滤与分组关键字，空列表表示不过滤
+        #[serde(default = "default_model_keywords")]
+        pub model_keywords: Vec<String>,
         #[serde(default)]
         pub agents: Vec<Agent>,
         #[serde(default)]
         pub default_model: String,
         #[serde(default)]
         pub default_prompt: String,
+        #[serde(default = "default_approval_timeout")]
+        pub approval_timeout_secs: u64,
+        #[serde(default = "default_max_concurrent")]
+        pub max_concurrent_requests: usize,
+        #[serde(default)]
+        pub confidence_check: bool,
+        #[serde(default)]
+        pub models_fetched_at: i64,
+        #[serde(default = "default_model_cache_ttl")]
+        pub model_cache_ttl_secs: u64,
+        #[serde(default)]
+        pub render_theme: String,
+        /// 界面语言 zh / en，留空为 zh
+        #[serde(default)]
+        pub lang: String,
+        #[serde(default = "default_render_pool_size")]
+        pub render_pool_size: usize,
+        #[serde(default = "default_max_text_len")]
+        pub max_text_len: usize,
+        /// 文本模式下超过该字符数的回复分多条发送
+        #[serde(default = "default_chunk_threshold")]
+        pub chunk_threshold: usize,
+        /// 模型列表每页数量
+        #[serde(default = "default_models_page_size")]
+        pub models_page_size: usize,
+        /// 自动保存间隔 (秒)，0 表示每次改动立即写盘
+        #[serde(default = "default_autosave_interval_secs")]
+        pub autosave_interval_secs: u64,
+        /// 对话记录写入后立即保存，避免崩溃时丢失
+        #[serde(default = "default_true")]
+        pub save_chat_immediately: bool,
+        #[serde(default = "default_render_cache_size")]
+        pub render_cache_size: usize,
+        /// 渲染缓存有效期 (秒)，0 为不过期
+        #[serde(default = "default_render_cache_ttl_secs")]
+        pub render_cache_ttl_secs: u64,
+        #[serde(default)]
+        pub blocked_keywords: Vec<String>,
+        /// 管理员 QQ 号，兼容写作 admin_users 的字符串列表
+        #[serde(default, alias = "admin_users", deserialize_with = "de_user_ids")]
+        pub admins: Vec<i64>,
+        /// 非空时仅名单内用户 (及 admins) 可使用插件
+        #[serde(default)]
+        pub whitelist: Vec<i64>,
+        /// 名单内用户的消息直接忽略
+        #[serde(default)]
+        pub blacklist: Vec<i64>,
+        #[serde(default = "default_warn_token_threshold")]
+        pub warn_token_threshold: usize,
+        #[serde(default = "default_tool_timeout")]
+        pub tool_timeout_secs: u64,
+        #[serde(default = "default_auto_fill_concurrency")]
+        pub auto_fill_concurrency: usize,
+        /// 对话请求的默认超时秒数，0 为不限时
+        #[serde(default = "default_timeout")]
+        pub timeout_secs: u64,
+        /// 审计日志超过该字节数时轮转为 audit.1.jsonl
+        #[serde(default = "default_audit_max_bytes")]
+        pub audit_max_bytes: u64,
+        /// 为 false 时允许任意 API 密钥 (Ollama 等本地服务)
+        #[serde(default = "default_true")]
+        pub require_sk_prefix: bool,
+        /// 回复时是否引用触发消息
+        #[serde(default = "default_true")]
+        pub reply_with_quote: bool,
+        /// 开启后将语音消息转写为文字作为输入
+        #[serde(default)]
+        pub enable_audio: bool,
+        /// 语音转写使用的模型
+        #[serde(default = "default_transcribe_model")]
+        pub transcribe_model: String,
+        /// 手动调整过顺序后，列表组内按 agents 中的顺序展示，否则按名称排序
+        #[serde(default)]
+        pub custom_agent_order: bool,
+        /// 单次对话中内置工具的最大调用轮数
+        #[serde(default = "default_max_tool_rounds")]
+        pub max_tool_rounds: usize,
+        /// 设置后 //users 列表中的 QQ 号以加盐哈希显示
+        #[serde(default)]
+        pub uid_hash_salt: Option<String>,
+        /// 查看全部历史时以合并转发发送，每条历史一个节点
+        #[serde(default)]
+        pub use_forward: bool,
+        /// API 请求使用的 HTTP(S) 代理，如 http://127.0.0.1:7890
+        #[serde(default)]
+        pub proxy: Option<String>,
+        /// 群聊回复时 @ 触发的用户
+        #[serde(default)]
+        pub reply_at: bool,
+        /// 收到对话时给消息贴的表情 ID，设为 null 不贴
+        #[serde(default = "default_react_emoji")]
+        pub react_emoji: Option<String>,
+        /// 用户首次与任一智能体对话时先发送的欢迎语
+        #[serde(default)]
+        pub welcome_message: Option<String>,
+    }
+
+    fn default_approval_timeout() -> u64 {
+        600
+    }
+
+    fn default_max_concurrent() -> usize {
+        8
+    }
+
+    fn default_model_cache_ttl() -> u64 {
+        3600
+    }
+
+    fn default_render_pool_size() -> usize {
+        2
+    }
+
+    fn default_model_keywords() -> Vec<String> {
+        crate::utils::MODEL_KEYWORDS
+            .iter()
+            .map(|k| k.to_string())
+            .collect()
+    }
+
+    fn default_react_emoji() -> Option<String> {
+        Some("124".to_string())
+    }
+
+    fn default_transcribe_model() -> String {
+        "whisper-1".to_string()
+    }
+
+    fn default_autosave_interval_secs() -> u64 {
+        30
+    }
+
+    fn default_models_page_size() -> usize {
+        50
+    }
+
+    fn default_chunk_threshold() -> usize {
+        1500
+    }
+
+    fn default_max_text_len() -> usize {
+        3000
+    }
+
+    fn default_render_cache_size() -> usize {
+        50
+    }
+
+    fn default_render_cache_ttl_secs() -> u64 {
+        300
+    }
+
+    fn default_warn_token_threshold() -> usize {
+        100_000
+    }
+
+    fn default_tool_timeout() -> u64 {
+        300
+    }
+
+    fn default_auto_fill_concurrency() -> usize {
+        5
+    }
+
+    fn default_timeout() -> u64 {
+        300
+    }
+
+    fn default_audit_max_bytes() -> u64 {
+        10 * 1024 * 1024
+    }
+
+    /// 同时接受数字与数字字符串形式的 QQ 号
+    fn de_user_ids<'de, D>(d: D) -> Result<Vec<i64>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Id {
+            Num(i64),
+            Str(String),
+        }
+        Vec::<Id>::deserialize(d)?
+            .into_iter()
+            .map(|id| match id {
+                Id::Num(n) => Ok(n),
+                Id::Str(s) => s.trim().parse().map_err(serde::de::Error::custom),
+            })
+            .collect()
+    }
+
+    fn default_true() -> bool {
+        true
+    }
+
+    fn default_max_tool_rounds() -> usize {
+        5
+    }
+
+    /// 重大操作前自动保存的历史快照
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct HistorySnapshot {
+        pub created_at: i64,
+        pub reason: String,
+        pub messages: Vec<ChatMessage>,
+    }
+
+    /// 旧版全局公有历史迁移后所在的桶；没有消息会映射到这里，需管理员用 /global 认领到某个群
+    pub const GLOBAL_BUCKET: &str = "global";
+
+    /// 审计日志中的一条指令记录
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct AuditEntry {
+        pub ts: i64,
+        pub user_id: i64,
+        pub group_id: Option<i64>,
+        pub agent: String,
+        pub action: String,
+        pub private: bool,
+    }
+
+    /// 会话历史的唯一键: 公有为 "智能体:public:桶"，私有为 "智能体:private:uid"
+    pub fn history_key(agent: &str, private: bool, owner: &str) -> String {
+        if private {
+            format!("{}:private:{}", agent, owner)
+        } else {
+            format!("{}:public:{}", agent, owner)
+        }
     }
 
     #[derive(Debug, Default)]
     pub struct GeneratingState {
+        /// 键为 "智能体:桶"
         pub public: HashSet<String>,
         pub private: HashMap<String, HashSet<String>>,
     }
 
     impl GeneratingState {
-        pub fn is_generating(&self, agent: &str, private: bool, uid: &str) -> bool {
+        pub fn is_generating(&self, agent: &str, private: bool, owner: &str) -> bool {
             if private {
                 self.private
                     .get(agent)
-                    .map(|s| s.contains(uid))
+                    .map(|s| s.contains(owner))
                     .unwrap_or(false)
             } else {
-                self.public.contains(agent)
+                self.public.contains(&format!("{}:{}", agent, owner))
             }
         }
 
-        pub fn set_generating(&mut self, agent: &str, private: bool, uid: &str, generating: bool) {
+        pub fn set_generating(
+            &mut self,
+            agent: &str,
+            private: bool,
+            owner: &str,
+            generating: bool,
+        ) {
             if private {
                 let set = self.private.entry(agent.to_string()).or_default();
                 if generating {
-                    set.insert(uid.to_string());
+                    set.insert(owner.to_string());
                 } else {
-                    set.remove(uid);
+                    set.remove(owner);
                 }
             } else if generating {
-                self.public.insert(agent.to_string());
+                self.public.insert(format!("{}:{}", agent, owner));
             } else {
-                self.public.remove(agent);
+                self.public.remove(&format!("{}:{}", agent, owner));
             }
         }
     }
@@ -175,95 +877,457 @@ mod types {
 
 // --- 工具函数 ---
 mod utils {
-    use cdp_html_shot::{Browser, CaptureOptions, Viewport};
+    use super::messages::{self, Msg};
+    use cdp_html_shot::{Browser, CaptureOptions, Tab, Viewport};
     use kovi::bot::message::Message;
     use kovi::tokio::time::{self, Duration};
-    use pulldown_cmark::{Options, Parser, html};
+    use kovi_plugin_expand_napcat::NapCatApi;
+    use pulldown_cmark::{Event, Options, Parser, html};
     use regex::Regex;
-    use std::sync::OnceLock;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{HashMap, VecDeque};
+    use std::hash::{Hash, Hasher};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Mutex, OnceLock};
 
     pub static RE_API: OnceLock<Regex> = OnceLock::new();
     pub static RE_IDX: OnceLock<Regex> = OnceLock::new();
+    pub static RE_FORK: OnceLock<Regex> = OnceLock::new();
+    pub static RE_NAMED_ARG: OnceLock<Regex> = OnceLock::new();
+    pub static RE_PROMPT_BLOCK: OnceLock<Regex> = OnceLock::new();
+    static RE_CODE_BLOCK: OnceLock<Regex> = OnceLock::new();
+    static RE_IMAGE_MD: OnceLock<Regex> = OnceLock::new();
+    static RE_IMAGE_URL: OnceLock<Regex> = OnceLock::new();
+    static RE_IMAGE_ANY: OnceLock<Regex> = OnceLock::new();
+    static RE_VIDEO: OnceLock<Regex> = OnceLock::new();
+
+    // 以下正则在渲染、导出、构建请求等热路径上反复使用，只编译一次。
+    // 每次 Regex::new 需数十微秒，长历史逐条格式化时可省去同等次数的编译。
+
+    /// 内嵌 base64 图片 `![..](data:image/..)`，第 1 组为数据 URI
+    pub fn image_md_re() -> &'static Regex {
+        RE_IMAGE_MD.get_or_init(|| Regex::new(r"!\[.*?\]\((data:image/[^\s\)]+)\)").unwrap())
+    }
 
-    pub const MODEL_KEYWORDS: &[&str] = &[
-        "gpt-5", "claude", "gemini-3", "deepseek", "kimi", "grok-4", "banana", "sora-2",
-    ];
-
-    /// 全角转半角
-    pub fn normalize(s: &str) -> String {
-        s.chars()
-            .map(|c| match c {
-                '！' => '!',
-                '＠' => '@',
-                '＃' => '#',
-                '＄' => '$',
-                '％' => '%',
-                '＊' => '*',
-                '（' => '(',
-                '）' => ')',
-                '－' => '-',
-                '＋' => '+',
-                '：' => ':',
-                '；' => ';',
-                '“' | '”' => '"',
-                '‘' | '’' => '\'',
-                '，' => ',',
-                '。' => '.',
-                '？' => '?',
-                '～' => '~',
-                '＿' => '_',
-                '＆' => '&',
-                '／' => '/',
-                '＝' => '=',
-                _ => c,
-            })
-            .collect()
+    /// Markdown 图片 (网络链接或数据 URI)，第 1 组为地址
+    pub fn image_url_re() -> &'static Regex {
+        RE_IMAGE_URL
+            .get_or_init(|| Regex::new(r"!\[.*?\]\(((?:https?://|data:image/)[^\s\)]+)\)").unwrap())
     }
 
-    /// 解析 API 配置
-    pub fn parse_api(text: &str) -> Option<(String, String)> {
-        let re = RE_API.get_or_init(|| {
-            Regex::new(r"(?s)^(https?://\S+)\s+(sk-\S+)$|^(sk-\S+)\s+(https?://\S+)$").unwrap()
-        });
-        let t = text.trim();
-        re.captures(t).and_then(|c| {
-            c.get(1)
-                .zip(c.get(2))
-                .map(|(u, k)| (u.as_str().to_string(), k.as_str().to_string()))
-                .or_else(|| {
-                    c.get(3)
-                        .zip(c.get(4))
-                        .map(|(k, u)| (u.as_str().to_string(), k.as_str().to_string()))
-                })
+    /// Markdown 图片或裸图片链接，Markdown 形式时第 1 组为地址
+    pub fn image_any_re() -> &'static Regex {
+        RE_IMAGE_ANY.get_or_init(|| {
+            Regex::new(
+                r"!\[.*?\]\(((?:https?://|data:image/)[^\s\)]+)\)|(?:https?://[^\s]+\.(?:png|jpg|jpeg|gif|webp|bmp))",
+            )
+            .unwrap()
         })
     }
 
-    /// 解析索引 (1, 1-5, 1,3,5)
-    pub fn parse_indices(s: &str) -> Vec<usize> {
-        let s = s.replace('，', ",");
-        let re = RE_IDX.get_or_init(|| Regex::new(r"(\d+)(?:-(\d+))?").unwrap());
-        let mut v = Vec::new();
-        for c in re.captures_iter(&s) {
-            if let Some(start) = c.get(1).and_then(|m| m.as_str().parse().ok()) {
-                if let Some(end) = c.get(2).and_then(|m| m.as_str().parse().ok()) {
-                    v.extend(start..=end);
-                } else {
-                    v.push(start);
+    /// 视频下载链接 `[download video](url)`，第 1 组为地址
+    pub fn video_re() -> &'static Regex {
+        RE_VIDEO.get_or_init(|| Regex::new(r"\[download video\]\((https?://[^\s\)]+)\)").unwrap())
+    }
+
+    /// 以盐为密钥对用户 QQ 做 HMAC-SHA256，仅用于展示时隐藏真实号码
+    /// 结果跨版本、跨平台稳定，取前 16 位十六进制
+    pub fn hash_uid(uid: &str, salt: &str) -> String {
+        hmac_sha256(salt.as_bytes(), uid.as_bytes())[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut k = [0u8; 64];
+        if key.len() > 64 {
+            k[..32].copy_from_slice(&sha256(key));
+        } else {
+            k[..key.len()].copy_from_slice(key);
+        }
+        let mut inner: Vec<u8> = k.iter().map(|b| b ^ 0x36).collect();
+        inner.extend_from_slice(data);
+        let mut outer: Vec<u8> = k.iter().map(|b| b ^ 0x5c).collect();
+        outer.extend_from_slice(&sha256(&inner));
+        sha256(&outer)
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        const K: [u32; 64] = [
+            0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+            0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+            0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+            0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+            0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+            0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+            0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+            0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+            0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+            0xc67178f2,
+        ];
+        let mut h: [u32; 8] = [
+            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+            0x5be0cd19,
+        ];
+        let mut msg = data.to_vec();
+        msg.push(0x80);
+        while msg.len() % 64 != 56 {
+            msg.push(0);
+        }
+        msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+        for block in msg.chunks(64) {
+            let mut w = [0u32; 64];
+            for (i, word) in block.chunks(4).enumerate() {
+                w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            }
+            for i in 16..64 {
+                let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+                let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+                w[i] = w[i - 16]
+                    .wrapping_add(s0)
+                    .wrapping_add(w[i - 7])
+                    .wrapping_add(s1);
+            }
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+            for i in 0..64 {
+                let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+                let ch = (e & f) ^ (!e & g);
+                let t1 = hh
+                    .wrapping_add(s1)
+                    .wrapping_add(ch)
+                    .wrapping_add(K[i])
+                    .wrapping_add(w[i]);
+                let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+                let maj = (a & b) ^ (a & c) ^ (b & c);
+                let t2 = s0.wrapping_add(maj);
+                hh = g;
+                g = f;
+                f = e;
+                e = d.wrapping_add(t1);
+                d = c;
+                c = b;
+                b = a;
+                a = t1.wrapping_add(t2);
+            }
+            for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+                *x = x.wrapping_add(y);
+            }
+        }
+        let mut out = [0u8; 32];
+        for (chunk, v) in out.chunks_mut(4).zip(h) {
+            chunk.copy_from_slice(&v.to_be_bytes());
+        }
+        out
+    }
+
+    /// 提取 Markdown 围栏代码块，返回 (语言, 代码)，未标注语言时为空串
+    pub fn extract_code_blocks(content: &str) -> Vec<(String, String)> {
+        let re = RE_CODE_BLOCK
+            .get_or_init(|| Regex::new(r"(?s)```([\w+#.-]*)[^\n]*\n(.*?)\n?```").unwrap());
+        re.captures_iter(content)
+            .map(|cap| (cap[1].to_lowercase(), cap[2].to_string()))
+            .filter(|(_, code)| !code.trim().is_empty())
+            .collect()
+    }
+
+    /// 代码语言对应的文件扩展名
+    pub fn code_extension(lang: &str) -> &str {
+        match lang {
+            "" | "text" | "plain" => "txt",
+            "rust" => "rs",
+            "python" | "py" => "py",
+            "javascript" | "js" => "js",
+            "typescript" | "ts" => "ts",
+            "bash" | "sh" | "shell" | "zsh" => "sh",
+            "c++" | "cpp" => "cpp",
+            "c#" | "csharp" => "cs",
+            "kotlin" => "kt",
+            "ruby" => "rb",
+            "golang" => "go",
+            "markdown" => "md",
+            "yml" => "yaml",
+            "powershell" => "ps1",
+            other => other,
+        }
+    }
+
+    /// 默认的模型过滤关键字，可通过 model_keywords 配置
+    pub const MODEL_KEYWORDS: &[&str] = &[
+        "gpt-5", "claude", "gemini-3", "deepseek", "kimi", "grok-4", "banana", "sora-2",
+    ];
+
+    /// 绘图模型名称特征
+    const IMAGE_MODEL_KEYWORDS: &[&str] = &[
+        "banana",
+        "sora",
+        "dall-e",
+        "gpt-image",
+        "imagen",
+        "flux",
+        "seedream",
+    ];
+
+    pub fn is_image_model(model: &str) -> bool {
+        let m = model.to_lowercase();
+        IMAGE_MODEL_KEYWORDS.iter().any(|k| m.contains(k))
+    }
+
+    /// 只能走 images 接口 (而非对话接口) 的绘图模型
+    pub fn uses_images_endpoint(model: &str) -> bool {
+        let m = model.to_lowercase();
+        ["dall-e", "gpt-image", "image-gen"]
+            .iter()
+            .any(|k| m.contains(k))
+    }
+
+    /// 超过该长度的 base64 图片先落盘再以文件路径发送 (约 3 MB)
+    const MAX_INLINE_IMAGE_B64: usize = 4 * 1024 * 1024;
+
+    /// 大图落盘目录，启动时写入
+    static IMAGE_DIR: OnceLock<std::path::PathBuf> = OnceLock::new();
+
+    pub fn set_image_dir(dir: std::path::PathBuf) {
+        let _ = IMAGE_DIR.set(dir);
+    }
+
+    /// 将图片链接转换为消息段可用的来源：普通链接原样返回，base64 过大时写入文件
+    pub fn image_source(url: &str) -> Option<String> {
+        let Some(rest) = url.strip_prefix("data:") else {
+            return Some(url.to_string());
+        };
+        let (meta, data) = rest.split_once(',')?;
+        if data.len() <= MAX_INLINE_IMAGE_B64 {
+            return Some(format!("base64://{}", data));
+        }
+        use base64::Engine;
+        let bytes = match base64::engine::general_purpose::STANDARD.decode(data) {
+            Ok(b) => b,
+            Err(e) => {
+                kovi::log::error!("图片解码失败: {}", e);
+                return None;
+            }
+        };
+        let ext = meta
+            .strip_prefix("image/")
+            .and_then(|m| m.split(';').next())
+            .filter(|e| !e.is_empty())
+            .unwrap_or("png");
+        let dir = IMAGE_DIR.get_or_init(|| std::env::temp_dir().join("kovi-plugin-oai"));
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            kovi::log::error!("创建图片目录失败: {}", e);
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let path = dir.join(format!("{:016x}.{}", hasher.finish(), ext));
+        if !path.exists()
+            && let Err(e) = std::fs::write(&path, bytes)
+        {
+            kovi::log::error!("写入图片失败: {}", e);
+            return None;
+        }
+        let abs = path.canonicalize().unwrap_or(path);
+        Some(format!("file://{}", abs.display()))
+    }
+
+    /// 全角转半角
+    pub fn normalize(s: &str) -> String {
+        s.chars()
+            .map(|c| match c {
+                '！' => '!',
+                '＠' => '@',
+                '＃' => '#',
+                '＄' => '$',
+                '％' => '%',
+                '＊' => '*',
+                '（' => '(',
+                '）' => ')',
+                '－' => '-',
+                '＋' => '+',
+                '：' => ':',
+                '；' => ';',
+                '“' | '”' => '"',
+                '‘' | '’' => '\'',
+                '，' => ',',
+                '。' => '.',
+                '？' => '?',
+                '～' => '~',
+                '＿' => '_',
+                '＆' => '&',
+                '｜' => '|',
+                '／' => '/',
+                '＝' => '=',
+                '〜' => '~',
+                '　' => ' ',
+                '【' => '[',
+                '】' => ']',
+                '『' | '』' => '"',
+                // 全角数字与全角英文字母 (与半角相差固定偏移 0xFEE0)
+                '０'..='９' | 'Ａ'..='Ｚ' | 'ａ'..='ｚ' => {
+                    char::from_u32(c as u32 - 0xFEE0).unwrap_or(c)
                 }
+                _ => c,
+            })
+            .collect()
+    }
+
+    /// 解析 API 配置
+    pub fn parse_api(text: &str) -> Option<(String, String)> {
+        let re = RE_API.get_or_init(|| {
+            // 地址在前时密钥可为任意占位符 (如 Ollama 的 "."), 密钥在前时需以 sk- 开头
+            Regex::new(r"(?s)^(https?://\S+)\s+(\S+)$|^(sk-\S+)\s+(https?://\S+)$").unwrap()
+        });
+        let t = text.trim();
+        re.captures(t).and_then(|c| {
+            c.get(1)
+                .zip(c.get(2))
+                .map(|(u, k)| (u.as_str().to_string(), k.as_str().to_string()))
+                .or_else(|| {
+                    c.get(3)
+                        .zip(c.get(4))
+                        .map(|(k, u)| (u.as_str().to_string(), k.as_str().to_string()))
+                })
+        })
+    }
+
+    /// 多数兼容端点允许的停止词数量上限
+    pub const MAX_STOP: usize = 4;
+
+    /// 解析逗号分隔的停止词，支持 \n \t \, \\ 转义
+    pub fn parse_stop_list(s: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        let mut cur = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => cur.push('\n'),
+                    Some('t') => cur.push('\t'),
+                    Some(other) => cur.push(other),
+                    None => cur.push('\\'),
+                },
+                ',' | '，' => out.push(std::mem::take(&mut cur)),
+                _ => cur.push(c),
+            }
+        }
+        out.push(cur);
+        out.retain(|s| !s.is_empty());
+        out
+    }
+
+    /// 停止词的可读形式，换行等以转义显示
+    pub fn escape_stop(s: &str) -> String {
+        s.replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\t', "\\t")
+            .replace(',', "\\,")
+    }
+
+    /// 总长未知时区间端点的上限，避免超大区间展开耗尽内存
+    const MAX_INDEX: usize = 10_000;
+
+    /// 解析索引 (1, 1-5, 5-1 倒序, 1,3,5, 1-10:2)，保持书写顺序并去重
+    /// total_len > 0 时支持负索引：-1 为最后一条，-3--1 为倒数三条
+    /// 区间端点截断到 total_len (未知时为 MAX_INDEX)
+    pub fn parse_indices(s: &str, total_len: usize) -> Vec<usize> {
+        let s = s.replace('，', ",").replace('：', ":");
+        let re = RE_IDX.get_or_init(|| Regex::new(r"(-?\d+)(?:-(-?\d+)(?::(\d+))?)?").unwrap());
+        let resolve = |m: &str| -> Option<usize> {
+            let n: i64 = m.parse().ok()?;
+            if n >= 0 {
+                return usize::try_from(n).ok();
+            }
+            let back = n.unsigned_abs() as usize;
+            (back <= total_len).then(|| total_len + 1 - back)
+        };
+        let mut v = Vec::new();
+        for c in re.captures_iter(&s) {
+            let Some(start) = resolve(&c[1]) else {
+                continue;
+            };
+            let Some(end) = c.get(2) else {
+                v.push(start);
+                continue;
+            };
+            let Some(end) = resolve(end.as_str()) else {
+                continue;
+            };
+            // 步长为 0 时忽略该区间
+            let step = match c.get(3) {
+                Some(m) => match m.as_str().parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => continue,
+                },
+                None => 1,
+            };
+            let limit = if total_len > 0 { total_len } else { MAX_INDEX };
+            let (start, end) = (start.min(limit), end.min(limit));
+            if start <= end {
+                v.extend((start..=end).step_by(step));
+            } else {
+                v.extend((end..=start).rev().step_by(step));
             }
         }
-        v.sort();
-        v.dedup();
+        let mut seen = std::collections::HashSet::new();
+        v.retain(|i| seen.insert(*i));
         v
     }
 
+    /// 解析工具结果提交：消息须以 `=>` 开头，否则视为普通对话返回 None
+    /// 多个调用时以 `=>序号 结果` 逐行对应，未写序号的按顺序填入空位
+    pub fn parse_tool_results(prompt: &str, calls: usize) -> Option<Vec<Option<String>>> {
+        let prompt = prompt.trim();
+        if !prompt.starts_with("=>") {
+            return None;
+        }
+        let mut segments: Vec<String> = Vec::new();
+        for line in prompt.lines() {
+            match line.trim_start().strip_prefix("=>") {
+                Some(rest) => segments.push(rest.to_string()),
+                None => {
+                    if let Some(last) = segments.last_mut() {
+                        last.push('\n');
+                        last.push_str(line);
+                    }
+                }
+            }
+        }
+        let mut results = vec![None; calls];
+        let mut unnumbered = Vec::new();
+        for seg in segments {
+            let seg = seg.trim();
+            let digits = seg.chars().take_while(|c| c.is_ascii_digit()).count();
+            let numbered = calls > 1
+                && digits > 0
+                && seg[digits..].chars().next().is_none_or(char::is_whitespace);
+            if numbered
+                && let Ok(n) = seg[..digits].parse::<usize>()
+                && (1..=calls).contains(&n)
+            {
+                results[n - 1] = Some(seg[digits..].trim().to_string());
+            } else {
+                unnumbered.push(seg.to_string());
+            }
+        }
+        let mut rest = unnumbered.into_iter();
+        for slot in results.iter_mut().filter(|r| r.is_none()) {
+            *slot = rest.next();
+        }
+        Some(results)
+    }
+
     /// 过滤模型列表
-    pub fn filter_models(models: &[String]) -> Vec<String> {
+    /// 按关键字过滤模型，关键字为空时保留全部
+    pub fn filter_models(models: &[String], keywords: &[String]) -> Vec<String> {
+        if keywords.is_empty() {
+            return models.to_vec();
+        }
         models
             .iter()
             .filter(|m| {
                 let lower = m.to_lowercase();
-                MODEL_KEYWORDS.iter().any(|kw| lower.contains(kw))
+                keywords.iter().any(|kw| lower.contains(&kw.to_lowercase()))
             })
             .cloned()
             .collect()
@@ -281,55 +1345,227 @@ mod utils {
         }
     }
 
-    pub async fn render_md(md: &str, title: &str) -> anyhow::Result<String> {
-        let mut opts = Options::empty();
-        opts.insert(Options::ENABLE_STRIKETHROUGH);
-        opts.insert(Options::ENABLE_TABLES);
-        let parser = Parser::new_ext(md, opts);
-        let mut html_body = String::new();
-        html::push_html(&mut html_body, parser);
+    /// 渲染主题，启动时及 /theme 切换时写入
+    static RENDER_THEME: std::sync::RwLock<String> = std::sync::RwLock::new(String::new());
+
+    pub fn set_render_theme(theme: &str) {
+        if let Ok(mut t) = RENDER_THEME.write() {
+            *t = theme.to_string();
+        }
+    }
+
+    fn render_theme() -> String {
+        RENDER_THEME.read().map(|t| t.clone()).unwrap_or_default()
+    }
+
+    /// 空闲标签页池，避免每次渲染都新建/关闭标签页
+    static TAB_POOL: OnceLock<Mutex<Vec<Tab>>> = OnceLock::new();
+    static TAB_POOL_SIZE: AtomicUsize = AtomicUsize::new(2);
+
+    fn tab_pool() -> &'static Mutex<Vec<Tab>> {
+        TAB_POOL.get_or_init(|| Mutex::new(Vec::new()))
+    }
+
+    pub fn set_render_pool_size(size: usize) {
+        TAB_POOL_SIZE.store(size, Ordering::Relaxed);
+    }
+
+    fn take_tab() -> Option<Tab> {
+        tab_pool().lock().ok()?.pop()
+    }
+
+    /// 归还标签页，池满时直接关闭
+    async fn release_tab(tab: Tab) {
+        let overflow = match tab_pool().lock() {
+            Ok(mut pool) if pool.len() < TAB_POOL_SIZE.load(Ordering::Relaxed) => {
+                pool.push(tab);
+                None
+            }
+            _ => Some(tab),
+        };
+        if let Some(tab) = overflow {
+            let _ = tab.close().await;
+        }
+    }
+
+    /// 关闭并清空标签页池
+    pub async fn clear_tab_pool() {
+        let tabs: Vec<Tab> = match tab_pool().lock() {
+            Ok(mut pool) => pool.drain(..).collect(),
+            Err(_) => return,
+        };
+        for tab in tabs {
+            let _ = tab.close().await;
+        }
+    }
+
+    /// 渲染结果的内存 LRU 缓存，以 (内容, 标题, 主题) 的哈希为键，超过 ttl 的条目视为未命中
+    pub struct RenderCache {
+        capacity: usize,
+        ttl: Option<std::time::Duration>,
+        entries: HashMap<u64, (String, std::time::Instant)>,
+        order: VecDeque<u64>,
+        hits: u64,
+        misses: u64,
+    }
+
+    impl RenderCache {
+        pub fn new(capacity: usize) -> Self {
+            Self {
+                capacity,
+                ttl: None,
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                hits: 0,
+                misses: 0,
+            }
+        }
+
+        pub fn key(md: &str, title: &str, theme: &str) -> u64 {
+            let mut h = DefaultHasher::new();
+            (md, title, theme).hash(&mut h);
+            h.finish()
+        }
+
+        pub fn get(&mut self, key: u64) -> Option<String> {
+            let expired = match (self.entries.get(&key), self.ttl) {
+                (Some((_, at)), Some(ttl)) => at.elapsed() > ttl,
+                _ => false,
+            };
+            if expired {
+                self.entries.remove(&key);
+                self.order.retain(|k| *k != key);
+            }
+            match self.entries.get(&key) {
+                Some((v, _)) => {
+                    let v = v.clone();
+                    self.order.retain(|k| *k != key);
+                    self.order.push_back(key);
+                    self.hits += 1;
+                    Some(v)
+                }
+                None => {
+                    self.misses += 1;
+                    None
+                }
+            }
+        }
+
+        pub fn put(&mut self, key: u64, value: String) {
+            if self.capacity == 0 {
+                return;
+            }
+            if self
+                .entries
+                .insert(key, (value, std::time::Instant::now()))
+                .is_none()
+            {
+                self.order.push_back(key);
+            }
+            while self.order.len() > self.capacity {
+                if let Some(old) = self.order.pop_front() {
+                    self.entries.remove(&old);
+                }
+            }
+        }
+
+        pub fn set_capacity(&mut self, capacity: usize) {
+            self.capacity = capacity;
+            while self.order.len() > capacity {
+                if let Some(old) = self.order.pop_front() {
+                    self.entries.remove(&old);
+                }
+            }
+        }
+
+        /// 0 表示不过期
+        pub fn set_ttl(&mut self, secs: u64) {
+            self.ttl = (secs > 0).then(|| std::time::Duration::from_secs(secs));
+        }
 
-        let css = r#"
+        pub fn stats(&self) -> (u64, u64) {
+            (self.hits, self.misses)
+        }
+    }
+
+    static RENDER_CACHE: OnceLock<Mutex<RenderCache>> = OnceLock::new();
+
+    fn render_cache() -> &'static Mutex<RenderCache> {
+        RENDER_CACHE.get_or_init(|| Mutex::new(RenderCache::new(50)))
+    }
+
+    pub fn set_render_cache_size(size: usize) {
+        if let Ok(mut cache) = render_cache().lock() {
+            cache.set_capacity(size);
+        }
+    }
+
+    pub fn set_render_cache_ttl(secs: u64) {
+        if let Ok(mut cache) = render_cache().lock() {
+            cache.set_ttl(secs);
+        }
+    }
+
+    /// 可用的渲染主题
+    pub const THEMES: [&str; 3] = ["light", "dark", "sepia"];
+
+    /// 主题对应的 CSS 变量，未知主题回退到 light
+    pub fn theme_css(theme: &str) -> &'static str {
+        match theme {
+            "dark" => {
+                ":root{--page-bg:#121212;--card-bg:#1e1e1e;--text:#e0e0e0;--muted:#9e9e9e;--border:#333;--border-strong:#444;--subtle-bg:#252525;--code-bg:#2c2c2c;--code-fg:#ff7ab2;--pre-bg:#161b22;--link:#58a6ff}"
+            }
+            "sepia" => {
+                ":root{--page-bg:#efe6d5;--card-bg:#f8f1e3;--text:#5b4636;--muted:#8a7560;--border:#e3d6bf;--border-strong:#d6c6a8;--subtle-bg:#f1e7d4;--code-bg:#eadfc8;--code-fg:#a0522d;--pre-bg:#efe4cd;--link:#8b5a2b}"
+            }
+            _ => {
+                ":root{--page-bg:#f5f5f5;--card-bg:#fff;--text:#333;--muted:#888;--border:#eee;--border-strong:#ddd;--subtle-bg:#fafafa;--code-bg:#f0f0f0;--code-fg:#d63384;--pre-bg:#f6f8fa;--link:#0066cc}"
+            }
+        }
+    }
+
+    const BASE_CSS: &str = r#"
  *{box-sizing:border-box}
- body{font-family:-apple-system,BlinkMacSystemFont,"Segoe UI","PingFang SC","Hiragino Sans GB","Microsoft YaHei",Helvetica,Arial,sans-serif;font-size:15px;line-height:1.6;background:#f5f5f5;color:#333;padding:0;margin:0}
- .md{background:#fff;padding:16px 14px;margin:0;max-width:480px;width:90vw;word-wrap:break-word;overflow-wrap:break-word}
- .title{font-size:13px;color:#888;border-bottom:1px solid #eee;padding-bottom:10px;margin-bottom:14px;font-weight:500}
+ body{font-family:-apple-system,BlinkMacSystemFont,"Segoe UI","PingFang SC","Hiragino Sans GB","Microsoft YaHei",Helvetica,Arial,sans-serif;font-size:15px;line-height:1.6;background:var(--page-bg);color:var(--text);padding:0;margin:0}
+ .md{background:var(--card-bg);padding:16px 14px;margin:0;max-width:480px;width:90vw;word-wrap:break-word;overflow-wrap:break-word}
+ .title{font-size:13px;color:var(--muted);border-bottom:1px solid var(--border);padding-bottom:10px;margin-bottom:14px;font-weight:500}
  h1,h2,h3{margin:16px 0 10px;font-weight:600;line-height:1.4}
- h1{font-size:20px;border-bottom:2px solid #eee;padding-bottom:8px}
- h2{font-size:18px;border-bottom:1px solid #eee;padding-bottom:6px}
+ h1{font-size:20px;border-bottom:2px solid var(--border);padding-bottom:8px}
+ h2{font-size:18px;border-bottom:1px solid var(--border);padding-bottom:6px}
  h3{font-size:16px}
  p{margin:10px 0}
  table{border-collapse:collapse;margin:12px 0;width:100%;font-size:13px;display:block;overflow-x:auto}
- td,th{padding:8px 10px;border:1px solid #ddd;text-align:left}
- th{font-weight:600;background:#f8f9fa}
- tr:nth-child(2n){background:#fafafa}
- code{padding:2px 6px;background:#f0f0f0;border-radius:4px;font-family:"SF Mono",Consolas,"Liberation Mono",Menlo,monospace;font-size:13px;color:#d63384;white-space:pre-wrap;word-wrap:break-word;}
- pre{background:#f6f8fa;border-radius:8px;padding:12px;overflow-x:auto;margin:12px 0;white-space:pre-wrap;word-wrap:break-word;overflow-wrap: break-word;}
- pre code{background:none;padding:0;color:#333}
- blockquote{margin:12px 0;padding:8px 12px;color:#666;border-left:3px solid #ddd;background:#fafafa;border-radius:0 4px 4px 0}
+ td,th{padding:8px 10px;border:1px solid var(--border-strong);text-align:left}
+ th{font-weight:600;background:var(--subtle-bg)}
+ tr:nth-child(2n){background:var(--subtle-bg)}
+ code{padding:2px 6px;background:var(--code-bg);border-radius:4px;font-family:"SF Mono",Consolas,"Liberation Mono",Menlo,monospace;font-size:13px;color:var(--code-fg);white-space:pre-wrap;word-wrap:break-word;}
+ pre{background:var(--pre-bg);border-radius:8px;padding:12px;overflow-x:auto;margin:12px 0;white-space:pre-wrap;word-wrap:break-word;overflow-wrap: break-word;}
+ pre code{background:none;padding:0;color:var(--text)}
+ blockquote{margin:12px 0;padding:8px 12px;color:var(--muted);border-left:3px solid var(--border-strong);background:var(--subtle-bg);border-radius:0 4px 4px 0}
  img{max-width:100%;height:auto;border-radius:6px;margin:8px 0}
  ul,ol{padding-left:20px;margin:10px 0}
  li{margin:4px 0}
- hr{border:none;border-top:1px solid #eee;margin:16px 0}
- a{color:#0066cc;text-decoration:none}
+ hr{border:none;border-top:1px solid var(--border);margin:16px 0}
+ a{color:var(--link);text-decoration:none}
  strong{font-weight:600}
- .agent-card{background:#fafbfc;border:1px solid #e8e8e8;border-radius:8px;padding:12px;margin:10px 0}
- .agent-name{font-size:16px;font-weight:600;color:#333;margin-bottom:8px}
- .agent-info{font-size:13px;color:#666;line-height:1.8}
+ .agent-card{background:var(--subtle-bg);border:1px solid var(--border);border-radius:8px;padding:12px;margin:10px 0}
+ .agent-name{font-size:16px;font-weight:600;color:var(--text);margin-bottom:8px}
+ .agent-info{font-size:13px;color:var(--muted);line-height:1.8}
  .agent-info code{font-size:12px}
  .model-group{margin-bottom:16px;break-inside:avoid;}
- .model-header{background:#f0f2f5;color:#444;padding:6px 10px;border-radius:6px;font-weight:600;font-size:13px;margin-bottom:8px;display:flex;justify-content:space-between;align-items:center;border-left:3px solid #0066cc;}
+ .model-header{background:var(--code-bg);color:var(--text);padding:6px 10px;border-radius:6px;font-weight:600;font-size:13px;margin-bottom:8px;display:flex;justify-content:space-between;align-items:center;border-left:3px solid #0066cc;}
  .model-count{background:rgba(0,0,0,0.05);color:#666;font-size:11px;padding:1px 6px;border-radius:4px;}
  .agent-grid{display:grid;/*手机端一行两列，充分利用宽度*/grid-template-columns:repeat(2,1fr);gap:8px;}
- .agent-mini{background:#fff;border:1px solid #eee;border-radius:6px;padding:8px;display:flex;flex-direction:column;justify-content:center;transition:background 0.2s;}
+ .agent-mini{background:var(--card-bg);border:1px solid var(--border);border-radius:6px;padding:8px;display:flex;flex-direction:column;justify-content:center;transition:background 0.2s;}
  .agent-mini-top{display:flex;align-items:center;margin-bottom:4px;}
  .agent-idx{background:#e6f0ff;color:#0066cc;font-size:10px;font-weight:700;min-width:18px;height:18px;border-radius:4px;display:flex;align-items:center;justify-content:center;margin-right:6px;flex-shrink:0;}
- .agent-mini-name{font-size:14px;font-weight:600;color:#333;overflow:hidden;white-space:nowrap;text-overflow:ellipsis;}
+ .agent-mini-name{font-size:14px;font-weight:600;color:var(--text);overflow:hidden;white-space:nowrap;text-overflow:ellipsis;}
  .agent-mini-desc{font-size:11px;color:#999;overflow:hidden;white-space:nowrap;text-overflow:ellipsis;}
+ .agent-mini-owner{font-size:10px;color:#bbb;margin-top:2px;overflow:hidden;white-space:nowrap;text-overflow:ellipsis;}
  .provider-section { margin-bottom: 20px; break-inside: avoid; }
  .provider-title { font-size: 14px; font-weight: 700; color: #555; margin-bottom: 8px; padding-left: 4px; border-left: 3px solid #666; line-height: 1.2; }
  .chip-container { display: flex; flex-wrap: wrap; gap: 8px; }
- .chip { background: #fff; border: 1px solid #ddd; border-radius: 6px; padding: 6px 10px; display: flex; align-items: center; font-size: 13px; color: #333; box-shadow: 0 1px 2px rgba(0,0,0,0.02); }
+ .chip { background: var(--card-bg); border: 1px solid var(--border-strong); border-radius: 6px; padding: 6px 10px; display: flex; align-items: center; font-size: 13px; color: var(--text); box-shadow: 0 1px 2px rgba(0,0,0,0.02); }
  .chip-idx { background: #f0f0f0; color: #666; font-size: 11px; padding: 2px 5px; border-radius: 4px; margin-right: 6px; font-family: monospace; font-weight: 600; }
  .chip-name { font-weight: 500; }
  .chip-badge { margin-left: 6px; background: #e6f0ff; color: #0066cc; font-size: 10px; padding: 1px 5px; border-radius: 10px; font-weight: 600; }
@@ -337,18 +1573,70 @@ mod utils {
   .mod-group { margin-bottom: 16px; break-inside: avoid; }
   .mod-title { font-size: 13px; font-weight: 700; color: #666; margin-bottom: 8px; text-transform: uppercase; letter-spacing: 0.5px; border-left: 3px solid #0066cc; padding-left: 6px; }
   .chip-box { display: flex; flex-wrap: wrap; gap: 8px; }
-  .chip { background: #fff; border: 1px solid #e0e0e0; border-radius: 6px; padding: 6px 10px; display: flex; align-items: center; font-size: 13px; color: #333; transition: all 0.2s; }
+  .chip { background: var(--card-bg); border: 1px solid var(--border-strong); border-radius: 6px; padding: 6px 10px; display: flex; align-items: center; font-size: 13px; color: var(--text); transition: all 0.2s; }
   .chip-idx { background: #f5f5f5; color: #888; font-size: 11px; padding: 2px 6px; border-radius: 4px; margin-right: 8px; font-family: monospace; font-weight: 600; }
   .chip-name { font-weight: 500; }
   /* 正在使用的模型的徽标样式 */
   .chip-bad { margin-left: 8px; background: #e6f7ff; color: #1890ff; font-size: 10px; padding: 2px 6px; border-radius: 10px; font-weight: 600; } "#;
+
+    pub async fn render_md(md: &str, title: &str) -> anyhow::Result<String> {
+        let theme = render_theme();
+        let key = RenderCache::key(md, title, &theme);
+        if let Ok(mut cache) = render_cache().lock()
+            && let Some(b64) = cache.get(key)
+        {
+            let (hits, misses) = cache.stats();
+            kovi::log::debug!("渲染缓存命中 (命中 {} / 未命中 {})", hits, misses);
+            return Ok(b64);
+        }
+        let b64 = render_uncached(md, title, &theme).await?;
+        if let Ok(mut cache) = render_cache().lock() {
+            cache.put(key, b64.clone());
+        }
+        Ok(b64)
+    }
+
+    async fn render_uncached(md: &str, title: &str, theme: &str) -> anyhow::Result<String> {
+        let mut opts = Options::empty();
+        opts.insert(Options::ENABLE_STRIKETHROUGH);
+        opts.insert(Options::ENABLE_TABLES);
+        let parser = Parser::new_ext(md, opts);
+        let mut html_body = String::new();
+        html::push_html(&mut html_body, parser);
+
+        let css = format!("{}{}", theme_css(theme), BASE_CSS);
         let html = format!(
             r#"<!DOCTYPE html><html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width,initial-scale=1"><style>{css}</style></head><body><div class="md"><div class="title">{title}</div>{html_body}</div></body></html>"#
         );
 
-        let browser = Browser::instance().await;
-        let tab = browser.new_tab().await?;
+        // 池中的标签页可能已失效，失败时丢弃并用新标签页重试一次
+        if let Some(tab) = take_tab() {
+            match capture(&tab, &html).await {
+                Ok(b64) => {
+                    release_tab(tab).await;
+                    return Ok(b64);
+                }
+                Err(_) => {
+                    let _ = tab.close().await;
+                }
+            }
+        }
+
+        let tab = Browser::instance().await.new_tab().await?;
+        match capture(&tab, &html).await {
+            Ok(b64) => {
+                release_tab(tab).await;
+                Ok(b64)
+            }
+            Err(e) => {
+                let _ = tab.close().await;
+                Err(e)
+            }
+        }
+    }
 
+    /// 在标签页中载入 HTML 并截取 .md 卡片
+    async fn capture(tab: &Tab, html: &str) -> anyhow::Result<String> {
         // 1. 设置初始视口
         // 宽度 600 以适应 .md max-width: 480px 的卡片设计
         // device_scale_factor: 2.0 提升截图清晰度
@@ -356,7 +1644,7 @@ mod utils {
         tab.set_viewport(&Viewport::new(width, 100).with_device_scale_factor(2.0))
             .await?;
 
-        tab.set_content(&html).await?;
+        tab.set_content(html).await?;
 
         time::sleep(Duration::from_millis(200)).await;
 
@@ -384,7 +1672,6 @@ mod utils {
             .screenshot_with_options(opts)
             .await?;
 
-        let _ = tab.close().await;
         Ok(b64)
     }
 
@@ -394,9 +1681,10 @@ mod utils {
         event: &std::sync::Arc<kovi::MsgEvent>,
         bot: &std::sync::Arc<kovi::RuntimeBot>,
         trigger_name: Option<&str>,
-    ) -> (String, Vec<String>) {
+    ) -> (String, Vec<String>, Vec<String>) {
         let mut quote_text = String::new();
         let mut imgs = Vec::new();
+        let mut records = Vec::new();
 
         // 1. 处理引用消息 (Reply)
         if let Some(reply) = event.message.iter().find(|s| s.type_ == "reply")
@@ -430,6 +1718,11 @@ mod utils {
                             imgs.push(u.to_string());
                         }
                     }
+                    "record" => {
+                        if let Some(f) = record_file(seg) {
+                            records.push(f);
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -465,6 +1758,10 @@ mod utils {
                 if let Some(u) = url {
                     imgs.push(u.to_string());
                 }
+            } else if seg.type_ == "record" {
+                if let Some(f) = record_file(seg) {
+                    records.push(f);
+                }
             } else if seg.type_ == "text" {
                 // 检查文本段中是否包含智能体名称
                 if let Some(name) = trigger_name
@@ -501,26 +1798,121 @@ mod utils {
             }
         }
 
-        (quote_text, imgs)
+        // 3. 保序去重 (引用图在前、当前消息图在后)，并过滤无法作为图片链接的地址
+        let mut seen = std::collections::HashSet::new();
+        imgs.retain(|u| {
+            if !is_media_url(u) {
+                kovi::log::warn!("忽略无效的图片地址: {}", truncate_str(u, 80));
+                return false;
+            }
+            seen.insert(u.clone())
+        });
+
+        (quote_text, imgs, records)
     }
-    /// 格式化历史记录
-    pub fn format_history(
-        hist: &[super::types::ChatMessage],
-        offset: usize,
-        text_mode: bool,
-    ) -> String {
-        let re = Regex::new(r"!\[.*?\]\((data:image/[^\s\)]+)\)").unwrap();
 
-        hist.iter()
-            .enumerate()
-            .map(|(i, m)| {
-                let emoji = match m.role.as_str() {
-                    "user" => "👤",
-                    "assistant" => "🤖",
-                    "system" => "⚙️",
-                    _ => "❓",
-                };
-                let time = chrono::DateTime::from_timestamp(m.timestamp, 0)
+    /// 语音段的文件标识：优先 file (可交给协议端转码)，其次 url
+    pub fn record_file(seg: &kovi::bot::message::Segment) -> Option<String> {
+        seg.data
+            .get("file")
+            .or(seg.data.get("url"))
+            .and_then(|v| v.as_str())
+            .filter(|f| !f.is_empty())
+            .map(|f| f.to_string())
+    }
+
+    /// 可直接交给模型的图片/视频地址：http(s) 链接或 data URL
+    fn is_media_url(url: &str) -> bool {
+        url.starts_with("http://") || url.starts_with("https://") || url.starts_with("data:")
+    }
+    /// 读取引用消息中的文件内容，返回 (文件名, 文本内容)
+    pub async fn get_reply_file(
+        event: &std::sync::Arc<kovi::MsgEvent>,
+        bot: &std::sync::Arc<kovi::RuntimeBot>,
+    ) -> anyhow::Result<(String, String)> {
+        let id = event
+            .message
+            .iter()
+            .find(|s| s.type_ == "reply")
+            .and_then(|s| s.data.get("id").and_then(|v| v.as_str()))
+            .and_then(|id| id.parse::<i32>().ok())
+            .ok_or_else(|| anyhow::anyhow!(Msg::QuoteFile.text()))?;
+
+        let ret = bot
+            .get_msg(id)
+            .await
+            .map_err(|_| anyhow::anyhow!(Msg::FetchQuoteFailed.text()))?;
+        let msg_data = ret
+            .data
+            .get("message")
+            .ok_or_else(|| anyhow::anyhow!(Msg::FetchQuoteFailed.text()))?;
+        let reply_msg = Message::from_value(msg_data.clone()).unwrap_or_default();
+
+        let seg = reply_msg
+            .iter()
+            .find(|s| s.type_ == "file")
+            .ok_or_else(|| anyhow::anyhow!(Msg::NotAFile.text()))?;
+        let fname = seg
+            .data
+            .get("file")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let file_id = seg
+            .data
+            .get("file_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow::anyhow!(Msg::FileInfoMissing.text()))?;
+
+        let info = bot
+            .get_file(file_id)
+            .await
+            .map_err(|_| anyhow::anyhow!(Msg::FetchFileFailed.text()))?;
+
+        // 优先读取本地路径，其次通过 URL 下载
+        if let Some(local) = info.data.get("file").and_then(|v| v.as_str())
+            && let Ok(text) = std::fs::read_to_string(local)
+        {
+            return Ok((fname, text));
+        }
+        if let Some(url) = info.data.get("url").and_then(|v| v.as_str())
+            && url.starts_with("http")
+        {
+            let text = reqwest::get(url).await?.text().await?;
+            return Ok((fname, text));
+        }
+        Err(anyhow::anyhow!(Msg::ReadFileFailed.text()))
+    }
+
+    /// 格式化历史记录
+    pub fn format_history(
+        hist: &[super::types::ChatMessage],
+        offset: usize,
+        text_mode: bool,
+    ) -> String {
+        format_history_entries(
+            hist.iter().enumerate().map(|(i, m)| (offset + i + 1, m)),
+            text_mode,
+        )
+    }
+
+    /// 按给定的原始序号 (1 起) 渲染不连续的历史条目
+    pub fn format_history_entries<'a>(
+        entries: impl Iterator<Item = (usize, &'a super::types::ChatMessage)>,
+        text_mode: bool,
+    ) -> String {
+        let re = image_md_re();
+
+        entries
+            .map(|(idx, m)| {
+                let emoji = match m.role.as_str() {
+                    "user" => "👤",
+                    "assistant" => "🤖",
+                    "system" => "⚙️",
+                    "tool" => "🔧",
+                    _ => "❓",
+                };
+                let time = chrono::DateTime::from_timestamp(m.timestamp, 0)
                     .map(|dt| {
                         use chrono::TimeZone;
                         chrono::Local
@@ -531,9 +1923,21 @@ mod utils {
                     .unwrap_or_default();
 
                 let mut body = m.content.clone();
+                // 字数不计内嵌的 base64 图片
+                let chars = re.replace_all(&m.content, "").chars().count();
+                let tokens = count_tokens_estimate(std::slice::from_ref(m));
 
                 if text_mode {
-                    body = re.replace_all(&body, "[图片]").to_string();
+                    body = re
+                        .replace_all(&body, Msg::ImagePlaceholder.text())
+                        .to_string();
+                }
+
+                for call in &m.tool_calls {
+                    if !body.is_empty() {
+                        body.push('\n');
+                    }
+                    body.push_str(&format!("🔧 {}({})", call.name, call.arguments));
                 }
 
                 if !m.images.is_empty() {
@@ -549,7 +1953,7 @@ mod utils {
                                 if u.starts_with("data:") {
                                     "- [Base64 Image]".to_string()
                                 } else {
-                                    format!("- [图片] {}", u)
+                                    format!("- {} {}", Msg::ImagePlaceholder.text(), u)
                                 }
                             })
                             .collect::<Vec<_>>()
@@ -567,15 +1971,527 @@ mod utils {
                 }
 
                 if body.trim().is_empty() {
-                    body = "(无内容)".to_string();
+                    body = Msg::NoContent.text().to_string();
                 }
 
-                format!("**#{} {} {}**\n{}", offset + i + 1, emoji, time, body)
+                let pin = if m.pinned { "📌 " } else { "" };
+                format!(
+                    "**{}#{} {} {} · {} · ~{}tok**\n{}",
+                    pin,
+                    idx,
+                    emoji,
+                    time,
+                    messages::char_count(chars),
+                    tokens,
+                    body
+                )
             })
             .collect::<Vec<_>>()
             .join("\n\n---\n\n")
     }
 
+    /// 解析相对时间范围 today / yesterday / Nd / Nh，返回 [起, 止) 时间戳
+    pub fn parse_time_range(spec: &str) -> Option<(i64, i64)> {
+        let now = chrono::Local::now();
+        let midnight = now
+            .date_naive()
+            .and_hms_opt(0, 0, 0)?
+            .and_local_timezone(chrono::Local)
+            .earliest()?
+            .timestamp();
+        let spec = spec.trim().to_lowercase();
+        match spec.as_str() {
+            "today" => return Some((midnight, i64::MAX)),
+            "yesterday" => return Some((midnight - 86400, midnight)),
+            _ => {}
+        }
+        let unit = match spec.chars().last()? {
+            'd' => 86400,
+            'h' => 3600,
+            _ => return None,
+        };
+        let n: i64 = spec[..spec.len() - 1].parse().ok().filter(|n| *n > 0)?;
+        Some((now.timestamp() - n * unit, i64::MAX))
+    }
+
+    /// 展开系统提示词中的变量：{date} {time} {user} {model}
+    pub fn expand_prompt(prompt: &str, uid: &str, model: &str) -> String {
+        if !prompt.contains('{') {
+            return prompt.to_string();
+        }
+        let now = chrono::Local::now();
+        prompt
+            .replace("{date}", &now.format("%Y-%m-%d").to_string())
+            .replace("{time}", &now.format("%H:%M").to_string())
+            .replace("{user}", uid)
+            .replace("{model}", model)
+    }
+
+    /// 公有历史所在的桶：群聊按群号，私聊按用户
+    pub fn public_bucket(event: &kovi::MsgEvent) -> String {
+        match event.group_id {
+            Some(gid) => format!("g{}", gid),
+            None => format!("u{}", event.user_id),
+        }
+    }
+
+    /// 每张图片按低精度估算的 token 数
+    pub const IMAGE_TOKENS: usize = 765;
+
+    /// 估算历史的 token 数：约 4 字符 1 token，图片按低精度计
+    pub fn count_tokens_estimate(messages: &[super::types::ChatMessage]) -> usize {
+        let re = image_md_re();
+        messages
+            .iter()
+            .map(|m| {
+                let inline = re.find_iter(&m.content).count();
+                let text = if inline > 0 {
+                    re.replace_all(&m.content, "").chars().count()
+                } else {
+                    m.content.chars().count()
+                };
+                text / 4 + (m.images.len() + inline) * IMAGE_TOKENS
+            })
+            .sum()
+    }
+
+    /// 估算一次请求的输入 token：系统提示词 + 参考图 + 历史
+    pub fn input_tokens_estimate(
+        agent: &super::types::Agent,
+        hist: &[super::types::ChatMessage],
+    ) -> usize {
+        count_tokens_estimate(hist)
+            + agent.system_prompt.chars().count() / 4
+            + agent.system_images.len() * IMAGE_TOKENS
+    }
+
+    /// 编辑距离 (按字符计算)
+    pub fn levenshtein(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        let mut prev: Vec<usize> = (0..=b.len()).collect();
+        let mut cur = vec![0; b.len() + 1];
+        for i in 1..=a.len() {
+            cur[0] = i;
+            for j in 1..=b.len() {
+                let cost = usize::from(a[i - 1] != b[j - 1]);
+                cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            }
+            std::mem::swap(&mut prev, &mut cur);
+        }
+        prev[b.len()]
+    }
+
+    /// 表达式来自模型参数，限制长度与嵌套深度，避免递归过深导致栈溢出
+    const MAX_EXPR_LEN: usize = 1000;
+    const MAX_EXPR_DEPTH: usize = 64;
+
+    /// 计算四则运算表达式，支持 + - * / % ^、括号与小数
+    pub fn eval_expr(expr: &str) -> Result<f64, String> {
+        struct P<'a> {
+            s: &'a [u8],
+            i: usize,
+            depth: usize,
+        }
+
+        impl P<'_> {
+            fn ws(&mut self) {
+                while self.i < self.s.len() && self.s[self.i].is_ascii_whitespace() {
+                    self.i += 1;
+                }
+            }
+
+            fn eat(&mut self, c: u8) -> bool {
+                self.ws();
+                if self.s.get(self.i) == Some(&c) {
+                    self.i += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+
+            fn expr(&mut self) -> Result<f64, String> {
+                let mut v = self.term()?;
+                loop {
+                    if self.eat(b'+') {
+                        v += self.term()?;
+                    } else if self.eat(b'-') {
+                        v -= self.term()?;
+                    } else {
+                        return Ok(v);
+                    }
+                }
+            }
+
+            fn term(&mut self) -> Result<f64, String> {
+                let mut v = self.power()?;
+                loop {
+                    if self.eat(b'*') {
+                        v *= self.power()?;
+                    } else if self.eat(b'/') {
+                        let d = self.power()?;
+                        if d == 0.0 {
+                            return Err("除数为 0".to_string());
+                        }
+                        v /= d;
+                    } else if self.eat(b'%') {
+                        let d = self.power()?;
+                        if d == 0.0 {
+                            return Err("除数为 0".to_string());
+                        }
+                        v %= d;
+                    } else {
+                        return Ok(v);
+                    }
+                }
+            }
+
+            fn power(&mut self) -> Result<f64, String> {
+                let base = self.unary()?;
+                if self.eat(b'^') {
+                    // 右结合
+                    return Ok(base.powf(self.power()?));
+                }
+                Ok(base)
+            }
+
+            /// 所有递归 (括号、一元符号、乘方) 都经过这里，在此统一计数
+            fn unary(&mut self) -> Result<f64, String> {
+                self.depth += 1;
+                if self.depth > MAX_EXPR_DEPTH {
+                    return Err(format!("嵌套超过 {} 层", MAX_EXPR_DEPTH));
+                }
+                let v = self.unary_inner();
+                self.depth -= 1;
+                v
+            }
+
+            fn unary_inner(&mut self) -> Result<f64, String> {
+                if self.eat(b'-') {
+                    return Ok(-self.unary()?);
+                }
+                if self.eat(b'+') {
+                    return self.unary();
+                }
+                self.atom()
+            }
+
+            fn atom(&mut self) -> Result<f64, String> {
+                if self.eat(b'(') {
+                    let v = self.expr()?;
+                    if !self.eat(b')') {
+                        return Err("缺少右括号".to_string());
+                    }
+                    return Ok(v);
+                }
+                self.ws();
+                let start = self.i;
+                while self.i < self.s.len()
+                    && (self.s[self.i].is_ascii_digit() || self.s[self.i] == b'.')
+                {
+                    self.i += 1;
+                }
+                std::str::from_utf8(&self.s[start..self.i])
+                    .ok()
+                    .and_then(|t| t.parse().ok())
+                    .ok_or_else(|| format!("无法解析位置 {} 处的内容", start + 1))
+            }
+        }
+
+        if expr.len() > MAX_EXPR_LEN {
+            return Err(format!("表达式过长 (上限 {} 字符)", MAX_EXPR_LEN));
+        }
+        let mut p = P {
+            s: expr.as_bytes(),
+            i: 0,
+            depth: 0,
+        };
+        let v = p.expr()?;
+        p.ws();
+        if p.i != p.s.len() {
+            return Err(format!("无法解析位置 {} 处的内容", p.i + 1));
+        }
+        if !v.is_finite() {
+            return Err("结果溢出".to_string());
+        }
+        Ok(v)
+    }
+
+    /// 回复是否引用触发消息
+    static REPLY_WITH_QUOTE: AtomicBool = AtomicBool::new(true);
+
+    pub fn set_reply_with_quote(quote: bool) {
+        REPLY_WITH_QUOTE.store(quote, Ordering::Relaxed);
+    }
+
+    pub fn reply_with_quote() -> bool {
+        REPLY_WITH_QUOTE.load(Ordering::Relaxed)
+    }
+
+    /// 群聊回复时是否 @ 触发用户
+    static REPLY_AT: AtomicBool = AtomicBool::new(false);
+
+    pub fn set_reply_at(at: bool) {
+        REPLY_AT.store(at, Ordering::Relaxed);
+    }
+
+    pub fn reply_at() -> bool {
+        REPLY_AT.load(Ordering::Relaxed)
+    }
+
+    /// 纯文本单条消息的最大字符数
+    static MAX_TEXT_LEN: AtomicUsize = AtomicUsize::new(3000);
+
+    pub fn set_max_text_len(len: usize) {
+        MAX_TEXT_LEN.store(len.max(100), Ordering::Relaxed);
+    }
+
+    pub fn max_text_len() -> usize {
+        MAX_TEXT_LEN.load(Ordering::Relaxed)
+    }
+
+    /// 按字符数切分长文本：优先在换行处切分，Markdown 表格整体保留
+    pub fn split_for_send(text: &str, max: usize) -> Vec<String> {
+        let max = max.max(1);
+
+        // 连续的表格行合并为一个整体，其余每行单独成块
+        let mut blocks: Vec<String> = Vec::new();
+        let mut in_table = false;
+        for line in text.lines() {
+            let is_table = line.trim_start().starts_with('|');
+            match blocks.last_mut() {
+                Some(last) if is_table && in_table => {
+                    last.push('\n');
+                    last.push_str(line);
+                }
+                _ => blocks.push(line.to_string()),
+            }
+            in_table = is_table;
+        }
+
+        let mut parts = Vec::new();
+        let mut cur = String::new();
+        let mut cur_len = 0;
+        for block in blocks {
+            let len = block.chars().count();
+            let sep = usize::from(!cur.is_empty());
+            if cur_len + sep + len <= max {
+                if sep == 1 {
+                    cur.push('\n');
+                }
+                cur.push_str(&block);
+                cur_len += sep + len;
+                continue;
+            }
+            if !cur.is_empty() {
+                parts.push(std::mem::take(&mut cur));
+                cur_len = 0;
+            }
+            if len <= max {
+                cur = block;
+                cur_len = len;
+            } else {
+                // 单块超长时只能按字符硬切
+                let chars: Vec<char> = block.chars().collect();
+                for chunk in chars.chunks(max) {
+                    parts.push(chunk.iter().collect());
+                }
+            }
+        }
+        if !cur.trim().is_empty() {
+            parts.push(cur);
+        }
+        parts
+    }
+
+    /// 文本模式分段发送的阈值
+    static CHUNK_THRESHOLD: AtomicUsize = AtomicUsize::new(1500);
+
+    pub fn set_chunk_threshold(len: usize) {
+        CHUNK_THRESHOLD.store(len.max(100), Ordering::Relaxed);
+    }
+
+    pub fn chunk_threshold() -> usize {
+        CHUNK_THRESHOLD.load(Ordering::Relaxed)
+    }
+
+    /// 按段落 (空行) 切分长文本，段落本身超长时才在段内切分
+    pub fn split_chunks(content: &str, max_chars: usize) -> Vec<String> {
+        let max = max_chars.max(1);
+        let mut parts = Vec::new();
+        let mut cur = String::new();
+        let mut cur_len = 0;
+        for para in content.split("\n\n").filter(|p| !p.trim().is_empty()) {
+            let len = para.chars().count();
+            let sep = if cur.is_empty() { 0 } else { 2 };
+            if cur_len + sep + len <= max {
+                if sep > 0 {
+                    cur.push_str("\n\n");
+                }
+                cur.push_str(para);
+                cur_len += sep + len;
+                continue;
+            }
+            if !cur.is_empty() {
+                parts.push(std::mem::take(&mut cur));
+                cur_len = 0;
+            }
+            if len <= max {
+                cur = para.to_string();
+                cur_len = len;
+            } else {
+                parts.extend(split_for_send(para, max));
+            }
+        }
+        if !cur.is_empty() {
+            parts.push(cur);
+        }
+        parts
+    }
+
+    /// 图片预检共用的 HTTP 客户端
+    static PROBE_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+    /// 预检图片链接：data URL 校验类型头与 base64，http(s) 发送 HEAD 检查可达与 content-type
+    pub async fn validate_image_url(url: &str) -> bool {
+        if let Some(rest) = url.strip_prefix("data:") {
+            let Some((meta, data)) = rest.split_once(',') else {
+                return false;
+            };
+            if !meta.starts_with("image/") || !meta.ends_with(";base64") {
+                return false;
+            }
+            // 只解码开头一段即可判断编码是否合法
+            use base64::Engine;
+            let head = &data[..data.len().min(4096) / 4 * 4];
+            return !data.is_empty()
+                && base64::engine::general_purpose::STANDARD
+                    .decode(head)
+                    .is_ok();
+        }
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return false;
+        }
+        let client = PROBE_CLIENT.get_or_init(|| {
+            reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(5))
+                .build()
+                .unwrap_or_default()
+        });
+        let resp = match client.head(url).send().await {
+            Ok(r) => r,
+            Err(e) => {
+                kovi::log::warn!("图片预检失败 {}: {}", truncate_str(url, 80), e);
+                return false;
+            }
+        };
+        let status = resp.status();
+        // 不支持 HEAD 的服务端无法判断，按有效处理
+        if status == reqwest::StatusCode::METHOD_NOT_ALLOWED
+            || status == reqwest::StatusCode::NOT_IMPLEMENTED
+        {
+            return true;
+        }
+        if !status.is_success() {
+            return false;
+        }
+        // 视频与图片共用输入通道，未声明类型时同样放行
+        match resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(ct) => {
+                ct.starts_with("image/")
+                    || ct.starts_with("video/")
+                    || ct.starts_with("application/octet-stream")
+            }
+            None => true,
+        }
+    }
+
+    /// 并发预检一组图片，保持原有顺序，返回 (有效图片, 跳过数量)
+    pub async fn filter_valid_images(imgs: Vec<String>) -> (Vec<String>, usize) {
+        if imgs.is_empty() {
+            return (imgs, 0);
+        }
+        let mut set = kovi::tokio::task::JoinSet::new();
+        for (i, url) in imgs.iter().cloned().enumerate() {
+            set.spawn(async move { (i, validate_image_url(&url).await) });
+        }
+        let mut ok = vec![false; imgs.len()];
+        while let Some(res) = set.join_next().await {
+            if let Ok((i, valid)) = res {
+                ok[i] = valid;
+            }
+        }
+        let total = imgs.len();
+        let valid: Vec<String> = imgs
+            .into_iter()
+            .zip(ok)
+            .filter_map(|(u, v)| v.then_some(u))
+            .collect();
+        let skipped = total - valid.len();
+        (valid, skipped)
+    }
+
+    /// 名称会直接用作导出与归档的文件名，并以 "名称:" 作为快照键前缀，
+    /// 因此不能含路径分隔符、冒号或控制字符，也不能只由点组成
+    pub fn path_safe_name(name: &str) -> bool {
+        !name
+            .chars()
+            .any(|c| matches!(c, '/' | '\\' | ':') || c.is_control())
+            && !name.chars().all(|c| c == '.')
+    }
+
+    /// 普通 (非引号) 智能体名称：1-7 字，不含指令符号
+    pub fn valid_agent_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.chars().count() <= 7
+            && !name.chars().any(|c| "&\"#~/ -_'!@$%:*".contains(c))
+            && path_safe_name(name)
+    }
+
+    /// 智能体名称匹配：@前缀 或 含 * ? 的通配符，其余按前缀匹配 (不区分大小写)
+    pub fn name_matches(pattern: &str, name: &str) -> bool {
+        let name = name.to_lowercase();
+        let pattern = pattern.to_lowercase();
+        if let Some(prefix) = pattern.strip_prefix('@') {
+            return name.starts_with(prefix);
+        }
+        if !pattern.contains(['*', '?']) {
+            return name.starts_with(&pattern);
+        }
+        fn glob(p: &[char], t: &[char]) -> bool {
+            match p.split_first() {
+                None => t.is_empty(),
+                Some(('*', rest)) => (0..=t.len()).any(|i| glob(rest, &t[i..])),
+                Some(('?', rest)) => !t.is_empty() && glob(rest, &t[1..]),
+                Some((c, rest)) => t.first() == Some(c) && glob(rest, &t[1..]),
+            }
+        }
+        let p: Vec<char> = pattern.chars().collect();
+        let t: Vec<char> = name.chars().collect();
+        glob(&p, &t)
+    }
+
+    /// 转义 HTML 特殊字符，用于拼接到渲染用的 HTML 片段中
+    pub fn escape_html(s: &str) -> String {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    /// 相对时间描述，ts 为 0 时视为从未使用
+    pub fn time_ago(ts: i64) -> String {
+        if ts <= 0 {
+            return Msg::NeverUsed.into();
+        }
+        messages::ago((chrono::Local::now().timestamp() - ts).max(0))
+    }
+
     /// 截断字符串
     pub fn truncate_str(s: &str, max_chars: usize) -> String {
         let chars: Vec<char> = s.chars().collect();
@@ -592,7 +2508,7 @@ mod utils {
         scope: &str,
         hist: &[super::types::ChatMessage],
     ) -> String {
-        let re = Regex::new(r"!\[.*?\]\((data:image/[^\s\)]+)\)").unwrap();
+        let re = image_md_re();
 
         let mut content = String::new();
         let separator = "─".repeat(40);
@@ -600,14 +2516,16 @@ mod utils {
 
         // 头部信息
         content.push_str(&format!("┏{}┓\n", "━".repeat(40)));
-        content.push_str(&format!("┃  智能体: {:<32}┃\n", agent_name));
-        content.push_str(&format!("┃  模  型: {:<32}┃\n", model));
-        content.push_str(&format!("┃  类  型: {:<32}┃\n", scope));
+        let [l_agent, l_model, l_scope, l_time, l_count] = messages::export_labels();
+        content.push_str(&format!("┃  {}: {:<32}┃\n", l_agent, agent_name));
+        content.push_str(&format!("┃  {}: {:<32}┃\n", l_model, model));
+        content.push_str(&format!("┃  {}: {:<32}┃\n", l_scope, scope));
         content.push_str(&format!(
-            "┃  导  出: {:<32}┃\n",
+            "┃  {}: {:<32}┃\n",
+            l_time,
             chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
         ));
-        content.push_str(&format!("┃  记录数: {:<32}┃\n", hist.len()));
+        content.push_str(&format!("┃  {}: {:<32}┃\n", l_count, hist.len()));
         content.push_str(&format!("┗{}┛\n\n", "━".repeat(40)));
 
         // 历史记录
@@ -620,24 +2538,22 @@ mod utils {
                         .format("%Y-%m-%d %H:%M:%S")
                         .to_string()
                 })
-                .unwrap_or_else(|| "未知时间".to_string());
+                .unwrap_or_else(|| Msg::UnknownTime.text().to_string());
 
             let role_name = match m.role.as_str() {
-                "user" => "👤 用户",
-                "assistant" => "🤖 助手",
-                "system" => "⚙️ 系统",
+                "user" | "assistant" | "system" => messages::role_label(&m.role),
                 _ => &m.role,
             };
 
             content.push_str(&format!("【#{} {} | {}】\n", i + 1, role_name, time));
             content.push_str(&format!("{}\n", thin_sep));
 
-            let clean_content = re.replace_all(&m.content, "[图片数据]");
+            let clean_content = re.replace_all(&m.content, Msg::ImageData.text());
             content.push_str(&clean_content);
             content.push('\n');
 
             if !m.images.is_empty() {
-                content.push_str(&format!("\n📷 附图 ({} 张):\n", m.images.len()));
+                content.push_str(&messages::attached_images(m.images.len()));
                 for (j, url) in m.images.iter().enumerate() {
                     if url.starts_with("data:") {
                         content.push_str(&format!("   {}. [Base64 Image Data]\n", j + 1));
@@ -652,1242 +2568,7628 @@ mod utils {
 
         content
     }
-}
 
-// --- 指令解析器 ---
-mod parser {
-    use super::utils::normalize;
+    /// 导出为单个 HTML 文件：样式与 render_md 相同并内联，base64 图片直接嵌入，无需联网即可打开
+    pub fn format_export_html(
+        agent_name: &str,
+        model: &str,
+        scope: &str,
+        hist: &[super::types::ChatMessage],
+    ) -> String {
+        const EXPORT_CSS: &str = r#"
+ body{padding:24px 0}
+ .md{max-width:760px;width:auto;margin:0 auto;border-radius:8px}
+ .msg{border:1px solid var(--border);border-radius:8px;padding:8px 12px;margin:12px 0}
+ .msg.user{background:var(--subtle-bg)}
+ .msg .meta{font-size:12px;color:var(--muted);margin-bottom:4px}"#;
 
-    #[derive(Debug, Clone, Copy, PartialEq)]
-    pub enum Scope {
-        Public,
-        Private,
-    }
+        let mut opts = Options::empty();
+        opts.insert(Options::ENABLE_STRIKETHROUGH);
+        opts.insert(Options::ENABLE_TABLES);
 
-    #[derive(Debug, Clone, PartialEq, Default)]
-    pub enum Action {
-        Chat,
-        Regenerate,
-        Stop,
-        #[default]
-        Create,
-        Copy,
-        Rename,
-        SetDesc,
-        Delete,
-        List,
-        SetModel,
-        SetPrompt,
-        ViewPrompt,
-        ListModels,
-        ViewAll(Scope),
-        ViewAt(Scope),
-        Export(Scope),
-        EditAt(Scope),
-        DeleteAt(Scope),
-        ClearHistory(Scope),
-        ClearAllPublic,
-        ClearEverything,
-        Help,
-        AutoFillDescriptions(String),
-        UpdateApi(String, String),
+        let mut body = String::new();
+        for (i, m) in hist.iter().enumerate() {
+            let time = chrono::DateTime::from_timestamp(m.timestamp, 0)
+                .map(|t| {
+                    t.with_timezone(&chrono::Local)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                })
+                .unwrap_or_else(|| Msg::UnknownTime.text().to_string());
+            let class = match m.role.as_str() {
+                "user" | "assistant" | "system" => m.role.as_str(),
+                _ => "tool",
+            };
+            let role_name = messages::role_label(class);
+            // 历史中的原始 HTML 一律转为文本输出，避免导出页执行其中的脚本
+            let events = Parser::new_ext(&m.content, opts).map(|e| match e {
+                Event::Html(h) | Event::InlineHtml(h) => Event::Text(h),
+                e => e,
+            });
+            let mut content = String::new();
+            html::push_html(&mut content, events);
+            for url in &m.images {
+                content.push_str(&format!(r#"<img src="{}">"#, escape_html(url)));
+            }
+            body.push_str(&format!(
+                r#"<div class="msg {}"><div class="meta">#{} {} · {}</div>{}</div>"#,
+                class,
+                i + 1,
+                role_name,
+                time,
+                content
+            ));
+        }
+
+        let title = messages::export_title(
+            &escape_html(agent_name),
+            &escape_html(model),
+            scope,
+            hist.len(),
+            &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        );
+        let css = format!("{}{}{}", theme_css(&render_theme()), BASE_CSS, EXPORT_CSS);
+        format!(
+            r#"<!DOCTYPE html><html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width,initial-scale=1"><title>{}</title><style>{css}</style></head><body><div class="md"><div class="title">{title}</div>{body}</div></body></html>"#,
+            messages::export_doc_title(&escape_html(agent_name))
+        )
     }
+}
 
-    #[derive(Debug, Clone)]
-    pub struct Command {
-        pub agent: String,
-        pub action: Action,
-        pub args: String,
-        pub indices: Vec<usize>,
-        pub private_reply: bool,
-        pub text_mode: bool,
-        pub temp_mode: bool,
+// --- 用户可见文案 ---
+// 所有回复与帮助文案集中于此，随 /lang 在中英文之间切换
+mod messages {
+    use super::types::EditMode;
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Lang {
+        Zh,
+        En,
     }
 
-    impl Command {
-        pub fn new(agent: &str, action: Action) -> Self {
-            Self {
-                agent: agent.to_string(),
-                action,
-                args: String::new(),
-                indices: Vec::new(),
-                private_reply: false,
-                text_mode: false,
-                temp_mode: false,
+    impl Lang {
+        pub fn parse(s: &str) -> Option<Self> {
+            match s.trim().to_lowercase().as_str() {
+                "" | "zh" | "cn" | "中文" => Some(Lang::Zh),
+                "en" | "english" | "英文" => Some(Lang::En),
+                _ => None,
+            }
+        }
+
+        pub fn code(self) -> &'static str {
+            match self {
+                Lang::Zh => "zh",
+                Lang::En => "en",
             }
         }
     }
 
-    pub fn parse_global(raw: &str) -> Option<Command> {
-        let norm = normalize(raw.trim());
+    pub const LANGS: &[&str] = &["zh", "en"];
 
-        if norm.starts_with("oai") {
-            let rest = norm.get(3..).unwrap_or("").trim();
-            if rest.is_empty() {
-                return Some(Command::new("", Action::Help));
-            }
-            if let Some((u, k)) = super::utils::parse_api(rest) {
-                return Some(Command::new("", Action::UpdateApi(u, k)));
-            }
+    /// 界面语言，启动时及 /lang 切换时写入
+    static LANG: AtomicU8 = AtomicU8::new(0);
+
+    pub fn set_lang(lang: Lang) {
+        LANG.store(lang as u8, Ordering::Relaxed);
+    }
+
+    pub fn lang() -> Lang {
+        match LANG.load(Ordering::Relaxed) {
+            1 => Lang::En,
+            _ => Lang::Zh,
         }
+    }
 
-        if norm == "/#" {
-            return Some(Command::new("", Action::List));
+    #[derive(Debug, Clone, Copy)]
+    pub enum Msg {
+        BlockedContent,
+        Generating,
+        ApiNotConfigured,
+        EmptyInput,
+        AdminRequired,
+        ApproverOnly,
+        NotApprover,
+        NoPending,
+        RetentionUsage,
+        Stopped,
+        CopyUsage,
+        RenameUsage,
+        InvalidName,
+        InvalidIndex,
+        DescUsage,
+        ModelUsage,
+        InvalidModel,
+        NoAgents,
+        SystemImageUsage,
+        SchemaNotObject,
+        KeywordUsage,
+        NoKeywords,
+        RefreshingModels,
+        FetchingModels,
+        NoModels,
+        ViewAtUsage,
+        EditUsage,
+        EditContentMissing,
+        DeleteUsage,
+        RestoreUsage,
+        EmptyHistory,
+        EmptyContext,
+        WriteFailed,
+        BuildingMindmap,
+        SummarizeBusy,
+        Summarizing,
+        AllDescribed,
+        DefaultPromptUpdated,
+        NoBackup,
+        InvalidFileName,
+        SkPrefixRequired,
+        InsertUsage,
+        PrivateSlotsFull,
+        MaxPrivateUsage,
+        ReorderUsage,
+        ImagePlaceholder,
+        ImageRenderFailed,
+        Empty,
+        ApiMissing,
+        HistoryTooShort,
+        NoSummary,
+        HistoryChanged,
+        BeforeSummary,
+        ToolCallTemp,
+        ImageLinks,
+        NoImageReturned,
+        NothingToTranslate,
+        ImageSizeUsage,
+        SeedUsage,
+        TimeoutUsage,
+        BudgetUsage,
+        LabelModel,
+        LabelDesc,
+        LabelPrompt,
+        LabelCreatedAt,
+        LabelLastActive,
+        LabelPublicHistory,
+        LabelPrivateUsers,
+        LabelGenId,
+        LabelTimeout,
+        LabelBudget,
+        LabelMaxHistory,
+        LabelTools,
+        LabelCreator,
+        LabelStop,
+        LabelImageSize,
+        LabelImageQuality,
+        PrivatePrompt,
+        SystemPrompt,
+        PrivateUsersHead,
+        Unknown,
+        Unlimited,
+        Nothing,
+        NoChats,
+        NoDescription,
+        NeverUsed,
+        JustNow,
+        TopAgentsTitle,
+        RecentAgentsTitle,
+        UsageHead,
+        ToolNameUsage,
+        AdminsCleared,
+        NoAdmins,
+        LangSwitched,
+        AgentOrderUsage,
+        NoModelKeywords,
+        TimeRangeUsage,
+        NoCodeBlocks,
+        ReplaceUsage,
+        SnapReplace,
+        SnapBatchDelete,
+        SnapClear,
+        SnapBeforeRestore,
+        SnapClearAllPublic,
+        SnapClearAll,
+        SnapImport,
+        EmptyPattern,
+        QuoteFile,
+        FetchQuoteFailed,
+        NotAFile,
+        FileInfoMissing,
+        FetchFileFailed,
+        ReadFileFailed,
+        NoContent,
+        UnknownTime,
+        ImageData,
+        NoDefaultModel,
+        RequestTimeout,
+        NoVoiceFile,
+        HelpTitle,
+    }
+
+    impl Msg {
+        pub fn text(self) -> &'static str {
+            self.text_in(lang())
         }
 
-        if norm == "/%" {
-            return Some(Command::new("", Action::ListModels));
+        pub fn text_in(self, lang: Lang) -> &'static str {
+            match lang {
+                Lang::Zh => self.zh(),
+                Lang::En => self.en(),
+            }
         }
 
-        if norm == "-*" {
-            return Some(Command::new("", Action::ClearAllPublic));
+        fn zh(self) -> &'static str {
+            match self {
+                Msg::BlockedContent => "⚠️ 消息包含禁止内容",
+                Msg::Generating => "⏳ 正在生成中，请等待或使用 智能体! 停止",
+                Msg::ApiNotConfigured => "❌ API 未配置",
+                Msg::EmptyInput => "💬 请输入内容",
+                Msg::AdminRequired => "⛔ 需要管理员权限",
+                Msg::ApproverOnly => "⛔ 仅审批人可修改审批设置",
+                Msg::NotApprover => "⛔ 你不是该智能体的审批人",
+                Msg::NoPending => "📭 暂无待审批请求",
+                Msg::RetentionUsage => "❌ 格式: 智能体/keep 天数 条数 [归档]，0 表示不限",
+                Msg::Stopped => "🛑 已停止",
+                Msg::CopyUsage => "❌ 请指定新名称: 智能体~#新名称",
+                Msg::RenameUsage => "❌ 请指定新名称: 智能体~=新名称",
+                Msg::InvalidName => "❌ 名称限制：最多7字且不能包含指令符号",
+                Msg::InvalidIndex => "❌ 索引无效",
+                Msg::DescUsage => "❌ 请提供描述: 智能体:描述内容",
+                Msg::ModelUsage => "❌ 请指定模型: 智能体%模型名",
+                Msg::InvalidModel => "❌ 无效模型",
+                Msg::NoAgents => "📋 暂无智能体，使用 ##名称 模型 提示词 创建",
+                Msg::SystemImageUsage => "❌ 请提供图片链接，或附带/引用图片: 智能体$@链接",
+                Msg::SchemaNotObject => "❌ 参数定义必须是 JSON 对象",
+                Msg::KeywordUsage => "❌ 请指定关键词: ##!关键词",
+                Msg::NoKeywords => "📭 禁止关键词列表为空",
+                Msg::RefreshingModels => "⏳ 正在刷新模型列表...",
+                Msg::FetchingModels => "⏳ 正在获取模型列表...",
+                Msg::NoModels => "📭 未找到可用模型 (请检查过滤关键字)",
+                Msg::ViewAtUsage => "❌ 请指定索引: 智能体/索引",
+                Msg::EditUsage => "❌ 请指定索引: 智能体'索引 新内容",
+                Msg::EditContentMissing => "❌ 请提供新内容",
+                Msg::DeleteUsage => "❌ 请指定索引: 智能体-索引 (支持 1,3,5 或 1-5)",
+                Msg::RestoreUsage => "❌ 请指定快照编号: 智能体/restore 编号",
+                Msg::EmptyHistory => "📭 历史为空",
+                Msg::EmptyContext => "📭 上下文为空",
+                Msg::WriteFailed => "❌ 写入失败",
+                Msg::BuildingMindmap => "🧠 正在整理思维导图...",
+                Msg::SummarizeBusy => "⏳ 正在生成中，请稍后再总结",
+                Msg::Summarizing => "📝 正在总结历史...",
+                Msg::AllDescribed => "✅ 所有智能体均已有描述，无需处理。",
+                Msg::DefaultPromptUpdated => "📝 默认提示词已更新",
+                Msg::NoBackup => "📭 未找到备份文件，请先使用 ##_backup 备份",
+                Msg::InvalidFileName => "❌ 无效的文件名",
+                Msg::SkPrefixRequired => {
+                    "❌ API 密钥需以 sk- 开头\n使用 Ollama 等无需密钥的服务时，请在配置中设置 require_sk_prefix 为 false，并以 . 作为占位密钥"
+                }
+                Msg::InsertUsage => {
+                    "❌ 格式: 智能体+位置 角色 内容，角色为 user / assistant / system"
+                }
+                Msg::PrivateSlotsFull => "❌ 该智能体私有会话已满",
+                Msg::MaxPrivateUsage => "❌ 格式: 智能体_maxprivate=人数，留空取消",
+                Msg::ReorderUsage => "❌ 格式: 智能体>序号,序号，如 助手>1,3",
+                Msg::ImagePlaceholder => "[图片]",
+                Msg::ImageRenderFailed => "[图片渲染失败]",
+                Msg::Empty => "(空)",
+                Msg::ApiMissing => "API 未配置",
+                Msg::HistoryTooShort => "历史太短，无需总结",
+                Msg::NoSummary => "模型未返回摘要",
+                Msg::HistoryChanged => "总结期间历史已被修改，请重试",
+                Msg::BeforeSummary => "总结前",
+                Msg::ToolCallTemp => "\n\n(临时会话不保留调用，无法提交结果)",
+                Msg::ImageLinks => "**图片链接:**",
+                Msg::NoImageReturned => "❌ 接口未返回图片",
+                Msg::NothingToTranslate => "❌ 暂无可翻译的回复",
+                Msg::ImageSizeUsage => "❌ 格式: 智能体%%size=1024x1024，留空清除",
+                Msg::SeedUsage => "❌ 格式: 智能体%%seed=整数，留空清除",
+                Msg::TimeoutUsage => "❌ 格式: 智能体%timeout=秒数，0 为不限时",
+                Msg::BudgetUsage => "❌ 格式: 智能体_budget=tokens，留空取消",
+                Msg::LabelModel => "模型",
+                Msg::LabelDesc => "描述",
+                Msg::LabelPrompt => "提示词",
+                Msg::LabelCreatedAt => "创建时间",
+                Msg::LabelLastActive => "最近活跃",
+                Msg::LabelPublicHistory => "公有历史",
+                Msg::LabelPrivateUsers => "私有用户",
+                Msg::LabelGenId => "生成 ID",
+                Msg::LabelTimeout => "超时",
+                Msg::LabelBudget => "单次预算",
+                Msg::LabelMaxHistory => "历史上限",
+                Msg::LabelTools => "工具",
+                Msg::LabelCreator => "创建者",
+                Msg::LabelStop => "停止词",
+                Msg::LabelImageSize => "图片尺寸",
+                Msg::LabelImageQuality => "图片质量",
+                Msg::PrivatePrompt => "私有设定",
+                Msg::SystemPrompt => "系统提示词",
+                Msg::PrivateUsersHead => "<th>用户</th><th>消息数</th><th>最近活跃</th>",
+                Msg::Unknown => "未知",
+                Msg::Unlimited => "不限",
+                Msg::Nothing => "无",
+                Msg::NoChats => "暂无对话",
+                Msg::NoDescription => "无描述",
+                Msg::NeverUsed => "从未使用",
+                Msg::JustNow => "刚刚",
+                Msg::TopAgentsTitle => "📊 智能体调用排行",
+                Msg::RecentAgentsTitle => "🕒 智能体最近使用",
+                Msg::UsageHead => "<th>智能体</th><th>调用次数</th><th>最近使用</th>",
+                Msg::ToolNameUsage => {
+                    "❌ 工具名仅支持字母、数字、_ 和 -，最长 64 位\n用法: 智能体+tool 名称 描述 {JSON Schema}"
+                }
+                Msg::AdminsCleared => "⚠️ 管理员列表已清空，所有人均可执行管理指令",
+                Msg::NoAdmins => "👑 未设置管理员，所有人均可执行管理指令",
+                Msg::LangSwitched => "🌐 已切换为中文",
+                Msg::AgentOrderUsage => "❌ 格式: ##>智能体 位置，如 ##>助手 1",
+                Msg::NoModelKeywords => "(空，显示全部模型)",
+                Msg::TimeRangeUsage => "❌ 格式: 智能体/today、/yesterday、/7d 或 /12h",
+                Msg::NoCodeBlocks => "📭 该消息中没有代码块",
+                Msg::ReplaceUsage => "❌ 格式: 智能体's/旧/新/，末尾加 r 为正则、加 i 忽略大小写",
+                Msg::SnapReplace => "查找替换",
+                Msg::SnapBatchDelete => "批量删除",
+                Msg::SnapClear => "清空历史",
+                Msg::SnapBeforeRestore => "回滚前",
+                Msg::SnapClearAllPublic => "清空所有公有历史",
+                Msg::SnapClearAll => "清空所有历史",
+                Msg::SnapImport => "导入配置",
+                Msg::EmptyPattern => "查找内容不能为空",
+                Msg::QuoteFile => "请引用一条文件消息",
+                Msg::FetchQuoteFailed => "获取引用消息失败",
+                Msg::NotAFile => "引用的消息不是文件",
+                Msg::FileInfoMissing => "文件信息缺失",
+                Msg::FetchFileFailed => "获取文件失败",
+                Msg::ReadFileFailed => "无法读取文件内容",
+                Msg::NoContent => "(无内容)",
+                Msg::UnknownTime => "未知时间",
+                Msg::ImageData => "[图片数据]",
+                Msg::NoDefaultModel => "未设置默认模型",
+                Msg::RequestTimeout => "请求超时",
+                Msg::NoVoiceFile => "协议端未返回语音文件",
+                Msg::HelpTitle => "🤖 OAI 符号指令帮助",
+            }
         }
 
-        if norm == "-*!" {
-            return Some(Command::new("", Action::ClearEverything));
+        fn en(self) -> &'static str {
+            match self {
+                Msg::BlockedContent => "⚠️ Message contains blocked content",
+                Msg::Generating => "⏳ Still generating, wait or stop it with agent!",
+                Msg::ApiNotConfigured => "❌ API is not configured",
+                Msg::EmptyInput => "💬 Please enter a message",
+                Msg::AdminRequired => "⛔ Admin permission required",
+                Msg::ApproverOnly => "⛔ Only approvers can change approval settings",
+                Msg::NotApprover => "⛔ You are not an approver of this agent",
+                Msg::NoPending => "📭 No pending requests",
+                Msg::RetentionUsage => {
+                    "❌ Usage: agent/keep days count [archive], 0 means unlimited"
+                }
+                Msg::Stopped => "🛑 Stopped",
+                Msg::CopyUsage => "❌ Specify a new name: agent~#new",
+                Msg::RenameUsage => "❌ Specify a new name: agent~=new",
+                Msg::InvalidName => "❌ Names are limited to 7 characters without command symbols",
+                Msg::InvalidIndex => "❌ Invalid index",
+                Msg::DescUsage => "❌ Provide a description: agent:description",
+                Msg::ModelUsage => "❌ Specify a model: agent%model",
+                Msg::InvalidModel => "❌ Invalid model",
+                Msg::NoAgents => "📋 No agents yet, create one with ##name model prompt",
+                Msg::SystemImageUsage => {
+                    "❌ Provide an image URL, or attach/quote an image: agent$@url"
+                }
+                Msg::SchemaNotObject => "❌ The parameter schema must be a JSON object",
+                Msg::KeywordUsage => "❌ Specify a keyword: ##!word",
+                Msg::NoKeywords => "📭 No blocked keywords",
+                Msg::RefreshingModels => "⏳ Refreshing model list...",
+                Msg::FetchingModels => "⏳ Fetching model list...",
+                Msg::NoModels => "📭 No models found (check the filter)",
+                Msg::ViewAtUsage => "❌ Specify an index: agent/index",
+                Msg::EditUsage => "❌ Specify an index: agent'index new text",
+                Msg::EditContentMissing => "❌ Provide the new content",
+                Msg::DeleteUsage => "❌ Specify indices: agent-index (1,3,5 or 1-5)",
+                Msg::RestoreUsage => "❌ Specify a snapshot: agent/restore number",
+                Msg::EmptyHistory => "📭 History is empty",
+                Msg::EmptyContext => "📭 Context is empty",
+                Msg::WriteFailed => "❌ Write failed",
+                Msg::BuildingMindmap => "🧠 Building mind map...",
+                Msg::SummarizeBusy => "⏳ Still generating, summarize later",
+                Msg::Summarizing => "📝 Summarizing history...",
+                Msg::AllDescribed => "✅ All agents already have descriptions.",
+                Msg::DefaultPromptUpdated => "📝 Default prompt updated",
+                Msg::NoBackup => "📭 No backup found, run ##_backup first",
+                Msg::InvalidFileName => "❌ Invalid file name",
+                Msg::SkPrefixRequired => {
+                    "❌ The API key must start with sk-\nFor keyless services such as Ollama, set require_sk_prefix to false in the config and use . as a placeholder key"
+                }
+                Msg::InsertUsage => {
+                    "❌ Usage: agent+position role text, role is user / assistant / system"
+                }
+                Msg::PrivateSlotsFull => "❌ This agent has no free private session slots",
+                Msg::MaxPrivateUsage => "❌ Usage: agent_maxprivate=count, empty to remove",
+                Msg::ReorderUsage => "❌ Usage: agent>index,index, e.g. bot>1,3",
+                Msg::ImagePlaceholder => "[image]",
+                Msg::ImageRenderFailed => "[image failed to render]",
+                Msg::Empty => "(empty)",
+                Msg::ApiMissing => "API is not configured",
+                Msg::HistoryTooShort => "History is too short to summarize",
+                Msg::NoSummary => "The model returned no summary",
+                Msg::HistoryChanged => "History changed while summarizing, please retry",
+                Msg::BeforeSummary => "before summary",
+                Msg::ToolCallTemp => {
+                    "\n\n(temporary chats keep no calls, results cannot be submitted)"
+                }
+                Msg::ImageLinks => "**Image links:**",
+                Msg::NoImageReturned => "❌ The endpoint returned no image",
+                Msg::NothingToTranslate => "❌ No reply to translate",
+                Msg::ImageSizeUsage => "❌ Usage: agent%%size=1024x1024, empty to clear",
+                Msg::SeedUsage => "❌ Usage: agent%%seed=integer, empty to clear",
+                Msg::TimeoutUsage => "❌ Usage: agent%timeout=seconds, 0 for no limit",
+                Msg::BudgetUsage => "❌ Usage: agent_budget=tokens, empty to remove",
+                Msg::LabelModel => "Model",
+                Msg::LabelDesc => "Description",
+                Msg::LabelPrompt => "Prompt",
+                Msg::LabelCreatedAt => "Created",
+                Msg::LabelLastActive => "Last active",
+                Msg::LabelPublicHistory => "Public history",
+                Msg::LabelPrivateUsers => "Private users",
+                Msg::LabelGenId => "Generation ID",
+                Msg::LabelTimeout => "Timeout",
+                Msg::LabelBudget => "Budget per request",
+                Msg::LabelMaxHistory => "History limit",
+                Msg::LabelTools => "Tools",
+                Msg::LabelCreator => "Creator",
+                Msg::LabelStop => "Stop words",
+                Msg::LabelImageSize => "Image size",
+                Msg::LabelImageQuality => "Image quality",
+                Msg::PrivatePrompt => "Private prompt",
+                Msg::SystemPrompt => "System prompt",
+                Msg::PrivateUsersHead => "<th>User</th><th>Messages</th><th>Last active</th>",
+                Msg::Unknown => "unknown",
+                Msg::Unlimited => "unlimited",
+                Msg::Nothing => "none",
+                Msg::NoChats => "no chats yet",
+                Msg::NoDescription => "no description",
+                Msg::NeverUsed => "never used",
+                Msg::JustNow => "just now",
+                Msg::TopAgentsTitle => "📊 Agents by calls",
+                Msg::RecentAgentsTitle => "🕒 Recently used agents",
+                Msg::UsageHead => "<th>Agent</th><th>Calls</th><th>Last used</th>",
+                Msg::ToolNameUsage => {
+                    "❌ Tool names may only use letters, digits, _ and -, up to 64 characters\nUsage: agent+tool name description {JSON Schema}"
+                }
+                Msg::AdminsCleared => "⚠️ Admin list cleared, everyone can run admin commands",
+                Msg::NoAdmins => "👑 No admins set, everyone can run admin commands",
+                Msg::LangSwitched => "🌐 Replies switched to English",
+                Msg::AgentOrderUsage => "❌ Usage: ##>agent position, e.g. ##>bot 1",
+                Msg::NoModelKeywords => "(none, showing all models)",
+                Msg::TimeRangeUsage => "❌ Usage: agent/today, /yesterday, /7d or /12h",
+                Msg::NoCodeBlocks => "📭 No code blocks in this message",
+                Msg::ReplaceUsage => {
+                    "❌ Usage: agent's/old/new/, add r for regex, i to ignore case"
+                }
+                Msg::SnapReplace => "find and replace",
+                Msg::SnapBatchDelete => "batch delete",
+                Msg::SnapClear => "clear history",
+                Msg::SnapBeforeRestore => "before restore",
+                Msg::SnapClearAllPublic => "clear all public history",
+                Msg::SnapClearAll => "clear all history",
+                Msg::SnapImport => "import config",
+                Msg::EmptyPattern => "The search text cannot be empty",
+                Msg::QuoteFile => "Quote a file message",
+                Msg::FetchQuoteFailed => "Failed to fetch the quoted message",
+                Msg::NotAFile => "The quoted message is not a file",
+                Msg::FileInfoMissing => "File information is missing",
+                Msg::FetchFileFailed => "Failed to fetch the file",
+                Msg::ReadFileFailed => "Cannot read the file content",
+                Msg::NoContent => "(no content)",
+                Msg::UnknownTime => "unknown time",
+                Msg::ImageData => "[image data]",
+                Msg::NoDefaultModel => "no default model is set",
+                Msg::RequestTimeout => "request timed out",
+                Msg::NoVoiceFile => "the protocol side returned no voice file",
+                Msg::HelpTitle => "🤖 OAI Command Help",
+            }
         }
+    }
 
-        if norm.starts_with("##:") {
-            let args = norm.get(3..).unwrap_or("").trim().to_string();
-            return Some(Command::new("", Action::AutoFillDescriptions(args)));
+    impl From<Msg> for String {
+        fn from(m: Msg) -> Self {
+            m.text().to_string()
         }
+    }
 
-        None
+    pub fn not_found(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ {} 不存在", name),
+            Lang::En => format!("❌ {} does not exist", name),
+        }
     }
 
-    pub fn parse_create(raw: &str) -> Option<(String, String, String, String)> {
-        let norm = normalize(raw.trim());
-        if !norm.starts_with("##") {
-            return None;
+    pub fn request_timeout(secs: u64) -> String {
+        match lang() {
+            Lang::Zh => format!("⏳ 请求超时：模型响应时间超过 {} 秒，已强制停止。", secs),
+            Lang::En => format!(
+                "⏳ Request timed out: the model took longer than {}s.",
+                secs
+            ),
         }
+    }
 
-        let start_pos = norm.find("##").unwrap() + "##".len();
-        let after = &raw.trim()[start_pos..];
+    pub fn global_claimed(name: &str, n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("📥 {} 已将 {} 条旧版全局公有历史迁入当前会话", name, n),
+            Lang::En => format!(
+                "📥 {}: moved {} legacy global messages into this chat",
+                name, n
+            ),
+        }
+    }
 
-        let name_end = after
-            .find(|c: char| c.is_whitespace() || c == '(' || c == '（')
-            .unwrap_or(after.len());
-        let name = after[..name_end].trim().to_string();
+    pub fn no_global_history(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("📭 {} 没有旧版全局公有历史", name),
+            Lang::En => format!("📭 {} has no legacy global history", name),
+        }
+    }
 
-        if name.is_empty()
-            || name.chars().count() > 7
-            || name.chars().any(|c| "&\"#~/ -_'!@$%:*".contains(c))
-        {
-            return None;
+    pub fn invalid_index_at(idx: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 索引 {} 无效", idx),
+            Lang::En => format!("❌ Invalid index {}", idx),
         }
+    }
 
-        let rest = &after[name_end..];
+    pub fn inserted(idx: usize, role: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("➕ 已在第 {} 条插入 {} 消息", idx, role),
+            Lang::En => format!("➕ Inserted a {} message at #{}", role, idx),
+        }
+    }
 
-        let (desc, after_desc) = if rest.starts_with('(') || rest.starts_with('（') {
-            if let Some(pos) = rest.find(')').or_else(|| rest.find('）')) {
-                (rest[1..pos].to_string(), &rest[pos + 1..])
-            } else {
-                (String::new(), rest)
-            }
-        } else {
-            (String::new(), rest)
-        };
+    pub fn pin_toggled(idx: usize, pinned: bool) -> String {
+        match (lang(), pinned) {
+            (Lang::Zh, true) => format!("📌 第 {} 条已置顶", idx),
+            (Lang::Zh, false) => format!("📍 第 {} 条已取消置顶", idx),
+            (Lang::En, true) => format!("📌 Pinned #{}", idx),
+            (Lang::En, false) => format!("📍 Unpinned #{}", idx),
+        }
+    }
 
-        let parts: Vec<&str> = after_desc.split_whitespace().collect();
-        let model = parts.first().unwrap_or(&"").to_string();
-        if model.chars().count() > 50 {
-            return None;
+    /// 清空历史的回复，pinned 为保留下来的置顶条数
+    pub fn history_cleared(name: &str, private: bool, pinned: usize) -> String {
+        match lang() {
+            Lang::Zh => {
+                let s = if private { "私有" } else { "公有" };
+                if pinned > 0 {
+                    format!("🧹 {} {}历史已清空，保留 {} 条置顶", name, s, pinned)
+                } else {
+                    format!("🧹 {} {}历史已清空", name, s)
+                }
+            }
+            Lang::En => {
+                let s = if private { "private" } else { "public" };
+                if pinned > 0 {
+                    format!("🧹 {}: {} history cleared, kept {} pinned", name, s, pinned)
+                } else {
+                    format!("🧹 {}: {} history cleared", name, s)
+                }
+            }
         }
-        let prompt = if parts.len() > 1 {
-            parts[1..].join(" ")
-        } else {
-            String::new()
-        };
+    }
 
-        Some((name, desc, model, prompt))
+    /// 私有会话上限：max 为 None 表示不限，current 为 Some 时附带当前人数
+    pub fn max_private(name: &str, max: Option<usize>, current: Option<usize>) -> String {
+        match lang() {
+            Lang::Zh => {
+                let limit = max.map_or("不限".to_string(), |n| format!("{} 人", n));
+                match current {
+                    Some(n) => format!("👥 {} 私有会话上限: {} (当前 {} 人)", name, limit, n),
+                    None => format!("👥 {} 私有会话上限: {}", name, limit),
+                }
+            }
+            Lang::En => {
+                let limit = max.map_or("unlimited".to_string(), |n| format!("{} users", n));
+                match current {
+                    Some(n) => format!("👥 {} private session limit: {} (now {})", name, limit, n),
+                    None => format!("👥 {} private session limit: {}", name, limit),
+                }
+            }
+        }
     }
 
-    pub fn parse_delete_agent(raw: &str, agents: &[String]) -> Option<String> {
-        let norm = normalize(raw.trim());
-        if !norm.starts_with("-#") {
-            return None;
+    pub fn max_private_cleared(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("👥 {} 已取消私有会话上限", name),
+            Lang::En => format!("👥 {}: private session limit removed", name),
         }
-        let name = norm[2..].trim();
-        if agents.iter().any(|a| a.eq_ignore_ascii_case(name)) {
-            Some(name.to_string())
-        } else {
-            None
+    }
+
+    pub fn unknown_option(key: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 未知选项: {}，可用: budget, maxprivate", key),
+            Lang::En => format!("❌ Unknown option: {}, available: budget, maxprivate", key),
         }
     }
 
-    pub fn parse_agent_cmd(raw: &str, agents: &[String]) -> Option<Command> {
-        let raw = raw.trim();
-        if raw.is_empty() {
-            return None;
+    pub fn swapped(i: usize, j: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("🔀 已交换第 {} 条与第 {} 条", i, j),
+            Lang::En => format!("🔀 Swapped #{} and #{}", i, j),
         }
+    }
 
-        let norm = normalize(raw);
-        let chars: Vec<char> = norm.chars().collect();
+    pub fn nothing_to_undo(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("📭 {} 没有可撤销的操作", name),
+            Lang::En => format!("📭 {}: nothing to undo", name),
+        }
+    }
 
-        let mut char_idx = 0;
-        let mut private_reply = false;
-        let mut text_mode = false;
-        let mut temp_mode = false;
+    pub fn undone(name: &str, len: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("↩️ {} 已撤销上一次修改 ({} 条)", name, len),
+            Lang::En => format!("↩️ {}: undid the last change ({} messages)", name, len),
+        }
+    }
 
-        // 1. 前缀解析
-        while char_idx < chars.len() {
-            match chars[char_idx] {
-                '&' => {
-                    private_reply = true;
-                    char_idx += 1;
-                }
-                '"' => {
-                    text_mode = true;
-                    char_idx += 1;
-                }
-                '~' => {
-                    temp_mode = true;
-                    char_idx += 1;
-                }
-                _ => break,
-            }
+    pub fn exported(fname: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("📤 已导出: {}", fname),
+            Lang::En => format!("📤 Exported: {}", fname),
         }
+    }
 
-        let byte_idx: usize = chars.iter().take(char_idx).map(|c| c.len_utf8()).sum();
-        let content = &norm[byte_idx..];
+    pub fn upload_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 上传失败: {}", e),
+            Lang::En => format!("❌ Upload failed: {}", e),
+        }
+    }
 
-        // 2. 智能体名称匹配 (按长度降序，解决包含关系问题)
-        let mut agent_name = String::new();
-        let mut match_char_len = 0;
-        let mut sorted = agents.to_vec();
-        // 关键：必须按长度倒序，确保 "小帅2" 先于 "小帅" 被匹配
-        sorted.sort_by_key(|b| std::cmp::Reverse(b.chars().count()));
+    /// 合并转发中各角色的昵称
+    pub fn role_label(role: &str) -> &'static str {
+        match (lang(), role) {
+            (Lang::Zh, "user") => "👤 用户",
+            (Lang::Zh, "assistant") => "🤖 助手",
+            (Lang::Zh, "tool") => "🔧 工具",
+            (Lang::Zh, _) => "⚙️ 系统",
+            (Lang::En, "user") => "👤 User",
+            (Lang::En, "assistant") => "🤖 Assistant",
+            (Lang::En, "tool") => "🔧 Tool",
+            (Lang::En, _) => "⚙️ System",
+        }
+    }
 
-        for name in &sorted {
-            let name_lower = name.to_lowercase();
-            let content_lower = content.to_lowercase();
-            if content_lower.starts_with(&name_lower) {
-                agent_name = name.clone();
-                match_char_len = name.chars().count();
-                break;
+    pub fn approval_notice(
+        id: u64,
+        agent: &str,
+        group: Option<i64>,
+        uid: i64,
+        prompt: &str,
+    ) -> String {
+        match lang() {
+            Lang::Zh => {
+                let origin = match group {
+                    Some(gid) => format!("群 {} 用户 {}", gid, uid),
+                    None => format!("私聊用户 {}", uid),
+                };
+                format!(
+                    "🔐 审批请求 #{}\n智能体: {}\n来源: {}\n内容: {}\n\n发送 ?+{} 同意 | ?-{} 拒绝",
+                    id, agent, origin, prompt, id, id
+                )
+            }
+            Lang::En => {
+                let origin = match group {
+                    Some(gid) => format!("group {} user {}", gid, uid),
+                    None => format!("private user {}", uid),
+                };
+                format!(
+                    "🔐 Approval request #{}\nAgent: {}\nFrom: {}\nContent: {}\n\nSend ?+{} to approve | ?-{} to reject",
+                    id, agent, origin, prompt, id, id
+                )
             }
         }
+    }
 
-        if agent_name.is_empty() {
-            return None;
+    pub fn approval_submitted(agent: &str, id: u64) -> String {
+        match lang() {
+            Lang::Zh => format!("🔐 {} 需要审批，已提交 (编号 #{})", agent, id),
+            Lang::En => format!("🔐 {} requires approval, submitted as #{}", agent, id),
         }
+    }
 
-        // 3. 后缀提取
-        let match_byte_len: usize = content
-            .chars()
-            .take(match_char_len)
-            .map(|c| c.len_utf8())
-            .sum();
-        let suffix = content[match_byte_len..].trim();
+    pub fn approval_expired(id: u64) -> String {
+        match lang() {
+            Lang::Zh => format!("⌛ 审批 #{} 超时，已自动拒绝", id),
+            Lang::En => format!("⌛ Approval #{} timed out and was rejected", id),
+        }
+    }
 
-        // 计算原始字符串中的后缀部分（为了保留参数的原始格式，如大小写）
-        let raw_suffix = {
-            let prefix_bytes: usize = raw.chars().take(char_idx).map(|c| c.len_utf8()).sum();
-            let agent_bytes: usize = raw[prefix_bytes..]
-                .chars()
-                .take(match_char_len)
-                .map(|c| c.len_utf8())
-                .sum();
-            raw[prefix_bytes + agent_bytes..].trim()
-        };
+    /// 不带图标的"不存在"，用于拼接进其他错误信息
+    pub fn no_such_agent(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("{} 不存在", name),
+            Lang::En => format!("{} does not exist", name),
+        }
+    }
 
-        let (action, args, indices) = parse_suffix(suffix, raw_suffix, private_reply);
+    pub fn summarized(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("📝 已将 {} 条旧消息压缩为摘要，保留最近原文", n),
+            Lang::En => format!("📝 Compressed {} older messages into a summary", n),
+        }
+    }
 
-        Some(Command {
-            agent: agent_name,
-            action,
-            args,
-            indices,
-            private_reply,
-            text_mode,
-            temp_mode,
-        })
+    pub fn summarize_failed(e: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 总结失败: {}", e),
+            Lang::En => format!("❌ Summary failed: {}", e),
+        }
     }
 
-    fn parse_suffix(norm: &str, raw: &str, has_priv_prefix: bool) -> (Action, String, Vec<usize>) {
-        let s = norm.trim(); // 此时 s 里的全角符号已被 normalize 转为半角
-        let r = raw.trim(); // r 是原始字符串
+    pub fn image_ref(url: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("\n[图片: {}]", url),
+            Lang::En => format!("\n[image: {}]", url),
+        }
+    }
 
-        // 1. 空指令 -> 聊天
-        if s.is_empty() {
-            return (Action::Chat, r.to_string(), vec![]);
+    pub fn context_footer(count: usize, total: usize, threshold: usize) -> String {
+        let warn = if total > threshold { " ⚠️" } else { "" };
+        match lang() {
+            Lang::Zh => format!(
+                "共 {} 条消息 · 估算 ~{} tokens · 预警阈值 {} tokens{}",
+                count, total, threshold, warn
+            ),
+            Lang::En => format!(
+                "{} messages · ~{} tokens · warning at {} tokens{}",
+                count, total, threshold, warn
+            ),
         }
+    }
 
-        // 2. 停止指令 (!)
-        if s == "!" {
-            return (Action::Stop, String::new(), vec![]);
+    pub fn over_budget(tokens: usize, budget: u32) -> String {
+        match lang() {
+            Lang::Zh => format!(
+                "⚠️ 本次请求估计消耗 {} tokens，超出预算限制 {} tokens",
+                tokens, budget
+            ),
+            Lang::En => format!(
+                "⚠️ This request needs about {} tokens, over the {} token budget",
+                tokens, budget
+            ),
         }
+    }
 
-        // 3. 复制指令 (~#) - 必须在普通 ~ 之前判断
-        // 注意：normalize 已经把 ～ 转为 ~，把 ＃ 转为 #
-        if s.starts_with("~#") {
-            // 计算原始字符串中需要跳过的长度
-            let skip_len = if r.starts_with("～＃") {
-                "～＃".len()
-            } else if r.starts_with("～#") {
-                "～#".len()
-            } else if r.starts_with("~＃") {
-                "~＃".len()
-            } else {
-                "~#".len()
-            };
-            let arg = r.get(skip_len..).unwrap_or("").trim();
-            return (Action::Copy, arg.to_string(), vec![]);
+    pub fn build_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 请求构建失败: {}", e),
+            Lang::En => format!("❌ Failed to build the request: {}", e),
         }
+    }
 
-        // 4. 重命名指令 (~=) - 必须在普通 ~ 之前判断
-        if s.starts_with("~=") {
-            let skip_len = if r.starts_with("～＝") {
-                "～＝".len()
-            } else if r.starts_with("～=") {
-                "～=".len()
-            } else if r.starts_with("~＝") {
-                "~＝".len()
-            } else {
-                "~=".len()
-            };
-            let arg = r.get(skip_len..).unwrap_or("").trim();
-            return (Action::Rename, arg.to_string(), vec![]);
+    pub fn tool_rounds_exceeded(name: &str, rounds: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("⚠️ {} 工具调用超过 {} 轮，已停止", name, rounds),
+            Lang::En => format!("⚠️ {}: stopped after {} tool rounds", name, rounds),
         }
+    }
 
-        // 5. 重新生成指令 (~) - 放在最后判断
-        // 匹配 "~" 单独出现，或者 "~内容"
-        if s.starts_with('~') {
-            let skip_len = if r.starts_with('～') {
-                '～'.len_utf8()
-            } else {
-                '~'.len_utf8()
-            };
-            let arg = r.get(skip_len..).unwrap_or("").trim();
-            return (Action::Regenerate, arg.to_string(), vec![]);
+    pub fn tool_call_header(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🔧 {} 请求调用工具:", name),
+            Lang::En => format!("🔧 {} wants to call tools:", name),
         }
+    }
 
-        // 6. 设置描述 (:)
-        if s.starts_with(':') && !s.starts_with(":/") {
-            let skip_len = if r.starts_with('：') {
-                '：'.len_utf8()
-            } else {
-                ':'.len_utf8()
-            };
-            let arg = r.get(skip_len..).unwrap_or("").trim();
-            return (Action::SetDesc, arg.to_string(), vec![]);
+    /// 提示用户提交工具结果，multi 为一次调用了多个工具
+    pub fn tool_call_hint(name: &str, secs: u64, multi: bool) -> String {
+        match (lang(), multi) {
+            (Lang::Zh, false) => format!("\n\n请在 {} 秒内提交结果: {} =>结果", secs, name),
+            (Lang::Zh, true) => format!(
+                "\n\n请在 {} 秒内逐行提交结果: {} =>1 结果 (换行) =>2 结果",
+                secs, name
+            ),
+            (Lang::En, false) => {
+                format!("\n\nSubmit the result within {}s: {} =>result", secs, name)
+            }
+            (Lang::En, true) => format!(
+                "\n\nSubmit one result per line within {}s: {} =>1 result (newline) =>2 result",
+                secs, name
+            ),
         }
+    }
 
-        // 7. 设置模型 (%)
-        if s.starts_with('%') {
-            let arg = r.get(1..).unwrap_or("").trim();
-            return (Action::SetModel, arg.to_string(), vec![]);
+    pub fn temp_reply_header(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("{} (临时会话)", name),
+            Lang::En => format!("{} (temporary)", name),
         }
+    }
 
-        // 8. 设置/查看提示词 ($)
-        if s == "/$" {
-            return (Action::ViewPrompt, String::new(), vec![]);
-        }
-        if s.starts_with('$') {
-            let arg = r.get(1..).unwrap_or("").trim();
-            return (Action::SetPrompt, arg.to_string(), vec![]);
+    pub fn reply_header(name: &str, index: usize, private: bool) -> String {
+        match (lang(), private) {
+            (Lang::Zh, false) => format!("{} #{}回复", name, index),
+            (Lang::Zh, true) => format!("{} #{}回复 (私有)", name, index),
+            (Lang::En, false) => format!("{} reply #{}", name, index),
+            (Lang::En, true) => format!("{} reply #{} (private)", name, index),
         }
+    }
 
-        // 9. 历史/查看/编辑/删除类操作
-        // 处理 & 后缀 (局部私有操作，如 智能体&/*)
-        let (has_local_priv, clean, clean_raw) = if let Some(stripped) = s.strip_prefix('&') {
-            (true, stripped, r.strip_prefix('&').unwrap_or("").trim())
-        } else {
-            (false, s, r)
-        };
+    pub fn images_skipped(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("⚠️ {} 张图片无效已跳过", n),
+            Lang::En => format!("⚠️ Skipped {} invalid images", n),
+        }
+    }
 
-        let scope = if has_priv_prefix || has_local_priv {
-            Scope::Private
-        } else {
-            Scope::Public
-        };
+    pub fn context_near_limit(tokens: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("⚠️ 上下文已接近限制 (~{} tokens)", tokens),
+            Lang::En => format!("⚠️ Context is close to the limit (~{} tokens)", tokens),
+        }
+    }
 
-        if clean == "/*" {
-            return (Action::ViewAll(scope), String::new(), vec![]);
+    pub fn seed_fixed(seed: i64) -> String {
+        match lang() {
+            Lang::Zh => format!("🎲 已固定 seed={}，重新生成的结果可能与上次相同", seed),
+            Lang::En => format!(
+                "🎲 seed={} is fixed, the regenerated reply may repeat",
+                seed
+            ),
         }
+    }
 
-        if clean.starts_with('/') && clean.len() > 1 {
-            let idx_part = &clean[1..];
-            let indices = super::utils::parse_indices(idx_part);
-            if !indices.is_empty() {
-                return (Action::ViewAt(scope), String::new(), indices);
-            }
+    pub fn seed_unsupported(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!(
+                "❌ 当前端点不支持 seed 参数，请使用 {}%%seed= 清除后重试",
+                name
+            ),
+            Lang::En => format!(
+                "❌ This endpoint does not support seed, clear it with {}%%seed= and retry",
+                name
+            ),
         }
+    }
 
-        if clean == "_*" {
-            return (Action::Export(scope), String::new(), vec![]);
+    pub fn api_error(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ API错误: {}", e),
+            Lang::En => format!("❌ API error: {}", e),
         }
+    }
 
-        // 编辑指令 ('): 支持 '1 新内容
-        if clean.starts_with('\'') {
-            // splitn(2) 确保只分割出索引和内容两部分
-            let parts: Vec<&str> = clean_raw.get(1..).unwrap_or("").splitn(2, ' ').collect();
-            if !parts.is_empty() {
-                let indices = super::utils::parse_indices(parts[0]);
-                let content = parts.get(1).unwrap_or(&"").to_string();
-                return (Action::EditAt(scope), content, indices);
-            }
+    pub fn api_configured(url: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("✅ API 已配置: {}", url),
+            Lang::En => format!("✅ API configured: {}", url),
         }
+    }
 
-        if clean == "-*" {
-            return (Action::ClearHistory(scope), String::new(), vec![]);
+    pub fn api_verified(models: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("📋 验证成功，已获取 {} 个模型", models),
+            Lang::En => format!("📋 Verified, found {} models", models),
         }
+    }
 
-        if clean.starts_with('-') && clean.len() > 1 {
-            let idx_part = &clean[1..];
-            let indices = super::utils::parse_indices(idx_part);
-            if !indices.is_empty() {
-                return (Action::DeleteAt(scope), String::new(), indices);
-            }
+    pub fn fetch_models_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("⚠️ 获取模型失败: {}", e),
+            Lang::En => format!("⚠️ Failed to fetch models: {}", e),
         }
+    }
 
-        // 默认 fallback: 视为普通聊天内容
-        (Action::Chat, r.to_string(), vec![])
+    pub fn relay_needs_approval(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("⛔ {} 需要审批，无法参与接力，请单独对话", name),
+            Lang::En => format!("⛔ {} requires approval and cannot join a relay", name),
+        }
     }
-}
 
-// --- 数据管理 ---
-mod data {
-    use super::types::{Config, GeneratingState};
-    use async_openai::Client;
-    use async_openai::config::OpenAIConfig;
-    use kovi::tokio::sync::RwLock;
-    use kovi::utils::{load_json_data, save_json_data};
-    use std::path::PathBuf;
+    pub fn relay_interrupted(name: &str, rest: &[String]) -> String {
+        match lang() {
+            Lang::Zh => format!("⛔ 接力在 {} 处中断，后续 {} 未执行", name, rest.join("、")),
+            Lang::En => format!("⛔ Relay stopped at {}, skipped {}", name, rest.join(", ")),
+        }
+    }
 
-    pub struct Manager {
-        pub config: RwLock<Config>,
-        pub generating: RwLock<GeneratingState>,
-        path: PathBuf,
+    /// list 为空表示关闭审批
+    pub fn approvers_set(name: &str, list: &str) -> String {
+        match (lang(), list.is_empty()) {
+            (Lang::Zh, true) => format!("🔓 {} 已关闭审批", name),
+            (Lang::Zh, false) => format!("🔐 {} 已开启审批，审批人: {}", name, list),
+            (Lang::En, true) => format!("🔓 {}: approval disabled", name),
+            (Lang::En, false) => format!("🔐 {}: approval enabled, approvers: {}", name, list),
+        }
     }
 
-    impl Manager {
-        pub fn new(dir: PathBuf) -> Self {
-            let path = dir.join("config.json");
-            let default = Config {
-                default_model: "gpt-4o".to_string(),
-                default_prompt: "You are a helpful assistant.".to_string(),
-                ..Default::default()
-            };
-            let config = load_json_data(default.clone(), path.clone()).unwrap_or(default);
-            Self {
-                config: RwLock::new(config),
-                generating: RwLock::new(GeneratingState::default()),
-                path,
+    /// 保留策略的说明，0 表示不限
+    pub fn retention_policy(days: u32, count: usize, archive: bool) -> String {
+        match lang() {
+            Lang::Zh => {
+                let days = if days > 0 {
+                    format!("{} 天", days)
+                } else {
+                    "不限".into()
+                };
+                let count = if count > 0 {
+                    format!("{} 条", count)
+                } else {
+                    "不限".into()
+                };
+                let mode = if archive { "归档" } else { "删除" };
+                format!("保留 {} / 最多 {} / 超出{}", days, count, mode)
+            }
+            Lang::En => {
+                let days = if days > 0 {
+                    format!("{} days", days)
+                } else {
+                    "unlimited".into()
+                };
+                let count = if count > 0 {
+                    format!("{} messages", count)
+                } else {
+                    "unlimited".into()
+                };
+                let mode = if archive { "archive" } else { "delete" };
+                format!("keep {} / at most {} / {} the rest", days, count, mode)
             }
         }
+    }
 
-        pub fn save(&self, cfg: &Config) {
-            let _ = save_json_data(cfg, &self.path);
+    pub fn retention_unset(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🗄️ {} 未设置保留策略", name),
+            Lang::En => format!("🗄️ {} has no retention policy", name),
         }
+    }
 
-        pub async fn fetch_models(&self) -> anyhow::Result<Vec<String>> {
-            let (base, key) = {
-                let c = self.config.read().await;
-                (c.api_base.clone(), c.api_key.clone())
-            };
+    pub fn retention_off(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🗄️ {} 已关闭保留策略", name),
+            Lang::En => format!("🗄️ {}: retention policy disabled", name),
+        }
+    }
 
-            if base.is_empty() {
-                return Err(anyhow::anyhow!("API未配置"));
-            }
+    pub fn retention_set(name: &str, policy: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🗄️ {} 已设置: {}", name, policy),
+            Lang::En => format!("🗄️ {} set: {}", name, policy),
+        }
+    }
 
-            let config = OpenAIConfig::new().with_api_base(base).with_api_key(key);
+    pub fn pending_item(id: u64, agent: &str, uid: i64, mins: i64, prompt: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("#{} {} ← {} ({}分钟前): {}", id, agent, uid, mins, prompt),
+            Lang::En => format!("#{} {} ← {} ({} min ago): {}", id, agent, uid, mins, prompt),
+        }
+    }
 
-            let client = Client::with_config(config);
+    pub fn pending_list(items: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🔐 待审批请求:\n{}", items),
+            Lang::En => format!("🔐 Pending requests:\n{}", items),
+        }
+    }
 
-            let response = client.models().list().await?;
+    pub fn approval_missing(id: u64) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 审批 #{} 不存在或已处理", id),
+            Lang::En => format!("❌ Approval #{} does not exist or was handled", id),
+        }
+    }
 
-            // 提取模型 ID 并排序
-            let mut models: Vec<String> = response.data.into_iter().map(|m| m.id).collect();
+    pub fn rejected(id: u64) -> String {
+        match lang() {
+            Lang::Zh => format!("🚫 已拒绝 #{}", id),
+            Lang::En => format!("🚫 Rejected #{}", id),
+        }
+    }
 
-            models.sort();
+    pub fn request_rejected(id: u64) -> String {
+        match lang() {
+            Lang::Zh => format!("🚫 审批 #{} 未通过", id),
+            Lang::En => format!("🚫 Approval #{} was rejected", id),
+        }
+    }
 
-            let filtered = super::utils::filter_models(&models);
-            let final_models = if filtered.is_empty() {
-                models
-            } else {
-                filtered
-            };
+    pub fn approved(id: u64) -> String {
+        match lang() {
+            Lang::Zh => format!("✅ 已同意 #{}", id),
+            Lang::En => format!("✅ Approved #{}", id),
+        }
+    }
 
-            {
-                let mut c = self.config.write().await;
-                c.models = final_models.clone();
-                self.save(&c);
-            }
-            Ok(final_models)
+    pub fn request_approved(id: u64) -> String {
+        match lang() {
+            Lang::Zh => format!("✅ 审批 #{} 已通过，开始生成", id),
+            Lang::En => format!("✅ Approval #{} granted, generating", id),
         }
+    }
 
-        pub fn resolve_model(&self, input: &str, models: &[String]) -> Option<String> {
-            if input.is_empty() {
-                return None;
-            }
-            if let Ok(i) = input.parse::<usize>()
-                && i > 0
-                && i <= models.len()
-            {
-                return Some(models[i - 1].clone());
-            }
-            let lower = input.to_lowercase();
-            for m in models {
-                if m.to_lowercase().contains(&lower) {
-                    return Some(m.clone());
-                }
-            }
-            Some(input.to_string())
+    pub fn already_exists(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ {} 已存在", name),
+            Lang::En => format!("❌ {} already exists", name),
         }
+    }
 
-        pub async fn agent_names(&self) -> Vec<String> {
-            self.config
-                .read()
-                .await
-                .agents
-                .iter()
-                .map(|a| a.name.clone())
-                .collect()
+    pub fn forked(from: &str, n: usize, to: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🌿 已从 {} 分叉 {} 条历史 → {}", from, n, to),
+            Lang::En => format!("🌿 Forked {} messages from {} → {}", n, from, to),
         }
     }
-}
 
-// --- 业务逻辑 ---
-mod logic {
-    use crate::utils::truncate_str;
+    /// history 为 Some 时表示连同历史一起复制
+    pub fn copied(from: &str, to: &str, history: Option<usize>) -> String {
+        match (lang(), history) {
+            (Lang::Zh, Some(n)) => format!("📑 已复制 {} → {} (含 {} 条历史)", from, to, n),
+            (Lang::Zh, None) => format!("📑 已复制 {} → {}", from, to),
+            (Lang::En, Some(n)) => format!("📑 Copied {} → {} (with {} messages)", from, to, n),
+            (Lang::En, None) => format!("📑 Copied {} → {}", from, to),
+        }
+    }
 
-    use super::data::Manager;
-    use super::parser::{Action, Command, Scope};
-    use super::types::{Agent, ChatMessage};
-    use super::utils::{escape_markdown_special, format_export_txt, format_history, render_md};
-    use async_openai::{
-        Client,
-        config::OpenAIConfig,
-        types::{
-            ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
-            ChatCompletionRequestMessageContentPartImageArgs,
-            ChatCompletionRequestMessageContentPartTextArgs,
-            ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
-            CreateChatCompletionRequestArgs, ImageUrlArgs,
-        },
-    };
-    use kovi::bot::message::Message;
-    use kovi_plugin_expand_napcat::NapCatApi;
-    use regex::Regex;
-    use std::{fs::File, io::Write, sync::Arc};
+    pub fn target_exists(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 目标名称 {} 已存在", name),
+            Lang::En => format!("❌ Target name {} already exists", name),
+        }
+    }
 
-    pub(crate) fn reply_text(event: &Arc<kovi::MsgEvent>, text: impl Into<String>) {
-        event.reply(
-            Message::new()
-                .add_reply(event.message_id)
-                .add_text(text.into()),
-        );
+    pub fn renamed(from: &str, to: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🏷️ 已重命名 {} → {}", from, to),
+            Lang::En => format!("🏷️ Renamed {} → {}", from, to),
+        }
     }
 
-    async fn reply(event: &Arc<kovi::MsgEvent>, text: &str, text_mode: bool, header: &str) {
-        let msg = Message::new().add_reply(event.message_id);
+    pub fn desc_updated(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("📝 {} 描述已更新", name),
+            Lang::En => format!("📝 {}: description updated", name),
+        }
+    }
 
-        if text_mode {
-            event.reply(msg.add_text(text));
-            return;
+    pub fn model_changed(name: &str, old: &str, new: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🔄 {} 模型: {} → {}", name, old, new),
+            Lang::En => format!("🔄 {} model: {} → {}", name, old, new),
         }
-        match render_md(text, header).await {
-            Ok(b64) => event.reply(msg.add_image(&format!("base64://{}", b64))),
-            Err(_) => {
-                let re = Regex::new(r"!\[.*?\]\((data:image/[^\s\)]+)\)").unwrap();
-                let clean_text = re.replace_all(text, "[图片渲染失败]").to_string();
-                event.reply(msg.add_text(&clean_text));
+    }
+
+    /// 图片尺寸/质量偏好，value 为 None 表示已清除；非绘图模型时附带提示
+    pub fn image_pref(name: &str, quality: bool, value: Option<&str>, image_model: bool) -> String {
+        match lang() {
+            Lang::Zh => {
+                let label = if quality { "质量" } else { "尺寸" };
+                let hint = if image_model {
+                    ""
+                } else {
+                    "\n💡 当前模型不是绘图模型，偏好将在切换到绘图模型后生效"
+                };
+                match value {
+                    Some(v) => format!("🖌️ {} 图片{}: {}{}", name, label, v, hint),
+                    None => format!("🖌️ {} 图片{}偏好已清除", name, label),
+                }
+            }
+            Lang::En => {
+                let label = if quality { "quality" } else { "size" };
+                let hint = if image_model {
+                    ""
+                } else {
+                    "\n💡 The current model does not draw, this applies after switching to an image model"
+                };
+                match value {
+                    Some(v) => format!("🖌️ {} image {}: {}{}", name, label, v, hint),
+                    None => format!("🖌️ {}: image {} cleared", name, label),
+                }
             }
         }
     }
 
-    fn extract_image_urls(content: &str) -> Vec<String> {
-        let re = Regex::new(
-                    r"!\[.*?\]\(((?:https?://|data:image/)[^\s\)]+)\)|(?:https?://[^\s]+\.(?:png|jpg|jpeg|gif|webp|bmp))",
-                )
-                .unwrap();
+    pub fn seed_set(name: &str, seed: Option<i64>) -> String {
+        match (lang(), seed) {
+            (_, Some(v)) => format!("🎲 {} seed: {}", name, v),
+            (Lang::Zh, None) => format!("🎲 {} seed 已清除", name),
+            (Lang::En, None) => format!("🎲 {}: seed cleared", name),
+        }
+    }
 
-        let mut urls: Vec<String> = re
-            .captures_iter(content)
-            .filter_map(|cap| cap.get(1).or(cap.get(0)).map(|m| m.as_str().to_string()))
-            .collect();
+    /// 停止词设置结果，display 为空表示已清除，truncated_at 为截断时的上限
+    pub fn stop_set(name: &str, display: &str, truncated_at: Option<usize>) -> String {
+        match lang() {
+            Lang::Zh if display.is_empty() => format!("🛑 {} 停止词已清除", name),
+            Lang::En if display.is_empty() => format!("🛑 {}: stop words cleared", name),
+            Lang::Zh => {
+                let warn = truncated_at
+                    .map(|n| format!("\n⚠️ 超过 {} 个，多余部分已截断", n))
+                    .unwrap_or_default();
+                format!("🛑 {} 停止词: {}{}", name, display, warn)
+            }
+            Lang::En => {
+                let warn = truncated_at
+                    .map(|n| format!("\n⚠️ More than {}, the rest were dropped", n))
+                    .unwrap_or_default();
+                format!("🛑 {} stop words: {}{}", name, display, warn)
+            }
+        }
+    }
 
-        let mut seen = std::collections::HashSet::new();
-        urls.retain(|url| seen.insert(url.clone()));
+    /// 超时时长的说明，0 为不限时，global 表示沿用全局默认
+    pub fn timeout_value(secs: u64, global: bool) -> String {
+        let v = match (lang(), secs) {
+            (Lang::Zh, 0) => "不限时".to_string(),
+            (Lang::Zh, n) => format!("{} 秒", n),
+            (Lang::En, 0) => "no limit".to_string(),
+            (Lang::En, n) => format!("{}s", n),
+        };
+        match (lang(), global) {
+            (_, false) => v,
+            (Lang::Zh, true) => format!("{} (全局默认)", v),
+            (Lang::En, true) => format!("{} (global default)", v),
+        }
+    }
 
-        urls
+    pub fn timeout_is(name: &str, value: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("⏱️ {} 超时: {}", name, value),
+            Lang::En => format!("⏱️ {} timeout: {}", name, value),
+        }
     }
 
-    fn extract_video_urls(content: &str) -> Vec<String> {
-        // 匹配 [download video](url)
-        let re = Regex::new(r"\[download video\]\((https?://[^\s\)]+)\)").unwrap();
-        re.captures_iter(content)
-            .filter_map(|cap| cap.get(1).map(|m| m.as_str().to_string()))
-            .collect()
+    pub fn timeout_reset(name: &str, global: u64) -> String {
+        let v = timeout_value(global, false);
+        match lang() {
+            Lang::Zh => format!("⏱️ {} 超时已恢复全局默认 ({})", name, v),
+            Lang::En => format!("⏱️ {}: timeout reset to the global default ({})", name, v),
+        }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    async fn chat(
-        name: &str,
-        prompt: &str,
-        imgs: Vec<String>,
-        regen: bool,
-        cmd: &Command,
-        event: &Arc<kovi::MsgEvent>,
-        mgr: &Arc<Manager>,
-        bot: &Arc<kovi::RuntimeBot>,
-    ) {
-        struct ChatContext<'a> {
-            name: &'a str,
-            prompt: &'a str,
-            imgs: Vec<String>,
-            regen: bool,
-            cmd: &'a Command,
-            event: &'a Arc<kovi::MsgEvent>,
-            mgr: &'a Arc<Manager>,
-            bot: &'a Arc<kovi::RuntimeBot>,
+    pub fn budget_is(name: &str, budget: Option<u32>) -> String {
+        match (lang(), budget) {
+            (Lang::Zh, Some(b)) => format!("💰 {} 单次预算: {} tokens", name, b),
+            (Lang::Zh, None) => format!("💰 {} 单次预算: 不限", name),
+            (Lang::En, Some(b)) => format!("💰 {} budget per request: {} tokens", name, b),
+            (Lang::En, None) => format!("💰 {} budget per request: unlimited", name),
         }
+    }
 
-        async fn inner(ctx: ChatContext<'_>) {
-            let is_priv_ctx = ctx.cmd.private_reply;
-            let uid = ctx.event.user_id.to_string();
-            let temp_mode = ctx.cmd.temp_mode;
+    pub fn budget_cleared(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("💰 {} 已取消预算限制", name),
+            Lang::En => format!("💰 {}: budget removed", name),
+        }
+    }
 
-            // 如果是临时模式，跳过"正在生成"检查，不阻塞
-            if !temp_mode {
-                let generating = ctx.mgr.generating.read().await;
-                if generating.is_generating(ctx.name, is_priv_ctx, &uid) {
-                    reply_text(ctx.event, "⏳ 正在生成中，请等待或使用 智能体! 停止");
-                    return;
-                }
-            }
+    /// set 为 false 表示已清除私有设定
+    pub fn private_prompt_set(name: &str, set: bool) -> String {
+        match (lang(), set) {
+            (Lang::Zh, true) => format!("📝 {} 私有设定已更新 (仅你的私有会话生效)", name),
+            (Lang::Zh, false) => format!("📝 {} 私有设定已清除，恢复默认提示词", name),
+            (Lang::En, true) => format!(
+                "📝 {}: private prompt updated (your private chat only)",
+                name
+            ),
+            (Lang::En, false) => format!("📝 {}: private prompt cleared, using the default", name),
+        }
+    }
 
-            let (agent, api) = {
+    /// set 为 false 表示提示词已清空
+    pub fn prompt_set(name: &str, set: bool) -> String {
+        match (lang(), set) {
+            (Lang::Zh, true) => format!("📝 {} 提示词已更新", name),
+            (Lang::Zh, false) => format!("📝 {} 提示词已清空", name),
+            (Lang::En, true) => format!("📝 {}: prompt updated", name),
+            (Lang::En, false) => format!("📝 {}: prompt cleared", name),
+        }
+    }
+
+    pub fn no_private_sessions(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("📭 {} 暂无私有会话", name),
+            Lang::En => format!("📭 {} has no private sessions", name),
+        }
+    }
+
+    pub fn private_sessions_title(name: &str, n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("{} 私有会话 ({} 人)", name, n),
+            Lang::En => format!("{} private sessions ({} users)", name, n),
+        }
+    }
+
+    pub fn private_session_line(i: usize, uid: &str, count: usize, last: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("\n{}. {} · {} 条 · {}", i, uid, count, last),
+            Lang::En => format!("\n{}. {} · {} messages · {}", i, uid, count, last),
+        }
+    }
+
+    pub fn message_count(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("{} 条", n),
+            Lang::En => format!("{} messages", n),
+        }
+    }
+
+    pub fn user_count(n: usize, max: Option<usize>) -> String {
+        match (lang(), max) {
+            (Lang::Zh, Some(m)) => format!("{} 人 (上限 {})", n, m),
+            (Lang::Zh, None) => format!("{} 人", n),
+            (Lang::En, Some(m)) => format!("{} users (limit {})", n, m),
+            (Lang::En, None) => format!("{} users", n),
+        }
+    }
+
+    pub fn budget_detail(budget: u32, used: usize) -> String {
+        let left = (budget as usize).saturating_sub(used);
+        match lang() {
+            Lang::Zh => format!("{} tokens (当前约 {}，剩余 {})", budget, used, left),
+            Lang::En => format!("{} tokens (about {} used, {} left)", budget, used, left),
+        }
+    }
+
+    pub fn detail_title(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("{} 详情", name),
+            Lang::En => format!("{} details", name),
+        }
+    }
+
+    /// 距今 secs 秒的相对时间
+    pub fn ago(secs: i64) -> String {
+        match (lang(), secs) {
+            (_, 0..60) => Msg::JustNow.into(),
+            (Lang::Zh, 60..3600) => format!("{} 分钟前", secs / 60),
+            (Lang::Zh, 3600..86400) => format!("{} 小时前", secs / 3600),
+            (Lang::Zh, _) => format!("{} 天前", secs / 86400),
+            (Lang::En, 60..3600) => format!("{} min ago", secs / 60),
+            (Lang::En, 3600..86400) => format!("{} h ago", secs / 3600),
+            (Lang::En, _) => format!("{} days ago", secs / 86400),
+        }
+    }
+
+    pub fn agent_list_title(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("📋 智能体列表 (共{}个)", n),
+            Lang::En => format!("📋 Agents ({})", n),
+        }
+    }
+
+    pub fn usage_line(i: usize, name: &str, calls: u64, last: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("\n{}. {} · {} 次 · {}", i, name, calls, last),
+            Lang::En => format!("\n{}. {} · {} calls · {}", i, name, calls, last),
+        }
+    }
+
+    pub fn deleted_agent(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🗑️ 已删除 {}", name),
+            Lang::En => format!("🗑️ Deleted {}", name),
+        }
+    }
+
+    pub fn system_images_cleared(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🖼️ {} 系统参考图已清空", name),
+            Lang::En => format!("🖼️ {}: system images cleared", name),
+        }
+    }
+
+    pub fn no_system_images(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🖼️ {} 暂无系统参考图", name),
+            Lang::En => format!("🖼️ {} has no system images", name),
+        }
+    }
+
+    pub fn system_images(name: &str, list: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🖼️ {} 系统参考图:\n{}", name, list),
+            Lang::En => format!("🖼️ {} system images:\n{}", name, list),
+        }
+    }
+
+    pub fn system_images_added(name: &str, total: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("🖼️ {} 已添加系统参考图，共 {} 张", name, total),
+            Lang::En => format!("🖼️ {}: system image added, {} in total", name, total),
+        }
+    }
+
+    pub fn schema_parse_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 参数定义解析失败: {}", e),
+            Lang::En => format!("❌ Failed to parse the parameter schema: {}", e),
+        }
+    }
+
+    pub fn tool_saved(name: &str, tool: &str, updated: bool) -> String {
+        match (lang(), updated) {
+            (Lang::Zh, true) => format!("🔧 {} 已更新工具 {}", name, tool),
+            (Lang::Zh, false) => format!("🔧 {} 已添加工具 {}", name, tool),
+            (Lang::En, true) => format!("🔧 {}: updated tool {}", name, tool),
+            (Lang::En, false) => format!("🔧 {}: added tool {}", name, tool),
+        }
+    }
+
+    pub fn no_such_tool(name: &str, tool: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ {} 没有工具 {}", name, tool),
+            Lang::En => format!("❌ {} has no tool {}", name, tool),
+        }
+    }
+
+    pub fn tool_removed(name: &str, tool: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🗑️ {} 已移除工具 {}", name, tool),
+            Lang::En => format!("🗑️ {}: removed tool {}", name, tool),
+        }
+    }
+
+    pub fn no_tools(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🔧 {} 暂无工具", name),
+            Lang::En => format!("🔧 {} has no tools", name),
+        }
+    }
+
+    pub fn tools(name: &str, list: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🔧 {} 的工具:\n{}", name, list),
+            Lang::En => format!("🔧 {} tools:\n{}", name, list),
+        }
+    }
+
+    pub fn keyword_exists(word: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("⚠️ 「{}」已在禁止列表中", word),
+            Lang::En => format!("⚠️ \"{}\" is already blocked", word),
+        }
+    }
+
+    pub fn keyword_added(word: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🚫 已添加禁止关键词「{}」", word),
+            Lang::En => format!("🚫 Blocked keyword \"{}\"", word),
+        }
+    }
+
+    pub fn keyword_missing(word: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 「{}」不在禁止列表中", word),
+            Lang::En => format!("❌ \"{}\" is not blocked", word),
+        }
+    }
+
+    pub fn keyword_removed(word: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("✅ 已移除禁止关键词「{}」", word),
+            Lang::En => format!("✅ Unblocked keyword \"{}\"", word),
+        }
+    }
+
+    pub fn keywords(list: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🚫 禁止关键词:\n{}", list),
+            Lang::En => format!("🚫 Blocked keywords:\n{}", list),
+        }
+    }
+
+    pub fn qq_usage(prefix: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 请指定 QQ 号: {}QQ号,QQ号", prefix),
+            Lang::En => format!("❌ Specify QQ numbers: {}id,id", prefix),
+        }
+    }
+
+    pub fn user_list_edited(black: bool, add: bool, changed: usize, total: usize) -> String {
+        match lang() {
+            Lang::Zh => {
+                let label = if black { "黑名单" } else { "白名单" };
+                let verb = if add { "已添加" } else { "已移除" };
+                format!("✅ {}{} {} 人，当前共 {} 人", label, verb, changed, total)
+            }
+            Lang::En => {
+                let label = if black { "Blacklist" } else { "Whitelist" };
+                let verb = if add { "added" } else { "removed" };
+                format!("✅ {}: {} {}, {} in total", label, verb, changed, total)
+            }
+        }
+    }
+
+    pub fn admins(list: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("👑 管理员: {}", list),
+            Lang::En => format!("👑 Admins: {}", list),
+        }
+    }
+
+    pub fn user_lists(white: &str, black: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("✅ 白名单: {}\n⛔ 黑名单: {}", white, black),
+            Lang::En => format!("✅ Whitelist: {}\n⛔ Blacklist: {}", white, black),
+        }
+    }
+
+    pub fn theme_status(current: &str, options: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🎨 当前主题: {}\n可选: {}", current, options),
+            Lang::En => format!("🎨 Current theme: {}\nOptions: {}", current, options),
+        }
+    }
+
+    pub fn unknown_theme(options: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 未知主题，可选: {}", options),
+            Lang::En => format!("❌ Unknown theme, options: {}", options),
+        }
+    }
+
+    pub fn theme_set(theme: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🎨 渲染主题已切换为 {}", theme),
+            Lang::En => format!("🎨 Render theme switched to {}", theme),
+        }
+    }
+
+    /// 查看与切换语言的提示同时给出两种语言，方便切错后找回
+    pub fn lang_status() -> String {
+        format!(
+            "🌐 当前语言 / Language: {}\n可选 / Options: {}",
+            lang().code(),
+            LANGS.join(" / ")
+        )
+    }
+
+    pub fn unknown_lang() -> String {
+        format!(
+            "❌ 未知语言 / Unknown language，可选 / Options: {}",
+            LANGS.join(" / ")
+        )
+    }
+
+    pub fn models_refreshed(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("🔄 已刷新，共 {} 个模型，发送 /% 查看", n),
+            Lang::En => format!("🔄 Refreshed {} models, send /% to view", n),
+        }
+    }
+
+    pub fn fetch_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 获取失败: {}", e),
+            Lang::En => format!("❌ Fetch failed: {}", e),
+        }
+    }
+
+    pub fn fetch_failed_keep_old(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 获取失败，继续使用旧的模型列表: {}", e),
+            Lang::En => format!("❌ Fetch failed, keeping the old model list: {}", e),
+        }
+    }
+
+    pub fn refresh_failed_cached(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("⚠️ 刷新失败，显示缓存的模型列表: {}", e),
+            Lang::En => format!("⚠️ Refresh failed, showing the cached model list: {}", e),
+        }
+    }
+
+    pub fn agent_moved(name: &str, pos: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("↕️ {} 已移动到第 {} 位", name, pos),
+            Lang::En => format!("↕️ Moved {} to position {}", name, pos),
+        }
+    }
+
+    pub fn config_reloaded(agents: usize, models: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("🔄 配置已重载: {} 个智能体，{} 个模型", agents, models),
+            Lang::En => format!("🔄 Config reloaded: {} agents, {} models", agents, models),
+        }
+    }
+
+    pub fn reload_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 重载失败，已保留当前配置: {}", e),
+            Lang::En => format!("❌ Reload failed, kept the current config: {}", e),
+        }
+    }
+
+    pub fn api_healthy(ms: u128) -> String {
+        match lang() {
+            Lang::Zh => format!("✅ API正常 (latency: {}ms)", ms),
+            Lang::En => format!("✅ API is up (latency: {}ms)", ms),
+        }
+    }
+
+    pub fn api_unreachable(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ API不可达: {}", e),
+            Lang::En => format!("❌ API unreachable: {}", e),
+        }
+    }
+
+    pub fn model_keyword_usage(current: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 格式: /%+关键字 或 /%-关键字\n当前过滤词: {}", current),
+            Lang::En => format!(
+                "❌ Usage: /%+keyword or /%-keyword\nCurrent filters: {}",
+                current
+            ),
+        }
+    }
+
+    pub fn model_keyword_exists(word: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("⚠️ 「{}」已在过滤词中", word),
+            Lang::En => format!("⚠️ \"{}\" is already a filter", word),
+        }
+    }
+
+    pub fn model_keyword_missing(word: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 「{}」不在过滤词中", word),
+            Lang::En => format!("❌ \"{}\" is not a filter", word),
+        }
+    }
+
+    pub fn model_keyword_edited(add: bool, word: &str, models: usize) -> String {
+        match (lang(), add) {
+            (Lang::Zh, true) => format!("✅ 已添加过滤词「{}」，当前 {} 个模型", word, models),
+            (Lang::Zh, false) => format!("✅ 已移除过滤词「{}」，当前 {} 个模型", word, models),
+            (Lang::En, true) => format!("✅ Added filter \"{}\", {} models now", word, models),
+            (Lang::En, false) => format!("✅ Removed filter \"{}\", {} models now", word, models),
+        }
+    }
+
+    /// 历史范围的称呼
+    pub fn scope(private: bool) -> &'static str {
+        match (lang(), private) {
+            (Lang::Zh, true) => "私有",
+            (Lang::Zh, false) => "公有",
+            (Lang::En, true) => "private",
+            (Lang::En, false) => "public",
+        }
+    }
+
+    pub fn used_by(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("{}用", n),
+            Lang::En => format!("{} using", n),
+        }
+    }
+
+    /// 模型列表分页说明，next 为查看下一页的指令
+    pub fn models_page(
+        from: usize,
+        to: usize,
+        page: usize,
+        pages: usize,
+        next: Option<&str>,
+    ) -> String {
+        match (lang(), next) {
+            (Lang::Zh, Some(n)) => format!(
+                "📄 显示第 {}-{} 个 ({}/{} 页)，输入 {} 查看更多",
+                from, to, page, pages, n
+            ),
+            (Lang::Zh, None) => format!("📄 显示第 {}-{} 个 ({}/{} 页)", from, to, page, pages),
+            (Lang::En, Some(n)) => format!(
+                "📄 Showing {}-{} (page {}/{}), send {} for more",
+                from, to, page, pages, n
+            ),
+            (Lang::En, None) => format!("📄 Showing {}-{} (page {}/{})", from, to, page, pages),
+        }
+    }
+
+    pub fn model_list_title(all: bool, n: usize) -> String {
+        match (lang(), all) {
+            (Lang::Zh, true) => format!("🧩 完整模型列表 (共{}个)", n),
+            (Lang::Zh, false) => format!("🧩 模型列表 (共{}个)", n),
+            (Lang::En, true) => format!("🧩 All models ({})", n),
+            (Lang::En, false) => format!("🧩 Models ({})", n),
+        }
+    }
+
+    pub fn history_empty(name: &str, private: bool) -> String {
+        match lang() {
+            Lang::Zh => format!("📭 {} {}历史为空", name, scope(private)),
+            Lang::En => format!("📭 {}: {} history is empty", name, scope(private)),
+        }
+    }
+
+    pub fn invalid_page(page: usize, pages: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 页码 {} 无效，共 {} 页", page, pages),
+            Lang::En => format!("❌ Invalid page {}, there are {} pages", page, pages),
+        }
+    }
+
+    pub fn history_header(name: &str, private: bool, n: usize, tokens: usize) -> String {
+        match lang() {
+            Lang::Zh => format!(
+                "{} {}历史 ({} 条 · ~{} tokens)",
+                name,
+                scope(private),
+                n,
+                tokens
+            ),
+            Lang::En => format!(
+                "{} {} history ({} messages · ~{} tokens)",
+                name,
+                scope(private),
+                n,
+                tokens
+            ),
+        }
+    }
+
+    pub fn page_of(page: usize, pages: usize) -> String {
+        match lang() {
+            Lang::Zh => format!(" · 📄 第{}页 / 共{}页", page, pages),
+            Lang::En => format!(" · 📄 page {} / {}", page, pages),
+        }
+    }
+
+    pub fn history_title(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("{} 历史记录", name),
+            Lang::En => format!("{} history", name),
+        }
+    }
+
+    pub fn no_history_in(name: &str, range: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("📭 {} 在 {} 内没有记录", name, range),
+            Lang::En => format!("📭 {} has no messages in {}", name, range),
+        }
+    }
+
+    pub fn history_range_header(name: &str, private: bool, range: &str, n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("{} {}历史 · {} ({} 条)", name, scope(private), range, n),
+            Lang::En => format!(
+                "{} {} history · {} ({} messages)",
+                name,
+                scope(private),
+                range,
+                n
+            ),
+        }
+    }
+
+    pub fn create_file_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 创建文件失败: {}", e),
+            Lang::En => format!("❌ Failed to create the file: {}", e),
+        }
+    }
+
+    pub fn write_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 写入失败: {}", e),
+            Lang::En => format!("❌ Write failed: {}", e),
+        }
+    }
+
+    pub fn code_blocks(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("🧩 共 {} 个代码块", n),
+            Lang::En => format!("🧩 {} code blocks", n),
+        }
+    }
+
+    pub fn context_header(name: &str, private: bool, model: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("{} {}上下文 · {}", name, scope(private), model),
+            Lang::En => format!("{} {} context · {}", name, scope(private), model),
+        }
+    }
+
+    pub fn edited(mode: EditMode, idx: usize) -> String {
+        match (lang(), mode) {
+            (Lang::Zh, EditMode::Replace) => format!("✏️ 已编辑第 {} 条", idx),
+            (Lang::Zh, EditMode::Append) => format!("✏️ 已追加到第 {} 条", idx),
+            (Lang::Zh, EditMode::Prepend) => format!("✏️ 已前插到第 {} 条", idx),
+            (Lang::En, EditMode::Replace) => format!("✏️ Edited #{}", idx),
+            (Lang::En, EditMode::Append) => format!("✏️ Appended to #{}", idx),
+            (Lang::En, EditMode::Prepend) => format!("✏️ Prepended to #{}", idx),
+        }
+    }
+
+    pub fn pattern_not_found(pattern: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🔍 未找到 {}", pattern),
+            Lang::En => format!("🔍 {} not found", pattern),
+        }
+    }
+
+    pub fn replaced(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("🔁 已替换 {} 处", n),
+            Lang::En => format!("🔁 Replaced {} matches", n),
+        }
+    }
+
+    pub fn invalid_regex(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 正则无效: {}", e),
+            Lang::En => format!("❌ Invalid regex: {}", e),
+        }
+    }
+
+    pub fn deleted_messages(list: &str, n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("🗑️ 已删除第 {} 条 (共{}条)", list, n),
+            Lang::En => format!("🗑️ Deleted #{} ({} in total)", list, n),
+        }
+    }
+
+    pub fn no_snapshots(name: &str, private: bool) -> String {
+        match lang() {
+            Lang::Zh => format!("📭 {} {}历史暂无快照", name, scope(private)),
+            Lang::En => format!("📭 {} has no {} history snapshots", name, scope(private)),
+        }
+    }
+
+    pub fn restore_hint(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("> 使用 `{}/restore 编号` 回滚", name),
+            Lang::En => format!("> Use `{}/restore number` to roll back", name),
+        }
+    }
+
+    pub fn snapshots_title(name: &str, private: bool, n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("{} {}历史快照 ({} 个)", name, scope(private), n),
+            Lang::En => format!("{} {} history snapshots ({})", name, scope(private), n),
+        }
+    }
+
+    pub fn no_such_snapshot(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 快照 #{} 不存在", n),
+            Lang::En => format!("❌ Snapshot #{} does not exist", n),
+        }
+    }
+
+    pub fn restored(name: &str, n: usize, len: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("⏪ {} 已回滚到快照 #{} ({} 条)", name, n, len),
+            Lang::En => format!("⏪ {}: restored snapshot #{} ({} messages)", name, n, len),
+        }
+    }
+
+    /// everything 为 true 时连同私有历史一起清空
+    pub fn cleared_all(n: usize, everything: bool) -> String {
+        match (lang(), everything) {
+            (Lang::Zh, false) => format!("🧹 已清空 {} 个智能体的公有历史", n),
+            (Lang::Zh, true) => format!("⚠️ 已清空 {} 个智能体的所有历史", n),
+            (Lang::En, false) => format!("🧹 Cleared public history of {} agents", n),
+            (Lang::En, true) => format!("⚠️ Cleared all history of {} agents", n),
+        }
+    }
+
+    pub fn describing(model: &str, n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!(
+                "🤖 开始使用 [{}] 为 {} 个智能体生成描述，请稍候...",
+                model, n
+            ),
+            Lang::En => format!(
+                "🤖 Writing descriptions for {} agents with [{}]...",
+                n, model
+            ),
+        }
+    }
+
+    pub fn described(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("✅ 批量处理完成，已更新 {} 个智能体的描述。", n),
+            Lang::En => format!("✅ Done, updated the descriptions of {} agents.", n),
+        }
+    }
+
+    pub fn default_model(model: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("📌 当前默认模型: {}", model),
+            Lang::En => format!("📌 Default model: {}", model),
+        }
+    }
+
+    pub fn default_model_changed(old: &str, new: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🔄 默认模型: {} → {}", old, new),
+            Lang::En => format!("🔄 Default model: {} → {}", old, new),
+        }
+    }
+
+    pub fn no_agents_match(pattern: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("🔍 没有名称匹配 {} 的智能体", pattern),
+            Lang::En => format!("🔍 No agent names match {}", pattern),
+        }
+    }
+
+    pub fn bulk_model_set(model: &str, names: &[String]) -> String {
+        match lang() {
+            Lang::Zh => format!(
+                "🔄 已更新 {} 个智能体的模型 → {}\n{}",
+                names.len(),
+                model,
+                names.join("、")
+            ),
+            Lang::En => format!(
+                "🔄 Set the model of {} agents → {}\n{}",
+                names.len(),
+                model,
+                names.join(", ")
+            ),
+        }
+    }
+
+    pub fn default_prompt(prompt: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("📌 当前默认提示词:\n{}", prompt),
+            Lang::En => format!("📌 Default prompt:\n{}", prompt),
+        }
+    }
+
+    pub fn serialize_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 序列化失败: {}", e),
+            Lang::En => format!("❌ Serialization failed: {}", e),
+        }
+    }
+
+    pub fn invalid_agent_def(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 智能体定义格式错误: {}", e),
+            Lang::En => format!("❌ Invalid agent definition: {}", e),
+        }
+    }
+
+    /// 导入的名称与原名不同时说明已改名
+    pub fn agent_imported(base: &str, name: &str, model: &str) -> String {
+        match (lang(), base == name) {
+            (Lang::Zh, true) => format!("📥 已导入 {} (模型: {})", name, model),
+            (Lang::Zh, false) => format!("📥 {} 已存在，已导入为 {} (模型: {})", base, name, model),
+            (Lang::En, true) => format!("📥 Imported {} (model: {})", name, model),
+            (Lang::En, false) => format!(
+                "📥 {} already exists, imported as {} (model: {})",
+                base, name, model
+            ),
+        }
+    }
+
+    pub fn backup_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 备份失败: {}", e),
+            Lang::En => format!("❌ Backup failed: {}", e),
+        }
+    }
+
+    pub fn config_restored(fname: &str, n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("♻️ 已从 {} 恢复配置 ({} 个智能体)", fname, n),
+            Lang::En => format!("♻️ Restored the config from {} ({} agents)", fname, n),
+        }
+    }
+
+    pub fn restore_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 恢复失败: {}", e),
+            Lang::En => format!("❌ Restore failed: {}", e),
+        }
+    }
+
+    pub fn config_too_new(found: u32, supported: u32) -> String {
+        match lang() {
+            Lang::Zh => format!("配置版本 {} 高于插件支持的版本 {}", found, supported),
+            Lang::En => format!(
+                "config version {} is newer than the supported version {}",
+                found, supported
+            ),
+        }
+    }
+
+    pub fn config_invalid(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("配置格式错误: {}", e),
+            Lang::En => format!("malformed config: {}", e),
+        }
+    }
+
+    pub fn agent_name_duplicate(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("智能体名称为空或重复: {}", name),
+            Lang::En => format!("empty or duplicate agent name: {}", name),
+        }
+    }
+
+    pub fn agent_name_illegal(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("智能体名称含非法字符: {}", name),
+            Lang::En => format!("agent name contains illegal characters: {}", name),
+        }
+    }
+
+    pub fn fetch_voice_failed(e: impl std::fmt::Debug) -> String {
+        match lang() {
+            Lang::Zh => format!("获取语音失败: {:?}", e),
+            Lang::En => format!("failed to fetch the voice message: {:?}", e),
+        }
+    }
+
+    /// created 为 false 表示更新了已有的智能体
+    pub fn agent_saved(name: &str, model: &str, created: bool) -> String {
+        match (lang(), created) {
+            (Lang::Zh, true) => format!("🤖 已创建 {} (模型: {})", name, model),
+            (Lang::Zh, false) => format!("📝 已更新 {} (模型: {})", name, model),
+            (Lang::En, true) => format!("🤖 Created {} (model: {})", name, model),
+            (Lang::En, false) => format!("📝 Updated {} (model: {})", name, model),
+        }
+    }
+
+    pub fn transcribe_failed(e: impl std::fmt::Display) -> String {
+        match lang() {
+            Lang::Zh => format!("⚠️ 语音转写失败: {}", e),
+            Lang::En => format!("⚠️ Voice transcription failed: {}", e),
+        }
+    }
+
+    pub fn char_count(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("{}字", n),
+            Lang::En => format!("{} chars", n),
+        }
+    }
+
+    /// 文本导出的表头：智能体、模型、类型、导出时间、记录数
+    pub fn export_labels() -> [&'static str; 5] {
+        match lang() {
+            Lang::Zh => ["智能体", "模  型", "类  型", "导  出", "记录数"],
+            Lang::En => ["Agent   ", "Model   ", "Scope   ", "Exported", "Messages"],
+        }
+    }
+
+    pub fn attached_images(n: usize) -> String {
+        match lang() {
+            Lang::Zh => format!("\n📷 附图 ({} 张):\n", n),
+            Lang::En => format!("\n📷 Images ({}):\n", n),
+        }
+    }
+
+    pub fn export_title(name: &str, model: &str, scope: &str, n: usize, time: &str) -> String {
+        match lang() {
+            Lang::Zh => format!(
+                "{} · {} · {}历史 · {} 条 · 导出于 {}",
+                name, model, scope, n, time
+            ),
+            Lang::En => format!(
+                "{} · {} · {} history · {} messages · exported {}",
+                name, model, scope, n, time
+            ),
+        }
+    }
+
+    pub fn export_doc_title(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("{} 对话记录", name),
+            Lang::En => format!("{} chat history", name),
+        }
+    }
+
+    pub fn did_you_mean(name: &str) -> String {
+        match lang() {
+            Lang::Zh => format!("❌ 未找到智能体，你是否想输入 '{}'？", name),
+            Lang::En => format!("❌ Agent not found, did you mean '{}'?", name),
+        }
+    }
+
+    pub fn help(lang: Lang) -> &'static str {
+        match lang {
+            Lang::Zh => HELP_ZH,
+            Lang::En => HELP_EN,
+        }
+    }
+
+    const HELP_ZH: &str = r#"## 模式前缀（可组合）
+| 符号 | 含义 |
+|:---:|------|
+| `&` | 私有模式 (独立历史) |
+| `"` | 文本模式 (不转图片) |
+| `~` | 临时模式 (无历史/不阻塞) |
+
+## 智能体管理
+| 指令 | 功能 | 示例 |
+|------|------|------|
+| `##名称 模型 提示词` | 创建/更新 | `##助手 gpt-4o 你是助手` |
+| `##名称 --model "模型" --prompt "提示词"` | 具名参数创建 | `##助手 --model "my model"` |
+| `##"名称" 模型 提示词` | 名称含符号时用引号包裹 | `##"C++" gpt-4o` |
+| `##名称 模型 """多行提示词"""` | 多行提示词 (也可用 ```) | `##助手 gpt-4o """第一行\n第二行"""` |
+| `##:模型` | 批量生成描述 | `##:gpt-4o` |
+| `智能体~=新名` | 重命名 | `助手~=管家` |
+| `智能体~#新名` | 复制 | `助手~#助手2` |
+| `智能体~#新名+` | 复制(含公有历史) | `助手~#助手2+` |
+| `智能体~1-5#新名` | 从第1-5条历史分叉 | `助手~1-4#支线` |
+| `智能体:描述` | 设置描述 | `助手:通用助手` |
+| `-#名称` | 删除 | `-#助手` |
+| `##@新名` | 引用定义文件导入 | `##@` |
+| `/#` | 列表 | `/#` |
+| `/#top` / `/#recent` | 按调用次数 / 最近使用排序 | `/#top` |
+| `##>名称 位置` | 调整列表顺序 | `##>助手 1` |
+
+## 配置修改
+| 指令 | 功能 | 示例 |
+|------|------|------|
+| `智能体%模型` | 修改模型 | `助手%gpt-4` |
+| `智能体%timeout=秒` | 请求超时 (0 不限，留空恢复默认) | `助手%timeout=60` |
+| `智能体_budget=N` | 单次输入 token 预算 (留空取消) | `助手_budget=1000` |
+| `智能体_maxprivate=N` | 私有会话人数上限 (留空取消) | `助手_maxprivate=50` |
+| `智能体%%stop=词,词` | 停止词 (支持 \n 转义，留空清除) | `助手%%stop=\n\n,END` |
+| `智能体%%seed=数字` | 固定随机种子 (留空清除) | `助手%%seed=42` |
+| `智能体%%size=尺寸` / `%%quality=质量` | 绘图模型图片偏好 (留空清除) | `助手%%size=1024x1024` |
+| `智能体$提示词` | 修改提示词 | `助手$你是...` |
+| `智能体$` | 清空提示词 | `助手$` |
+| `智能体/$` | 查看提示词 | `助手/$` |
+| `&智能体$设定` | 私有会话专属提示词 (留空恢复默认) | `&助手$叫我主人` |
+| `智能体//` | 查看详情卡片 | `助手//` |
+| `智能体//users` | 私有会话用户列表 (管理员) | `助手//u` |
+| `智能体$@链接` | 添加系统参考图 (留空查看) | `助手$@https://...` |
+| `智能体$@-` | 清空系统参考图 | `助手$@-` |
+| `智能体+tool 名 描述 {参数}` | 添加工具 (留空查看) | `助手+tool weather 查天气 {...}` |
+| `智能体+tool-名` | 移除工具 | `助手+tool-weather` |
+| `/%` | 模型列表（`/%2` 查看第 2 页） | `/%` `/%2` |
+| `/%all` | 完整模型列表 (不过滤) | `/%all` |
+| `/%!` | 强制刷新模型列表 | `/%!` |
+| `/%+关键字` `/%-关键字` | 增删模型过滤词 | `/%+qwen` |
+| `/theme 主题` | 切换渲染主题 (light/dark/sepia) | `/theme dark` |
+| `/lang 语言` | 回复与帮助的语言 (zh/en) | `/lang en` |
+| `##%模型` | 设置默认模型 | `##%gpt-4o` |
+| `##%模型 模式` | 批量修改模型 (前缀/通配符) | `##%gpt-4o 助*` |
+| `##$提示词` | 设置默认提示词 | `##$你是助手` |
+
+> 提示词变量: `{date}` `{time}` `{user}` `{model}`
+> 内置工具 (本地执行): `current_time` `calculate`，如 `助手+tool calculate`
+
+## 对话控制
+| 指令 | 功能 |
+|------|------|
+| `智能体 内容` | 正常对话 |
+| `~智能体 内容` | 临时对话 (一次性) |
+| `"智能体 内容` | 文本回复对话 |
+| `&智能体 内容` | 私有历史对话 |
+| `智能体\|sys\|要求\|内容` | 附带一次性系统消息对话 (不写入历史) |
+| `智能体A+智能体B 内容` | 接力回复 (B 可见 A 的回答) |
+| `智能体~` | 重新生成上一条 |
+| `智能体~%模型 内容` | 临时换模型重新生成 |
+| `智能体/t 语言` | 翻译上一条回复 (不写入历史，默认英语) |
+| `智能体~sum N` | 总结压缩历史 (保留最近N条) |
+| `智能体!` | 停止生成 |
+| `智能体!image 描述` | 调用 images 接口绘图 |
+
+## 历史管理
+| 指令 | 功能 |
+|------|------|
+| `智能体/*` | 查看历史 (分页，默认最新页) |
+| `智能体/*N` | 查看第N页 |
+| `智能体/ctx` | 查看 API 上下文 |
+| `智能体/code [N]` | 提取回复中的代码块 (默认最后一条) |
+| `智能体/1` | 查看第1条 |
+| `智能体/1src` | 第1条原始文本 (同 `/1"`) |
+| `智能体/1-5` | 查看范围 |
+| `智能体/today` | 按时间查看 (today/yesterday/7d/12h) |
+| `智能体/-1` | 查看最后一条 (`/-3--1` 倒数三条，`/5-1` 倒序) |
+| `智能体_*` | 导出(.txt) |
+| `智能体_*html` | 导出网页 (.html，可离线打开) |
+| `智能体_*mindmap` | 导出思维导图(.md，markmap 格式) |
+| `智能体_@` | 导出智能体定义(.json) |
+| `智能体'1 内容` | 编辑第1条 |
+| `智能体'1+ 内容` / `'1^ 内容` | 追加 / 前插到第1条 |
+| `智能体's/旧/新/` | 全部历史查找替换 (同 `'*/`，末尾加 r 为正则、i 忽略大小写) |
+| `智能体>1,3` | 交换两条消息 (恰好两个不同序号) |
+| `智能体*1,3` | 切换置顶 (清空与自动清理时保留) |
+| `智能体+2 角色 内容` | 插入为第2条 (user/assistant/system) |
+| `智能体-1` | 删除第1条 |
+| `智能体-1,3` | 删除多条 |
+| `智能体-1-10:2` | 按步长删除 (1,3,5,7,9) |
+| `智能体-*` | 清空历史 |
+| `智能体/history` | 查看历史快照 |
+| `智能体/restore 1` | 回滚到快照 |
+| `智能体^^` | 撤销最近一次删除/编辑/清空 (最多 3 步) |
+| `智能体/keep 天数 条数 [归档]` | 自动保留策略 (off 关闭) |
+| `智能体/global` | 认领旧版全局公有历史到当前会话 (管理员) |
+
+> 清空、批量删除、查找替换、导入配置前会自动保存快照
+> 所有符号支持半角/全角兼容 (如 ～, ＃, ＝)
+> 加 `&` 前缀可操作私有历史: `&智能体/*`
+
+## 危险操作
+| 指令 | 功能 |
+|------|------|
+| `-*` | 清空所有智能体公有历史 |
+| `-*!` | 清空数据库所有历史 |
+
+> 配置 admins 后仅管理员可用
+
+## 审批
+| 指令 | 功能 |
+|------|------|
+| `智能体?=QQ号,QQ号` | 设置审批人并开启审批 |
+| `智能体?=` | 关闭审批 |
+| `?*` | 查看待审批请求 |
+| `?+编号` / `?-编号` | 同意 / 拒绝 |
+
+## 配置备份
+| 指令 | 功能 |
+|------|------|
+| `##_backup` | 备份完整配置(.json) |
+| `##_restore 文件名` | 从备份恢复 (缺省为最新) |
+
+## 内容过滤
+| 指令 | 功能 |
+|------|------|
+| `##!关键词` | 添加禁止关键词 |
+| `##!-关键词` | 移除禁止关键词 |
+| `##!*` | 查看禁止关键词 |
+
+## 用户名单
+| 指令 | 功能 |
+|------|------|
+| `##+QQ号` / `##+-QQ号` | 添加 / 移除白名单 |
+| `##^QQ号` / `##^-QQ号` | 添加 / 移除黑名单 |
+| `##+*` | 查看名单 |
+
+## 管理员
+| 指令 | 功能 |
+|------|------|
+| `##admin+QQ号` / `##admin-QQ号` | 添加 / 移除管理员 |
+| `##admin` | 查看管理员 |
+
+## 英文别名
+`oai help` `oai list` `oai models` `oai clearall` 分别等同 `oai` `/#` `/%` `-*`
+智能体后缀 (需整体匹配): `stop` `regen` `history` `context` `prompt` `export` `clear`
+例: `助手 stop` = `助手!`，`助手 regen` = `助手~`
+
+## API 配置
+更新指令: `oai API地址 API密钥`
+帮助: `oai` (默认语言) / `oai en` / `oai zh`
+连通性检查: `oai health`
+重载配置文件: `oai reload` (管理员)
+"#;
+
+    const HELP_EN: &str = r#"## Mode prefixes (combinable)
+| Symbol | Meaning |
+|:---:|------|
+| `&` | Private mode (separate history) |
+| `"` | Text mode (no image rendering) |
+| `~` | Temporary mode (no history, non-blocking) |
+
+## Agent management
+| Command | Function | Example |
+|------|------|------|
+| `##name model prompt` | Create / update | `##bot gpt-4o You are a helper` |
+| `##name --model "m" --prompt "p"` | Create with named args | `##bot --model "my model"` |
+| `##"name" model prompt` | Quote names containing symbols | `##"C++" gpt-4o` |
+| `##name model """lines"""` | Multi-line prompt (``` also works) | `##bot gpt-4o """line 1\nline 2"""` |
+| `##:model` | Generate missing descriptions | `##:gpt-4o` |
+| `agent~=new` | Rename | `bot~=butler` |
+| `agent~#new` | Copy | `bot~#bot2` |
+| `agent~#new+` | Copy with public history | `bot~#bot2+` |
+| `agent~1-5#new` | Fork from messages 1-5 | `bot~1-4#branch` |
+| `agent:desc` | Set description | `bot:General helper` |
+| `-#name` | Delete | `-#bot` |
+| `##@new` | Import a quoted definition file | `##@` |
+| `/#` | List agents | `/#` |
+| `/#top` / `/#recent` | Sort by call count / last used | `/#top` |
+| `##>name pos` | Move agent in the list | `##>bot 1` |
+
+## Configuration
+| Command | Function | Example |
+|------|------|------|
+| `agent%model` | Change model | `bot%gpt-4` |
+| `agent%timeout=secs` | Request timeout (0 = none, empty = default) | `bot%timeout=60` |
+| `agent_budget=N` | Input token budget per request (empty = none) | `bot_budget=1000` |
+| `agent_maxprivate=N` | Max users with private sessions (empty = none) | `bot_maxprivate=50` |
+| `agent%%stop=a,b` | Stop sequences (\n escapes, empty to clear) | `bot%%stop=\n\n,END` |
+| `agent%%seed=n` | Fixed seed (empty to clear) | `bot%%seed=42` |
+| `agent%%size=WxH` / `%%quality=q` | Image model preferences (empty to clear) | `bot%%size=1024x1024` |
+| `agent$prompt` | Change prompt | `bot$You are...` |
+| `agent$` | Clear prompt | `bot$` |
+| `agent/$` | View prompt | `bot/$` |
+| `&agent$prompt` | Prompt for your private chats (empty = default) | `&bot$Call me boss` |
+| `agent//` | View detail card | `bot//` |
+| `agent//users` | List private session users (admin) | `bot//u` |
+| `agent$@url` | Add reference image (empty to view) | `bot$@https://...` |
+| `agent$@-` | Clear reference images | `bot$@-` |
+| `agent+tool name desc {schema}` | Add tool (empty to view) | `bot+tool weather Get weather {...}` |
+| `agent+tool-name` | Remove tool | `bot+tool-weather` |
+| `/%` | List models (`/%2` for page 2) | `/%` `/%2` |
+| `/%all` | Full model list (unfiltered) | `/%all` |
+| `/%!` | Force refresh models | `/%!` |
+| `/%+kw` `/%-kw` | Add/remove model filter keyword | `/%+qwen` |
+| `/theme name` | Render theme (light/dark/sepia) | `/theme dark` |
+| `/lang code` | Language of replies and help (zh/en) | `/lang en` |
+| `##%model` | Set default model | `##%gpt-4o` |
+| `##%model pattern` | Bulk change model (prefix/glob) | `##%gpt-4o bot*` |
+| `##$prompt` | Set default prompt | `##$You are a helper` |
+
+> Prompt variables: `{date}` `{time}` `{user}` `{model}`
+> Built-in tools (run locally): `current_time` `calculate`, e.g. `bot+tool calculate`
+
+## Chat
+| Command | Function |
+|------|------|
+| `agent text` | Chat |
+| `~agent text` | One-off chat |
+| `"agent text` | Reply as text |
+| `&agent text` | Chat with private history |
+| `agent\|sys\|rule\|text` | Chat with a one-off system message (not saved) |
+| `agentA+agentB text` | Relay (B sees A's answer) |
+| `agent~` | Regenerate last reply |
+| `agent~%model text` | Regenerate with another model |
+| `agent/t lang` | Translate last reply (not saved, default English) |
+| `agent~sum N` | Summarize history (keep last N) |
+| `agent!` | Stop generating |
+| `agent!image prompt` | Generate an image via the images endpoint |
+
+## History
+| Command | Function |
+|------|------|
+| `agent/*` | View history (paged, latest page) |
+| `agent/*N` | View page N |
+| `agent/ctx` | View API context |
+| `agent/code [N]` | Extract code blocks from a reply (default last) |
+| `agent/1` | View message 1 |
+| `agent/1src` | Raw text of message 1 (same as `/1"`) |
+| `agent/1-5` | View a range |
+| `agent/today` | View by time (today/yesterday/7d/12h) |
+| `agent/-1` | View the last one (`/-3--1` last three, `/5-1` reversed) |
+| `agent_*` | Export (.txt) |
+| `agent_*html` | Export web page (.html, works offline) |
+| `agent_*mindmap` | Export mind map (.md, markmap) |
+| `agent_@` | Export agent definition (.json) |
+| `agent'1 text` | Edit message 1 |
+| `agent'1+ text` / `'1^ text` | Append / prepend to message 1 |
+| `agent's/old/new/` | Find and replace in history (same as `'*/`; `r` regex, `i` ignore case) |
+| `agent>1,3` | Swap two messages (exactly two distinct indices) |
+| `agent*1,3` | Toggle pin (kept on clear and auto cleanup) |
+| `agent+2 role text` | Insert as message 2 (user/assistant/system) |
+| `agent-1` | Delete message 1 |
+| `agent-1,3` | Delete several |
+| `agent-1-10:2` | Delete with a step (1,3,5,7,9) |
+| `agent-*` | Clear history |
+| `agent/history` | List snapshots |
+| `agent/restore 1` | Restore a snapshot |
+| `agent^^` | Undo the last delete/edit/clear (up to 3 steps) |
+| `agent/keep days count [archive]` | Retention policy (off to disable) |
+| `agent/global` | Claim legacy global history into this chat (admin) |
+
+> Snapshots are saved before clearing, bulk deletion, find-and-replace and config import
+> Full-width symbols are accepted (e.g. ～, ＃, ＝)
+> Prefix `&` for private history: `&agent/*`
+
+## Dangerous
+| Command | Function |
+|------|------|
+| `-*` | Clear public history of all agents |
+| `-*!` | Clear all history |
+
+> Admin only when `admins` is configured
+
+## Approval
+| Command | Function |
+|------|------|
+| `agent?=QQ,QQ` | Set approvers and enable approval |
+| `agent?=` | Disable approval |
+| `?*` | List pending requests |
+| `?+id` / `?-id` | Approve / reject |
+
+## Backup
+| Command | Function |
+|------|------|
+| `##_backup` | Back up the full config (.json) |
+| `##_restore file` | Restore from backup (latest by default) |
+
+## Content filter
+| Command | Function |
+|------|------|
+| `##!word` | Add blocked keyword |
+| `##!-word` | Remove blocked keyword |
+| `##!*` | List blocked keywords |
+
+## User lists
+| Command | Function |
+|------|------|
+| `##+QQ` / `##+-QQ` | Add / remove whitelist |
+| `##^QQ` / `##^-QQ` | Add / remove blacklist |
+| `##+*` | View lists |
+
+## Admins
+| Command | Function |
+|------|------|
+| `##admin+QQ` / `##admin-QQ` | Add / remove admin |
+| `##admin` | List admins |
+
+## English aliases
+`oai help` `oai list` `oai models` `oai clearall` equal `oai` `/#` `/%` `-*`
+Agent suffixes (exact match): `stop` `regen` `history` `context` `prompt` `export` `clear`
+e.g. `bot stop` = `bot!`, `bot regen` = `bot~`
+
+## API
+Configure: `oai API_BASE API_KEY`
+Help: `oai` (default language) / `oai en` / `oai zh`
+Connectivity check: `oai health`
+Reload config file: `oai reload` (admin)
+"#;
+}
+
+// --- 指令解析器 ---
+mod parser {
+    use super::utils::normalize;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Scope {
+        Public,
+        Private,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub enum Action {
+        Chat,
+        /// 多智能体接力 (A+B 话题)，cmd.agent 为首个，此处为其后的智能体
+        Relay(Vec<String>),
+        /// 翻译上一条回复，结果不写入历史
+        Translate(String),
+        /// 调用 images 接口绘图，参数为描述
+        ImageGen(String),
+        Regenerate {
+            model: Option<String>,
+        },
+        Stop,
+        #[default]
+        Create,
+        Copy {
+            with_history: bool,
+        },
+        Rename,
+        SetDesc,
+        Delete,
+        List,
+        /// 按使用情况排序的列表：/#top 按调用次数，/#recent 按最近使用
+        ListByUsage {
+            by_calls: bool,
+        },
+        SetModel,
+        SetTimeout,
+        /// `_键=值` 智能体选项，目前支持 budget
+        SetOption(String),
+        SetStop,
+        SetSeed,
+        SetImagePref {
+            quality: bool,
+        },
+        SetPrompt,
+        ViewPrompt,
+        ViewDetail,
+        /// 私有会话用户及其消息数 (管理员)
+        ListPrivateUsers,
+        /// 模型列表 (页码从 1 起)，all 为不过滤的完整列表
+        ListModels {
+            page: usize,
+            all: bool,
+        },
+        /// 增删模型过滤关键字
+        EditModelKeyword {
+            add: bool,
+            word: String,
+        },
+        RefreshModels,
+        SetTheme(String),
+        SetLang(String),
+        AddBlockedKeyword(String),
+        RemoveBlockedKeyword(String),
+        ListBlockedKeywords,
+        EditUserList {
+            black: bool,
+            add: bool,
+            ids: String,
+        },
+        ListUserLists,
+        EditAdmins {
+            add: bool,
+            ids: String,
+        },
+        ListAdmins,
+        SetSystemImage,
+        AddTool,
+        RemoveTool(String),
+        ListTools,
+        DidYouMean(String),
+        ViewAll(Scope),
+        ViewAt(Scope),
+        ShowContext(Scope),
+        /// 以纯文本发送历史的原始内容
+        ViewSource(Scope),
+        /// 按相对时间范围查看历史，参数如 today / 7d
+        ViewTimeRange(Scope),
+        /// 提取回复中的代码块逐条发送，默认最后一条回复
+        ExtractCode(Scope),
+        Export(Scope),
+        /// 导出为可离线打开的 HTML (_*html)
+        ExportHtml(Scope),
+        ExportMindmap(Scope),
+        Summarize(Scope),
+        EditAt(Scope, super::types::EditMode),
+        Reorder(Scope),
+        TogglePin(Scope),
+        ReplaceAll {
+            scope: Scope,
+            pattern: String,
+            replacement: String,
+            regex: bool,
+            ignore_case: bool,
+        },
+        InsertAt(Scope),
+        DeleteAt(Scope),
+        ClearHistory(Scope),
+        ClearAllPublic,
+        ClearEverything,
+        Help(Option<super::messages::Lang>),
+        AutoFillDescriptions(String),
+        UpdateApi(String, String),
+        BackupConfig,
+        RestoreConfig(String),
+        ExportAgent,
+        ImportAgent(String),
+        SetDefaultModel(String),
+        HealthCheck,
+        ReloadConfig,
+        /// 把旧版全局公有历史认领到当前群/私聊的公有历史 (管理员)
+        ClaimGlobal,
+        SetAgentOrder {
+            name: String,
+            pos: usize,
+        },
+        BulkSetModel {
+            model: String,
+            pattern: String,
+        },
+        SetDefaultPrompt(String),
+        ListSnapshots(Scope),
+        RestoreSnapshot(Scope),
+        /// 撤销最近一次删除/编辑/清空 (^^)
+        Undo(Scope),
+        SetApprovers,
+        SetRetention,
+        ListPending,
+        Approve(u64),
+        Reject(u64),
+    }
+
+    impl Action {
+        /// 审计日志中记录的指令名，隐去 API 密钥等敏感参数
+        pub fn audit_name(&self) -> String {
+            match self {
+                Action::UpdateApi(url, _) => format!("UpdateApi({:?})", url),
+                other => format!("{:?}", other),
+            }
+        }
+
+        /// 破坏性或涉及全局配置的指令，配置了管理员时仅管理员可用
+        pub fn requires_admin(&self) -> bool {
+            matches!(
+                self,
+                Action::Delete
+                    | Action::ClearAllPublic
+                    | Action::ClearEverything
+                    | Action::UpdateApi(..)
+                    | Action::BackupConfig
+                    | Action::RestoreConfig(_)
+                    | Action::AddBlockedKeyword(_)
+                    | Action::RemoveBlockedKeyword(_)
+                    | Action::EditUserList { .. }
+                    | Action::AutoFillDescriptions(_)
+                    | Action::EditAdmins { .. }
+                    | Action::BulkSetModel { .. }
+                    | Action::ReloadConfig
+                    | Action::ClaimGlobal
+                    | Action::EditModelKeyword { .. }
+                    | Action::ListPrivateUsers
+                    | Action::SetAgentOrder { .. }
+                    | Action::SetApprovers
+                    | Action::SetRetention
+            ) || matches!(
+                // 全局设置：留空查看所有人可用，修改仅限管理员
+                self,
+                Action::SetDefaultModel(v)
+                    | Action::SetDefaultPrompt(v)
+                    | Action::SetTheme(v)
+                    | Action::SetLang(v) if !v.is_empty()
+            )
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Command {
+        pub agent: String,
+        pub action: Action,
+        pub args: String,
+        pub indices: Vec<usize>,
+        pub private_reply: bool,
+        pub text_mode: bool,
+        pub temp_mode: bool,
+        /// `|sys|要求|内容`：仅对本轮生效的系统消息，不写入历史
+        pub system_injection: Option<String>,
+    }
+
+    impl Command {
+        pub fn new(agent: &str, action: Action) -> Self {
+            Self {
+                agent: agent.to_string(),
+                action,
+                args: String::new(),
+                indices: Vec::new(),
+                private_reply: false,
+                text_mode: false,
+                temp_mode: false,
+                system_injection: None,
+            }
+        }
+    }
+
+    /// 跳过原始字符串的前 n 个字符 (normalize 不改变字符数，可据此保留参数原文)
+    fn raw_after(raw: &str, n: usize) -> &str {
+        let skip: usize = raw.chars().take(n).map(|c| c.len_utf8()).sum();
+        raw[skip..].trim()
+    }
+
+    /// 智能体后缀的英文别名，仅在后缀整体等于别名词时生效
+    const SUFFIX_ALIASES: &[(&str, &str)] = &[
+        ("stop", "!"),
+        ("regen", "~"),
+        ("history", "/*"),
+        ("context", "/ctx"),
+        ("prompt", "/$"),
+        ("export", "_*"),
+        ("clear", "-*"),
+    ];
+
+    fn suffix_alias(suffix: &str) -> Option<&'static str> {
+        SUFFIX_ALIASES
+            .iter()
+            .find(|(word, _)| suffix.eq_ignore_ascii_case(word))
+            .map(|(_, sym)| *sym)
+    }
+
+    pub fn parse_global(raw: &str) -> Option<Command> {
+        let norm = normalize(raw.trim());
+
+        if norm.starts_with("oai") {
+            let rest = norm.get(3..).unwrap_or("").trim();
+            if rest.is_empty() {
+                return Some(Command::new("", Action::Help(None)));
+            }
+            // 英文别名须带 oai 前缀，避免误伤群里的普通聊天
+            match rest.to_lowercase().as_str() {
+                "help" => return Some(Command::new("", Action::Help(None))),
+                "list" => return Some(Command::new("", Action::List)),
+                "models" => {
+                    return Some(Command::new(
+                        "",
+                        Action::ListModels {
+                            page: 1,
+                            all: false,
+                        },
+                    ));
+                }
+                "clearall" => return Some(Command::new("", Action::ClearAllPublic)),
+                _ => {}
+            }
+            if let Some(lang) = super::messages::Lang::parse(rest) {
+                return Some(Command::new("", Action::Help(Some(lang))));
+            }
+            if rest.eq_ignore_ascii_case("health") {
+                return Some(Command::new("", Action::HealthCheck));
+            }
+            if rest.eq_ignore_ascii_case("reload") {
+                return Some(Command::new("", Action::ReloadConfig));
+            }
+            if let Some((u, k)) = super::utils::parse_api(rest) {
+                return Some(Command::new("", Action::UpdateApi(u, k)));
+            }
+        }
+
+        if norm == "/#" {
+            return Some(Command::new("", Action::List));
+        }
+        if norm == "/#recent" || norm == "/#top" {
+            let by_calls = norm == "/#top";
+            return Some(Command::new("", Action::ListByUsage { by_calls }));
+        }
+
+        // /% 模型列表，/%2 查看第 2 页；/%all 为不过滤的完整列表
+        if let Some(rest) = norm.strip_prefix("/%") {
+            let (page, all) = match rest.strip_prefix("all") {
+                Some(p) => (p.trim(), true),
+                None => (rest, false),
+            };
+            if page.chars().all(|c| c.is_ascii_digit()) {
+                let page = page.parse().unwrap_or(1).max(1);
+                return Some(Command::new("", Action::ListModels { page, all }));
+            }
+        }
+
+        if norm == "/%!" {
+            return Some(Command::new("", Action::RefreshModels));
+        }
+
+        // /%+关键字 /%-关键字 编辑模型过滤词
+        if norm.starts_with("/%+") || norm.starts_with("/%-") {
+            let add = norm.starts_with("/%+");
+            let word = raw_after(raw.trim(), 3).to_string();
+            return Some(Command::new("", Action::EditModelKeyword { add, word }));
+        }
+
+        if let Some(rest) = norm.strip_prefix("/theme") {
+            let args = rest.trim().to_lowercase();
+            return Some(Command::new("", Action::SetTheme(args)));
+        }
+
+        if let Some(rest) = norm.strip_prefix("/lang") {
+            let args = rest.trim().to_lowercase();
+            return Some(Command::new("", Action::SetLang(args)));
+        }
+
+        if norm == "-*" {
+            return Some(Command::new("", Action::ClearAllPublic));
+        }
+
+        if norm == "-*!" {
+            return Some(Command::new("", Action::ClearEverything));
+        }
+
+        if norm.starts_with("##:") {
+            let args = norm.get(3..).unwrap_or("").trim().to_string();
+            return Some(Command::new("", Action::AutoFillDescriptions(args)));
+        }
+
+        if norm == "##_*" || norm == "##_backup" {
+            return Some(Command::new("", Action::BackupConfig));
+        }
+
+        if norm.starts_with("##%") {
+            let args = raw_after(raw.trim(), 3).to_string();
+            // ##%模型 匹配模式 / ##%模型*前缀：批量修改智能体模型
+            let bulk = args
+                .split_once(char::is_whitespace)
+                .map(|(m, p)| (m.to_string(), p.trim().to_string()))
+                .or_else(|| {
+                    args.split_once('*')
+                        .map(|(m, p)| (m.to_string(), format!("{}*", p.trim())))
+                });
+            if let Some((model, pattern)) = bulk
+                && !model.is_empty()
+                && !pattern.trim_matches(['*', '@']).is_empty()
+            {
+                return Some(Command::new("", Action::BulkSetModel { model, pattern }));
+            }
+            return Some(Command::new("", Action::SetDefaultModel(args)));
+        }
+
+        if norm.starts_with("##$") {
+            let args = raw_after(raw.trim(), 3).to_string();
+            return Some(Command::new("", Action::SetDefaultPrompt(args)));
+        }
+
+        if let Some(rest) = norm.strip_prefix("##@") {
+            let args = rest.trim().to_string();
+            return Some(Command::new("", Action::ImportAgent(args)));
+        }
+
+        if norm == "?*" {
+            return Some(Command::new("", Action::ListPending));
+        }
+
+        if let Some(rest) = norm.strip_prefix("?+")
+            && let Ok(id) = rest.trim().parse::<u64>()
+        {
+            return Some(Command::new("", Action::Approve(id)));
+        }
+
+        if let Some(rest) = norm.strip_prefix("?-")
+            && let Ok(id) = rest.trim().parse::<u64>()
+        {
+            return Some(Command::new("", Action::Reject(id)));
+        }
+
+        if norm == "##!*" {
+            return Some(Command::new("", Action::ListBlockedKeywords));
+        }
+
+        if norm.starts_with("##!-") {
+            let args = raw_after(raw.trim(), 4).to_string();
+            return Some(Command::new("", Action::RemoveBlockedKeyword(args)));
+        }
+
+        if norm.starts_with("##!") {
+            let args = raw_after(raw.trim(), 3).to_string();
+            return Some(Command::new("", Action::AddBlockedKeyword(args)));
+        }
+
+        // ##admin+QQ号 / ##admin-QQ号 管理员，##admin 查看
+        if let Some(rest) = norm.strip_prefix("##admin") {
+            let rest = rest.trim();
+            if rest.is_empty() {
+                return Some(Command::new("", Action::ListAdmins));
+            }
+            if let Some(ids) = rest.strip_prefix('+') {
+                let ids = ids.trim().to_string();
+                return Some(Command::new("", Action::EditAdmins { add: true, ids }));
+            }
+            if let Some(ids) = rest.strip_prefix('-') {
+                let ids = ids.trim().to_string();
+                return Some(Command::new("", Action::EditAdmins { add: false, ids }));
+            }
+        }
+
+        // ##>名称 位置：调整智能体在列表中的顺序
+        if norm.starts_with("##>") {
+            let rest = raw_after(raw.trim(), 3);
+            let mut parts = rest.split_whitespace();
+            let name = parts.next().unwrap_or("").trim_matches('"').to_string();
+            let pos = parts.next().and_then(|n| n.parse().ok()).unwrap_or(0);
+            return Some(Command::new("", Action::SetAgentOrder { name, pos }));
+        }
+
+        // ##+QQ号 / ##+-QQ号 白名单，##^QQ号 / ##^-QQ号 黑名单，##+* 查看
+        if norm == "##+*" || norm == "##^*" {
+            return Some(Command::new("", Action::ListUserLists));
+        }
+
+        for (prefix, black) in [("##+", false), ("##^", true)] {
+            if let Some(rest) = norm.strip_prefix(prefix) {
+                let (add, ids) = match rest.strip_prefix('-') {
+                    Some(r) => (false, r),
+                    None => (true, rest),
+                };
+                return Some(Command::new(
+                    "",
+                    Action::EditUserList {
+                        black,
+                        add,
+                        ids: ids.trim().to_string(),
+                    },
+                ));
+            }
+        }
+
+        if let Some(rest) = norm.strip_prefix("##_restore") {
+            let args = rest.trim().to_string();
+            return Some(Command::new("", Action::RestoreConfig(args)));
+        }
+
+        None
+    }
+
+    pub fn parse_create(raw: &str) -> Option<(String, String, String, String)> {
+        let norm = normalize(raw.trim());
+        if !norm.starts_with("##") {
+            return None;
+        }
+
+        let start_pos = norm.find("##").unwrap() + "##".len();
+        let after = &raw.trim()[start_pos..];
+
+        // 引号包裹的名称按原文处理，可包含指令符号，如 ##"C++"
+        let (name, rest) = if let Some(quoted) = normalize(after).strip_prefix('"') {
+            let close = quoted.chars().position(|c| c == '"')?;
+            let name: String = after.chars().skip(1).take(close).collect();
+            let name = name.trim().to_string();
+            if name.is_empty()
+                || name.chars().count() > 7
+                || name.chars().any(|c| c.is_whitespace() || c == '"')
+                || !super::utils::path_safe_name(&name)
+            {
+                return None;
+            }
+            let skip: usize = after.chars().take(close + 2).map(|c| c.len_utf8()).sum();
+            (name, &after[skip..])
+        } else {
+            let name_end = after
+                .find(|c: char| c.is_whitespace() || c == '(' || c == '（')
+                .unwrap_or(after.len());
+            let name = after[..name_end].trim().to_string();
+
+            if !super::utils::valid_agent_name(&name) {
+                return None;
+            }
+            (name, &after[name_end..])
+        };
+
+        let (desc, after_desc) = if rest.starts_with('(') || rest.starts_with('（') {
+            if let Some(pos) = rest.find(')').or_else(|| rest.find('）')) {
+                (rest[1..pos].to_string(), &rest[pos + 1..])
+            } else {
+                (String::new(), rest)
+            }
+        } else {
+            (String::new(), rest)
+        };
+
+        // 多行提示词块 """...""" 或 ```...```，块内原样保留换行
+        let block_re = super::utils::RE_PROMPT_BLOCK.get_or_init(|| {
+            regex::Regex::new(r#"(?s)"""\n?(.*?)"""|```(?:[\w-]*\n)?(.*?)```"#).unwrap()
+        });
+        let (after_desc, block) = match block_re.captures(after_desc) {
+            Some(cap) => {
+                let body = cap.get(1).or(cap.get(2)).map_or("", |m| m.as_str());
+                let start = cap.get(0).map_or(0, |m| m.start());
+                (&after_desc[..start], Some(body.trim_end().to_string()))
+            }
+            None => (after_desc, None),
+        };
+
+        // 具名参数 --model "名称" / --prompt "内容"，可与位置参数混用
+        let re = super::utils::RE_NAMED_ARG.get_or_init(|| {
+            regex::Regex::new(r#"--(\w+)\s+(?:"([^"]*)"|“([^”]*)”|(\S+))"#).unwrap()
+        });
+        let mut named_model = None;
+        let mut named_prompt = None;
+        let mut positional = after_desc.to_string();
+        if after_desc.contains("--") {
+            for cap in re.captures_iter(after_desc) {
+                let value = cap
+                    .get(2)
+                    .or(cap.get(3))
+                    .or(cap.get(4))
+                    .map_or("", |m| m.as_str())
+                    .trim()
+                    .to_string();
+                match &cap[1] {
+                    "model" => named_model = Some(value),
+                    "prompt" => named_prompt = Some(value),
+                    _ => continue,
+                }
+                positional = positional.replacen(&cap[0], " ", 1);
+            }
+        }
+
+        let mut parts = positional.split_whitespace();
+        let model = match named_model {
+            Some(m) => m,
+            None => parts.next().unwrap_or("").to_string(),
+        };
+        if model.chars().count() > 50 {
+            return None;
+        }
+        let prompt = match (named_prompt, block) {
+            (Some(p), _) | (None, Some(p)) => p,
+            (None, None) => parts.collect::<Vec<_>>().join(" "),
+        };
+
+        Some((name, desc, model, prompt))
+    }
+
+    pub fn parse_delete_agent(raw: &str, agents: &[String]) -> Option<String> {
+        let norm = normalize(raw.trim());
+        if !norm.starts_with("-#") {
+            return None;
+        }
+        let name = norm[2..].trim();
+        // 允许用引号包裹含符号的名称
+        let name = name
+            .strip_prefix('"')
+            .and_then(|n| n.strip_suffix('"'))
+            .unwrap_or(name);
+        if agents.iter().any(|a| a.eq_ignore_ascii_case(name)) {
+            Some(name.to_string())
+        } else {
+            None
+        }
+    }
+
+    pub fn parse_agent_cmd(raw: &str, agents: &[String]) -> Option<Command> {
+        let raw = raw.trim();
+        if raw.is_empty() {
+            return None;
+        }
+
+        let norm = normalize(raw);
+        let chars: Vec<char> = norm.chars().collect();
+
+        let mut char_idx = 0;
+        let mut private_reply = false;
+        let mut text_mode = false;
+        let mut temp_mode = false;
+        let mut quoted = false;
+
+        // 1. 前缀解析
+        while char_idx < chars.len() {
+            match chars[char_idx] {
+                // 引号包裹的已注册名称 ("C++")，不视为文本模式前缀
+                '"' if quoted_name_at(&chars[char_idx..], agents).is_some() => {
+                    quoted = true;
+                    break;
+                }
+                '&' => {
+                    private_reply = true;
+                    char_idx += 1;
+                }
+                '"' => {
+                    text_mode = true;
+                    char_idx += 1;
+                }
+                '~' => {
+                    temp_mode = true;
+                    char_idx += 1;
+                }
+                _ => break,
+            }
+        }
+
+        let byte_idx: usize = chars.iter().take(char_idx).map(|c| c.len_utf8()).sum();
+        let content = &norm[byte_idx..];
+
+        // 2. 智能体名称匹配 (按长度降序，解决包含关系问题)
+        let mut agent_name = String::new();
+        let mut match_char_len = 0;
+        let mut sorted = agents.to_vec();
+        // 关键：必须按长度倒序，确保 "小帅2" 先于 "小帅" 被匹配
+        sorted.sort_by_key(|b| std::cmp::Reverse(b.chars().count()));
+
+        if quoted {
+            let chars: Vec<char> = content.chars().collect();
+            if let Some((name, len)) = quoted_name_at(&chars, agents) {
+                agent_name = name.clone();
+                match_char_len = len;
+            }
+        }
+        for name in sorted.iter().filter(|_| agent_name.is_empty()) {
+            let name_lower = name.to_lowercase();
+            let content_lower = content.to_lowercase();
+            if content_lower.starts_with(&name_lower) {
+                agent_name = name.clone();
+                match_char_len = name.chars().count();
+                break;
+            }
+        }
+
+        if agent_name.is_empty() {
+            return fuzzy_match(content, &sorted).map(|name| Command {
+                agent: name.clone(),
+                action: Action::DidYouMean(name),
+                args: String::new(),
+                indices: vec![],
+                private_reply,
+                text_mode,
+                temp_mode,
+                system_injection: None,
+            });
+        }
+
+        // 3. 后缀提取
+        let match_byte_len: usize = content
+            .chars()
+            .take(match_char_len)
+            .map(|c| c.len_utf8())
+            .sum();
+        let suffix = content[match_byte_len..].trim();
+
+        // 计算原始字符串中的后缀部分（为了保留参数的原始格式，如大小写）
+        let raw_suffix = {
+            let prefix_bytes: usize = raw.chars().take(char_idx).map(|c| c.len_utf8()).sum();
+            let agent_bytes: usize = raw[prefix_bytes..]
+                .chars()
+                .take(match_char_len)
+                .map(|c| c.len_utf8())
+                .sum();
+            raw[prefix_bytes + agent_bytes..].trim()
+        };
+
+        // 智能体A+智能体B 话题：依次接力回复
+        let mut relay = Vec::new();
+        let mut consumed = 0;
+        while let Some(rest) = suffix
+            .chars()
+            .skip(consumed)
+            .collect::<String>()
+            .strip_prefix('+')
+        {
+            let rest_lower = rest.to_lowercase();
+            let Some(next) = sorted
+                .iter()
+                .find(|n| rest_lower.starts_with(&n.to_lowercase()))
+            else {
+                break;
+            };
+            consumed += 1 + next.chars().count();
+            relay.push(next.clone());
+        }
+        if !relay.is_empty() {
+            return Some(Command {
+                agent: agent_name,
+                action: Action::Relay(relay),
+                args: raw_after(raw_suffix, consumed).to_string(),
+                indices: vec![],
+                private_reply,
+                text_mode,
+                temp_mode,
+                system_injection: None,
+            });
+        }
+
+        // 智能体|sys|要求|内容：为本轮对话注入一条系统消息
+        if suffix.starts_with("|sys|") {
+            let body = raw_after(raw_suffix, "|sys|".len());
+            let (sys, msg) = body.split_once(['|', '｜']).unwrap_or((body, ""));
+            return Some(Command {
+                agent: agent_name,
+                action: Action::Chat,
+                args: msg.trim().to_string(),
+                indices: vec![],
+                private_reply,
+                text_mode,
+                temp_mode,
+                system_injection: Some(sys.trim().to_string()).filter(|s| !s.is_empty()),
+            });
+        }
+
+        let (action, args, indices) = match suffix_alias(suffix) {
+            Some(sym) => parse_suffix(sym, sym, private_reply),
+            None => parse_suffix(suffix, raw_suffix, private_reply),
+        };
+
+        Some(Command {
+            agent: agent_name,
+            action,
+            args,
+            indices,
+            private_reply,
+            text_mode,
+            temp_mode,
+            system_injection: None,
+        })
+    }
+
+    /// 解析 /旧/新/标志 形式的替换表达式，\/ 表示字面斜杠
+    fn parse_sed(body: &str) -> Option<(String, String, bool, bool)> {
+        let body = body.strip_prefix('/')?;
+        let mut fields = vec![String::new()];
+        let mut chars = body.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&'/') => {
+                    fields.last_mut()?.push('/');
+                    chars.next();
+                }
+                '/' => fields.push(String::new()),
+                _ => fields.last_mut()?.push(c),
+            }
+        }
+        let flags = match fields.len() {
+            2 => String::new(),
+            3 => fields.pop()?,
+            _ => return None,
+        };
+        // 标志 r 为正则，i 为忽略大小写，可组合
+        let flags = flags.trim().to_lowercase();
+        if !flags.chars().all(|c| c == 'r' || c == 'i') {
+            return None;
+        }
+        let regex = flags.contains('r');
+        let ignore_case = flags.contains('i');
+        let replacement = fields.pop()?;
+        let pattern = fields.pop()?;
+        (!pattern.is_empty()).then_some((pattern, replacement, regex, ignore_case))
+    }
+
+    /// 以引号开头且引号内恰为已注册名称时，返回该名称及连同引号占用的字符数
+    fn quoted_name_at<'a>(chars: &[char], agents: &'a [String]) -> Option<(&'a String, usize)> {
+        if chars.first() != Some(&'"') {
+            return None;
+        }
+        let close = chars[1..].iter().position(|&c| c == '"')?;
+        let inner: String = chars[1..=close].iter().collect();
+        agents
+            .iter()
+            .find(|a| a.eq_ignore_ascii_case(inner.trim()))
+            .map(|a| (a, close + 2))
+    }
+
+    /// 名称未精确命中时，取开头的名称片段做编辑距离 ≤ 1 的模糊匹配
+    fn fuzzy_match(content: &str, agents: &[String]) -> Option<String> {
+        let token: String = content
+            .chars()
+            .take_while(|c| !c.is_whitespace() && !"~#=/%$:?&'-_!*@\"".contains(*c))
+            .collect::<String>()
+            .to_lowercase();
+        if token.chars().count() < 2 {
+            return None;
+        }
+        // 取编辑距离最小者，距离相同时按名称排序，结果不受智能体列表顺序影响
+        agents
+            .iter()
+            .filter(|n| n.chars().count() >= 2)
+            .map(|n| (super::utils::levenshtein(&token, &n.to_lowercase()), n))
+            .filter(|(d, _)| *d <= 1)
+            .min()
+            .map(|(_, n)| n.clone())
+    }
+
+    fn parse_suffix(norm: &str, raw: &str, has_priv_prefix: bool) -> (Action, String, Vec<usize>) {
+        let s = norm.trim(); // 此时 s 里的全角符号已被 normalize 转为半角
+        let r = raw.trim(); // r 是原始字符串
+
+        // 1. 空指令 -> 聊天
+        if s.is_empty() {
+            return (Action::Chat, r.to_string(), vec![]);
+        }
+
+        // 2. 停止指令 (!)
+        if s == "!" {
+            return (Action::Stop, String::new(), vec![]);
+        }
+
+        // 绘图 (!image 描述)：调用 images 接口
+        if s.starts_with("!image") {
+            let arg = raw_after(r, "!image".len());
+            return (Action::ImageGen(arg.to_string()), String::new(), vec![]);
+        }
+
+        // 3. 复制指令 (~#) - 必须在普通 ~ 之前判断
+        // 注意：normalize 已经把 ～ 转为 ~，把 ＃ 转为 #
+        if s.starts_with("~#") {
+            // 计算原始字符串中需要跳过的长度
+            let skip_len = if r.starts_with("～＃") {
+                "～＃".len()
+            } else if r.starts_with("～#") {
+                "～#".len()
+            } else if r.starts_with("~＃") {
+                "~＃".len()
+            } else {
+                "~#".len()
+            };
+            let arg = r.get(skip_len..).unwrap_or("").trim();
+            // 尾部 + 表示连同当前作用域的历史一起复制
+            let (arg, with_history) = match arg.strip_suffix(['+', '＋']) {
+                Some(a) => (a.trim(), true),
+                None => (arg, false),
+            };
+            return (Action::Copy { with_history }, arg.to_string(), vec![]);
+        }
+
+        // 4. 重命名指令 (~=) - 必须在普通 ~ 之前判断
+        if s.starts_with("~=") {
+            let skip_len = if r.starts_with("～＝") {
+                "～＝".len()
+            } else if r.starts_with("～=") {
+                "～=".len()
+            } else if r.starts_with("~＝") {
+                "~＝".len()
+            } else {
+                "~=".len()
+            };
+            let arg = r.get(skip_len..).unwrap_or("").trim();
+            return (Action::Rename, arg.to_string(), vec![]);
+        }
+
+        // 5. 重新生成指令 (~) - 放在最后判断
+        // 匹配 "~" 单独出现，或者 "~内容"
+        if s.starts_with('~') {
+            let skip_len = if r.starts_with('～') {
+                '～'.len_utf8()
+            } else {
+                '~'.len_utf8()
+            };
+            let arg = r.get(skip_len..).unwrap_or("").trim();
+
+            // ~1-5#新名：从指定区间的历史分叉出新智能体 (须有 # 分隔，避免误伤以数字开头的重新生成提示)
+            let re = super::utils::RE_FORK
+                .get_or_init(|| regex::Regex::new(r"^([\d,，\-]+)[#＃](\S+)$").unwrap());
+            if let Some(caps) = re.captures(arg) {
+                let indices = super::utils::parse_indices(&caps[1], 0);
+                if !indices.is_empty() {
+                    return (
+                        Action::Copy {
+                            with_history: false,
+                        },
+                        caps[2].to_string(),
+                        indices,
+                    );
+                }
+            }
+
+            // ~t [语言]：翻译上一条回复 (语言须为单个词，避免误伤重新生成)
+            if let Some(rest) = arg.strip_prefix('t')
+                && (rest.is_empty() || rest.starts_with(char::is_whitespace))
+                && rest.split_whitespace().count() <= 1
+            {
+                return (
+                    Action::Translate(rest.trim().to_string()),
+                    String::new(),
+                    vec![],
+                );
+            }
+
+            // ~sum [N]：总结压缩历史，保留最近 N 条原文
+            if let Some(rest) = arg.strip_prefix("sum")
+                && rest.trim().chars().all(|c| c.is_ascii_digit())
+            {
+                let scope = if has_priv_prefix {
+                    Scope::Private
+                } else {
+                    Scope::Public
+                };
+                let indices = super::utils::parse_indices(rest, 0);
+                return (Action::Summarize(scope), String::new(), indices);
+            }
+
+            // ~%模型 可选新提示：本次重新生成临时使用指定模型
+            if let Some(rest) = arg.strip_prefix(['%', '％']) {
+                let (model, prompt) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                if !model.is_empty() {
+                    return (
+                        Action::Regenerate {
+                            model: Some(model.to_string()),
+                        },
+                        prompt.trim().to_string(),
+                        vec![],
+                    );
+                }
+            }
+            return (Action::Regenerate { model: None }, arg.to_string(), vec![]);
+        }
+
+        // 6. 设置描述 (:)
+        if s.starts_with(':') && !s.starts_with(":/") {
+            let skip_len = if r.starts_with('：') {
+                '：'.len_utf8()
+            } else {
+                ':'.len_utf8()
+            };
+            let arg = r.get(skip_len..).unwrap_or("").trim();
+            return (Action::SetDesc, arg.to_string(), vec![]);
+        }
+
+        // 停止词 (%%stop=词1,词2)，留空清除
+        if s.starts_with("%%stop") {
+            let arg = raw_after(r, 6);
+            let arg = arg.strip_prefix(['=', '＝']).unwrap_or(arg);
+            return (Action::SetStop, arg.to_string(), vec![]);
+        }
+
+        // 绘图偏好 (%%size=1024x1024 / %%quality=high)，留空清除
+        for (prefix, quality) in [("%%size", false), ("%%quality", true)] {
+            if let Some(rest) = s.strip_prefix(prefix) {
+                let arg = rest.trim();
+                let arg = arg.strip_prefix('=').unwrap_or(arg).trim();
+                return (Action::SetImagePref { quality }, arg.to_string(), vec![]);
+            }
+        }
+
+        // 随机种子 (%%seed=42)，留空清除
+        if let Some(rest) = s.strip_prefix("%%seed") {
+            let arg = rest.trim();
+            let arg = arg.strip_prefix('=').unwrap_or(arg).trim();
+            return (Action::SetSeed, arg.to_string(), vec![]);
+        }
+
+        // 超时 (%timeout=秒)，留空恢复全局默认，不带 = 查看
+        if let Some(rest) = s.strip_prefix("%timeout") {
+            return (Action::SetTimeout, rest.trim().to_string(), vec![]);
+        }
+
+        // 智能体选项 (_budget=1000, _maxprivate=50)，留空清除，不带 = 查看
+        if let Some(rest) = s.strip_prefix('_') {
+            let key_len = rest
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .count();
+            if key_len > 0 {
+                let key = rest[..key_len].to_ascii_lowercase();
+                return (
+                    Action::SetOption(key),
+                    raw_after(r, 1 + key_len).to_string(),
+                    vec![],
+                );
+            }
+        }
+
+        // 7. 设置模型 (%)
+        if s.starts_with('%') {
+            let arg = r.get(1..).unwrap_or("").trim();
+            return (Action::SetModel, arg.to_string(), vec![]);
+        }
+
+        // 8. 设置审批人 (?=)
+        if s.starts_with("?=") {
+            let arg = s.get(2..).unwrap_or("").trim();
+            return (Action::SetApprovers, arg.to_string(), vec![]);
+        }
+
+        // 工具 (+tool 名称 描述 {schema})，+tool-名称 删除，留空查看
+        if s.starts_with("+tool") {
+            let arg = raw_after(r, 5);
+            if arg.is_empty() {
+                return (Action::ListTools, String::new(), vec![]);
+            }
+            if let Some(name) = arg.strip_prefix(['-', '－']) {
+                return (
+                    Action::RemoveTool(name.trim().to_string()),
+                    String::new(),
+                    vec![],
+                );
+            }
+            return (Action::AddTool, arg.to_string(), vec![]);
+        }
+
+        // 认领旧版全局公有历史 (/global)
+        if s == "/global" {
+            return (Action::ClaimGlobal, String::new(), vec![]);
+        }
+
+        // 保留策略 (/keep 天数 条数 [归档])，留空查看，off 关闭
+        if let Some(rest) = s.strip_prefix("/keep") {
+            return (Action::SetRetention, rest.trim().to_string(), vec![]);
+        }
+
+        // 9. 设置/查看提示词 ($)
+        if s == "/$" {
+            return (Action::ViewPrompt, String::new(), vec![]);
+        }
+        // 私有会话用户 (//users 或 //u)
+        if s == "//users" || s == "//u" {
+            return (Action::ListPrivateUsers, String::new(), vec![]);
+        }
+        // 详情卡片 (// 或 /:)
+        if s == "//" || s == "/:" {
+            return (Action::ViewDetail, String::new(), vec![]);
+        }
+        // $@url 追加系统参考图，$@- 清空
+        if s.starts_with("$@") {
+            let arg = raw_after(r, 2).trim();
+            return (Action::SetSystemImage, arg.to_string(), vec![]);
+        }
+        if s.starts_with('$') {
+            let arg = r.get(1..).unwrap_or("").trim();
+            return (Action::SetPrompt, arg.to_string(), vec![]);
+        }
+
+        // 10. 历史/查看/编辑/删除类操作
+        // 处理 & 后缀 (局部私有操作，如 智能体&/*)
+        let (has_local_priv, clean, clean_raw) = if let Some(stripped) = s.strip_prefix('&') {
+            (true, stripped, r.strip_prefix('&').unwrap_or("").trim())
+        } else {
+            (false, s, r)
+        };
+
+        let scope = if has_priv_prefix || has_local_priv {
+            Scope::Private
+        } else {
+            Scope::Public
+        };
+
+        if clean == "/*" {
+            return (Action::ViewAll(scope), String::new(), vec![]);
+        }
+
+        // /*N 查看第 N 页
+        if let Some(page) = clean.strip_prefix("/*")
+            && let Ok(n) = page.trim().parse::<usize>()
+        {
+            return (Action::ViewAll(scope), String::new(), vec![n]);
+        }
+
+        // /t [语言]：翻译上一条回复
+        if let Some(rest) = clean.strip_prefix("/t")
+            && (rest.is_empty() || rest.starts_with(char::is_whitespace))
+        {
+            let lang = raw_after(clean_raw, 2).to_string();
+            return (Action::Translate(lang), String::new(), vec![]);
+        }
+
+        // /today /yesterday /7d /12h：按时间范围查看
+        if let Some(spec) = clean.strip_prefix('/')
+            && super::utils::parse_time_range(spec).is_some()
+        {
+            return (Action::ViewTimeRange(scope), spec.to_string(), vec![]);
+        }
+
+        // /code [N]：提取第 N 条 (默认最后一条) 回复中的代码块
+        if let Some(rest) = clean.strip_prefix("/code") {
+            let indices = super::utils::parse_indices(rest, 0);
+            return (Action::ExtractCode(scope), String::new(), indices);
+        }
+
+        if clean == "/ctx" || clean == "/api" {
+            return (Action::ShowContext(scope), String::new(), vec![]);
+        }
+
+        // 快照指令需在 /索引 之前判断，避免 /restore 3 被解析为查看
+        if clean == "/history" {
+            return (Action::ListSnapshots(scope), String::new(), vec![]);
+        }
+
+        if let Some(rest) = clean.strip_prefix("/restore") {
+            let indices = super::utils::parse_indices(rest, 0);
+            return (Action::RestoreSnapshot(scope), String::new(), indices);
+        }
+
+        // /1src 或 /1" 查看原始内容，不渲染也不替换图片
+        if let Some(idx_part) = clean
+            .strip_prefix('/')
+            .and_then(|p| p.strip_suffix("src").or_else(|| p.strip_suffix('"')))
+            && idx_part.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+        {
+            if idx_part.starts_with('-') || idx_part.contains(",-") || idx_part.contains("--") {
+                return (Action::ViewSource(scope), idx_part.to_string(), vec![]);
+            }
+            let indices = super::utils::parse_indices(idx_part, 0);
+            if !indices.is_empty() {
+                return (Action::ViewSource(scope), String::new(), indices);
+            }
+        }
+
+        if clean.starts_with('/') && clean.len() > 1 {
+            let idx_part = &clean[1..];
+            // 含负索引时需按历史长度换算，交由执行阶段解析
+            if idx_part.starts_with('-') || idx_part.contains(",-") || idx_part.contains("--") {
+                return (Action::ViewAt(scope), idx_part.to_string(), vec![]);
+            }
+            let indices = super::utils::parse_indices(idx_part, 0);
+            if !indices.is_empty() {
+                return (Action::ViewAt(scope), String::new(), indices);
+            }
+        }
+
+        if clean == "_*" {
+            return (Action::Export(scope), String::new(), vec![]);
+        }
+
+        if clean == "_*html" {
+            return (Action::ExportHtml(scope), String::new(), vec![]);
+        }
+
+        if clean == "_*mindmap" || clean == "_*导图" {
+            return (Action::ExportMindmap(scope), String::new(), vec![]);
+        }
+
+        // _@ 导出智能体定义 (不含历史)，与 _* 导出历史区分
+        if clean == "_@" {
+            return (Action::ExportAgent, String::new(), vec![]);
+        }
+
+        // 置顶保护 (*1,3)，仅 * 后紧跟数字时生效，与 /* -* _* 区分
+        if let Some(rest) = clean.strip_prefix('*')
+            && rest.starts_with(|c: char| c.is_ascii_digit())
+        {
+            let indices = super::utils::parse_indices(rest, 0);
+            return (Action::TogglePin(scope), String::new(), indices);
+        }
+
+        // 交换两条消息 (>1,3)
+        if let Some(rest) = clean.strip_prefix(['>', '＞'])
+            && rest.starts_with(|c: char| c.is_ascii_digit())
+        {
+            let indices = super::utils::parse_indices(rest, 0);
+            return (Action::Reorder(scope), String::new(), indices);
+        }
+
+        // 查找替换 ('s/旧/新/，'s/正则/新/r，'*/旧/新/i 忽略大小写)
+        if clean.starts_with("'s/") || clean.starts_with("'*/") {
+            let body: String = clean_raw.chars().skip(2).collect();
+            // 格式错误时 pattern 为空，由执行阶段提示用法
+            let (pattern, replacement, regex, ignore_case) = parse_sed(&body).unwrap_or_default();
+            return (
+                Action::ReplaceAll {
+                    scope,
+                    pattern,
+                    replacement,
+                    regex,
+                    ignore_case,
+                },
+                String::new(),
+                vec![],
+            );
+        }
+
+        // 编辑指令 ('): 支持 '1 新内容，'1+ 追加，'1^ 前插
+        if clean.starts_with('\'') {
+            use super::types::EditMode;
+            // splitn(2) 确保只分割出索引和内容两部分
+            let parts: Vec<&str> = clean_raw.get(1..).unwrap_or("").splitn(2, ' ').collect();
+            if !parts.is_empty() {
+                let (idx_part, mode) = if let Some(p) = parts[0].strip_suffix(['+', '＋']) {
+                    (p, EditMode::Append)
+                } else if let Some(p) = parts[0].strip_suffix('^') {
+                    (p, EditMode::Prepend)
+                } else {
+                    (parts[0], EditMode::Replace)
+                };
+                let indices = super::utils::parse_indices(idx_part, 0);
+                let content = parts.get(1).unwrap_or(&"").to_string();
+                return (Action::EditAt(scope, mode), content, indices);
+            }
+        }
+
+        // 插入指令 (+): +N 角色 内容；角色须为 user / assistant / system，
+        // 否则按普通对话处理 (如 "助手+1 等于几")
+        if let Some(rest) = clean.strip_prefix('+')
+            && rest.starts_with(|c: char| c.is_ascii_digit())
+        {
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            let role = rest[digits..].split_whitespace().next().unwrap_or("");
+            if rest[digits..].starts_with(char::is_whitespace)
+                && ["user", "assistant", "system"].contains(&role.to_lowercase().as_str())
+            {
+                let indices = super::utils::parse_indices(&rest[..digits], 0);
+                let content = raw_after(clean_raw, 1 + digits).to_string();
+                return (Action::InsertAt(scope), content, indices);
+            }
+        }
+
+        if clean == "^^" {
+            return (Action::Undo(scope), String::new(), vec![]);
+        }
+
+        if clean == "-*" {
+            return (Action::ClearHistory(scope), String::new(), vec![]);
+        }
+
+        if clean.starts_with('-') && clean.len() > 1 {
+            let idx_part = &clean[1..];
+            // 含负索引时同 ViewAt 交由执行阶段按历史长度换算
+            if (idx_part.starts_with('-') || idx_part.contains(",-") || idx_part.contains("--"))
+                && idx_part.chars().any(|c| c.is_ascii_digit())
+                && idx_part
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || matches!(c, '-' | ',' | ':' | '，' | '：'))
+            {
+                return (Action::DeleteAt(scope), idx_part.to_string(), vec![]);
+            }
+            let indices = super::utils::parse_indices(idx_part, 0);
+            if !indices.is_empty() {
+                return (Action::DeleteAt(scope), String::new(), indices);
+            }
+        }
+
+        // 默认 fallback: 视为普通聊天内容
+        (Action::Chat, r.to_string(), vec![])
+    }
+}
+
+// --- 数据管理 ---
+mod data {
+    use super::messages::{self, Msg};
+    use super::parser::Command;
+    use super::types::{
+        AuditEntry, CONFIG_VERSION, ChatMessage, Config, GLOBAL_BUCKET, GeneratingState,
+        HistorySnapshot, history_key,
+    };
+    use async_openai::Client;
+    use async_openai::config::OpenAIConfig;
+    use async_openai::types::{
+        AudioInput, ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+        CreateTranscriptionRequestArgs,
+    };
+    use kovi::tokio::sync::{Mutex, Notify, RwLock, Semaphore, SemaphorePermit};
+    use kovi::utils::{load_json_data, save_json_data};
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+    /// 按 schema_version 逐级迁移配置，返回是否有改动需要写回
+    fn migrate(config: &mut Config) -> anyhow::Result<bool> {
+        let from = config.schema_version;
+        if from > CONFIG_VERSION {
+            return Err(anyhow::anyhow!(messages::config_too_new(
+                from,
+                CONFIG_VERSION
+            )));
+        }
+        if config.schema_version < 1 {
+            // v1: 旧版全局公有历史迁入分桶结构，取值为 0 会导致功能失效的字段补回默认值
+            for a in config.agents.iter_mut() {
+                a.migrate_legacy();
+            }
+            if config.default_model.is_empty() {
+                config.default_model = "gpt-4o".to_string();
+            }
+            if config.max_concurrent_requests == 0 {
+                config.max_concurrent_requests = 8;
+            }
+            if config.render_pool_size == 0 {
+                config.render_pool_size = 2;
+            }
+            config.admins.sort_unstable();
+            config.admins.dedup();
+            config.schema_version = 1;
+        }
+        Ok(config.schema_version != from)
+    }
+
+    /// 将配置中的渲染、语言等全局设置写入运行时 (启动与热重载时调用)
+    fn apply_runtime_settings(config: &Config) {
+        super::utils::set_render_theme(&config.render_theme);
+        super::messages::set_lang(
+            super::messages::Lang::parse(&config.lang).unwrap_or(super::messages::Lang::Zh),
+        );
+        super::utils::set_render_pool_size(config.render_pool_size);
+        super::utils::set_max_text_len(config.max_text_len);
+        super::utils::set_chunk_threshold(config.chunk_threshold);
+        super::utils::set_render_cache_size(config.render_cache_size);
+        super::utils::set_render_cache_ttl(config.render_cache_ttl_secs);
+        super::utils::set_reply_with_quote(config.reply_with_quote);
+        super::utils::set_reply_at(config.reply_at);
+    }
+
+    /// 加载或迁移失败时保留原文件，避免被默认配置覆盖
+    fn backup_config(path: &Path) {
+        if !path.exists() {
+            return;
+        }
+        let bak = path.with_extension("json.bak");
+        match std::fs::copy(path, &bak) {
+            Ok(_) => kovi::log::warn!("原配置已备份到 {}", bak.display()),
+            Err(e) => kovi::log::error!("备份配置失败: {}", e),
+        }
+    }
+
+    /// 等待审批的对话请求
+    pub struct PendingApproval {
+        pub cmd: Command,
+        pub prompt: String,
+        pub imgs: Vec<String>,
+        pub event: Arc<kovi::MsgEvent>,
+        pub created_at: i64,
+    }
+
+    /// 请求优先级：交互式对话优先于批量任务
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Priority {
+        Interactive,
+        Batch,
+    }
+
+    /// 请求调度器：限制同一 API 的并发请求数，并让交互式请求优先获得名额
+    pub struct Scheduler {
+        permits: Semaphore,
+        interactive_waiting: AtomicUsize,
+        idle: Notify,
+    }
+
+    impl Scheduler {
+        pub fn new(max: usize) -> Self {
+            Self {
+                permits: Semaphore::new(max.max(1)),
+                interactive_waiting: AtomicUsize::new(0),
+                idle: Notify::new(),
+            }
+        }
+
+        pub async fn acquire(&self, priority: Priority) -> SemaphorePermit<'_> {
+            match priority {
+                Priority::Interactive => {
+                    self.interactive_waiting.fetch_add(1, Ordering::SeqCst);
+                    let permit = self.permits.acquire().await.unwrap();
+                    if self.interactive_waiting.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        self.idle.notify_waiters();
+                    }
+                    permit
+                }
+                Priority::Batch => loop {
+                    // 有交互式请求在排队时让出，待其拿到名额后再竞争
+                    let idle = self.idle.notified();
+                    if self.interactive_waiting.load(Ordering::SeqCst) > 0 {
+                        idle.await;
+                        continue;
+                    }
+                    let permit = self.permits.acquire().await.unwrap();
+                    if self.interactive_waiting.load(Ordering::SeqCst) == 0 {
+                        return permit;
+                    }
+                },
+            }
+        }
+    }
+
+    /// 一次成功回复的信息，供其他插件订阅 (记录、转发 webhook、接力给其他智能体等)
+    #[derive(Debug, Clone)]
+    pub struct AgentResponseEvent {
+        pub agent_name: String,
+        /// "private" 或 "public"
+        pub scope: String,
+        pub uid: String,
+        pub prompt: String,
+        pub response: String,
+        pub model: String,
+        pub latency_ms: u64,
+    }
+
+    pub type ResponseHook = Arc<dyn Fn(AgentResponseEvent) + Send + Sync>;
+
+    /// 缓存的 API 客户端及其 (地址, 密钥, 代理)
+    type CachedClient = (String, String, Option<String>, Client<OpenAIConfig>);
+
+    pub struct Manager {
+        /// 锁粒度：全部智能体与历史共用这一把读写锁，不按会话分锁。
+        /// 历史的读改写 (追加、删除、编辑、总结写回) 都在同一次写锁内完成，
+        /// 因此同一 (智能体, 作用域, 用户) 的并发修改被串行化，不会互相覆盖；
+        /// 耗时的模型请求在锁外进行，写回时基于最新历史增量修改而不是整体替换
+        pub config: RwLock<Config>,
+        pub scheduler: Scheduler,
+        client: RwLock<Option<CachedClient>>,
+        pub generating: RwLock<GeneratingState>,
+        pub pending: RwLock<HashMap<u64, PendingApproval>>,
+        pub snapshots: RwLock<HashMap<String, Vec<HistorySnapshot>>>,
+        pub undo_store: UndoStore,
+        /// 本次运行中已见过的用户，用于只发送一次欢迎语 (不落盘)
+        seen_users: RwLock<HashSet<String>>,
+        next_pending_id: AtomicU64,
+        /// 内存配置有未写盘的改动，由定时任务或 flush 落盘
+        dirty: AtomicBool,
+        path: PathBuf,
+        snapshot_path: PathBuf,
+        archive_dir: PathBuf,
+        audit_path: PathBuf,
+        /// 串行化审计日志的写入与轮转
+        audit_lock: Arc<Mutex<()>>,
+        audit_max_bytes: u64,
+        /// 回复成功后的回调，由 set_response_hook 注册
+        on_response: std::sync::RwLock<Option<ResponseHook>>,
+    }
+
+    /// 每个会话保留的最大快照数量
+    pub const MAX_SNAPSHOTS: usize = 10;
+
+    /// 每个会话可撤销的步数
+    pub const UNDO_DEPTH: usize = 3;
+
+    /// 删除、编辑、清空前的历史，仅存内存，键同 history_key
+    #[derive(Default)]
+    pub struct UndoStore(RwLock<HashMap<String, VecDeque<Vec<ChatMessage>>>>);
+
+    impl UndoStore {
+        pub async fn push(&self, key: String, hist: Vec<ChatMessage>) {
+            let mut map = self.0.write().await;
+            let stack = map.entry(key).or_default();
+            stack.push_back(hist);
+            if stack.len() > UNDO_DEPTH {
+                stack.pop_front();
+            }
+        }
+
+        /// 清除键以 prefix 开头的全部撤销记录
+        pub async fn remove_prefix(&self, prefix: &str) {
+            self.0.write().await.retain(|k, _| !k.starts_with(prefix));
+        }
+
+        pub async fn pop(&self, key: &str) -> Option<Vec<ChatMessage>> {
+            let mut map = self.0.write().await;
+            let stack = map.get_mut(key)?;
+            let hist = stack.pop_back();
+            if stack.is_empty() {
+                map.remove(key);
+            }
+            hist
+        }
+    }
+
+    /// 去除模型列表中的重复项 (保持原顺序)，手动编辑配置时可能出现；返回是否有改动
+    pub fn dedup_models(c: &mut Config) -> bool {
+        let before = c.models.len() + c.all_models.len();
+        for list in [&mut c.models, &mut c.all_models] {
+            let mut seen = std::collections::HashSet::new();
+            list.retain(|m| seen.insert(m.clone()));
+        }
+        before != c.models.len() + c.all_models.len()
+    }
+
+    /// 用 model_keywords 过滤完整列表，无匹配时显示全部
+    pub fn apply_model_filter(c: &mut Config) {
+        let filtered = super::utils::filter_models(&c.all_models, &c.model_keywords);
+        c.models = if filtered.is_empty() {
+            c.all_models.clone()
+        } else {
+            filtered
+        };
+    }
+
+    impl Manager {
+        pub fn new(dir: PathBuf) -> Self {
+            let path = dir.join("config.json");
+            let snapshot_path = dir.join("snapshots.json");
+            let default = Config {
+                schema_version: CONFIG_VERSION,
+                default_model: "gpt-4o".to_string(),
+                default_prompt: "You are a helpful assistant.".to_string(),
+                approval_timeout_secs: 600,
+                max_concurrent_requests: 8,
+                model_cache_ttl_secs: 3600,
+                render_pool_size: 2,
+                max_text_len: 3000,
+                chunk_threshold: 1500,
+                models_page_size: 50,
+                model_keywords: crate::utils::MODEL_KEYWORDS
+                    .iter()
+                    .map(|k| k.to_string())
+                    .collect(),
+                autosave_interval_secs: 30,
+                save_chat_immediately: true,
+                render_cache_size: 50,
+                render_cache_ttl_secs: 300,
+                warn_token_threshold: 100_000,
+                tool_timeout_secs: 300,
+                auto_fill_concurrency: 5,
+                timeout_secs: 300,
+                audit_max_bytes: 10 * 1024 * 1024,
+                max_tool_rounds: 5,
+                require_sk_prefix: true,
+                reply_with_quote: true,
+                transcribe_model: "whisper-1".to_string(),
+                react_emoji: Some("124".to_string()),
+                ..Default::default()
+            };
+            let mut config: Config = match load_json_data(default.clone(), path.clone()) {
+                Ok(c) => c,
+                Err(e) => {
+                    kovi::log::error!("配置加载失败，已使用默认配置: {}", e);
+                    backup_config(&path);
+                    default.clone()
+                }
+            };
+            match migrate(&mut config) {
+                Ok(false) => {}
+                Ok(true) => {
+                    kovi::log::info!("配置已迁移到版本 {}", CONFIG_VERSION);
+                    let _ = save_json_data(&config, &path);
+                }
+                Err(e) => {
+                    kovi::log::error!("配置迁移失败，已使用默认配置: {}", e);
+                    backup_config(&path);
+                    config = default;
+                    let _ = save_json_data(&config, &path);
+                }
+            }
+            if dedup_models(&mut config) {
+                kovi::log::warn!("模型列表中存在重复项，已去重");
+            }
+            // 重启后作废上次会话中未完成的生成任务
+            for a in config.agents.iter_mut() {
+                a.migrate_legacy();
+                a.generation_id = a.generation_id.wrapping_add(1);
+            }
+            let unclaimed: Vec<&str> = config
+                .agents
+                .iter()
+                .filter(|a| {
+                    a.public_histories
+                        .get(GLOBAL_BUCKET)
+                        .is_some_and(|h| !h.is_empty())
+                })
+                .map(|a| a.name.as_str())
+                .collect();
+            if !unclaimed.is_empty() {
+                kovi::log::warn!(
+                    "{} 存在旧版全局公有历史，管理员可在目标群发送 智能体/global 认领",
+                    unclaimed.join(", ")
+                );
+            }
+            let scheduler = Scheduler::new(config.max_concurrent_requests);
+            let audit_max_bytes = config.audit_max_bytes;
+            apply_runtime_settings(&config);
+            super::utils::set_image_dir(dir.join("images"));
+            let mut snapshots: HashMap<String, Vec<HistorySnapshot>> =
+                load_json_data(HashMap::new(), snapshot_path.clone()).unwrap_or_default();
+            // 旧版公有快照键 "智能体:public" 归入全局桶
+            let legacy: Vec<String> = snapshots
+                .keys()
+                .filter(|k| k.ends_with(":public"))
+                .cloned()
+                .collect();
+            for k in legacy {
+                if let Some(v) = snapshots.remove(&k) {
+                    snapshots.insert(format!("{}:{}", k, GLOBAL_BUCKET), v);
+                }
+            }
+            Self {
+                config: RwLock::new(config),
+                scheduler,
+                client: RwLock::new(None),
+                generating: RwLock::new(GeneratingState::default()),
+                pending: RwLock::new(HashMap::new()),
+                snapshots: RwLock::new(snapshots),
+                undo_store: UndoStore::default(),
+                seen_users: RwLock::new(HashSet::new()),
+                next_pending_id: AtomicU64::new(1),
+                dirty: AtomicBool::new(false),
+                path,
+                snapshot_path,
+                archive_dir: dir.join("archive"),
+                audit_path: dir.join("audit.jsonl"),
+                audit_lock: Arc::new(Mutex::new(())),
+                audit_max_bytes,
+                on_response: std::sync::RwLock::new(None),
+            }
+        }
+
+        /// 后台追加一条审计记录，不阻塞指令处理
+        pub fn log_command(&self, entry: AuditEntry) {
+            let path = self.audit_path.clone();
+            let lock = self.audit_lock.clone();
+            let max = self.audit_max_bytes;
+            kovi::tokio::spawn(async move {
+                use kovi::tokio::io::AsyncWriteExt;
+                let Ok(mut line) = serde_json::to_string(&entry) else {
+                    return;
+                };
+                line.push('\n');
+                let _guard = lock.lock().await;
+                if max > 0
+                    && let Ok(meta) = kovi::tokio::fs::metadata(&path).await
+                    && meta.len() + line.len() as u64 > max
+                {
+                    let _ =
+                        kovi::tokio::fs::rename(&path, path.with_file_name("audit.1.jsonl")).await;
+                }
+                let file = kovi::tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await;
+                match file {
+                    Ok(mut f) => {
+                        if let Err(e) = f.write_all(line.as_bytes()).await {
+                            kovi::log::error!("写入审计日志失败: {}", e);
+                        }
+                    }
+                    Err(e) => kovi::log::error!("打开审计日志失败: {}", e),
+                }
+            });
+        }
+
+        /// 按各智能体的保留策略清理历史，返回被清理的消息数
+        pub async fn run_retention(&self) -> usize {
+            let now = chrono::Local::now().timestamp();
+            let mut c = self.config.write().await;
+            let mut total = 0;
+            for a in c.agents.iter_mut() {
+                let Some(policy) = a.retention_policy.clone() else {
+                    continue;
+                };
+                let mut removed = Vec::new();
+                for (bucket, h) in a.public_histories.iter_mut() {
+                    removed.push((history_key(&a.name, false, bucket), policy.apply(h, now)));
+                }
+                for (uid, h) in a.private_histories.iter_mut() {
+                    removed.push((history_key(&a.name, true, uid), policy.apply(h, now)));
+                }
+                for (key, msgs) in removed {
+                    if msgs.is_empty() {
+                        continue;
+                    }
+                    total += msgs.len();
+                    if policy.archive {
+                        self.archive(&a.name, &key, &msgs);
+                    }
+                }
+            }
+            if total > 0 {
+                self.save(&c);
+            }
+            total
+        }
+
+        /// 追加写入归档文件 archive/<智能体>.jsonl
+        fn archive(&self, name: &str, key: &str, msgs: &[ChatMessage]) {
+            use std::io::Write;
+            if std::fs::create_dir_all(&self.archive_dir).is_err() {
+                return;
+            }
+            let path = self.archive_dir.join(format!("{}.jsonl", name));
+            let Ok(mut f) = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+            else {
+                return;
+            };
+            for m in msgs {
+                let line = serde_json::json!({ "key": key, "message": m });
+                let _ = writeln!(f, "{}", line);
+            }
+        }
+
+        /// 保存一份历史快照，超出上限时丢弃最旧的
+        pub async fn snapshot(&self, key: String, reason: &str, hist: &[ChatMessage]) {
+            self.snapshot_all(reason, [(key, hist)]).await;
+        }
+
+        /// 批量保存快照，整批只写盘一次 (清空全部、导入配置等涉及大量会话的操作)
+        pub async fn snapshot_all<'a>(
+            &self,
+            reason: &str,
+            entries: impl IntoIterator<Item = (String, &'a [ChatMessage])>,
+        ) {
+            let mut snaps = self.snapshots.write().await;
+            let now = chrono::Local::now().timestamp();
+            let mut changed = false;
+            for (key, hist) in entries {
+                if hist.is_empty() {
+                    continue;
+                }
+                let list = snaps.entry(key).or_default();
+                list.push(HistorySnapshot {
+                    created_at: now,
+                    reason: reason.to_string(),
+                    messages: hist.to_vec(),
+                });
+                if list.len() > MAX_SNAPSHOTS {
+                    let excess = list.len() - MAX_SNAPSHOTS;
+                    list.drain(..excess);
+                }
+                changed = true;
+            }
+            if changed {
+                let _ = save_json_data(&*snaps, &self.snapshot_path);
+            }
+        }
+
+        /// 删除智能体时一并清除其快照与撤销记录，避免同名新智能体恢复到旧历史
+        pub async fn remove_snapshots(&self, name: &str) {
+            let prefix = format!("{}:", name);
+            self.undo_store.remove_prefix(&prefix).await;
+            let mut snaps = self.snapshots.write().await;
+            let before = snaps.len();
+            snaps.retain(|k, _| !k.starts_with(&prefix));
+            if snaps.len() != before {
+                let _ = save_json_data(&*snaps, &self.snapshot_path);
+            }
+        }
+
+        /// 智能体改名时同步迁移其快照
+        pub async fn rename_snapshots(&self, old: &str, new: &str) {
+            let mut snaps = self.snapshots.write().await;
+            let prefix = format!("{}:", old);
+            let keys: Vec<String> = snaps
+                .keys()
+                .filter(|k| k.starts_with(&prefix))
+                .cloned()
+                .collect();
+            if keys.is_empty() {
+                return;
+            }
+            for k in keys {
+                if let Some(v) = snaps.remove(&k) {
+                    snaps.insert(format!("{}:{}", new, &k[prefix.len()..]), v);
+                }
+            }
+            let _ = save_json_data(&*snaps, &self.snapshot_path);
+        }
+
+        /// 暂存一条待审批请求，返回审批编号
+        pub async fn add_pending(&self, req: PendingApproval) -> u64 {
+            let id = self.next_pending_id.fetch_add(1, Ordering::Relaxed);
+            self.pending.write().await.insert(id, req);
+            id
+        }
+
+        /// 标记配置已修改，由自动保存任务统一写盘；间隔为 0 时立即写盘
+        pub fn save(&self, cfg: &Config) {
+            if cfg.autosave_interval_secs == 0 {
+                self.save_now(cfg);
+            } else {
+                self.dirty.store(true, Ordering::Release);
+            }
+        }
+
+        /// 对话写入等关键路径使用，开启 save_chat_immediately 时立即写盘以防崩溃丢失
+        pub fn save_chat(&self, cfg: &Config) {
+            if cfg.save_chat_immediately {
+                self.save_now(cfg);
+            } else {
+                self.save(cfg);
+            }
+        }
+
+        /// 立即写盘并清除脏标记
+        pub fn save_now(&self, cfg: &Config) {
+            self.dirty.store(false, Ordering::Release);
+            if save_json_data(cfg, &self.path).is_err() {
+                self.dirty.store(true, Ordering::Release);
+            }
+        }
+
+        /// 用户首次出现 (本次运行未见过且没有任何私有历史) 时返回欢迎语
+        pub async fn welcome_for(&self, uid: &str) -> Option<String> {
+            if !self.seen_users.write().await.insert(uid.to_string()) {
+                return None;
+            }
+            let c = self.config.read().await;
+            let msg = c
+                .welcome_message
+                .as_ref()
+                .filter(|m| !m.trim().is_empty())?;
+            let known = c
+                .agents
+                .iter()
+                .any(|a| a.private_histories.get(uid).is_some_and(|h| !h.is_empty()));
+            (!known).then(|| msg.clone())
+        }
+
+        /// 注册回复回调，重复注册时覆盖之前的回调
+        pub fn set_response_hook(&self, f: impl Fn(AgentResponseEvent) + Send + Sync + 'static) {
+            if let Ok(mut hook) = self.on_response.write() {
+                *hook = Some(Arc::new(f));
+            }
+        }
+
+        /// 通知回复回调；回调在独立任务中执行，不阻塞回复发送
+        pub fn emit_response(&self, event: AgentResponseEvent) {
+            let hook = self.on_response.read().ok().and_then(|h| h.clone());
+            if let Some(hook) = hook {
+                kovi::tokio::spawn(async move { hook(event) });
+            }
+        }
+
+        /// 存在未写盘的改动时落盘
+        pub async fn flush(&self) {
+            if self.dirty.load(Ordering::Acquire) {
+                let c = self.config.read().await;
+                self.save_now(&c);
+            }
+        }
+
+        /// 导出完整配置 (含全部智能体与历史)，返回写入字节数
+        pub async fn export_config_json(&self, target: &Path) -> anyhow::Result<usize> {
+            let json = {
+                let c = self.config.read().await;
+                serde_json::to_string_pretty(&*c)?
+            };
+            std::fs::write(target, &json)?;
+            Ok(json.len())
+        }
+
+        /// 从磁盘重新加载配置并替换内存配置，返回 (智能体数, 模型数)
+        /// 文件缺失或损坏时返回错误，内存配置保持不变；生成状态不受影响
+        pub async fn reload_config(&self) -> anyhow::Result<(usize, usize)> {
+            let json = std::fs::read_to_string(&self.path)?;
+            let mut cfg: Config = serde_json::from_str(&json)
+                .map_err(|e| anyhow::anyhow!(messages::config_invalid(e)))?;
+            let migrated = migrate(&mut cfg)?;
+
+            let mut c = self.config.write().await;
+            // 沿用内存中的 generation_id，避免进行中的生成被误判为已过期
+            for a in cfg.agents.iter_mut() {
+                a.migrate_legacy();
+                if let Some(old) = c.agents.iter().find(|o| o.name == a.name) {
+                    a.generation_id = a.generation_id.max(old.generation_id);
+                    for (k, v) in &old.session_generations {
+                        let id = a.session_generations.entry(k.clone()).or_default();
+                        *id = (*id).max(*v);
+                    }
+                }
+            }
+            dedup_models(&mut cfg);
+            *c = cfg;
+            apply_runtime_settings(&c);
+            // 以磁盘内容为准，丢弃尚未写盘的内存改动
+            self.dirty.store(false, Ordering::Release);
+            if migrated {
+                self.save(&c);
+            }
+            Ok((c.agents.len(), c.models.len()))
+        }
+
+        /// 将智能体移动到第 target_idx 个位置 (1 起，超出时放到末尾)，返回实际位置
+        pub async fn move_agent(&self, name: &str, target_idx: usize) -> Option<usize> {
+            let mut c = self.config.write().await;
+            let from = c
+                .agents
+                .iter()
+                .position(|a| a.name.eq_ignore_ascii_case(name))?;
+            let agent = c.agents.remove(from);
+            let to = target_idx.saturating_sub(1).min(c.agents.len());
+            c.agents.insert(to, agent);
+            c.custom_agent_order = true;
+            self.save(&c);
+            Some(to + 1)
+        }
+
+        /// 从备份文件恢复配置，校验通过后才覆盖，返回恢复的智能体数量
+        pub async fn import_config_json(&self, source: &Path) -> anyhow::Result<usize> {
+            let json = std::fs::read_to_string(source)?;
+            let mut cfg: Config = serde_json::from_str(&json)
+                .map_err(|e| anyhow::anyhow!(messages::config_invalid(e)))?;
+            migrate(&mut cfg)?;
+
+            let mut names = std::collections::HashSet::new();
+            for a in &cfg.agents {
+                if a.name.is_empty() || !names.insert(a.name.clone()) {
+                    return Err(anyhow::anyhow!(messages::agent_name_duplicate(&a.name)));
+                }
+                if !super::utils::path_safe_name(&a.name) {
+                    return Err(anyhow::anyhow!(messages::agent_name_illegal(&a.name)));
+                }
+            }
+
+            let cnt = cfg.agents.len();
+            let mut c = self.config.write().await;
+            // 覆盖前为现有历史保存快照
+            let entries: Vec<_> = c.agents.iter().flat_map(|a| a.all_histories()).collect();
+            self.snapshot_all(Msg::SnapImport.text(), entries).await;
+            // 使恢复前仍在进行的生成任务失效：取内存与备份中较大的 ID 再递增，
+            // 备份里的旧 ID 可能小于当前值，直接递增仍可能与进行中的任务撞号
+            for a in cfg.agents.iter_mut() {
+                a.migrate_legacy();
+                if let Some(old) = c.agents.iter().find(|o| o.name == a.name) {
+                    a.generation_id = a.generation_id.max(old.generation_id);
+                    for (k, v) in &old.session_generations {
+                        let id = a.session_generations.entry(k.clone()).or_default();
+                        *id = (*id).max(*v);
+                    }
+                }
+                a.generation_id = a.generation_id.wrapping_add(1);
+            }
+            *c = cfg;
+            apply_runtime_settings(&c);
+            self.save(&c);
+            drop(c);
+            // 导入成功后才清除生成标记，失败时进行中的任务不受影响
+            let mut generating = self.generating.write().await;
+            generating.public.clear();
+            generating.private.clear();
+            Ok(cnt)
+        }
+
+        /// 获取 API 客户端，相同配置下复用同一实例以共享底层连接池
+        pub async fn client(&self, base: &str, key: &str) -> Client<OpenAIConfig> {
+            let proxy = self.config.read().await.proxy.clone();
+            if let Some((b, k, p, c)) = &*self.client.read().await
+                && b == base
+                && k == key
+                && *p == proxy
+            {
+                return c.clone();
+            }
+            let client = self.build_client(base, key).await;
+            *self.client.write().await =
+                Some((base.to_string(), key.to_string(), proxy, client.clone()));
+            client
+        }
+
+        /// 构建 API 客户端：配置了 proxy 时底层 HTTP 请求经代理发出，代理地址无效时直连
+        pub async fn build_client(&self, base: &str, key: &str) -> Client<OpenAIConfig> {
+            let client =
+                Client::with_config(OpenAIConfig::new().with_api_base(base).with_api_key(key));
+            let proxy = self.config.read().await.proxy.clone();
+            let Some(url) = proxy.filter(|p| !p.trim().is_empty()) else {
+                return client;
+            };
+            let http = reqwest::Proxy::all(url.trim())
+                .and_then(|p| reqwest::Client::builder().proxy(p).build());
+            match http {
+                Ok(http) => client.with_http_client(http),
+                Err(e) => {
+                    kovi::log::error!("代理 {} 无效，已改为直连: {}", url, e);
+                    client
+                }
+            }
+        }
+
+        /// 转写一条语音：file 为语音段的 file 参数 (经协议端转为 mp3) 或直链
+        pub async fn transcribe(
+            &self,
+            bot: &kovi::RuntimeBot,
+            file: &str,
+        ) -> anyhow::Result<String> {
+            let (base, key, model) = {
+                let c = self.config.read().await;
+                (
+                    c.api_base.clone(),
+                    c.api_key.clone(),
+                    c.transcribe_model.clone(),
+                )
+            };
+            if base.is_empty() {
+                return Err(anyhow::anyhow!(Msg::ApiMissing.text()));
+            }
+
+            let bytes = if file.starts_with("http://") || file.starts_with("https://") {
+                reqwest::get(file).await?.bytes().await?.to_vec()
+            } else {
+                let ret = bot
+                    .get_record(file, "mp3")
+                    .await
+                    .map_err(|e| anyhow::anyhow!(messages::fetch_voice_failed(e)))?;
+                let path = ret
+                    .data
+                    .get("file")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| anyhow::anyhow!(Msg::NoVoiceFile.text()))?;
+                kovi::tokio::fs::read(path).await?
+            };
+
+            let request = CreateTranscriptionRequestArgs::default()
+                .file(AudioInput::from_vec_u8("voice.mp3".to_string(), bytes))
+                .model(model)
+                .build()?;
+            let client = self.client(&base, &key).await;
+            let res = {
+                let _permit = self.scheduler.acquire(Priority::Interactive).await;
+                client.audio().transcribe(request).await?
+            };
+            Ok(res.text.trim().to_string())
+        }
+
+        /// 用默认模型发送最小的补全请求，检查 API 连通性并返回往返耗时
+        pub async fn health_check(&self) -> anyhow::Result<std::time::Duration> {
+            let (base, key, model) = {
+                let c = self.config.read().await;
+                (
+                    c.api_base.clone(),
+                    c.api_key.clone(),
+                    c.default_model.clone(),
+                )
+            };
+            if base.is_empty() || key.is_empty() {
+                return Err(anyhow::anyhow!(Msg::ApiMissing.text()));
+            }
+            if model.is_empty() {
+                return Err(anyhow::anyhow!(Msg::NoDefaultModel.text()));
+            }
+
+            let client = self.client(&base, &key).await;
+            let req = CreateChatCompletionRequestArgs::default()
+                .model(model)
+                .max_tokens(1u32)
+                .messages([ChatCompletionRequestUserMessageArgs::default()
+                    .content("ping")
+                    .build()?
+                    .into()])
+                .build()?;
+
+            let start = std::time::Instant::now();
+            kovi::tokio::time::timeout(
+                std::time::Duration::from_secs(30),
+                client.chat().create(req),
+            )
+            .await
+            .map_err(|_| anyhow::anyhow!(Msg::RequestTimeout.text()))??;
+            Ok(start.elapsed())
+        }
+
+        pub async fn fetch_models(&self) -> anyhow::Result<Vec<String>> {
+            let (base, key) = {
+                let c = self.config.read().await;
+                (c.api_base.clone(), c.api_key.clone())
+            };
+
+            if base.is_empty() {
+                return Err(anyhow::anyhow!(Msg::ApiMissing.text()));
+            }
+
+            let client = self.client(&base, &key).await;
+
+            let response = {
+                let _permit = self.scheduler.acquire(Priority::Batch).await;
+                client.models().list().await?
+            };
+
+            // 提取模型 ID 并排序
+            let mut models: Vec<String> = response.data.into_iter().map(|m| m.id).collect();
+
+            // 部分服务商会返回重复的模型 ID
+            models.sort();
+            models.dedup();
+
+            let mut c = self.config.write().await;
+            c.all_models = models;
+            c.models_fetched_at = chrono::Local::now().timestamp();
+            apply_model_filter(&mut c);
+            self.save(&c);
+            Ok(c.models.clone())
+        }
+
+        /// 模型缓存非空且在有效期内
+        pub async fn models_fresh(&self) -> bool {
+            let c = self.config.read().await;
+            let age = chrono::Local::now().timestamp() - c.models_fetched_at;
+            !c.models.is_empty() && age >= 0 && (age as u64) < c.model_cache_ttl_secs
+        }
+
+        /// 模型缓存未过期时直接返回缓存，否则重新获取
+        pub async fn fetch_models_with_refresh(&self) -> anyhow::Result<Vec<String>> {
+            if self.models_fresh().await {
+                return Ok(self.config.read().await.models.clone());
+            }
+            self.fetch_models().await
+        }
+
+        /// 依次按序号、展示列表、完整列表模糊匹配，均未命中时原样使用
+        pub fn resolve_model(&self, input: &str, cfg: &Config) -> Option<String> {
+            if input.is_empty() {
+                return None;
+            }
+            let models = &cfg.models;
+            if let Ok(i) = input.parse::<usize>()
+                && i > 0
+                && i <= models.len()
+            {
+                return Some(models[i - 1].clone());
+            }
+            let lower = input.to_lowercase();
+            for m in models.iter().chain(&cfg.all_models) {
+                if m.to_lowercase().contains(&lower) {
+                    return Some(m.clone());
+                }
+            }
+            Some(input.to_string())
+        }
+
+        /// 黑名单优先；白名单非空时仅名单内用户与 admins 可用
+        pub async fn is_user_allowed(&self, uid: i64) -> bool {
+            let c = self.config.read().await;
+            if c.blacklist.contains(&uid) {
+                return false;
+            }
+            c.whitelist.is_empty() || c.whitelist.contains(&uid) || c.admins.contains(&uid)
+        }
+
+        pub async fn agent_names(&self) -> Vec<String> {
+            self.config
+                .read()
+                .await
+                .agents
+                .iter()
+                .map(|a| a.name.clone())
+                .collect()
+        }
+    }
+}
+
+// --- 业务逻辑 ---
+mod logic {
+    use crate::utils::truncate_str;
+
+    use super::data::{AgentResponseEvent, Manager, PendingApproval, Priority, apply_model_filter};
+    use super::messages::{self, Msg};
+    use super::parser::{Action, Command, Scope};
+    use super::types::{
+        Agent, AgentDef, AgentTool, AuditEntry, ChatMessage, RetentionPolicy, ToolCall, history_key,
+    };
+    use super::utils::{
+        IMAGE_TOKENS, MAX_STOP, THEMES, chunk_threshold, code_extension, count_tokens_estimate,
+        escape_html, escape_markdown_special, escape_stop, eval_expr, expand_prompt,
+        extract_code_blocks, filter_valid_images, format_export_html, format_export_txt,
+        format_history, format_history_entries, get_reply_file, hash_uid, image_any_re,
+        image_md_re, image_source, image_url_re, input_tokens_estimate, is_image_model,
+        max_text_len, name_matches, parse_indices, parse_stop_list, parse_time_range,
+        parse_tool_results, public_bucket, render_md, reply_at, reply_with_quote, set_render_theme,
+        split_chunks, split_for_send, time_ago, uses_images_endpoint, valid_agent_name, video_re,
+    };
+    use async_openai::types::{
+        ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
+        ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPartImageArgs,
+        ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
+        ChatCompletionRequestUserMessageContentPart, ChatCompletionToolArgs,
+        ChatCompletionToolChoiceOption, ChatCompletionToolType, CreateChatCompletionRequestArgs,
+        CreateImageRequestArgs, FunctionCall, FunctionObjectArgs, Image, ImageModel, ImageQuality,
+        ImageSize, ImageUrlArgs,
+    };
+    use async_openai::{Client, config::OpenAIConfig};
+    use kovi::bot::message::Message;
+    use kovi_plugin_expand_napcat::NapCatApi;
+    use std::{fs::File, io::Write, path::Path, sync::Arc};
+
+    /// 回复消息的起始段：按配置决定是否 @ 发送者、是否引用触发消息
+    fn reply_base(event: &Arc<kovi::MsgEvent>) -> Message {
+        let mut msg = Message::new();
+        if reply_at() && event.group_id.is_some() {
+            msg = msg.add_at(&event.user_id.to_string()).add_text(" ");
+        }
+        if reply_with_quote() {
+            msg = msg.add_reply(event.message_id);
+        }
+        msg
+    }
+
+    pub(crate) fn reply_text(event: &Arc<kovi::MsgEvent>, text: impl Into<String>) {
+        event.reply(reply_base(event).add_text(text.into()));
+    }
+
+    /// 历史归属：私有历史按用户，公有历史按所在的桶
+    fn owner_key(event: &kovi::MsgEvent, private: bool) -> String {
+        if private {
+            event.user_id.to_string()
+        } else {
+            public_bucket(event)
+        }
+    }
+
+    /// admins 为空时人人可用；否则需在 admins 中或为 Kovi 管理员
+    async fn is_admin(
+        event: &Arc<kovi::MsgEvent>,
+        mgr: &Arc<Manager>,
+        bot: &Arc<kovi::RuntimeBot>,
+    ) -> bool {
+        if mgr.config.read().await.admins.is_empty() {
+            return true;
+        }
+        is_listed_admin(event, mgr, bot).await
+    }
+
+    /// 是否为明确配置的管理员 (admins 或 Kovi 管理员)，admins 为空时不视为所有人
+    async fn is_listed_admin(
+        event: &Arc<kovi::MsgEvent>,
+        mgr: &Arc<Manager>,
+        bot: &Arc<kovi::RuntimeBot>,
+    ) -> bool {
+        if mgr.config.read().await.admins.contains(&event.user_id) {
+            return true;
+        }
+        bot.get_all_admin()
+            .map(|admins| admins.contains(&event.user_id))
+            .unwrap_or(false)
+    }
+
+    async fn reply(event: &Arc<kovi::MsgEvent>, text: &str, text_mode: bool, header: &str) {
+        let msg = reply_base(event);
+
+        if text_mode {
+            if text.chars().count() <= chunk_threshold() {
+                event.reply(msg.add_text(text));
+                return;
+            }
+            // 长回复按段落拆成多条，首条保留引用
+            let mut parts = split_chunks(text, chunk_threshold()).into_iter();
+            if let Some(first) = parts.next() {
+                event.reply(msg.add_text(first));
+            }
+            for part in parts {
+                event.reply(Message::new().add_text(part));
+            }
+            return;
+        }
+        match render_md(text, header).await {
+            Ok(b64) => event.reply(msg.add_image(&format!("base64://{}", b64))),
+            Err(_) => {
+                let re = image_md_re();
+                let clean_text = re
+                    .replace_all(text, Msg::ImageRenderFailed.text())
+                    .to_string();
+                // 回退为纯文本时分段发送，避免超长消息被截断
+                let mut parts = split_for_send(&clean_text, max_text_len()).into_iter();
+                if let Some(first) = parts.next() {
+                    event.reply(msg.add_text(first));
+                }
+                for part in parts {
+                    event.reply(Message::new().add_text(part));
+                }
+            }
+        }
+    }
+
+    /// 上传文件到群文件或私聊文件
+    async fn upload_file(
+        event: &Arc<kovi::MsgEvent>,
+        bot: &Arc<kovi::RuntimeBot>,
+        path: &Path,
+        fname: &str,
+    ) {
+        let path_str = path.to_string_lossy().to_string();
+        let result = if let Some(gid) = event.group_id {
+            bot.upload_group_file(gid, &path_str, fname, None).await
+        } else {
+            bot.upload_private_file(event.user_id, &path_str, fname)
+                .await
+        };
+        match result {
+            Ok(_) => reply_text(event, messages::exported(fname)),
+            Err(e) => reply_text(event, messages::upload_failed(e)),
+        }
+    }
+
+    /// 以合并转发发送历史，每条消息一个节点 (角色名 + 内容)，失败时返回 false 以便回退截图
+    async fn send_history_forward(
+        event: &Arc<kovi::MsgEvent>,
+        bot: &Arc<kovi::RuntimeBot>,
+        agent_name: &str,
+        hist: &[ChatMessage],
+        offset: usize,
+        header: &str,
+    ) -> bool {
+        let node = |uid: i64, nickname: String, content: serde_json::Value| {
+            kovi::bot::message::Segment::new(
+                "node",
+                serde_json::json!({
+                    "user_id": uid.to_string(),
+                    "nickname": nickname,
+                    "content": content,
+                }),
+            )
+        };
+        let text = |t: &str| serde_json::json!({ "type": "text", "data": { "text": t } });
+
+        let mut nodes = vec![node(
+            event.self_id,
+            agent_name.to_string(),
+            serde_json::json!([text(header)]),
+        )];
+        for (i, m) in hist.iter().enumerate() {
+            let uid = match m.role.as_str() {
+                "user" => event.user_id,
+                _ => event.self_id,
+            };
+            let role = messages::role_label(&m.role);
+            let mut content = Vec::new();
+            let body = image_md_re().replace_all(&m.content, Msg::ImagePlaceholder.text());
+            if !body.trim().is_empty() {
+                content.push(text(&body));
+            }
+            for img in &m.images {
+                if let Some(src) = image_source(img) {
+                    content.push(serde_json::json!({ "type": "image", "data": { "file": src } }));
+                }
+            }
+            if content.is_empty() {
+                content.push(text(Msg::Empty.text()));
+            }
+            let nickname = format!("#{} {}", offset + i + 1, role);
+            nodes.push(node(uid, nickname, serde_json::Value::Array(content)));
+        }
+
+        let result = match event.group_id {
+            Some(gid) => bot.send_group_forward_msg(gid, nodes).await,
+            None => bot.send_private_forward_msg(event.user_id, nodes).await,
+        };
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                kovi::log::warn!("合并转发发送失败，回退为截图: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// 智能体需要审批时暂存请求并通知审批人，返回 true 表示请求已被拦截
+    async fn request_approval(
+        cmd: &Command,
+        prompt: &str,
+        imgs: &[String],
+        event: &Arc<kovi::MsgEvent>,
+        mgr: &Arc<Manager>,
+        bot: &Arc<kovi::RuntimeBot>,
+    ) -> bool {
+        let (approvers, timeout) = {
+            let c = mgr.config.read().await;
+            match c.agents.iter().find(|a| a.name == cmd.agent) {
+                Some(a) if a.require_approval && !a.approvers.is_empty() => {
+                    (a.approvers.clone(), c.approval_timeout_secs)
+                }
+                _ => return false,
+            }
+        };
+
+        // 审批人本人使用时无需审批
+        if approvers.contains(&event.user_id) {
+            return false;
+        }
+
+        let id = mgr
+            .add_pending(PendingApproval {
+                cmd: cmd.clone(),
+                prompt: prompt.to_string(),
+                imgs: imgs.to_vec(),
+                event: event.clone(),
+                created_at: chrono::Local::now().timestamp(),
+            })
+            .await;
+
+        let notice = messages::approval_notice(
+            id,
+            &cmd.agent,
+            event.group_id,
+            event.user_id,
+            &truncate_str(prompt, 100),
+        );
+        for uid in &approvers {
+            bot.send_private_msg(*uid, Message::new().add_text(&notice));
+        }
+        reply_text(event, messages::approval_submitted(&cmd.agent, id));
+
+        if timeout > 0 {
+            let mgr = mgr.clone();
+            kovi::tokio::spawn(async move {
+                kovi::tokio::time::sleep(std::time::Duration::from_secs(timeout)).await;
+                if let Some(req) = mgr.pending.write().await.remove(&id) {
+                    reply_text(&req.event, messages::approval_expired(id));
+                }
+            });
+        }
+
+        true
+    }
+
+    /// 查看历史时每页显示的消息数
+    const HISTORY_PAGE_SIZE: usize = 20;
+
+    /// 请求中断后保留下来的部分回复末尾的标记
+    const INTERRUPTED_MARK: &str = "[已中断]";
+
+    /// 内置工具定义，模型调用时在本地执行
+    fn builtin_tool(name: &str) -> Option<AgentTool> {
+        let (description, parameters) = match name {
+            "current_time" => (
+                "获取当前的日期、时间与星期",
+                serde_json::json!({"type": "object", "properties": {}}),
+            ),
+            "calculate" => (
+                "计算数学表达式，支持 + - * / % ^ 与括号",
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "expression": {"type": "string", "description": "如 (1+2)*3^2"}
+                    },
+                    "required": ["expression"]
+                }),
+            ),
+            _ => return None,
+        };
+        Some(AgentTool {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+        })
+    }
+
+    fn run_builtin_tool(name: &str, arguments: &str) -> String {
+        let args: serde_json::Value = serde_json::from_str(arguments).unwrap_or_default();
+        match name {
+            "current_time" => chrono::Local::now()
+                .format("%Y-%m-%d %H:%M:%S %A")
+                .to_string(),
+            "calculate" => {
+                let expr = args["expression"].as_str().unwrap_or_default();
+                match eval_expr(expr) {
+                    Ok(v) => v.to_string(),
+                    Err(e) => format!("计算失败: {}", e),
+                }
+            }
+            _ => format!("未知工具: {}", name),
+        }
+    }
+
+    /// 单轮补全：发送一条用户消息并返回回复文本，失败或超时返回 None
+    async fn complete_once(
+        mgr: &Arc<Manager>,
+        client: &Client<OpenAIConfig>,
+        model: &str,
+        prompt: String,
+        priority: Priority,
+        timeout_secs: u64,
+    ) -> Option<String> {
+        let req = CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .messages(vec![
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(prompt)
+                    .build()
+                    .ok()?
+                    .into(),
+            ])
+            .build()
+            .ok()?;
+
+        let res = {
+            let _permit = mgr.scheduler.acquire(priority).await;
+            kovi::tokio::time::timeout(
+                std::time::Duration::from_secs(timeout_secs),
+                client.chat().create(req),
+            )
+            .await
+            .ok()?
+            .ok()?
+        };
+
+        res.choices.first()?.message.content.clone()
+    }
+
+    /// 后台为公有历史生成话题名，仅在话题仍为空时写回
+    async fn generate_topic(
+        mgr: Arc<Manager>,
+        client: Client<OpenAIConfig>,
+        model: String,
+        name: String,
+        question: String,
+        answer: String,
+    ) {
+        let prompt = format!(
+            "为下面这轮对话起一个话题名，不超过 6 个字，不要标点，直接输出话题名。\n\n用户: {}\n\n助手: {}",
+            truncate_str(&question, 500),
+            truncate_str(&answer, 500)
+        );
+        let Some(topic) = complete_once(&mgr, &client, &model, prompt, Priority::Batch, 60).await
+        else {
+            return;
+        };
+        let topic: String = topic
+            .trim()
+            .replace(['"', '“', '”', '。', '.', '《', '》'], "")
+            .chars()
+            .take(6)
+            .collect();
+        if topic.is_empty() {
+            return;
+        }
+        let mut c = mgr.config.write().await;
+        // 生成期间历史可能已被清空，此时不再写回
+        if let Some(a) = c.agents.iter_mut().find(|a| a.name == name)
+            && a.topic.is_none()
+            && a.public_histories.values().any(|h| !h.is_empty())
+        {
+            a.topic = Some(topic);
+            mgr.save(&c);
+        }
+    }
+
+    /// 对事实性回复做二次校验，返回置信度标注；非事实性内容或校验失败时返回 None
+    async fn assess_confidence(
+        mgr: &Arc<Manager>,
+        client: &Client<OpenAIConfig>,
+        model: &str,
+        question: &str,
+        answer: &str,
+    ) -> Option<String> {
+        let check_prompt = format!(
+            "请评估下面这段回答的可信度。\n\
+             如果回答不包含可核实的事实性陈述（如闲聊、创作、代码），只输出 NONE。\n\
+             否则只输出一行，格式为「置信度：高/中/低，简短理由或核实建议」，不超过 30 字。\n\n\
+             问题:\n{}\n\n回答:\n{}",
+            question, answer
+        );
+        let text =
+            complete_once(mgr, client, model, check_prompt, Priority::Interactive, 60).await?;
+        let text = text.trim();
+        if text.is_empty() || text.to_uppercase().starts_with("NONE") {
+            None
+        } else {
+            Some(truncate_str(text, 60))
+        }
+    }
+
+    /// 将对话整理为 markmap 思维导图；模型提取失败时按问答顺序生成简单结构
+    async fn build_mindmap(
+        mgr: &Arc<Manager>,
+        api: &(String, String),
+        model: &str,
+        name: &str,
+        hist: &[ChatMessage],
+    ) -> String {
+        let re = image_md_re();
+        let transcript = hist
+            .iter()
+            .map(|m| {
+                let role = if m.role == "user" { "用户" } else { "助手" };
+                let body = re.replace_all(&m.content, "[图片]");
+                format!("{}: {}", role, truncate_str(&body, 1500))
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let extracted = if api.0.is_empty() || api.1.is_empty() {
+            None
+        } else {
+            let client = mgr.client(&api.0, &api.1).await;
+            let prompt = format!(
+                "请把下面的对话整理成思维导图的 Markdown 大纲。\n\
+                 要求：\n1. 第一行是 `# 总主题`\n2. 用 `##` 表示讨论的主要话题分支，用缩进的 `-` 列表表示要点和子分支\n\
+                 3. 每个节点不超过 20 字\n4. 只输出大纲本身，不要代码块和解释\n\n对话:\n{}",
+                transcript
+            );
+            complete_once(mgr, &client, model, prompt, Priority::Batch, 180)
+                .await
+                .map(|t| {
+                    t.trim()
+                        .trim_start_matches("```markdown")
+                        .trim_start_matches("```")
+                        .trim_end_matches("```")
+                        .trim()
+                        .to_string()
+                })
+                .filter(|t| t.starts_with('#'))
+        };
+
+        let body = extracted.unwrap_or_else(|| {
+            let mut out = format!("# {}", name);
+            for m in hist {
+                let text = re.replace_all(&m.content, Msg::ImagePlaceholder.text());
+                if m.role == "user" {
+                    let q = text.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+                    out.push_str(&format!("\n\n## {}", truncate_str(q.trim(), 30)));
+                } else {
+                    let heads: Vec<&str> = text
+                        .lines()
+                        .filter_map(|l| l.trim().strip_prefix('#'))
+                        .map(|h| h.trim_start_matches('#').trim())
+                        .filter(|h| !h.is_empty())
+                        .collect();
+                    if heads.is_empty() {
+                        let first = text.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+                        out.push_str(&format!("\n- {}", truncate_str(first.trim(), 40)));
+                    } else {
+                        for h in heads {
+                            out.push_str(&format!("\n- {}", truncate_str(h, 40)));
+                        }
+                    }
+                }
+            }
+            out
+        });
+
+        format!("---\nmarkmap:\n  colorFreezeLevel: 2\n---\n\n{}\n", body)
+    }
+
+    /// 总结较早的历史：保留最近 keep 条原文，其余替换为一条 [摘要] 消息。
+    /// 成功返回被压缩的消息数；失败时不改动历史。
+    pub async fn summarize_history(
+        mgr: &Arc<Manager>,
+        name: &str,
+        private: bool,
+        uid: &str,
+        keep: usize,
+    ) -> Result<usize, String> {
+        let (hist, model, api) = {
+            let c = mgr.config.read().await;
+            let a = c
+                .agents
+                .iter()
+                .find(|a| a.name == name)
+                .ok_or_else(|| messages::no_such_agent(name))?;
+            (
+                a.history(private, uid).to_vec(),
+                a.model.clone(),
+                (c.api_base.clone(), c.api_key.clone()),
+            )
+        };
+        if api.0.is_empty() || api.1.is_empty() {
+            return Err(Msg::ApiMissing.into());
+        }
+
+        // 让保留段从用户消息开始，避免拆开一问一答
+        let mut split = hist.len().saturating_sub(keep);
+        while split > 0 && split < hist.len() && hist[split].role != "user" {
+            split -= 1;
+        }
+        if split < 2 {
+            return Err(Msg::HistoryTooShort.into());
+        }
+
+        let re = image_md_re();
+        // 置顶消息不参与压缩，原样保留在摘要之后
+        let transcript = hist[..split]
+            .iter()
+            .filter(|m| !m.pinned)
+            .map(|m| {
+                let role = if m.role == "user" { "用户" } else { "助手" };
+                format!("{}: {}", role, re.replace_all(&m.content, "[图片]"))
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let prompt = format!(
+            "请把下面的对话总结为要点摘要，供后续对话作为上下文使用。\n\
+             要求：保留关键事实、结论、用户偏好和未完成的事项；使用简洁的列表；直接输出摘要，不要解释。\n\n对话:\n{}",
+            transcript
+        );
+
+        let client = mgr.client(&api.0, &api.1).await;
+        let summary = complete_once(mgr, &client, &model, prompt, Priority::Interactive, 300)
+            .await
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .ok_or_else(|| String::from(Msg::NoSummary))?;
+
+        // 总结期间可能有新消息写入：在写锁内对最新历史替换已总结的前缀，其后的消息原样保留。
+        // 不递增生成 ID，以免误判进行中的对话已被停止
+        let mut c = mgr.config.write().await;
+        let a = c
+            .agents
+            .iter_mut()
+            .find(|a| a.name == name)
+            .ok_or_else(|| messages::no_such_agent(name))?;
+        let live = a.history(private, uid).to_vec();
+        let summary = ChatMessage::new("assistant", &format!("[摘要]\n{}", summary), vec![]);
+        let Some((new_hist, cnt)) = splice_summary(&live, &hist[..split], summary) else {
+            return Err(Msg::HistoryChanged.into());
+        };
+        mgr.snapshot(
+            history_key(name, private, uid),
+            Msg::BeforeSummary.text(),
+            &live,
+        )
+        .await;
+        *a.history_mut(private, uid) = new_hist;
+        mgr.save(&c);
+        Ok(cnt)
+    }
+
+    /// 用摘要替换 live 开头的 prefix 段，置顶消息保留在摘要之后，prefix 之后的消息原样保留。
+    /// live 的开头已不是 prefix (被删除、编辑或清空) 时返回 None；成功返回 (新历史, 压缩条数)
+    pub fn splice_summary(
+        live: &[ChatMessage],
+        prefix: &[ChatMessage],
+        summary: ChatMessage,
+    ) -> Option<(Vec<ChatMessage>, usize)> {
+        if live.len() < prefix.len() || !live.iter().zip(prefix).all(|(l, p)| l.same_as(p)) {
+            return None;
+        }
+        let (head, tail) = live.split_at(prefix.len());
+        let mut new_hist = vec![summary];
+        new_hist.extend(head.iter().filter(|m| m.pinned).cloned());
+        new_hist.extend_from_slice(tail);
+        Some((new_hist, head.iter().filter(|m| !m.pinned).count()))
+    }
+
+    /// 按历史构建发送给 API 的消息数组（chat 与 /ctx 共用）
+    fn build_messages(
+        agent: &Agent,
+        hist: &[ChatMessage],
+        uid: &str,
+        model: &str,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        let mut msgs: Vec<ChatCompletionRequestMessage> = vec![];
+
+        let mut system = expand_prompt(&agent.system_prompt, uid, model);
+        // 绘图模型：尺寸/质量偏好以说明的形式附在系统提示词后
+        if is_image_model(model) {
+            let mut prefs = Vec::new();
+            if let Some(size) = &agent.image_size {
+                prefs.push(format!("尺寸 {}", size));
+            }
+            if let Some(quality) = &agent.image_quality {
+                prefs.push(format!("质量 {}", quality));
+            }
+            if !prefs.is_empty() {
+                if !system.is_empty() {
+                    system.push_str("\n\n");
+                }
+                system.push_str(&format!("图像输出偏好：{}", prefs.join("，")));
+            }
+        }
+        if !system.is_empty() {
+            msgs.push(
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(system)
+                    .build()
+                    .unwrap()
+                    .into(),
+            );
+        }
+        // 系统参考图：每次请求都以一条仅含图片的用户消息附在历史之前
+        if !agent.system_images.is_empty() {
+            let parts: Vec<ChatCompletionRequestUserMessageContentPart> = agent
+                .system_images
+                .iter()
+                .map(|url| {
+                    ChatCompletionRequestMessageContentPartImageArgs::default()
+                        .image_url(ImageUrlArgs::default().url(url).build().unwrap())
+                        .build()
+                        .unwrap()
+                        .into()
+                })
+                .collect();
+            msgs.push(
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(parts)
+                    .build()
+                    .unwrap()
+                    .into(),
+            );
+        }
+        let re = image_md_re();
+        for m in hist {
+            if m.role == "user" {
+                let mut parts = Vec::new();
+                if !m.content.is_empty() {
+                    parts.push(
+                        ChatCompletionRequestMessageContentPartTextArgs::default()
+                            .text(m.content.clone())
+                            .build()
+                            .unwrap()
+                            .into(),
+                    );
+                }
+                for url in &m.images {
+                    parts.push(
+                        ChatCompletionRequestMessageContentPartImageArgs::default()
+                            .image_url(ImageUrlArgs::default().url(url).build().unwrap())
+                            .build()
+                            .unwrap()
+                            .into(),
+                    );
+                }
+                if parts.is_empty() {
+                    continue;
+                }
+                msgs.push(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(parts)
+                        .build()
+                        .unwrap()
+                        .into(),
+                );
+            } else if m.role == "system" {
+                msgs.push(
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(m.content.clone())
+                        .build()
+                        .unwrap()
+                        .into(),
+                );
+            } else if m.role == "tool" {
+                msgs.push(
+                    ChatCompletionRequestToolMessageArgs::default()
+                        .content(m.content.clone())
+                        .tool_call_id(m.tool_call_id.clone().unwrap_or_default())
+                        .build()
+                        .unwrap()
+                        .into(),
+                );
+            } else if m.role == "assistant" {
+                let clean_content = re.replace_all(&m.content, "[Image Created]").to_string();
+
+                let mut builder = ChatCompletionRequestAssistantMessageArgs::default();
+                if !clean_content.is_empty() || m.tool_calls.is_empty() {
+                    builder.content(clean_content);
+                }
+                if !m.tool_calls.is_empty() {
+                    builder.tool_calls(
+                        m.tool_calls
+                            .iter()
+                            .map(|c| ChatCompletionMessageToolCall {
+                                id: c.id.clone(),
+                                r#type: ChatCompletionToolType::Function,
+                                function: FunctionCall {
+                                    name: c.name.clone(),
+                                    arguments: c.arguments.clone(),
+                                },
+                            })
+                            .collect::<Vec<_>>(),
+                    );
+                }
+                msgs.push(builder.build().unwrap().into());
+
+                // 内容中的图片与 images 接口生成后记录的图片
+                let mut gen_imgs = extract_image_urls(&m.content);
+                for url in &m.images {
+                    if !gen_imgs.contains(url) {
+                        gen_imgs.push(url.clone());
+                    }
+                }
+                if !gen_imgs.is_empty() {
+                    let mut img_parts = Vec::new();
+                    for url in gen_imgs {
+                        img_parts.push(
+                            ChatCompletionRequestMessageContentPartImageArgs::default()
+                                .image_url(ImageUrlArgs::default().url(url).build().unwrap())
+                                .build()
+                                .unwrap()
+                                .into(),
+                        );
+                    }
+                    msgs.push(
+                        ChatCompletionRequestUserMessageArgs::default()
+                            .content(img_parts)
+                            .build()
+                            .unwrap()
+                            .into(),
+                    );
+                }
+            }
+        }
+        msgs
+    }
+
+    /// 将消息数组渲染为可读文本，附带估算 token 数 (字符数 / 4，图片按低精度计)
+    fn format_context(msgs: &[ChatCompletionRequestMessage], threshold: usize) -> String {
+        let mut total = 0;
+        let mut blocks = Vec::new();
+        for (i, m) in msgs.iter().enumerate() {
+            let v = serde_json::to_value(m).unwrap_or_default();
+            let role = v["role"].as_str().unwrap_or("?");
+            let mut text = String::new();
+            let mut images = 0;
+            match &v["content"] {
+                serde_json::Value::String(t) => text.push_str(t),
+                serde_json::Value::Array(parts) => {
+                    for p in parts {
+                        if let Some(t) = p["text"].as_str() {
+                            text.push_str(t);
+                        } else if let Some(url) = p["image_url"]["url"].as_str() {
+                            images += 1;
+                            text.push_str(&messages::image_ref(&truncate_str(url, 60)));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            let tokens = text.chars().count() / 4 + images * IMAGE_TOKENS;
+            total += tokens;
+            blocks.push(format!(
+                "**[{}] {}** · ~{} tokens\n```\n{}\n```",
+                i + 1,
+                role,
+                tokens,
+                text.trim()
+            ));
+        }
+        format!(
+            "{}\n\n---\n{}",
+            blocks.join("\n\n"),
+            messages::context_footer(msgs.len(), total, threshold)
+        )
+    }
+
+    fn extract_image_urls(content: &str) -> Vec<String> {
+        let re = image_any_re();
+
+        let mut urls: Vec<String> = re
+            .captures_iter(content)
+            .filter_map(|cap| cap.get(1).or(cap.get(0)).map(|m| m.as_str().to_string()))
+            .collect();
+
+        let mut seen = std::collections::HashSet::new();
+        urls.retain(|url| seen.insert(url.clone()));
+
+        urls
+    }
+
+    fn extract_video_urls(content: &str) -> Vec<String> {
+        // 匹配 [download video](url)
+        let re = video_re();
+        re.captures_iter(content)
+            .filter_map(|cap| cap.get(1).map(|m| m.as_str().to_string()))
+            .collect()
+    }
+
+    /// 翻译目标语言：常见语言代码转为英文名，其余原样使用，留空为英语
+    fn language_name(lang: &str) -> &str {
+        match lang.to_lowercase().as_str() {
+            "" | "en" => "English",
+            "zh" | "cn" | "中文" => "Simplified Chinese",
+            "tw" | "繁体" => "Traditional Chinese",
+            "ja" | "jp" | "日语" => "Japanese",
+            "ko" | "kr" | "韩语" => "Korean",
+            "fr" => "French",
+            "de" => "German",
+            "es" => "Spanish",
+            "ru" => "Russian",
+            _ => lang,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn chat(
+        name: &str,
+        prompt: &str,
+        imgs: Vec<String>,
+        regen: bool,
+        transient: bool,
+        cmd: &Command,
+        event: &Arc<kovi::MsgEvent>,
+        mgr: &Arc<Manager>,
+        bot: &Arc<kovi::RuntimeBot>,
+    ) -> Option<String> {
+        struct ChatContext<'a> {
+            name: &'a str,
+            prompt: &'a str,
+            imgs: Vec<String>,
+            regen: bool,
+            /// 一次性请求 (如翻译)：与临时会话相同，不读取也不写入历史
+            transient: bool,
+            cmd: &'a Command,
+            event: &'a Arc<kovi::MsgEvent>,
+            mgr: &'a Arc<Manager>,
+            bot: &'a Arc<kovi::RuntimeBot>,
+        }
+
+        async fn inner(ctx: ChatContext<'_>) -> Option<String> {
+            let is_priv_ctx = ctx.cmd.private_reply;
+            let uid = ctx.event.user_id.to_string();
+            let hkey = owner_key(ctx.event, is_priv_ctx);
+            let temp_mode = ctx.cmd.temp_mode || ctx.transient;
+
+            // 违禁词过滤 (不区分大小写，引用内容已拼接在 prompt 中)
+            {
+                let c = ctx.mgr.config.read().await;
+                let lower = ctx.prompt.to_lowercase();
+                if c.blocked_keywords
+                    .iter()
+                    .any(|k| !k.is_empty() && lower.contains(&k.to_lowercase()))
+                {
+                    reply_text(ctx.event, Msg::BlockedContent);
+                    return None;
+                }
+            }
+
+            // 如果是临时模式，跳过"正在生成"检查，不阻塞
+            if !temp_mode {
+                let generating = ctx.mgr.generating.read().await;
+                if generating.is_generating(ctx.name, is_priv_ctx, &hkey) {
+                    reply_text(ctx.event, Msg::Generating);
+                    return None;
+                }
+            }
+
+            let (agent, api) = {
                 let c = ctx.mgr.config.read().await;
                 let a = c.agents.iter().find(|a| a.name == ctx.name).cloned();
                 (a, (c.api_base.clone(), c.api_key.clone()))
             };
 
-            let agent = match agent {
-                Some(a) => a,
-                None => {
-                    reply_text(ctx.event, format!("❌ 智能体 {} 不存在", ctx.name));
+            let mut agent = match agent {
+                Some(a) => a,
+                None => {
+                    reply_text(ctx.event, messages::not_found(ctx.name));
+                    return None;
+                }
+            };
+            // 私有会话已满时拒绝新用户，已有会话和管理员不受限
+            if is_priv_ctx
+                && !temp_mode
+                && let Some(max) = agent.max_private_users
+                && !agent.private_histories.contains_key(&hkey)
+                && agent.private_histories.len() >= max
+                && !is_admin(ctx.event, ctx.mgr, ctx.bot).await
+            {
+                reply_text(ctx.event, Msg::PrivateSlotsFull);
+                return None;
+            }
+            // 私有会话优先使用用户自己的设定
+            if is_priv_ctx && let Some(p) = agent.private_prompts.get(&uid) {
+                agent.system_prompt = p.clone();
+            }
+
+            if api.0.is_empty() || api.1.is_empty() {
+                reply_text(ctx.event, Msg::ApiNotConfigured);
+                return None;
+            }
+            // 通过各项检查后，新用户先收到欢迎语，再收到本次回复
+            if let Some(welcome) = ctx.mgr.welcome_for(&uid).await {
+                reply_text(ctx.event, welcome);
+            }
+
+            // 预检输入图片，失效链接不发给模型
+            let (imgs, skipped_imgs) = filter_valid_images(ctx.imgs.clone()).await;
+
+            // 重新生成时可临时切换模型，不修改智能体的持久配置
+            let model = match &ctx.cmd.action {
+                Action::Regenerate { model: Some(m) } => {
+                    let c = ctx.mgr.config.read().await;
+                    ctx.mgr.resolve_model(m, &c).unwrap_or_else(|| m.clone())
+                }
+                _ => agent.model.clone(),
+            };
+
+            // 部分协议端不支持贴表情，失败时只记 debug 日志
+            let emoji = ctx.mgr.config.read().await.react_emoji.clone();
+            if let Some(emoji) = emoji.filter(|e| !e.is_empty())
+                && let Err(e) = ctx
+                    .bot
+                    .set_msg_emoji_like(ctx.event.message_id.into(), &emoji)
+                    .await
+            {
+                kovi::log::debug!("点赞失败: {:?}", e);
+            }
+
+            // 显式 !image 或 images 接口模型的普通对话直接绘图
+            let image_gen = match &ctx.cmd.action {
+                Action::ImageGen(_) => true,
+                Action::Chat => uses_images_endpoint(&model),
+                _ => false,
+            };
+            if image_gen {
+                let client = ctx.mgr.client(&api.0, &api.1).await;
+                return generate_image(&ctx, &agent, &model, &client).await;
+            }
+
+            // 临时模式下不加载历史，创建一个空历史用于本次构建消息
+            let mut hist = if temp_mode {
+                Vec::new()
+            } else {
+                agent.history(is_priv_ctx, &hkey).to_vec()
+            };
+
+            // 重新生成时裁掉末尾的回复（以及要被替换的提问），记下被裁的消息供写回时核对
+            let mut trimmed = Vec::new();
+            if ctx.regen {
+                if hist.last().is_some_and(|m| m.role == "assistant") {
+                    trimmed.extend(hist.pop());
+                }
+                if !ctx.prompt.is_empty() && hist.last().is_some_and(|m| m.role == "user") {
+                    trimmed.extend(hist.pop());
+                }
+                trimmed.reverse();
+            }
+            // 本轮新增的消息从这里开始，写回时只追加这部分
+            let kept_len = hist.len();
+
+            if ctx.regen {
+                if !ctx.prompt.is_empty() {
+                    hist.push(ChatMessage::new("user", ctx.prompt, imgs.clone()));
+                }
+            } else {
+                if ctx.prompt.is_empty() && imgs.is_empty() {
+                    reply_text(ctx.event, Msg::EmptyInput);
+                    return None;
+                }
+                // 上一轮模型发起了工具调用：本条消息作为工具结果提交
+                let pending_calls = match hist.last() {
+                    Some(m) if m.role == "assistant" && !m.tool_calls.is_empty() => {
+                        Some((m.tool_calls.clone(), m.timestamp))
+                    }
+                    _ => None,
+                };
+                match pending_calls {
+                    Some((calls, ts)) => {
+                        let timeout = ctx.mgr.config.read().await.tool_timeout_secs as i64;
+                        let in_time = chrono::Local::now().timestamp() - ts <= timeout;
+                        // 只有以 => 开头的消息才算结果，按调用序号逐个对应
+                        let results =
+                            parse_tool_results(ctx.prompt, calls.len()).filter(|_| in_time);
+                        let submitted = results.is_some();
+                        let results = results.unwrap_or_else(|| vec![None; calls.len()]);
+                        for (call, result) in calls.iter().zip(results) {
+                            let content = match result {
+                                Some(r) => r,
+                                None if !submitted && !in_time => {
+                                    "(用户未在时限内提供结果)".to_string()
+                                }
+                                None => "(用户未提供结果)".to_string(),
+                            };
+                            hist.push(ChatMessage::tool_result(&call.id, &content));
+                        }
+                        if !submitted {
+                            hist.push(ChatMessage::new("user", ctx.prompt, imgs.clone()));
+                        }
+                    }
+                    None => hist.push(ChatMessage::new("user", ctx.prompt, imgs.clone())),
+                }
+            }
+
+            // 预估上下文大小：超出智能体预算时直接放弃，超过阈值时在回复前提示
+            let ctx_tokens = input_tokens_estimate(&agent, &hist)
+                + ctx
+                    .cmd
+                    .system_injection
+                    .as_ref()
+                    .map_or(0, |s| s.chars().count() / 4);
+            if let Some(budget) = agent.token_budget
+                && ctx_tokens > budget as usize
+            {
+                reply_text(ctx.event, messages::over_budget(ctx_tokens, budget));
+                return None;
+            }
+
+            // 临时模式不保存历史，也不更新 generation_id
+            let gen_id = if temp_mode {
+                0 // 临时 ID
+            } else {
+                // 读取快照到写回之间可能有其他消息修改了同一会话（删除、编辑等），
+                // 因此不整体覆盖，而是在配置写锁内核对并裁剪最新历史、只追加新消息 (见 Manager::config)
+                let new_msgs = hist.split_off(kept_len);
+                let mut c = ctx.mgr.config.write().await;
+                if let Some(a) = c.agents.iter_mut().find(|a| a.name == ctx.name) {
+                    a.apply_turn(is_priv_ctx, &hkey, &trimmed, new_msgs);
+                    hist = a.history(is_priv_ctx, &hkey).to_vec();
+                    let id = a.bump_generation(is_priv_ctx, &hkey);
+                    ctx.mgr.save(&c);
+                    id
+                } else {
+                    return None;
+                }
+            };
+
+            // 临时模式不设置生成锁，避免阻塞主对话
+            if !temp_mode {
+                let mut generating = ctx.mgr.generating.write().await;
+                generating.set_generating(ctx.name, is_priv_ctx, &hkey, true);
+            }
+
+            let client = ctx.mgr.client(&api.0, &api.1).await;
+
+            let (token_warning, max_tool_rounds) = {
+                let c = ctx.mgr.config.read().await;
+                (ctx_tokens > c.warn_token_threshold, c.max_tool_rounds)
+            };
+
+            // 智能体超时优先，其次全局；0 表示不限时
+            let timeout_secs = match agent.timeout_secs {
+                Some(t) => t,
+                None => ctx.mgr.config.read().await.timeout_secs,
+            };
+
+            let started = std::time::Instant::now();
+            let mut tool_rounds = 0;
+            // 工具轮次中模型已给出的文字，后续请求超时时作为部分回复保留
+            let mut partial = String::new();
+            let result = loop {
+                let mut msgs = build_messages(&agent, &hist, &uid, &model);
+                // 一次性系统消息插在最后一条用户消息之前，不进入历史
+                if let Some(sys) = &ctx.cmd.system_injection {
+                    let pos = msgs
+                        .iter()
+                        .rposition(|m| matches!(m, ChatCompletionRequestMessage::User(_)))
+                        .unwrap_or(msgs.len());
+                    msgs.insert(
+                        pos,
+                        ChatCompletionRequestSystemMessageArgs::default()
+                            .content(sys.as_str())
+                            .build()
+                            .unwrap()
+                            .into(),
+                    );
+                }
+
+                let mut req_builder = CreateChatCompletionRequestArgs::default();
+                req_builder.model(&model).messages(msgs);
+                if !agent.stop.is_empty() {
+                    req_builder.stop(agent.stop.clone());
+                }
+                if let Some(seed) = agent.seed {
+                    req_builder.seed(seed);
+                }
+                if !agent.tools.is_empty() {
+                    let tools: Vec<_> = agent
+                        .tools
+                        .iter()
+                        .map(|t| {
+                            ChatCompletionToolArgs::default()
+                                .function(
+                                    FunctionObjectArgs::default()
+                                        .name(&t.name)
+                                        .description(&t.description)
+                                        .parameters(t.parameters.clone())
+                                        .build()
+                                        .unwrap(),
+                                )
+                                .build()
+                                .unwrap()
+                        })
+                        .collect();
+                    req_builder.tools(tools);
+                    // 内置工具轮数已用尽：最后一轮禁止再调用工具，逼模型直接作答
+                    if tool_rounds >= max_tool_rounds {
+                        req_builder.tool_choice(ChatCompletionToolChoiceOption::None);
+                    }
+                }
+
+                let req = match req_builder.build() {
+                    Ok(r) => r,
+                    Err(e) => {
+                        if !temp_mode {
+                            let mut generating = ctx.mgr.generating.write().await;
+                            generating.set_generating(ctx.name, is_priv_ctx, &hkey, false);
+                        }
+                        reply_text(ctx.event, messages::build_failed(e));
+                        return None;
+                    }
+                };
+
+                let permit = ctx.mgr.scheduler.acquire(Priority::Interactive).await;
+                let result = if timeout_secs == 0 {
+                    Ok(client.chat().create(req).await)
+                } else {
+                    kovi::tokio::time::timeout(
+                        std::time::Duration::from_secs(timeout_secs),
+                        client.chat().create(req),
+                    )
+                    .await
+                };
+                drop(permit);
+
+                // 内置工具：本地执行后带上结果再次请求，直到模型给出最终回答
+                if let Ok(Ok(res)) = &result
+                    && tool_rounds < max_tool_rounds
+                    && let Some(choice) = res.choices.first()
+                    && let Some(calls) = &choice.message.tool_calls
+                    && !calls.is_empty()
+                    && calls
+                        .iter()
+                        .all(|c| builtin_tool(&c.function.name).is_some())
+                {
+                    tool_rounds += 1;
+                    let mut call_msg = ChatMessage::new(
+                        "assistant",
+                        choice.message.content.as_deref().unwrap_or(""),
+                        vec![],
+                    );
+                    call_msg.tool_calls = calls
+                        .iter()
+                        .map(|c| ToolCall {
+                            id: c.id.clone(),
+                            name: c.function.name.clone(),
+                            arguments: c.function.arguments.clone(),
+                        })
+                        .collect();
+                    if let Some(text) = choice.message.content.as_deref()
+                        && !text.trim().is_empty()
+                    {
+                        if !partial.is_empty() {
+                            partial.push_str("\n\n");
+                        }
+                        partial.push_str(text.trim());
+                    }
+                    let mut round = vec![call_msg];
+                    for c in calls {
+                        let output = run_builtin_tool(&c.function.name, &c.function.arguments);
+                        round.push(ChatMessage::tool_result(&c.id, &output));
+                    }
+                    if !temp_mode {
+                        let mut c = ctx.mgr.config.write().await;
+                        let a = c.agents.iter_mut().find(|a| a.name == ctx.name);
+                        // 工具执行期间被删除或被手动停止
+                        let Some(a) =
+                            a.filter(|a| a.session_generation(is_priv_ctx, &hkey) == gen_id)
+                        else {
+                            drop(c);
+                            let mut generating = ctx.mgr.generating.write().await;
+                            generating.set_generating(ctx.name, is_priv_ctx, &hkey, false);
+                            return None;
+                        };
+                        a.history_mut(is_priv_ctx, &hkey).extend(round.clone());
+                        ctx.mgr.save_chat(&c);
+                    }
+                    hist.extend(round);
+                    continue;
+                }
+                break result;
+            };
+
+            let mut replied = None;
+            match result {
+                // 超时
+                Err(_) => {
+                    if !temp_mode {
+                        let mut generating = ctx.mgr.generating.write().await;
+                        generating.set_generating(ctx.name, is_priv_ctx, &hkey, false);
+                    }
+                    if partial.is_empty() {
+                        reply_text(ctx.event, messages::request_timeout(timeout_secs));
+                    } else {
+                        // 已有部分回复：文本已随工具调用消息写入历史，这里只补中断标记，
+                        // 回复中仍附上文本，避免用户看不到已生成的内容
+                        let text = format!("{}\n\n{}", partial, INTERRUPTED_MARK);
+                        if !temp_mode {
+                            let mut c = ctx.mgr.config.write().await;
+                            if let Some(a) = c.agents.iter_mut().find(|a| a.name == ctx.name)
+                                && a.session_generation(is_priv_ctx, &hkey) == gen_id
+                            {
+                                a.history_mut(is_priv_ctx, &hkey).push(ChatMessage::new(
+                                    "assistant",
+                                    INTERRUPTED_MARK,
+                                    vec![],
+                                ));
+                                ctx.mgr.save_chat(&c);
+                            }
+                        }
+                        reply_text(
+                            ctx.event,
+                            format!("{}\n\n{}", text, messages::request_timeout(timeout_secs)),
+                        );
+                    }
+                }
+                // 完成
+                Ok(result) => match result {
+                    Ok(res) => {
+                        if !temp_mode {
+                            let mut generating = ctx.mgr.generating.write().await;
+                            generating.set_generating(ctx.name, is_priv_ctx, &hkey, false);
+                        }
+
+                        // 非临时模式下检查 ID 是否变更（是否被手动停止）
+                        if !temp_mode {
+                            let c = ctx.mgr.config.read().await;
+                            if let Some(a) = c.agents.iter().find(|a| a.name == ctx.name)
+                                && a.session_generation(is_priv_ctx, &hkey) != gen_id
+                            {
+                                return None;
+                            }
+                        }
+
+                        // 模型请求调用工具：记录调用并等待用户回复结果
+                        if let Some(choice) = res.choices.first()
+                            && let Some(calls) = &choice.message.tool_calls
+                            && !calls.is_empty()
+                        {
+                            let calls: Vec<ToolCall> = calls
+                                .iter()
+                                .map(|c| ToolCall {
+                                    id: c.id.clone(),
+                                    name: c.function.name.clone(),
+                                    arguments: c.function.arguments.clone(),
+                                })
+                                .collect();
+                            // 仅含内置工具却仍在调用：说明已达轮数上限
+                            if calls.iter().all(|c| builtin_tool(&c.name).is_some()) {
+                                reply_text(
+                                    ctx.event,
+                                    messages::tool_rounds_exceeded(&agent.name, max_tool_rounds),
+                                );
+                                return None;
+                            }
+                            let text = choice.message.content.clone().unwrap_or_default();
+                            if !temp_mode {
+                                let mut c = ctx.mgr.config.write().await;
+                                if let Some(a) = c.agents.iter_mut().find(|a| a.name == ctx.name) {
+                                    let mut m = ChatMessage::new("assistant", &text, vec![]);
+                                    m.tool_calls = calls.clone();
+                                    a.history_mut(is_priv_ctx, &hkey).push(m);
+                                }
+                                ctx.mgr.save_chat(&c);
+                            }
+                            let timeout = ctx.mgr.config.read().await.tool_timeout_secs;
+                            let mut out = messages::tool_call_header(&agent.name);
+                            if !text.is_empty() {
+                                out = format!("{}\n\n{}", text, out);
+                            }
+                            for (i, call) in calls.iter().enumerate() {
+                                out.push_str(&format!(
+                                    "\n{}. {}({})",
+                                    i + 1,
+                                    call.name,
+                                    call.arguments
+                                ));
+                            }
+                            if temp_mode {
+                                out.push_str(Msg::ToolCallTemp.text());
+                            } else {
+                                out.push_str(&messages::tool_call_hint(
+                                    &agent.name,
+                                    timeout,
+                                    calls.len() > 1,
+                                ));
+                            }
+                            reply_text(ctx.event, out);
+                            return None;
+                        }
+
+                        if let Some(choice) = res.choices.first()
+                            && let Some(content) = &choice.message.content
+                        {
+                            replied = Some(content.clone());
+                            ctx.mgr.emit_response(AgentResponseEvent {
+                                agent_name: agent.name.clone(),
+                                scope: if is_priv_ctx { "private" } else { "public" }.to_string(),
+                                uid: uid.clone(),
+                                prompt: ctx.prompt.to_string(),
+                                response: content.clone(),
+                                model: model.clone(),
+                                latency_ms: started.elapsed().as_millis() as u64,
+                            });
+                            let msg_index = if temp_mode {
+                                0
+                            } else {
+                                let c = ctx.mgr.config.read().await;
+                                if let Some(a) = c.agents.iter().find(|a| a.name == ctx.name) {
+                                    a.history(is_priv_ctx, &hkey).len() + 1
+                                } else {
+                                    0
+                                }
+                            };
+
+                            // 临时模式不保存回复到历史
+                            if !temp_mode {
+                                let mut c = ctx.mgr.config.write().await;
+                                let mut first_round = None;
+                                if let Some(a) = c.agents.iter_mut().find(|a| a.name == ctx.name) {
+                                    a.history_mut(is_priv_ctx, &hkey).push(ChatMessage::new(
+                                        "assistant",
+                                        content,
+                                        vec![],
+                                    ));
+                                    a.record_use();
+                                    // 公有历史的首轮回复：记下问答用于生成话题名
+                                    let public = a.history(false, &hkey);
+                                    if !is_priv_ctx
+                                        && a.topic.is_none()
+                                        && public.iter().filter(|m| m.role == "assistant").count()
+                                            == 1
+                                    {
+                                        first_round = public
+                                            .iter()
+                                            .find(|m| m.role == "user")
+                                            .map(|m| (m.content.clone(), content.clone()));
+                                    }
+                                }
+                                ctx.mgr.save_chat(&c);
+                                drop(c);
+
+                                if let Some((question, answer)) = first_round {
+                                    kovi::tokio::spawn(generate_topic(
+                                        ctx.mgr.clone(),
+                                        client.clone(),
+                                        model.clone(),
+                                        ctx.name.to_string(),
+                                        question,
+                                        answer,
+                                    ));
+                                }
+                            }
+
+                            let image_urls = extract_image_urls(content);
+
+                            let header = if temp_mode {
+                                messages::temp_reply_header(&agent.name)
+                            } else {
+                                let mut h = messages::reply_header(
+                                    &agent.name,
+                                    msg_index,
+                                    ctx.cmd.private_reply,
+                                );
+                                if model != agent.model {
+                                    h.push_str(&format!(" · {}", model));
+                                }
+                                h
+                            };
+
+                            let display_content = if !image_urls.is_empty() && !ctx.cmd.text_mode {
+                                let urls_text = image_urls
+                                    .iter()
+                                    .map(|u| {
+                                        if u.starts_with("data:") {
+                                            "- [Base64 Image]".to_string()
+                                        } else {
+                                            format!("- {}", u)
+                                        }
+                                    })
+                                    .collect::<Vec<_>>()
+                                    .join("\n");
+                                format!(
+                                    "{}\n\n---\n{}\n{}",
+                                    content,
+                                    Msg::ImageLinks.text(),
+                                    urls_text
+                                )
+                            } else {
+                                content.clone()
+                            };
+
+                            let mut reply_text_content =
+                                if ctx.cmd.text_mode && !image_urls.is_empty() {
+                                    // 使用与 extract_image_urls 相同的逻辑替换
+                                    image_url_re()
+                                        .replace_all(content, |caps: &regex::Captures| {
+                                            let url = &caps[1];
+                                            if url.starts_with("data:") {
+                                                Msg::ImagePlaceholder.text().to_string()
+                                            } else {
+                                                url.to_string()
+                                            }
+                                        })
+                                        .to_string()
+                                } else {
+                                    display_content.clone()
+                                };
+
+                            // 可信度校验：仅用于展示，不写入历史
+                            let confidence_check = ctx.mgr.config.read().await.confidence_check;
+                            if confidence_check && image_urls.is_empty() {
+                                let question = hist
+                                    .iter()
+                                    .rev()
+                                    .find(|m| m.role == "user")
+                                    .map(|m| m.content.as_str())
+                                    .unwrap_or(ctx.prompt);
+                                if let Some(note) =
+                                    assess_confidence(ctx.mgr, &client, &model, question, content)
+                                        .await
+                                {
+                                    reply_text_content.push_str(&format!("\n\n---\n> 🔍 {}", note));
+                                }
+                            }
+
+                            if skipped_imgs > 0 {
+                                reply_text_content = format!(
+                                    "{}\n\n{}",
+                                    messages::images_skipped(skipped_imgs),
+                                    reply_text_content
+                                );
+                            }
+
+                            if token_warning {
+                                reply_text_content = format!(
+                                    "{}\n\n{}",
+                                    messages::context_near_limit(ctx_tokens),
+                                    reply_text_content
+                                );
+                            }
+
+                            if ctx.regen
+                                && let Some(seed) = agent.seed
+                            {
+                                reply_text_content = format!(
+                                    "{}\n\n{}",
+                                    messages::seed_fixed(seed),
+                                    reply_text_content
+                                );
+                            }
+
+                            reply(ctx.event, &reply_text_content, ctx.cmd.text_mode, &header).await;
+
+                            for url in &image_urls {
+                                if let Some(src) = image_source(url) {
+                                    ctx.event.reply(Message::new().add_image(&src));
+                                }
+                            }
+
+                            let video_urls = extract_video_urls(content);
+                            for url in video_urls {
+                                // 使用 OneBot 标准 video 段发送，data 放 file 字段，框架会自动处理下载/转发
+                                let mut vec = Vec::new();
+                                let segment = kovi::bot::message::Segment::new(
+                                    "video",
+                                    kovi::serde_json::json!({
+                                        "file": url
+                                    }),
+                                );
+                                vec.push(segment);
+                                let msg = kovi::bot::message::Message::from(vec);
+                                ctx.event.reply(msg);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        {
+                            let mut generating = ctx.mgr.generating.write().await;
+                            generating.set_generating(ctx.name, is_priv_ctx, &hkey, false);
+                        }
+                        // 端点不支持 seed 时给出可操作的提示
+                        let err = e.to_string();
+                        if agent.seed.is_some() && err.to_lowercase().contains("seed") {
+                            reply_text(ctx.event, messages::seed_unsupported(&agent.name));
+                        } else {
+                            reply_text(ctx.event, messages::api_error(err));
+                        }
+                    }
+                },
+            }
+            replied
+        }
+
+        /// 调用 images 接口生成一张图片，成功后与描述一起写入历史
+        async fn generate_image(
+            ctx: &ChatContext<'_>,
+            agent: &Agent,
+            model: &str,
+            client: &Client<OpenAIConfig>,
+        ) -> Option<String> {
+            if ctx.prompt.is_empty() {
+                reply_text(ctx.event, Msg::EmptyInput);
+                return None;
+            }
+
+            let mut req = CreateImageRequestArgs::default();
+            req.prompt(ctx.prompt).n(1u8);
+            // 智能体不是绘图模型时使用 dall-e-3
+            if uses_images_endpoint(model) {
+                req.model(ImageModel::Other(model.to_string()));
+            } else {
+                req.model(ImageModel::DallE3);
+            }
+            if let Some(size) = agent
+                .image_size
+                .as_deref()
+                .and_then(|s| serde_json::from_value::<ImageSize>(serde_json::json!(s)).ok())
+            {
+                req.size(size);
+            }
+            if let Some(quality) = agent.image_quality.as_deref().and_then(|q| {
+                serde_json::from_value::<ImageQuality>(serde_json::json!(q.to_lowercase())).ok()
+            }) {
+                req.quality(quality);
+            }
+            let request = match req.build() {
+                Ok(r) => r,
+                Err(e) => {
+                    reply_text(ctx.event, messages::build_failed(e));
+                    return None;
+                }
+            };
+
+            let result = {
+                let _permit = ctx.mgr.scheduler.acquire(Priority::Interactive).await;
+                client.images().create(request).await
+            };
+            let res = match result {
+                Ok(r) => r,
+                Err(e) => {
+                    reply_text(ctx.event, messages::api_error(e));
+                    return None;
+                }
+            };
+            let Some(image) = res.data.first() else {
+                reply_text(ctx.event, Msg::NoImageReturned);
+                return None;
+            };
+            let (url, revised) = match image.as_ref() {
+                Image::Url {
+                    url,
+                    revised_prompt,
+                } => (url.clone(), revised_prompt.clone()),
+                Image::B64Json {
+                    b64_json,
+                    revised_prompt,
+                } => (
+                    format!("data:image/png;base64,{}", b64_json),
+                    revised_prompt.clone(),
+                ),
+            };
+            let content = format!(
+                "{} {}",
+                Msg::ImagePlaceholder.text(),
+                revised.as_deref().unwrap_or(ctx.prompt)
+            );
+
+            // 图片地址记录在 images 中，后续对话可继续引用
+            if !ctx.cmd.temp_mode && !ctx.transient {
+                let is_priv_ctx = ctx.cmd.private_reply;
+                let hkey = owner_key(ctx.event, is_priv_ctx);
+                let mut c = ctx.mgr.config.write().await;
+                if let Some(a) = c.agents.iter_mut().find(|a| a.name == ctx.name) {
+                    let hist = a.history_mut(is_priv_ctx, &hkey);
+                    hist.push(ChatMessage::new("user", ctx.prompt, vec![]));
+                    hist.push(ChatMessage::new("assistant", &content, vec![url.clone()]));
+                    a.record_use();
+                    ctx.mgr.save_chat(&c);
+                }
+            }
+
+            reply_text(ctx.event, format!("🎨 {} · {}", agent.name, content));
+            if let Some(src) = image_source(&url) {
+                ctx.event.reply(reply_base(ctx.event).add_image(&src));
+            }
+            Some(content)
+        }
+
+        inner(ChatContext {
+            name,
+            prompt,
+            imgs,
+            regen,
+            transient,
+            cmd,
+            event,
+            mgr,
+            bot,
+        })
+        .await
+    }
+
+    pub async fn execute(
+        cmd: Command,
+        prompt: String,
+        imgs: Vec<String>,
+        event: &Arc<kovi::MsgEvent>,
+        mgr: &Arc<Manager>,
+        bot: &Arc<kovi::RuntimeBot>,
+    ) {
+        let name = &cmd.agent;
+        let uid = event.user_id.to_string();
+        let bucket = public_bucket(event);
+        let owner = |private: bool| if private { uid.clone() } else { bucket.clone() };
+
+        mgr.log_command(AuditEntry {
+            ts: chrono::Local::now().timestamp(),
+            user_id: event.user_id,
+            group_id: event.group_id,
+            agent: cmd.agent.clone(),
+            action: cmd.action.audit_name(),
+            private: cmd.private_reply,
+        });
+
+        if cmd.action.requires_admin() && !is_admin(event, mgr, bot).await {
+            reply_text(event, Msg::AdminRequired);
+            return;
+        }
+
+        match cmd.action {
+            Action::UpdateApi(url, key) => {
+                let mut c = mgr.config.write().await;
+                if c.require_sk_prefix && !key.starts_with("sk-") {
+                    reply_text(event, Msg::SkPrefixRequired);
+                    return;
+                }
+                c.api_base = url.clone();
+                c.api_key = key;
+                mgr.save(&c);
+                drop(c);
+
+                reply_text(event, messages::api_configured(&url));
+
+                match mgr.fetch_models().await {
+                    Ok(models) => reply_text(event, messages::api_verified(models.len())),
+                    Err(e) => reply_text(event, messages::fetch_models_failed(e)),
+                }
+            }
+
+            Action::Chat => {
+                if request_approval(&cmd, &prompt, &imgs, event, mgr, bot).await {
+                    return;
+                }
+                chat(name, &prompt, imgs, false, false, &cmd, event, mgr, bot).await;
+            }
+
+            Action::ImageGen(ref desc) => {
+                if request_approval(&cmd, desc, &[], event, mgr, bot).await {
+                    return;
+                }
+                chat(name, desc, vec![], false, false, &cmd, event, mgr, bot).await;
+            }
+
+            Action::Translate(ref lang) => {
+                let content = {
+                    let c = mgr.config.read().await;
+                    let Some(a) = c.agents.iter().find(|a| a.name == *name) else {
+                        reply_text(event, messages::not_found(name));
+                        return;
+                    };
+                    let key = owner_key(event, cmd.private_reply);
+                    a.history(cmd.private_reply, &key)
+                        .iter()
+                        .rev()
+                        .find(|m| m.role == "assistant" && !m.content.trim().is_empty())
+                        .map(|m| m.content.clone())
+                };
+                let Some(content) = content else {
+                    reply_text(event, Msg::NothingToTranslate);
+                    return;
+                };
+                let prompt = format!(
+                    "Translate the following to {}. Output only the translation.\n\n{}",
+                    language_name(lang),
+                    content
+                );
+                if request_approval(&cmd, &prompt, &[], event, mgr, bot).await {
+                    return;
+                }
+                chat(name, &prompt, vec![], false, true, &cmd, event, mgr, bot).await;
+            }
+
+            Action::Relay(ref rest) => {
+                let chain: Vec<String> = std::iter::once(name.clone())
+                    .chain(rest.iter().cloned())
+                    .collect();
+                {
+                    let c = mgr.config.read().await;
+                    for n in &chain {
+                        match c.agents.iter().find(|a| a.name == *n) {
+                            None => {
+                                reply_text(event, messages::not_found(n));
+                                return;
+                            }
+                            Some(a)
+                                if a.require_approval
+                                    && !a.approvers.is_empty()
+                                    && !a.approvers.contains(&event.user_id) =>
+                            {
+                                reply_text(event, messages::relay_needs_approval(n));
+                                return;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                if prompt.is_empty() && imgs.is_empty() {
+                    reply_text(event, Msg::EmptyInput);
+                    return;
+                }
+
+                // 依次回复，后一个智能体以前一个的回答作为输入前缀；任一失败即中断
+                let mut input = prompt.clone();
+                for (i, n) in chain.iter().enumerate() {
+                    let step_imgs = if i == 0 { imgs.clone() } else { vec![] };
+                    let Some(answer) =
+                        chat(n, &input, step_imgs, false, false, &cmd, event, mgr, bot).await
+                    else {
+                        if i + 1 < chain.len() {
+                            reply_text(event, messages::relay_interrupted(n, &chain[i + 1..]));
+                        }
+                        return;
+                    };
+                    input = format!("「{}」的回答：\n{}\n\n{}", n, answer, prompt);
+                }
+            }
+
+            Action::Regenerate { .. } => {
+                if request_approval(&cmd, &cmd.args, &imgs, event, mgr, bot).await {
+                    return;
+                }
+                chat(name, &cmd.args, imgs, true, false, &cmd, event, mgr, bot).await;
+            }
+
+            Action::SetApprovers => {
+                let approvers: Vec<i64> = cmd
+                    .args
+                    .split(|c: char| !c.is_ascii_digit())
+                    .filter_map(|s| s.parse().ok())
+                    .collect();
+                let listed_admin = is_listed_admin(event, mgr, bot).await;
+                let mut c = mgr.config.write().await;
+                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
+                    // 已开启审批时，只有现有审批人或管理员可以修改
+                    if a.require_approval && !listed_admin && !a.approvers.contains(&event.user_id)
+                    {
+                        reply_text(event, Msg::ApproverOnly);
+                        return;
+                    }
+                    let list = approvers
+                        .iter()
+                        .map(|i| i.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    a.require_approval = !approvers.is_empty();
+                    a.approvers = approvers;
+                    mgr.save(&c);
+                    reply_text(event, messages::approvers_set(name, &list));
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
+
+            Action::SetRetention => {
+                let describe = |p: &RetentionPolicy| {
+                    messages::retention_policy(p.max_days, p.max_messages, p.archive)
+                };
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let args = cmd.args.trim();
+                if args.is_empty() {
+                    match &a.retention_policy {
+                        Some(p) => reply_text(event, format!("🗄️ {} {}", name, describe(p))),
+                        None => reply_text(event, messages::retention_unset(name)),
+                    }
+                    return;
+                }
+                if matches!(args, "off" | "关闭") {
+                    a.retention_policy = None;
+                    mgr.save(&c);
+                    reply_text(event, messages::retention_off(name));
+                    return;
+                }
+                let mut policy = RetentionPolicy::default();
+                let mut nums = Vec::new();
+                for t in args.split_whitespace() {
+                    if matches!(t, "归档" | "archive") {
+                        policy.archive = true;
+                    } else if let Ok(n) = t.trim_end_matches(['d', '天']).parse::<usize>() {
+                        nums.push(n);
+                    } else {
+                        reply_text(event, Msg::RetentionUsage);
+                        return;
+                    }
+                }
+                policy.max_days = nums.first().copied().unwrap_or(0) as u32;
+                policy.max_messages = nums.get(1).copied().unwrap_or(0);
+                let text = messages::retention_set(name, &describe(&policy));
+                a.retention_policy = Some(policy);
+                mgr.save(&c);
+                reply_text(event, text);
+            }
+
+            Action::ListPending => {
+                let c = mgr.config.read().await;
+                let pending = mgr.pending.read().await;
+                let mut items: Vec<String> = pending
+                    .iter()
+                    .filter(|(_, p)| {
+                        c.agents
+                            .iter()
+                            .find(|a| a.name == p.cmd.agent)
+                            .map(|a| a.approvers.contains(&event.user_id))
+                            .unwrap_or(false)
+                    })
+                    .map(|(id, p)| {
+                        let mins = (chrono::Local::now().timestamp() - p.created_at) / 60;
+                        messages::pending_item(
+                            *id,
+                            &p.cmd.agent,
+                            p.event.user_id,
+                            mins,
+                            &truncate_str(&p.prompt, 30),
+                        )
+                    })
+                    .collect();
+                if items.is_empty() {
+                    reply_text(event, Msg::NoPending);
+                    return;
+                }
+                items.sort();
+                reply_text(event, messages::pending_list(&items.join("\n")));
+            }
+
+            Action::Approve(id) | Action::Reject(id) => {
+                let approve = matches!(cmd.action, Action::Approve(_));
+                let agent = match mgr.pending.read().await.get(&id) {
+                    Some(p) => p.cmd.agent.clone(),
+                    None => {
+                        reply_text(event, messages::approval_missing(id));
+                        return;
+                    }
+                };
+                let allowed = mgr
+                    .config
+                    .read()
+                    .await
+                    .agents
+                    .iter()
+                    .find(|a| a.name == agent)
+                    .map(|a| a.approvers.contains(&event.user_id))
+                    .unwrap_or(false);
+                if !allowed {
+                    reply_text(event, Msg::NotApprover);
+                    return;
+                }
+                // 二次确认仍在队列中，避免与超时任务竞争
+                let req = match mgr.pending.write().await.remove(&id) {
+                    Some(r) => r,
+                    None => {
+                        reply_text(event, messages::approval_missing(id));
+                        return;
+                    }
+                };
+
+                if !approve {
+                    reply_text(event, messages::rejected(id));
+                    reply_text(&req.event, messages::request_rejected(id));
+                    return;
+                }
+
+                reply_text(event, messages::approved(id));
+                reply_text(&req.event, messages::request_approved(id));
+                let regen = matches!(req.cmd.action, Action::Regenerate { .. });
+                let transient = matches!(req.cmd.action, Action::Translate(_));
+                chat(
+                    &req.cmd.agent,
+                    &req.prompt,
+                    req.imgs.clone(),
+                    regen,
+                    transient,
+                    &req.cmd,
+                    &req.event,
+                    mgr,
+                    bot,
+                )
+                .await;
+            }
+
+            Action::Stop => {
+                let is_priv_ctx = cmd.private_reply;
+                {
+                    let mut generating = mgr.generating.write().await;
+                    generating.set_generating(name, is_priv_ctx, &owner(is_priv_ctx), false);
+                }
+                let mut c = mgr.config.write().await;
+                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
+                    a.bump_generation(is_priv_ctx, &owner(is_priv_ctx));
+                    mgr.save(&c);
+                    reply_text(event, Msg::Stopped);
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
+
+            Action::Copy { with_history } => {
+                if cmd.args.is_empty() {
+                    reply_text(event, Msg::CopyUsage);
+                    return;
+                }
+
+                if !valid_agent_name(&cmd.args) {
+                    reply_text(event, Msg::InvalidName);
                     return;
                 }
-            };
 
-            if api.0.is_empty() || api.1.is_empty() {
-                reply_text(ctx.event, "❌ API 未配置");
-                return;
+                let mut c = mgr.config.write().await;
+                if c.agents.iter().any(|a| a.name == cmd.args) {
+                    reply_text(event, messages::already_exists(&cmd.args));
+                    return;
+                }
+                if let Some(src) = c.agents.iter().find(|a| a.name == *name).cloned() {
+                    let mut new_agent = src.duplicate(&cmd.args);
+                    new_agent.created_by = uid.clone();
+                    let is_priv_ctx = cmd.private_reply;
+                    let src_hist = src.history(is_priv_ctx, &owner(is_priv_ctx));
+                    if !cmd.indices.is_empty() {
+                        // 分叉：仅复制选定区间的历史
+                        let picked: Vec<ChatMessage> = cmd
+                            .indices
+                            .iter()
+                            .filter(|&&i| i > 0 && i <= src_hist.len())
+                            .map(|&i| src_hist[i - 1].clone())
+                            .collect();
+                        if picked.is_empty() {
+                            reply_text(event, Msg::InvalidIndex);
+                            return;
+                        }
+                        *new_agent.history_mut(is_priv_ctx, &owner(is_priv_ctx)) = picked;
+                    } else if with_history {
+                        // 整体复制只带当前群的公有历史，私有历史不外传
+                        *new_agent.history_mut(false, &bucket) =
+                            src.history(false, &bucket).to_vec();
+                        new_agent.topic = src.topic.clone();
+                    }
+                    let cnt = if with_history {
+                        new_agent.history(false, &bucket).len()
+                    } else {
+                        new_agent.history(is_priv_ctx, &owner(is_priv_ctx)).len()
+                    };
+                    c.agents.push(new_agent);
+                    mgr.save(&c);
+                    if !cmd.indices.is_empty() {
+                        reply_text(event, messages::forked(name, cnt, &cmd.args));
+                    } else if with_history {
+                        reply_text(event, messages::copied(name, &cmd.args, Some(cnt)));
+                    } else {
+                        reply_text(event, messages::copied(name, &cmd.args, None));
+                    }
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
             }
 
-            match ctx
-                .bot
-                .set_msg_emoji_like(ctx.event.message_id.into(), "124")
-                .await
-            {
-                Ok(_) => {}
-                Err(e) => {
-                    kovi::log::error!("点赞失败: {:?}", e);
+            Action::Rename => {
+                if cmd.args.is_empty() {
+                    reply_text(event, Msg::RenameUsage);
+                    return;
+                }
+
+                if !valid_agent_name(&cmd.args) {
+                    reply_text(event, Msg::InvalidName);
+                    return;
+                }
+
+                let mut c = mgr.config.write().await;
+                if c.agents.iter().any(|a| a.name == cmd.args) {
+                    reply_text(event, messages::target_exists(&cmd.args));
+                    return;
+                }
+
+                // 先找要重命名的智能体的索引
+                let idx_opt = c.agents.iter().position(|a| a.name == *name);
+                if let Some(idx) = idx_opt {
+                    c.agents[idx].name = cmd.args.clone();
+                    mgr.save(&c);
+                    mgr.rename_snapshots(name, &cmd.args).await;
+                    reply_text(event, messages::renamed(name, &cmd.args));
+                } else {
+                    reply_text(event, messages::not_found(name));
                 }
             }
 
-            // 临时模式下不加载历史，创建一个空历史用于本次构建消息
-            let mut hist = if temp_mode {
-                Vec::new()
-            } else {
-                agent.history(is_priv_ctx, &uid).to_vec()
-            };
+            Action::SetDesc => {
+                if cmd.args.is_empty() {
+                    reply_text(event, Msg::DescUsage);
+                    return;
+                }
+                let mut c = mgr.config.write().await;
+                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
+                    a.description = cmd.args.clone();
+                    mgr.save(&c);
+                    reply_text(event, messages::desc_updated(name));
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
 
-            if ctx.regen {
-                if hist.last().map(|m| m.role == "assistant").unwrap_or(false) {
-                    hist.pop();
+            Action::SetModel => {
+                if cmd.args.is_empty() {
+                    reply_text(event, Msg::ModelUsage);
+                    return;
                 }
-                if !ctx.prompt.is_empty() {
-                    if hist.last().map(|m| m.role == "user").unwrap_or(false) {
-                        hist.pop();
+                let mut c = mgr.config.write().await;
+                if let Some(model) = mgr.resolve_model(&cmd.args, &c) {
+                    if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
+                        let old = a.model.clone();
+                        a.model = model.clone();
+                        mgr.save(&c);
+                        reply_text(event, messages::model_changed(name, &old, &model));
+                    } else {
+                        reply_text(event, messages::not_found(name));
                     }
-                    hist.push(ChatMessage::new("user", ctx.prompt, ctx.imgs.clone()));
+                } else {
+                    reply_text(event, Msg::InvalidModel);
                 }
-            } else {
-                if ctx.prompt.is_empty() && ctx.imgs.is_empty() {
-                    reply_text(ctx.event, "💬 请输入内容");
+            }
+
+            Action::SetImagePref { quality } => {
+                let value = (!cmd.args.is_empty()).then(|| cmd.args.clone());
+                if !quality
+                    && let Some(v) = &value
+                    && !v.contains(['x', 'X', '*', ':'])
+                {
+                    reply_text(event, Msg::ImageSizeUsage);
+                    return;
+                }
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
                     return;
+                };
+                if quality {
+                    a.image_quality = value.clone();
+                } else {
+                    a.image_size = value.clone();
                 }
-                hist.push(ChatMessage::new("user", ctx.prompt, ctx.imgs.clone()));
+                let image_model = is_image_model(&a.model);
+                mgr.save(&c);
+                reply_text(
+                    event,
+                    messages::image_pref(name, quality, value.as_deref(), image_model),
+                );
             }
 
-            // 临时模式不保存历史，也不更新 generation_id
-            let gen_id = if temp_mode {
-                0 // 临时 ID
-            } else {
-                let mut c = ctx.mgr.config.write().await;
-                if let Some(a) = c.agents.iter_mut().find(|a| a.name == ctx.name) {
-                    *a.history_mut(is_priv_ctx, &uid) = hist.clone();
-                    a.generation_id += 1;
-                    let id = a.generation_id;
-                    ctx.mgr.save(&c);
-                    id
+            Action::SetSeed => {
+                let seed = if cmd.args.is_empty() {
+                    None
                 } else {
+                    match cmd.args.parse::<i64>() {
+                        Ok(v) => Some(v),
+                        Err(_) => {
+                            reply_text(event, Msg::SeedUsage);
+                            return;
+                        }
+                    }
+                };
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
                     return;
+                };
+                a.seed = seed;
+                mgr.save(&c);
+                reply_text(event, messages::seed_set(name, seed));
+            }
+
+            Action::SetStop => {
+                let mut stop = parse_stop_list(&cmd.args);
+                let truncated = stop.len() > MAX_STOP;
+                if truncated {
+                    kovi::log::warn!(
+                        "{} 停止词 {} 个，超过上限 {}，已截断",
+                        name,
+                        stop.len(),
+                        MAX_STOP
+                    );
+                    stop.truncate(MAX_STOP);
                 }
-            };
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                a.stop = stop;
+                let display = a
+                    .stop
+                    .iter()
+                    .map(|s| escape_stop(s))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                mgr.save(&c);
+                let max = truncated.then_some(MAX_STOP);
+                reply_text(event, messages::stop_set(name, &display, max));
+            }
 
-            // 临时模式不设置生成锁，避免阻塞主对话
-            if !temp_mode {
-                let mut generating = ctx.mgr.generating.write().await;
-                generating.set_generating(ctx.name, is_priv_ctx, &uid, true);
+            Action::SetTimeout => {
+                let mut c = mgr.config.write().await;
+                let global = c.timeout_secs;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let Some(value) = cmd.args.strip_prefix('=') else {
+                    let current = match a.timeout_secs {
+                        Some(t) => messages::timeout_value(t, false),
+                        None => messages::timeout_value(global, true),
+                    };
+                    reply_text(event, messages::timeout_is(name, &current));
+                    return;
+                };
+                let value = value.trim();
+                if value.is_empty() {
+                    a.timeout_secs = None;
+                    mgr.save(&c);
+                    reply_text(event, messages::timeout_reset(name, global));
+                    return;
+                }
+                let Ok(secs) = value.parse::<u64>() else {
+                    reply_text(event, Msg::TimeoutUsage);
+                    return;
+                };
+                a.timeout_secs = Some(secs);
+                mgr.save(&c);
+                reply_text(
+                    event,
+                    messages::timeout_is(name, &messages::timeout_value(secs, false)),
+                );
             }
 
-            let client =
-                Client::with_config(OpenAIConfig::new().with_api_base(api.0).with_api_key(api.1));
+            Action::SetOption(key) if key == "maxprivate" => {
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let Some(value) = cmd.args.strip_prefix('=') else {
+                    let current = Some(a.private_histories.len());
+                    reply_text(
+                        event,
+                        messages::max_private(name, a.max_private_users, current),
+                    );
+                    return;
+                };
+                let value = value.trim();
+                if value.is_empty() {
+                    a.max_private_users = None;
+                    mgr.save(&c);
+                    reply_text(event, messages::max_private_cleared(name));
+                    return;
+                }
+                let Ok(max) = value.parse::<usize>() else {
+                    reply_text(event, Msg::MaxPrivateUsage);
+                    return;
+                };
+                a.max_private_users = Some(max);
+                mgr.save(&c);
+                reply_text(event, messages::max_private(name, Some(max), None));
+            }
 
-            let mut msgs: Vec<ChatCompletionRequestMessage> = vec![];
+            Action::SetOption(key) => {
+                if key != "budget" {
+                    reply_text(event, messages::unknown_option(&key));
+                    return;
+                }
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let Some(value) = cmd.args.strip_prefix('=') else {
+                    reply_text(event, messages::budget_is(name, a.token_budget));
+                    return;
+                };
+                let value = value.trim();
+                if value.is_empty() {
+                    a.token_budget = None;
+                    mgr.save(&c);
+                    reply_text(event, messages::budget_cleared(name));
+                    return;
+                }
+                let Ok(budget) = value.parse::<u32>() else {
+                    reply_text(event, Msg::BudgetUsage);
+                    return;
+                };
+                a.token_budget = Some(budget);
+                mgr.save(&c);
+                reply_text(event, messages::budget_is(name, Some(budget)));
+            }
 
-            if !agent.system_prompt.is_empty() {
-                msgs.push(
-                    ChatCompletionRequestSystemMessageArgs::default()
-                        .content(agent.system_prompt.clone())
-                        .build()
-                        .unwrap()
-                        .into(),
-                );
+            // &智能体$设定：仅覆盖自己私有会话的提示词，留空恢复默认
+            Action::SetPrompt if cmd.private_reply => {
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                if cmd.args.is_empty() {
+                    a.private_prompts.remove(&uid);
+                    mgr.save(&c);
+                    reply_text(event, messages::private_prompt_set(name, false));
+                } else {
+                    a.private_prompts.insert(uid.clone(), cmd.args.clone());
+                    mgr.save(&c);
+                    reply_text(event, messages::private_prompt_set(name, true));
+                }
             }
-            let re = Regex::new(r"!\[.*?\]\((data:image/[^\s\)]+)\)").unwrap();
-            for m in &hist {
-                if m.role == "user" {
-                    let mut parts = Vec::new();
-                    if !m.content.is_empty() {
-                        parts.push(
-                            ChatCompletionRequestMessageContentPartTextArgs::default()
-                                .text(m.content.clone())
-                                .build()
-                                .unwrap()
-                                .into(),
-                        );
+
+            Action::SetPrompt => {
+                let mut c = mgr.config.write().await;
+                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
+                    a.system_prompt = cmd.args.clone();
+                    mgr.save(&c);
+                    reply_text(event, messages::prompt_set(name, !cmd.args.is_empty()));
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
+
+            Action::ViewPrompt => {
+                let c = mgr.config.read().await;
+                if let Some(a) = c.agents.iter().find(|a| a.name == *name) {
+                    // 私有上下文显示当前生效的提示词
+                    let private_prompt = a.private_prompts.get(&uid).filter(|_| cmd.private_reply);
+                    let system_prompt = private_prompt.unwrap_or(&a.system_prompt);
+                    let stop_display = a
+                        .stop
+                        .iter()
+                        .map(|s| escape_stop(s))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    if cmd.text_mode {
+                        let mut text = system_prompt.clone();
+                        if !stop_display.is_empty() {
+                            text.push_str(&format!(
+                                "\n\n{}: {}",
+                                Msg::LabelStop.text(),
+                                stop_display
+                            ));
+                        }
+                        if let Some(seed) = a.seed {
+                            text.push_str(&format!("\nSeed: {}", seed));
+                        }
+                        if let Some(size) = &a.image_size {
+                            text.push_str(&format!("\n{}: {}", Msg::LabelImageSize.text(), size));
+                        }
+                        if let Some(quality) = &a.image_quality {
+                            text.push_str(&format!(
+                                "\n{}: {}",
+                                Msg::LabelImageQuality.text(),
+                                quality
+                            ));
+                        }
+                        reply_text(event, text);
+                        return;
                     }
-                    for url in &m.images {
-                        parts.push(
-                            ChatCompletionRequestMessageContentPartImageArgs::default()
-                                .image_url(ImageUrlArgs::default().url(url).build().unwrap())
-                                .build()
-                                .unwrap()
-                                .into(),
-                        );
+                    let prompt_display = if system_prompt.is_empty() {
+                        Msg::Empty.text().to_string()
+                    } else {
+                        escape_markdown_special(system_prompt)
+                    };
+                    let owner_display = if a.created_by.is_empty() {
+                        String::new()
+                    } else {
+                        format!("**{}**: `{}`\n\n", Msg::LabelCreator.text(), a.created_by)
+                    };
+                    let mut stop_line = if stop_display.is_empty() {
+                        String::new()
+                    } else {
+                        format!("**{}**: `{}`\n\n", Msg::LabelStop.text(), stop_display)
+                    };
+                    if let Some(seed) = a.seed {
+                        stop_line.push_str(&format!("**Seed**: `{}`\n\n", seed));
                     }
-                    if parts.is_empty() {
-                        continue;
+                    if let Some(size) = &a.image_size {
+                        stop_line.push_str(&format!(
+                            "**{}**: `{}`\n\n",
+                            Msg::LabelImageSize.text(),
+                            size
+                        ));
                     }
-                    msgs.push(
-                        ChatCompletionRequestUserMessageArgs::default()
-                            .content(parts)
-                            .build()
-                            .unwrap()
-                            .into(),
+                    if let Some(quality) = &a.image_quality {
+                        stop_line.push_str(&format!(
+                            "**{}**: `{}`\n\n",
+                            Msg::LabelImageQuality.text(),
+                            quality
+                        ));
+                    }
+                    let content = format!(
+                        "**{}**: `{}`\n\n{}{}**{}**:\n```\n{}\n```",
+                        Msg::LabelModel.text(),
+                        a.model,
+                        owner_display,
+                        stop_line,
+                        Msg::LabelPrompt.text(),
+                        prompt_display
                     );
-                } else if m.role == "assistant" {
-                    let clean_content = re.replace_all(&m.content, "[Image Created]").to_string();
+                    reply(
+                        event,
+                        &content,
+                        cmd.text_mode,
+                        &format!(
+                            "{} {}",
+                            a.name,
+                            if private_prompt.is_some() {
+                                Msg::PrivatePrompt.text()
+                            } else {
+                                Msg::SystemPrompt.text()
+                            }
+                        ),
+                    )
+                    .await;
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
 
-                    msgs.push(
-                        ChatCompletionRequestAssistantMessageArgs::default()
-                            .content(clean_content)
-                            .build()
-                            .unwrap()
-                            .into(),
-                    );
+            Action::ListPrivateUsers => {
+                let c = mgr.config.read().await;
+                let Some(a) = c.agents.iter().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let mut users: Vec<(&String, &Vec<ChatMessage>)> = a
+                    .private_histories
+                    .iter()
+                    .filter(|(_, h)| !h.is_empty())
+                    .collect();
+                if users.is_empty() {
+                    reply_text(event, messages::no_private_sessions(name));
+                    return;
+                }
+                // 最近活跃的排在前面
+                users.sort_by_key(|(_, h)| std::cmp::Reverse(h.last().map(|m| m.timestamp)));
+                let show_uid = |uid: &str| match &c.uid_hash_salt {
+                    Some(salt) => hash_uid(uid, salt),
+                    None => uid.to_string(),
+                };
+                let fmt_time = |h: &[ChatMessage]| {
+                    h.last()
+                        .and_then(|m| chrono::DateTime::from_timestamp(m.timestamp, 0))
+                        .map(|t| {
+                            t.with_timezone(&chrono::Local)
+                                .format("%m-%d %H:%M")
+                                .to_string()
+                        })
+                        .unwrap_or_default()
+                };
+                let title = messages::private_sessions_title(name, users.len());
+
+                if cmd.text_mode {
+                    let mut text = format!("👥 {}", title);
+                    for (i, (uid, h)) in users.iter().enumerate() {
+                        text.push_str(&messages::private_session_line(
+                            i + 1,
+                            &show_uid(uid),
+                            h.len(),
+                            &fmt_time(h),
+                        ));
+                    }
+                    reply_text(event, text);
+                    return;
+                }
+                let rows = users
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (uid, h))| {
+                        format!(
+                            "<tr><td>{}</td><td><code>{}</code></td><td>{}</td><td>{}</td></tr>",
+                            i + 1,
+                            escape_html(&show_uid(uid)),
+                            h.len(),
+                            fmt_time(h)
+                        )
+                    })
+                    .collect::<String>();
+                let table = format!(
+                    "<table><tr><th>#</th>{}</tr>{}</table>",
+                    Msg::PrivateUsersHead.text(),
+                    rows
+                );
+                reply(event, &table, false, &title).await;
+            }
 
-                    let gen_imgs = extract_image_urls(&m.content);
-                    if !gen_imgs.is_empty() {
-                        let mut img_parts = Vec::new();
-                        for url in gen_imgs {
-                            img_parts.push(
-                                ChatCompletionRequestMessageContentPartImageArgs::default()
-                                    .image_url(ImageUrlArgs::default().url(url).build().unwrap())
-                                    .build()
-                                    .unwrap()
-                                    .into(),
-                            );
-                        }
-                        msgs.push(
-                            ChatCompletionRequestUserMessageArgs::default()
-                                .content(img_parts)
-                                .build()
-                                .unwrap()
-                                .into(),
-                        );
+            Action::ViewDetail => {
+                let c = mgr.config.read().await;
+                let Some(a) = c.agents.iter().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let fmt_time = |ts: i64| {
+                    chrono::DateTime::from_timestamp(ts, 0)
+                        .map(|t| {
+                            t.with_timezone(&chrono::Local)
+                                .format("%Y-%m-%d %H:%M")
+                                .to_string()
+                        })
+                        .unwrap_or_else(|| Msg::Unknown.text().to_string())
+                };
+                let last_active = a
+                    .public_histories
+                    .values()
+                    .chain(a.private_histories.values())
+                    .filter_map(|h| h.last().map(|m| m.timestamp))
+                    .max();
+                let public_count: usize = a.public_histories.values().map(|h| h.len()).sum();
+                let private_users = a
+                    .private_histories
+                    .values()
+                    .filter(|h| !h.is_empty())
+                    .count();
+                let timeout = match a.timeout_secs {
+                    Some(t) => messages::timeout_value(t, false),
+                    None => messages::timeout_value(c.timeout_secs, true),
+                };
+                // 剩余预算按当前会话历史估算
+                let budget = match a.token_budget {
+                    Some(b) => {
+                        let hist =
+                            a.history(cmd.private_reply, &owner_key(event, cmd.private_reply));
+                        let used = input_tokens_estimate(a, hist);
+                        messages::budget_detail(b, used)
                     }
+                    None => Msg::Unlimited.text().to_string(),
+                };
+                let max_history = match &a.retention_policy {
+                    Some(p) if p.max_messages > 0 => messages::message_count(p.max_messages),
+                    _ => Msg::Unlimited.text().to_string(),
+                };
+                let tools = if a.tools.is_empty() {
+                    Msg::Nothing.text().to_string()
+                } else {
+                    a.tools
+                        .iter()
+                        .map(|t| t.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+
+                let mut rows = vec![
+                    (Msg::LabelModel.text(), a.model.clone()),
+                    (
+                        Msg::LabelDesc.text(),
+                        if a.description.is_empty() {
+                            Msg::Nothing.text().to_string()
+                        } else {
+                            a.description.clone()
+                        },
+                    ),
+                    (
+                        Msg::LabelPrompt.text(),
+                        if a.system_prompt.is_empty() {
+                            Msg::Empty.text().to_string()
+                        } else {
+                            truncate_str(&a.system_prompt, 80)
+                        },
+                    ),
+                    (Msg::LabelCreatedAt.text(), fmt_time(a.created_at)),
+                    (
+                        Msg::LabelLastActive.text(),
+                        last_active.map_or_else(|| Msg::NoChats.text().to_string(), fmt_time),
+                    ),
+                    (
+                        Msg::LabelPublicHistory.text(),
+                        messages::message_count(public_count),
+                    ),
+                    (
+                        Msg::LabelPrivateUsers.text(),
+                        messages::user_count(private_users, a.max_private_users),
+                    ),
+                    (
+                        Msg::LabelGenId.text(),
+                        a.session_generation(
+                            cmd.private_reply,
+                            &owner_key(event, cmd.private_reply),
+                        )
+                        .to_string(),
+                    ),
+                    (Msg::LabelTimeout.text(), timeout),
+                    (Msg::LabelBudget.text(), budget),
+                    (Msg::LabelMaxHistory.text(), max_history),
+                    (Msg::LabelTools.text(), tools),
+                ];
+                if !a.created_by.is_empty() {
+                    rows.insert(1, (Msg::LabelCreator.text(), a.created_by.clone()));
                 }
-            }
 
-            let req = match CreateChatCompletionRequestArgs::default()
-                .model(&agent.model)
-                .messages(msgs)
-                .build()
-            {
-                Ok(r) => r,
-                Err(e) => {
-                    if !temp_mode {
-                        let mut generating = ctx.mgr.generating.write().await;
-                        generating.set_generating(ctx.name, is_priv_ctx, &uid, false);
+                if cmd.text_mode {
+                    let mut text = format!("📇 {}", a.name);
+                    for (k, v) in &rows {
+                        text.push_str(&format!("\n{}: {}", k, v));
                     }
-                    reply_text(ctx.event, format!("❌ 请求构建失败: {}", e));
+                    reply_text(event, text);
                     return;
                 }
-            };
+                let info = rows
+                    .iter()
+                    .map(|(k, v)| format!("<b>{}</b>：<code>{}</code>", k, escape_html(v)))
+                    .collect::<Vec<_>>()
+                    .join("<br>");
+                let card = format!(
+                    r#"<div class="agent-card"><div class="agent-name">🤖 {}</div><div class="agent-info">{}</div></div>"#,
+                    escape_html(&a.name),
+                    info
+                );
+                reply(event, &card, false, &messages::detail_title(&a.name)).await;
+            }
 
-            match kovi::tokio::time::timeout(
-                std::time::Duration::from_secs(300),
-                client.chat().create(req),
-            )
-            .await
-            {
-                // 超时
-                Err(_) => {
-                    if !temp_mode {
-                        let mut generating = ctx.mgr.generating.write().await;
-                        generating.set_generating(ctx.name, is_priv_ctx, &uid, false);
-                    }
-                    reply_text(
-                        ctx.event,
-                        "⏳ 请求超时：模型响应时间超过 5 分钟，已强制停止。",
-                    );
+            Action::List => {
+                let c = mgr.config.read().await;
+                if c.agents.is_empty() {
+                    reply_text(event, Msg::NoAgents);
+                    return;
                 }
-                // 完成
-                Ok(result) => match result {
-                    Ok(res) => {
-                        if !temp_mode {
-                            let mut generating = ctx.mgr.generating.write().await;
-                            generating.set_generating(ctx.name, is_priv_ctx, &uid, false);
-                        }
-
-                        // 非临时模式下检查 ID 是否变更（是否被手动停止）
-                        if !temp_mode {
-                            let c = ctx.mgr.config.read().await;
-                            if let Some(a) = c.agents.iter().find(|a| a.name == ctx.name)
-                                && a.generation_id != gen_id
-                            {
-                                return;
-                            }
-                        }
-
-                        if let Some(choice) = res.choices.first()
-                            && let Some(content) = &choice.message.content
-                        {
-                            let msg_index = if temp_mode {
-                                0
-                            } else {
-                                let c = ctx.mgr.config.read().await;
-                                if let Some(a) = c.agents.iter().find(|a| a.name == ctx.name) {
-                                    a.history(is_priv_ctx, &uid).len() + 1
-                                } else {
-                                    0
-                                }
-                            };
 
-                            // 临时模式不保存回复到历史
-                            if !temp_mode {
-                                let mut c = ctx.mgr.config.write().await;
-                                if let Some(a) = c.agents.iter_mut().find(|a| a.name == ctx.name) {
-                                    a.history_mut(is_priv_ctx, &uid).push(ChatMessage::new(
-                                        "assistant",
-                                        content,
-                                        vec![],
-                                    ));
-                                }
-                                ctx.mgr.save(&c);
-                            }
+                // 分组逻辑：使用 BTreeMap 自动按模型名称排序
+                use std::collections::BTreeMap;
+                let mut groups: BTreeMap<String, Vec<(usize, &Agent)>> = BTreeMap::new();
 
-                            let image_urls = extract_image_urls(content);
+                // 遍历并分组 (保留原始索引 i+1 以便用户操作)
+                for (i, a) in c.agents.iter().enumerate() {
+                    groups.entry(a.model.clone()).or_default().push((i + 1, a));
+                }
 
-                            let header = if temp_mode {
-                                format!("{} (临时会话)", agent.name)
-                            } else {
-                                format!(
-                                    "{} #{}回复{}",
-                                    agent.name,
-                                    msg_index,
-                                    if ctx.cmd.private_reply {
-                                        " (私有)"
-                                    } else {
-                                        ""
-                                    }
-                                )
-                            };
+                // 生成 HTML
+                let mut html_parts = Vec::new();
 
-                            let display_content = if !image_urls.is_empty() && !ctx.cmd.text_mode {
-                                let urls_text = image_urls
-                                    .iter()
-                                    .map(|u| {
-                                        if u.starts_with("data:") {
-                                            "- [Base64 Image]".to_string()
-                                        } else {
-                                            format!("- {}", u)
-                                        }
-                                    })
-                                    .collect::<Vec<_>>()
-                                    .join("\n");
-                                format!("{}\n\n---\n**图片链接:**\n{}", content, urls_text)
-                            } else {
-                                content.clone()
-                            };
+                // 遍历每一个模型分组
+                for (model, mut agents) in groups {
+                    // 组内按智能体名称排序；手动调整过顺序时保持 agents 中的顺序
+                    if !c.custom_agent_order {
+                        agents.sort_by_key(|a| a.1.name.to_lowercase());
+                    }
 
-                            let reply_text_content = if ctx.cmd.text_mode && !image_urls.is_empty()
-                            {
-                                // 使用与 extract_image_urls 相同的逻辑替换
-                                let re =
-                                    Regex::new(r"!\[.*?\]\(((?:https?://|data:image/)[^\s\)]+)\)")
-                                        .unwrap();
-                                re.replace_all(content, |caps: &regex::Captures| {
-                                    let url = &caps[1];
-                                    if url.starts_with("data:") {
-                                        "[图片]".to_string()
-                                    } else {
-                                        url.to_string()
-                                    }
-                                })
-                                .to_string()
-                            } else {
-                                display_content.clone()
-                            };
+                    // 组头
+                    html_parts.push(format!(
+                                              r#"<div class="model-group"><div class="model-header"><span>📦 {}</span><span class="model-count">{}</span></div><div class="agent-grid">"#,
+                                              model, agents.len()
+                                          ));
 
-                            reply(ctx.event, &reply_text_content, ctx.cmd.text_mode, &header).await;
+                    // 组内网格
+                    for (real_idx, a) in agents {
+                        // 逻辑：优先显示话题，其次描述；都没有时截取系统提示词的前 20 个字作为预览；
+                        let desc_display = if let Some(topic) = &a.topic {
+                            format!("💬 {}", topic)
+                        } else if !a.description.is_empty() {
+                            truncate_str(&a.description, 20)
+                        } else if !a.system_prompt.is_empty() {
+                            truncate_str(&a.system_prompt, 20)
+                        } else {
+                            Msg::NoDescription.text().to_string()
+                        };
 
-                            for url in &image_urls {
-                                if url.starts_with("data:") {
-                                    if let Some(base64_data) = url.split(',').nth(1) {
-                                        ctx.event.reply(
-                                            Message::new()
-                                                .add_image(&format!("base64://{}", base64_data)),
-                                        );
-                                    }
-                                } else {
-                                    ctx.event.reply(Message::new().add_image(url));
-                                }
-                            }
+                        let mut owner_display = if a.created_by.is_empty() {
+                            String::new()
+                        } else {
+                            format!(r#"<div class="agent-mini-owner">👤 {}</div>"#, a.created_by)
+                        };
+                        owner_display.push_str(&format!(
+                            r#"<div class="agent-mini-owner">🕒 {}</div>"#,
+                            time_ago(a.last_used_at)
+                        ));
 
-                            let video_urls = extract_video_urls(content);
-                            for url in video_urls {
-                                // 使用 OneBot 标准 video 段发送，data 放 file 字段，框架会自动处理下载/转发
-                                let mut vec = Vec::new();
-                                let segment = kovi::bot::message::Segment::new(
-                                    "video",
-                                    kovi::serde_json::json!({
-                                        "file": url
-                                    }),
-                                );
-                                vec.push(segment);
-                                let msg = kovi::bot::message::Message::from(vec);
-                                ctx.event.reply(msg);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        {
-                            let mut generating = ctx.mgr.generating.write().await;
-                            generating.set_generating(ctx.name, is_priv_ctx, &uid, false);
-                        }
-                        reply_text(ctx.event, format!("❌ API错误: {}", e));
+                        html_parts.push(format!(
+                                            r#"<div class="agent-mini"><div class="agent-mini-top"><div class="agent-idx">{}</div><div class="agent-mini-name">{}</div></div><div class="agent-mini-desc">{}</div>{}</div>"#,
+                                            real_idx, a.name, desc_display, owner_display
+                                        ));
                     }
-                },
-            }
-        }
-
-        inner(ChatContext {
-            name,
-            prompt,
-            imgs,
-            regen,
-            cmd,
-            event,
-            mgr,
-            bot,
-        })
-        .await;
-    }
-
-    pub async fn execute(
-        cmd: Command,
-        prompt: String,
-        imgs: Vec<String>,
-        event: &Arc<kovi::MsgEvent>,
-        mgr: &Arc<Manager>,
-        bot: &Arc<kovi::RuntimeBot>,
-    ) {
-        let name = &cmd.agent;
-        let uid = event.user_id.to_string();
-
-        match cmd.action {
-            Action::UpdateApi(url, key) => {
-                let mut c = mgr.config.write().await;
-                c.api_base = url.clone();
-                c.api_key = key;
-                mgr.save(&c);
-                drop(c);
+                    html_parts.push("</div></div>".to_string());
+                }
 
-                reply_text(event, format!("✅ API 已配置: {}", url));
+                let list = html_parts.join("\n");
 
-                match mgr.fetch_models().await {
-                    Ok(models) => reply_text(
-                        event,
-                        format!("📋 验证成功，已获取 {} 个模型", models.len()),
-                    ),
-                    Err(e) => reply_text(event, format!("⚠️ 获取模型失败: {}", e)),
-                }
+                reply(
+                    event,
+                    &list,
+                    cmd.text_mode,
+                    &messages::agent_list_title(c.agents.len()),
+                )
+                .await;
             }
 
-            Action::Chat => {
-                chat(name, &prompt, imgs, false, &cmd, event, mgr, bot).await;
-            }
+            Action::ListByUsage { by_calls } => {
+                let c = mgr.config.read().await;
+                if c.agents.is_empty() {
+                    reply_text(event, Msg::NoAgents);
+                    return;
+                }
+                let mut agents: Vec<(usize, &Agent)> = c.agents.iter().enumerate().collect();
+                if by_calls {
+                    agents.sort_by_key(|(_, a)| std::cmp::Reverse((a.call_count, a.last_used_at)));
+                } else {
+                    agents.sort_by_key(|(_, a)| std::cmp::Reverse((a.last_used_at, a.call_count)));
+                }
+                let title = if by_calls {
+                    Msg::TopAgentsTitle.text()
+                } else {
+                    Msg::RecentAgentsTitle.text()
+                };
 
-            Action::Regenerate => {
-                chat(name, &cmd.args, imgs, true, &cmd, event, mgr, bot).await;
+                if cmd.text_mode {
+                    let mut text = title.to_string();
+                    for (i, a) in &agents {
+                        text.push_str(&messages::usage_line(
+                            i + 1,
+                            &a.name,
+                            a.call_count,
+                            &time_ago(a.last_used_at),
+                        ));
+                    }
+                    reply_text(event, text);
+                    return;
+                }
+                let rows = agents
+                    .iter()
+                    .map(|(i, a)| {
+                        format!(
+                            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                            i + 1,
+                            escape_html(&a.name),
+                            a.call_count,
+                            time_ago(a.last_used_at)
+                        )
+                    })
+                    .collect::<String>();
+                let table = format!(
+                    "<table><tr><th>#</th>{}</tr>{}</table>",
+                    Msg::UsageHead.text(),
+                    rows
+                );
+                reply(event, &table, false, title).await;
             }
 
-            Action::Stop => {
-                let is_priv_ctx = cmd.private_reply;
-                {
-                    let mut generating = mgr.generating.write().await;
-                    generating.set_generating(name, is_priv_ctx, &uid, false);
-                }
+            Action::Delete => {
                 let mut c = mgr.config.write().await;
-                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
-                    a.generation_id += 1;
+                if let Some(idx) = c.agents.iter().position(|a| a.name == *name) {
+                    c.agents.remove(idx);
                     mgr.save(&c);
-                    reply_text(event, "🛑 已停止");
+                    drop(c);
+                    mgr.remove_snapshots(name).await;
+                    reply_text(event, messages::deleted_agent(name));
                 } else {
-                    reply_text(event, format!("❌ 智能体 {} 不存在", name));
+                    reply_text(event, messages::not_found(name));
                 }
             }
 
-            Action::Copy => {
-                if cmd.args.is_empty() {
-                    reply_text(event, "❌ 请指定新名称: 智能体~#新名称");
+            Action::SetSystemImage => {
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let arg = cmd.args.trim();
+                if arg == "-" {
+                    a.system_images.clear();
+                    mgr.save(&c);
+                    reply_text(event, messages::system_images_cleared(name));
+                    return;
+                }
+                let mut added: Vec<String> = imgs;
+                if arg.starts_with("http://") || arg.starts_with("https://") {
+                    added.push(arg.to_string());
+                } else if !arg.is_empty() {
+                    reply_text(event, Msg::SystemImageUsage);
+                    return;
+                }
+                if added.is_empty() {
+                    if a.system_images.is_empty() {
+                        reply_text(event, messages::no_system_images(name));
+                    } else {
+                        let list = a
+                            .system_images
+                            .iter()
+                            .enumerate()
+                            .map(|(i, u)| format!("{}. {}", i + 1, truncate_str(u, 80)))
+                            .collect::<Vec<_>>()
+                            .join("\n");
+                        reply_text(event, messages::system_images(name, &list));
+                    }
                     return;
                 }
+                a.system_images.extend(added);
+                let cnt = a.system_images.len();
+                mgr.save(&c);
+                reply_text(event, messages::system_images_added(name, cnt));
+            }
 
-                if cmd.args.chars().count() > 7
-                    || cmd.args.chars().any(|c| "&\"#~/ -_'!@$%:*".contains(c))
+            Action::AddTool => {
+                let arg = cmd.args.trim();
+                let (head, schema) = match arg.find('{') {
+                    Some(i) => (arg[..i].trim(), arg[i..].trim()),
+                    None => (arg, ""),
+                };
+                let (tool_name, desc) = head
+                    .split_once(char::is_whitespace)
+                    .map(|(n, d)| (n, d.trim()))
+                    .unwrap_or((head, ""));
+                if tool_name.is_empty()
+                    || tool_name.chars().count() > 64
+                    || !tool_name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
                 {
-                    reply_text(event, "❌ 名称限制：最多7字且不能包含指令符号");
+                    reply_text(event, Msg::ToolNameUsage);
                     return;
                 }
+                let builtin = builtin_tool(tool_name);
+                let desc = match &builtin {
+                    Some(b) if desc.is_empty() => b.description.as_str(),
+                    _ => desc,
+                };
+                let parameters = if schema.is_empty() {
+                    match &builtin {
+                        Some(b) => b.parameters.clone(),
+                        None => serde_json::json!({"type": "object", "properties": {}}),
+                    }
+                } else {
+                    match serde_json::from_str::<serde_json::Value>(schema) {
+                        Ok(v) if v.is_object() => v,
+                        Ok(_) => {
+                            reply_text(event, Msg::SchemaNotObject);
+                            return;
+                        }
+                        Err(e) => {
+                            reply_text(event, messages::schema_parse_failed(e));
+                            return;
+                        }
+                    }
+                };
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let tool = AgentTool {
+                    name: tool_name.to_string(),
+                    description: desc.to_string(),
+                    parameters,
+                };
+                let updated = match a.tools.iter_mut().find(|t| t.name == tool.name) {
+                    Some(t) => {
+                        *t = tool;
+                        true
+                    }
+                    None => {
+                        a.tools.push(tool);
+                        false
+                    }
+                };
+                mgr.save(&c);
+                reply_text(event, messages::tool_saved(name, tool_name, updated));
+            }
 
+            Action::RemoveTool(tool_name) => {
                 let mut c = mgr.config.write().await;
-                if c.agents.iter().any(|a| a.name == cmd.args) {
-                    reply_text(event, format!("❌ {} 已存在", cmd.args));
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let before = a.tools.len();
+                a.tools.retain(|t| t.name != *tool_name);
+                if a.tools.len() == before {
+                    reply_text(event, messages::no_such_tool(name, &tool_name));
                     return;
                 }
-                if let Some(src) = c.agents.iter().find(|a| a.name == *name).cloned() {
-                    let mut new_agent = Agent::new(
-                        &cmd.args,
-                        &src.model,
-                        &src.system_prompt,
-                        &format!("复制自 {}", name),
-                    );
-                    new_agent.description = src.description.clone();
-                    c.agents.push(new_agent);
-                    mgr.save(&c);
-                    reply_text(event, format!("📑 已复制 {} → {}", name, cmd.args));
-                } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
-                }
+                mgr.save(&c);
+                reply_text(event, messages::tool_removed(name, &tool_name));
             }
 
-            Action::Rename => {
-                if cmd.args.is_empty() {
-                    reply_text(event, "❌ 请指定新名称: 智能体~=新名称");
+            Action::ListTools => {
+                let c = mgr.config.read().await;
+                let Some(a) = c.agents.iter().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                if a.tools.is_empty() {
+                    reply_text(event, messages::no_tools(name));
                     return;
                 }
+                let list = a
+                    .tools
+                    .iter()
+                    .map(|t| {
+                        format!(
+                            "• {} {}\n  {}",
+                            t.name,
+                            t.description,
+                            truncate_str(&t.parameters.to_string(), 120)
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                reply_text(event, messages::tools(name, &list));
+            }
 
-                if cmd.args.chars().count() > 7
-                    || cmd.args.chars().any(|c| "&\"#~/ -_'!@$%:*".contains(c))
-                {
-                    reply_text(event, "❌ 名称限制：最多7字且不能包含指令符号");
+            Action::DidYouMean(suggest) => {
+                reply_text(event, messages::did_you_mean(&suggest));
+            }
+
+            Action::AddBlockedKeyword(word) => {
+                if word.is_empty() {
+                    reply_text(event, Msg::KeywordUsage);
+                    return;
+                }
+                let mut c = mgr.config.write().await;
+                let lower = word.to_lowercase();
+                if c.blocked_keywords.iter().any(|k| k.to_lowercase() == lower) {
+                    reply_text(event, messages::keyword_exists(&word));
                     return;
                 }
+                c.blocked_keywords.push(word.clone());
+                mgr.save(&c);
+                reply_text(event, messages::keyword_added(&word));
+            }
 
+            Action::RemoveBlockedKeyword(word) => {
                 let mut c = mgr.config.write().await;
-                if c.agents.iter().any(|a| a.name == cmd.args) {
-                    reply_text(event, format!("❌ 目标名称 {} 已存在", cmd.args));
+                let lower = word.to_lowercase();
+                let before = c.blocked_keywords.len();
+                c.blocked_keywords.retain(|k| k.to_lowercase() != lower);
+                if c.blocked_keywords.len() == before {
+                    reply_text(event, messages::keyword_missing(&word));
                     return;
                 }
+                mgr.save(&c);
+                reply_text(event, messages::keyword_removed(&word));
+            }
 
-                // 先找要重命名的智能体的索引
-                let idx_opt = c.agents.iter().position(|a| a.name == *name);
-                if let Some(idx) = idx_opt {
-                    c.agents[idx].name = cmd.args.clone();
-                    mgr.save(&c);
-                    reply_text(event, format!("🏷️ 已重命名 {} → {}", name, cmd.args));
+            Action::ListBlockedKeywords => {
+                let c = mgr.config.read().await;
+                if c.blocked_keywords.is_empty() {
+                    reply_text(event, Msg::NoKeywords);
                 } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    reply_text(event, messages::keywords(&c.blocked_keywords.join("\n")));
                 }
             }
 
-            Action::SetDesc => {
-                if cmd.args.is_empty() {
-                    reply_text(event, "❌ 请提供描述: 智能体:描述内容");
+            Action::EditUserList { black, add, ids } => {
+                let parsed: Vec<i64> = ids
+                    .split(|c: char| c == ',' || c == '，' || c.is_whitespace())
+                    .filter_map(|s| s.trim().parse().ok())
+                    .collect();
+                if parsed.is_empty() {
+                    let prefix = if black { "##^" } else { "##+" };
+                    reply_text(event, messages::qq_usage(prefix));
                     return;
                 }
                 let mut c = mgr.config.write().await;
-                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
-                    a.description = cmd.args.clone();
-                    mgr.save(&c);
-                    reply_text(event, format!("📝 {} 描述已更新", name));
+                let list = if black {
+                    &mut c.blacklist
+                } else {
+                    &mut c.whitelist
+                };
+                let before = list.len();
+                if add {
+                    for id in &parsed {
+                        if !list.contains(id) {
+                            list.push(*id);
+                        }
+                    }
                 } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    list.retain(|id| !parsed.contains(id));
                 }
+                let changed = list.len().abs_diff(before);
+                let total = list.len();
+                mgr.save(&c);
+                reply_text(
+                    event,
+                    messages::user_list_edited(black, add, changed, total),
+                );
             }
 
-            Action::SetModel => {
-                if cmd.args.is_empty() {
-                    reply_text(event, "❌ 请指定模型: 智能体%模型名");
+            Action::EditAdmins { add, ids } => {
+                let parsed: Vec<i64> = ids
+                    .split(|c: char| c == ',' || c == '，' || c.is_whitespace())
+                    .filter_map(|s| s.trim().parse().ok())
+                    .collect();
+                if parsed.is_empty() {
+                    let prefix = if add { "##admin+" } else { "##admin-" };
+                    reply_text(event, messages::qq_usage(prefix));
                     return;
                 }
                 let mut c = mgr.config.write().await;
-                let models = c.models.clone();
-                if let Some(model) = mgr.resolve_model(&cmd.args, &models) {
-                    if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
-                        let old = a.model.clone();
-                        a.model = model.clone();
-                        mgr.save(&c);
-                        reply_text(event, format!("🔄 {} 模型: {} → {}", name, old, model));
-                    } else {
-                        reply_text(event, format!("❌ {} 不存在", name));
+                let was_empty = c.admins.is_empty();
+                if add {
+                    // 首次设置管理员时把操作者一并加入，避免把自己锁在外面
+                    if was_empty && !parsed.contains(&event.user_id) {
+                        c.admins.push(event.user_id);
                     }
+                    for id in &parsed {
+                        if !c.admins.contains(id) {
+                            c.admins.push(*id);
+                        }
+                    }
+                } else {
+                    c.admins.retain(|id| !parsed.contains(id));
+                }
+                let list = c
+                    .admins
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let now_empty = c.admins.is_empty();
+                mgr.save(&c);
+                if now_empty {
+                    reply_text(event, Msg::AdminsCleared);
                 } else {
-                    reply_text(event, "❌ 无效模型");
+                    reply_text(event, messages::admins(&list));
                 }
             }
 
-            Action::SetPrompt => {
-                let mut c = mgr.config.write().await;
-                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
-                    a.system_prompt = cmd.args.clone();
-                    mgr.save(&c);
-                    if cmd.args.is_empty() {
-                        reply_text(event, format!("📝 {} 提示词已清空", name));
-                    } else {
-                        reply_text(event, format!("📝 {} 提示词已更新", name));
-                    }
+            Action::ListAdmins => {
+                let c = mgr.config.read().await;
+                if c.admins.is_empty() {
+                    reply_text(event, Msg::NoAdmins);
                 } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    let list = c
+                        .admins
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    reply_text(event, messages::admins(&list));
                 }
             }
 
-            Action::ViewPrompt => {
+            Action::ListUserLists => {
                 let c = mgr.config.read().await;
-                if let Some(a) = c.agents.iter().find(|a| a.name == *name) {
-                    if cmd.text_mode {
-                        reply_text(event, &a.system_prompt);
-                        return;
+                let fmt = |l: &[i64]| {
+                    if l.is_empty() {
+                        Msg::Empty.text().to_string()
+                    } else {
+                        l.iter()
+                            .map(|id| id.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
                     }
-                    let prompt_display = if a.system_prompt.is_empty() {
-                        "(空)".to_string()
+                };
+                reply_text(
+                    event,
+                    messages::user_lists(&fmt(&c.whitelist), &fmt(&c.blacklist)),
+                );
+            }
+
+            Action::SetTheme(theme) => {
+                if theme.is_empty() {
+                    let c = mgr.config.read().await;
+                    let current = if c.render_theme.is_empty() {
+                        "light"
                     } else {
-                        escape_markdown_special(&a.system_prompt)
+                        &c.render_theme
                     };
-                    let content = format!(
-                        "**模型**: `{}`\n\n**提示词**:\n```\n{}\n```",
-                        a.model, prompt_display
-                    );
-                    reply(
-                        event,
-                        &content,
-                        cmd.text_mode,
-                        &format!("{} 系统提示词", a.name),
-                    )
-                    .await;
-                } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    reply_text(event, messages::theme_status(current, &THEMES.join(" / ")));
+                    return;
+                }
+                if !THEMES.contains(&theme.as_str()) {
+                    reply_text(event, messages::unknown_theme(&THEMES.join(" / ")));
+                    return;
                 }
+                let mut c = mgr.config.write().await;
+                c.render_theme = theme.clone();
+                mgr.save(&c);
+                set_render_theme(&theme);
+                reply_text(event, messages::theme_set(&theme));
             }
 
-            Action::List => {
-                let c = mgr.config.read().await;
-                if c.agents.is_empty() {
-                    reply_text(event, "📋 暂无智能体，使用 ##名称 模型 提示词 创建");
+            Action::SetLang(code) => {
+                if code.is_empty() {
+                    reply_text(event, messages::lang_status());
                     return;
                 }
+                let Some(lang) = messages::Lang::parse(&code) else {
+                    reply_text(event, messages::unknown_lang());
+                    return;
+                };
+                let mut c = mgr.config.write().await;
+                c.lang = lang.code().to_string();
+                mgr.save(&c);
+                messages::set_lang(lang);
+                reply_text(event, Msg::LangSwitched);
+            }
 
-                // 分组逻辑：使用 BTreeMap 自动按模型名称排序
-                use std::collections::BTreeMap;
-                let mut groups: BTreeMap<String, Vec<(usize, &Agent)>> = BTreeMap::new();
-
-                // 遍历并分组 (保留原始索引 i+1 以便用户操作)
-                for (i, a) in c.agents.iter().enumerate() {
-                    groups.entry(a.model.clone()).or_default().push((i + 1, a));
+            Action::RefreshModels => {
+                reply_text(event, Msg::RefreshingModels);
+                match mgr.fetch_models().await {
+                    Ok(models) => reply_text(event, messages::models_refreshed(models.len())),
+                    Err(e) if mgr.config.read().await.models.is_empty() => {
+                        reply_text(event, messages::fetch_failed(e))
+                    }
+                    Err(e) => reply_text(event, messages::fetch_failed_keep_old(e)),
                 }
+            }
 
-                // 生成 HTML
-                let mut html_parts = Vec::new();
-
-                // 遍历每一个模型分组
-                for (model, mut agents) in groups {
-                    // 组内按智能体名称排序
-                    agents.sort_by(|a, b| a.1.name.to_lowercase().cmp(&b.1.name.to_lowercase()));
-
-                    // 组头
-                    html_parts.push(format!(
-                                              r#"<div class="model-group"><div class="model-header"><span>📦 {}</span><span class="model-count">{}</span></div><div class="agent-grid">"#,
-                                              model, agents.len()
-                                          ));
-
-                    // 组内网格
-                    for (real_idx, a) in agents {
-                        // 逻辑：优先显示描述；如果没有描述，则截取系统提示词的前 20 个字作为预览；
-                        let desc_display = if !a.description.is_empty() {
-                            truncate_str(&a.description, 20)
-                        } else if !a.system_prompt.is_empty() {
-                            truncate_str(&a.system_prompt, 20)
-                        } else {
-                            "无描述".to_string()
-                        };
-
-                        html_parts.push(format!(
-                                            r#"<div class="agent-mini"><div class="agent-mini-top"><div class="agent-idx">{}</div><div class="agent-mini-name">{}</div></div><div class="agent-mini-desc">{}</div></div>"#,
-                                            real_idx, a.name, desc_display
-                                        ));
-                    }
-                    html_parts.push("</div></div>".to_string());
+            Action::SetAgentOrder { name, pos } => {
+                if name.is_empty() || pos == 0 {
+                    reply_text(event, Msg::AgentOrderUsage);
+                    return;
                 }
+                match mgr.move_agent(&name, pos).await {
+                    Some(to) => reply_text(event, messages::agent_moved(&name, to)),
+                    None => reply_text(event, messages::not_found(&name)),
+                }
+            }
 
-                let list = html_parts.join("\n");
+            Action::ReloadConfig => match mgr.reload_config().await {
+                Ok((agents, models)) => {
+                    reply_text(event, messages::config_reloaded(agents, models))
+                }
+                Err(e) => reply_text(event, messages::reload_failed(e)),
+            },
 
-                reply(
-                    event,
-                    &list,
-                    cmd.text_mode,
-                    &format!("📋 智能体列表 (共{}个)", c.agents.len()),
-                )
-                .await;
-            }
+            Action::HealthCheck => match mgr.health_check().await {
+                Ok(d) => reply_text(event, messages::api_healthy(d.as_millis())),
+                Err(e) => reply_text(event, messages::api_unreachable(e)),
+            },
 
-            Action::Delete => {
-                let mut c = mgr.config.write().await;
-                if let Some(idx) = c.agents.iter().position(|a| a.name == *name) {
-                    c.agents.remove(idx);
+            Action::EditModelKeyword { add, word } => {
+                if word.is_empty() {
+                    let c = mgr.config.read().await;
+                    let current = if c.model_keywords.is_empty() {
+                        Msg::NoModelKeywords.text().to_string()
+                    } else {
+                        c.model_keywords.join(", ")
+                    };
+                    reply_text(event, messages::model_keyword_usage(&current));
+                    return;
+                }
+                // 旧配置没有保存完整列表，先抓取一次
+                if mgr.config.read().await.all_models.is_empty()
+                    && let Err(e) = mgr.fetch_models().await
+                {
+                    reply_text(event, messages::fetch_failed(e));
+                    return;
+                }
+                {
+                    let mut c = mgr.config.write().await;
+                    let lower = word.to_lowercase();
+                    let exists = c.model_keywords.iter().any(|k| k.to_lowercase() == lower);
+                    if add && exists {
+                        reply_text(event, messages::model_keyword_exists(&word));
+                        return;
+                    }
+                    if !add && !exists {
+                        reply_text(event, messages::model_keyword_missing(&word));
+                        return;
+                    }
+                    if add {
+                        c.model_keywords.push(word.clone());
+                    } else {
+                        c.model_keywords.retain(|k| k.to_lowercase() != lower);
+                    }
+                    apply_model_filter(&mut c);
                     mgr.save(&c);
-                    reply_text(event, format!("🗑️ 已删除 {}", name));
-                } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    reply_text(
+                        event,
+                        messages::model_keyword_edited(add, &word, c.models.len()),
+                    );
                 }
+                let cmd = Command::new(
+                    "",
+                    Action::ListModels {
+                        page: 1,
+                        all: false,
+                    },
+                );
+                Box::pin(execute(cmd, String::new(), vec![], event, mgr, bot)).await;
             }
 
-            Action::ListModels => {
-                let c = mgr.config.read().await;
-
-                // 1. 如果配置为空，尝试抓取
-                if c.models.is_empty() {
-                    drop(c);
-                    reply_text(event, "⏳ 正在获取模型列表...");
+            Action::ListModels { page, all } => {
+                // 1. 缓存为空或已过期时重新抓取，失败则沿用旧缓存
+                if !mgr.models_fresh().await {
+                    let empty = mgr.config.read().await.models.is_empty();
+                    if empty {
+                        reply_text(event, Msg::FetchingModels);
+                    }
                     if let Err(e) = mgr.fetch_models().await {
-                        reply_text(event, format!("❌ 获取失败: {}", e));
-                        return;
+                        if empty {
+                            reply_text(event, messages::fetch_failed(e));
+                            return;
+                        }
+                        reply_text(event, messages::refresh_failed_cached(e));
                     }
                 }
 
                 // 重新读取
                 let c = mgr.config.read().await;
-                let models = &c.models;
+                // 完整列表中的序号沿用过滤后列表的编号，未展示的模型需按名称使用
+                let models = if all && !c.all_models.is_empty() {
+                    &c.all_models
+                } else {
+                    &c.models
+                };
 
                 if models.is_empty() {
-                    reply_text(event, "📭 未找到可用模型 (请检查过滤关键字)");
+                    reply_text(event, Msg::NoModels);
                     return;
                 }
 
@@ -1898,24 +10200,42 @@ mod logic {
                     *usage_count.entry(agent.model.clone()).or_insert(0) += 1;
                 }
 
+                // 分页：超出范围时显示最后一页，序号保持全局编号
+                let page_size = c.models_page_size.max(1);
+                let pages = models.len().div_ceil(page_size);
+                let page = page.min(pages);
+                let start = (page - 1) * page_size;
+                let end = (start + page_size).min(models.len());
+
                 // 3. 动态分组逻辑
-                // 直接利用 utils::MODEL_KEYWORDS 进行分组
-                let mut groups: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+                // 按配置的过滤关键字分组，组名为首字母大写的关键字
+                let group_name = |kw: &str| {
+                    let mut chars = kw.chars();
+                    let first = chars.next().map(|c| c.to_uppercase().to_string());
+                    format!("{}{} Series", first.unwrap_or_default(), chars.as_str())
+                };
+                let mut groups: HashMap<String, Vec<(String, String)>> = HashMap::new();
                 let mut other_models = Vec::new();
 
-                for (i, m) in models.iter().enumerate() {
-                    let idx = i + 1;
+                for (i, m) in models.iter().enumerate().take(end).skip(start) {
+                    let idx = if all {
+                        c.models
+                            .iter()
+                            .position(|x| x == m)
+                            .map_or_else(|| "·".to_string(), |p| (p + 1).to_string())
+                    } else {
+                        (i + 1).to_string()
+                    };
                     let lower = m.to_lowercase();
                     let mut matched = false;
 
-                    for &kw in crate::utils::MODEL_KEYWORDS {
-                        if lower.contains(kw) {
+                    for kw in &c.model_keywords {
+                        if lower.contains(&kw.to_lowercase()) {
                             // 将关键字首字母大写作为组名 (e.g. "gpt-5" -> "Gpt-5 Series")
-                            let group_name = format!(
-                                "{} Series",
-                                kw.chars().next().unwrap().to_uppercase().to_string() + &kw[1..]
-                            );
-                            groups.entry(group_name).or_default().push((idx, m.clone()));
+                            groups
+                                .entry(group_name(kw))
+                                .or_default()
+                                .push((idx.clone(), m.clone()));
                             matched = true;
                             break;
                         }
@@ -1930,14 +10250,17 @@ mod logic {
                 let mut html = String::new();
 
                 // 辅助渲染函数
-                let render_group = |title: &str, items: &Vec<(usize, String)>| -> String {
+                let render_group = |title: &str, items: &Vec<(String, String)>| -> String {
                     let mut s = format!(
                         r#"<div class="mod-group"><div class="mod-title">{}</div><div class="chip-box">"#,
                         title
                     );
                     for (idx, name) in items {
                         let badge = if let Some(cnt) = usage_count.get(name) {
-                            format!(r#"<span class="chip-bad">{}用</span>"#, cnt)
+                            format!(
+                                r#"<span class="chip-bad">{}</span>"#,
+                                messages::used_by(*cnt)
+                            )
                         } else {
                             String::new()
                         };
@@ -1950,14 +10273,11 @@ mod logic {
                     s
                 };
 
-                // 按 MODEL_KEYWORDS 的定义顺序渲染 (保证顺序可控)
-                for &kw in crate::utils::MODEL_KEYWORDS {
-                    let group_name = format!(
-                        "{} Series",
-                        kw.chars().next().unwrap().to_uppercase().to_string() + &kw[1..]
-                    );
-                    if let Some(items) = groups.get(&group_name) {
-                        html.push_str(&render_group(&group_name, items));
+                // 按 model_keywords 的配置顺序渲染 (保证顺序可控)
+                for kw in &c.model_keywords {
+                    let name = group_name(kw);
+                    if let Some(items) = groups.remove(&name) {
+                        html.push_str(&render_group(&name, &items));
                     }
                 }
 
@@ -1966,12 +10286,20 @@ mod logic {
                     html.push_str(&render_group("Other Models", &other_models));
                 }
 
+                let cmd_prefix = if all { "/%all" } else { "/%" };
+                if pages > 1 {
+                    let next = (page < pages).then(|| format!("{}{}", cmd_prefix, page + 1));
+                    let footer =
+                        messages::models_page(start + 1, end, page, pages, next.as_deref());
+                    html.push_str(&format!("\n\n{}", footer));
+                }
+
                 // 5. 发送
                 reply(
                     event,
                     &html,
                     cmd.text_mode,
-                    &format!("🧩 模型列表 (共{}个)", models.len()),
+                    &messages::model_list_title(all, models.len()),
                 )
                 .await;
             }
@@ -1980,41 +10308,70 @@ mod logic {
                 let c = mgr.config.read().await;
                 if let Some(a) = c.agents.iter().find(|a| a.name == *name) {
                     let priv_scope = matches!(scope, Scope::Private);
-                    let hist = a.history(priv_scope, &uid);
+                    let hist = a.history(priv_scope, &owner(priv_scope));
                     if hist.is_empty() {
-                        let s = if priv_scope { "私有" } else { "公有" };
-                        reply_text(event, format!("📭 {} {}历史为空", name, s));
+                        reply_text(event, messages::history_empty(name, priv_scope));
                         return;
                     }
-                    let content = format_history(hist, 0, cmd.text_mode);
-                    let header = format!(
-                        "{} {}历史 ({} 条)",
+                    // 分页显示，未指定页码时显示最新一页
+                    let pages = hist.len().div_ceil(HISTORY_PAGE_SIZE);
+                    let page = match cmd.indices.first() {
+                        Some(&p) if p >= 1 && p <= pages => p,
+                        Some(&p) => {
+                            reply_text(event, messages::invalid_page(p, pages));
+                            return;
+                        }
+                        None => pages,
+                    };
+                    let start = (page - 1) * HISTORY_PAGE_SIZE;
+                    let end = (start + HISTORY_PAGE_SIZE).min(hist.len());
+                    let mut header = messages::history_header(
                         name,
-                        if priv_scope { "私有" } else { "公有" },
-                        hist.len()
+                        priv_scope,
+                        hist.len(),
+                        count_tokens_estimate(hist),
                     );
+                    if pages > 1 {
+                        header.push_str(&messages::page_of(page, pages));
+                    }
+                    if c.use_forward
+                        && !cmd.text_mode
+                        && send_history_forward(event, bot, name, &hist[start..end], start, &header)
+                            .await
+                    {
+                        return;
+                    }
+                    let content = format_history(&hist[start..end], start, cmd.text_mode);
                     reply(event, &content, cmd.text_mode, &header).await;
                 } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    reply_text(event, messages::not_found(name));
                 }
             }
 
             Action::ViewAt(scope) => {
-                if cmd.indices.is_empty() {
-                    reply_text(event, "❌ 请指定索引: 智能体/索引");
+                if cmd.indices.is_empty() && cmd.args.is_empty() {
+                    reply_text(event, Msg::ViewAtUsage);
                     return;
                 }
                 let c = mgr.config.read().await;
                 if let Some(a) = c.agents.iter().find(|a| a.name == *name) {
                     let priv_scope = matches!(scope, Scope::Private);
-                    let hist = a.history(priv_scope, &uid);
+                    let hist = a.history(priv_scope, &owner(priv_scope));
+                    let indices = if cmd.indices.is_empty() {
+                        parse_indices(&cmd.args, hist.len())
+                    } else {
+                        cmd.indices.clone()
+                    };
+                    if indices.is_empty() {
+                        reply_text(event, Msg::InvalidIndex);
+                        return;
+                    }
                     let mut results = Vec::new();
                     let mut extra_images = Vec::new();
 
-                    let re =
-                        Regex::new(r"!\[.*?\]\(((?:https?://|data:image/)[^\s\)]+)\)").unwrap();
+                    let re = image_url_re();
 
-                    for i in &cmd.indices {
+                    for i in &indices {
                         if *i > 0 && *i <= hist.len() {
                             let m = &hist[i - 1];
                             let emoji = match m.role.as_str() {
@@ -2032,7 +10389,7 @@ mod logic {
                                     .replace_all(&content, |caps: &regex::Captures| {
                                         let url = &caps[1];
                                         if url.starts_with("data:") {
-                                            "[图片]".to_string()
+                                            Msg::ImagePlaceholder.text().to_string()
                                         } else {
                                             url.to_string()
                                         }
@@ -2064,31 +10421,87 @@ mod logic {
                     }
 
                     if results.is_empty() {
-                        reply_text(event, "❌ 索引无效");
+                        reply_text(event, Msg::InvalidIndex);
+                    } else {
+                        reply(
+                            event,
+                            &results.join("\n\n---\n\n"),
+                            cmd.text_mode,
+                            &messages::history_title(name),
+                        )
+                        .await;
+
+                        for url in extra_images {
+                            if let Some(src) = image_source(&url) {
+                                event.reply(Message::new().add_image(&src));
+                            }
+                        }
+                    }
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
+
+            Action::ViewTimeRange(scope) => {
+                let Some((from, to)) = parse_time_range(&cmd.args) else {
+                    reply_text(event, Msg::TimeRangeUsage);
+                    return;
+                };
+                let c = mgr.config.read().await;
+                let Some(a) = c.agents.iter().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let priv_scope = matches!(scope, Scope::Private);
+                let hist = a.history(priv_scope, &owner(priv_scope));
+                // 保留原始序号，便于按编号继续操作
+                let entries: Vec<(usize, &ChatMessage)> = hist
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, m)| m.timestamp >= from && m.timestamp < to)
+                    .map(|(i, m)| (i + 1, m))
+                    .collect();
+                if entries.is_empty() {
+                    reply_text(event, messages::no_history_in(name, &cmd.args));
+                    return;
+                }
+                let header =
+                    messages::history_range_header(name, priv_scope, &cmd.args, entries.len());
+                let content = format_history_entries(entries.into_iter(), cmd.text_mode);
+                reply(event, &content, cmd.text_mode, &header).await;
+            }
+
+            Action::ViewSource(scope) => {
+                let sources = {
+                    let c = mgr.config.read().await;
+                    let Some(a) = c.agents.iter().find(|a| a.name == *name) else {
+                        reply_text(event, messages::not_found(name));
+                        return;
+                    };
+                    let priv_scope = matches!(scope, Scope::Private);
+                    let hist = a.history(priv_scope, &owner(priv_scope));
+                    let indices = if cmd.indices.is_empty() {
+                        parse_indices(&cmd.args, hist.len())
+                    } else {
+                        cmd.indices.clone()
+                    };
+                    indices
+                        .iter()
+                        .filter_map(|&i| hist.get(i.wrapping_sub(1)))
+                        .map(|m| m.content.clone())
+                        .collect::<Vec<_>>()
+                };
+                if sources.is_empty() {
+                    reply_text(event, Msg::InvalidIndex);
+                    return;
+                }
+                // 每条单独发送便于复制，超长时按段落分条
+                for content in sources {
+                    if content.is_empty() {
+                        reply_text(event, Msg::Empty);
                     } else {
-                        reply(
-                            event,
-                            &results.join("\n\n---\n\n"),
-                            cmd.text_mode,
-                            &format!("{} 历史记录", name),
-                        )
-                        .await;
-
-                        for url in extra_images {
-                            if url.starts_with("data:") {
-                                if let Some(base64_data) = url.split(',').nth(1) {
-                                    event.reply(
-                                        Message::new()
-                                            .add_image(&format!("base64://{}", base64_data)),
-                                    );
-                                }
-                            } else {
-                                event.reply(Message::new().add_image(&url));
-                            }
-                        }
+                        reply(event, &content, true, "").await;
                     }
-                } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
                 }
             }
 
@@ -2096,14 +10509,14 @@ mod logic {
                 let c = mgr.config.read().await;
                 if let Some(a) = c.agents.iter().find(|a| a.name == *name) {
                     let priv_scope = matches!(scope, Scope::Private);
-                    let hist = a.history(priv_scope, &uid);
+                    let hist = a.history(priv_scope, &owner(priv_scope));
                     if hist.is_empty() {
-                        reply_text(event, "📭 历史为空");
+                        reply_text(event, Msg::EmptyHistory);
                         return;
                     }
 
-                    let scope_str = if priv_scope { "私有" } else { "公有" };
-                    let content = format_export_txt(name, &a.model, scope_str, hist);
+                    let content =
+                        format_export_txt(name, &a.model, messages::scope(priv_scope), hist);
 
                     let scope_file = if priv_scope { "private" } else { "public" };
                     let fname = format!(
@@ -2117,77 +10530,358 @@ mod logic {
                     match File::create(&path) {
                         Ok(mut f) => {
                             if f.write_all(content.as_bytes()).is_ok() {
-                                let path_str = path.to_string_lossy().to_string();
-                                let result = if let Some(gid) = event.group_id {
-                                    bot.upload_group_file(gid, &path_str, &fname, None).await
-                                } else {
-                                    bot.upload_private_file(event.user_id, &path_str, &fname)
-                                        .await
-                                };
-                                match result {
-                                    Ok(_) => reply_text(event, format!("📤 已导出: {}", fname)),
-                                    Err(e) => reply_text(event, format!("❌ 上传失败: {}", e)),
-                                }
+                                upload_file(event, bot, &path, &fname).await;
                             } else {
-                                reply_text(event, "❌ 写入失败");
+                                reply_text(event, Msg::WriteFailed);
                             }
                         }
-                        Err(e) => reply_text(event, format!("❌ 创建文件失败: {}", e)),
+                        Err(e) => reply_text(event, messages::create_file_failed(e)),
+                    }
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
+
+            Action::ExportHtml(scope) => {
+                let content = {
+                    let c = mgr.config.read().await;
+                    let Some(a) = c.agents.iter().find(|a| a.name == *name) else {
+                        reply_text(event, messages::not_found(name));
+                        return;
+                    };
+                    let priv_scope = matches!(scope, Scope::Private);
+                    let hist = a.history(priv_scope, &owner(priv_scope));
+                    if hist.is_empty() {
+                        reply_text(event, Msg::EmptyHistory);
+                        return;
+                    }
+                    format_export_html(name, &a.model, messages::scope(priv_scope), hist)
+                };
+                let scope_file = if matches!(scope, Scope::Private) {
+                    "private"
+                } else {
+                    "public"
+                };
+                let fname = format!(
+                    "{}_{}_{}_{}.html",
+                    name,
+                    scope_file,
+                    uid,
+                    chrono::Local::now().format("%Y%m%d%H%M%S")
+                );
+                let path = bot.get_data_path().join(&fname);
+                match std::fs::write(&path, content) {
+                    Ok(_) => upload_file(event, bot, &path, &fname).await,
+                    Err(e) => reply_text(event, messages::create_file_failed(e)),
+                }
+            }
+
+            Action::ExtractCode(scope) => {
+                let content = {
+                    let c = mgr.config.read().await;
+                    let Some(a) = c.agents.iter().find(|a| a.name == *name) else {
+                        reply_text(event, messages::not_found(name));
+                        return;
+                    };
+                    let priv_scope = matches!(scope, Scope::Private);
+                    let hist = a.history(priv_scope, &owner(priv_scope));
+                    let msg = match cmd.indices.first() {
+                        Some(&i) => hist.get(i.wrapping_sub(1)),
+                        None => hist.iter().rev().find(|m| m.role == "assistant"),
+                    };
+                    let Some(msg) = msg else {
+                        reply_text(event, Msg::EmptyHistory);
+                        return;
+                    };
+                    msg.content.clone()
+                };
+                let blocks = extract_code_blocks(&content);
+                if blocks.is_empty() {
+                    reply_text(event, Msg::NoCodeBlocks);
+                    return;
+                }
+                reply_text(event, messages::code_blocks(blocks.len()));
+                // 逐块单独发送便于复制，过长的代码改为上传文件
+                for (i, (lang, code)) in blocks.iter().enumerate() {
+                    if code.chars().count() <= max_text_len() {
+                        reply_text(event, code.clone());
+                        continue;
                     }
+                    let fname = format!(
+                        "{}_code_{}_{}.{}",
+                        name,
+                        i + 1,
+                        chrono::Local::now().format("%Y%m%d%H%M%S"),
+                        code_extension(lang)
+                    );
+                    let path = bot.get_data_path().join(&fname);
+                    match std::fs::write(&path, code) {
+                        Ok(_) => upload_file(event, bot, &path, &fname).await,
+                        Err(e) => reply_text(event, messages::create_file_failed(e)),
+                    }
+                }
+            }
+
+            Action::ShowContext(scope) => {
+                let c = mgr.config.read().await;
+                if let Some(a) = c.agents.iter().find(|a| a.name == *name) {
+                    let priv_scope = matches!(scope, Scope::Private);
+                    let msgs = build_messages(
+                        a,
+                        a.history(priv_scope, &owner(priv_scope)),
+                        &uid,
+                        &a.model,
+                    );
+                    if msgs.is_empty() {
+                        reply_text(event, Msg::EmptyContext);
+                        return;
+                    }
+                    let header = messages::context_header(name, priv_scope, &a.model);
+                    let content = format_context(&msgs, c.warn_token_threshold);
+                    reply(event, &content, cmd.text_mode, &header).await;
                 } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    reply_text(event, messages::not_found(name));
+                }
+            }
+
+            Action::ExportMindmap(scope) => {
+                let priv_scope = matches!(scope, Scope::Private);
+                let (hist, model, api) = {
+                    let c = mgr.config.read().await;
+                    match c.agents.iter().find(|a| a.name == *name) {
+                        Some(a) => (
+                            a.history(priv_scope, &owner(priv_scope)).to_vec(),
+                            a.model.clone(),
+                            (c.api_base.clone(), c.api_key.clone()),
+                        ),
+                        None => {
+                            reply_text(event, messages::not_found(name));
+                            return;
+                        }
+                    }
+                };
+                if hist.is_empty() {
+                    reply_text(event, Msg::EmptyHistory);
+                    return;
+                }
+
+                reply_text(event, Msg::BuildingMindmap);
+                let content = build_mindmap(mgr, &api, &model, name, &hist).await;
+
+                let scope_file = if priv_scope { "private" } else { "public" };
+                let fname = format!(
+                    "{}_{}_{}_mindmap_{}.md",
+                    name,
+                    scope_file,
+                    uid,
+                    chrono::Local::now().format("%Y%m%d%H%M%S")
+                );
+                let path = bot.get_data_path().join(&fname);
+                match std::fs::write(&path, content) {
+                    Ok(_) => upload_file(event, bot, &path, &fname).await,
+                    Err(e) => reply_text(event, messages::write_failed(e)),
+                }
+            }
+
+            Action::Summarize(scope) => {
+                let priv_scope = matches!(scope, Scope::Private);
+                if mgr
+                    .generating
+                    .read()
+                    .await
+                    .is_generating(name, priv_scope, &owner(priv_scope))
+                {
+                    reply_text(event, Msg::SummarizeBusy);
+                    return;
+                }
+                let keep = cmd.indices.first().copied().unwrap_or(4);
+                reply_text(event, Msg::Summarizing);
+                match summarize_history(mgr, name, priv_scope, &owner(priv_scope), keep).await {
+                    Ok(n) => reply_text(event, messages::summarized(n)),
+                    Err(e) => reply_text(event, messages::summarize_failed(&e)),
                 }
             }
 
-            Action::EditAt(scope) => {
+            Action::EditAt(scope, mode) => {
                 if cmd.indices.is_empty() {
-                    reply_text(event, "❌ 请指定索引: 智能体'索引 新内容");
+                    reply_text(event, Msg::EditUsage);
                     return;
                 }
                 if cmd.args.is_empty() {
-                    reply_text(event, "❌ 请提供新内容");
+                    reply_text(event, Msg::EditContentMissing);
+                    return;
+                }
+                let idx = cmd.indices[0];
+                let mut c = mgr.config.write().await;
+                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
+                    let priv_scope = matches!(scope, Scope::Private);
+                    let before = a.history(priv_scope, &owner(priv_scope)).to_vec();
+                    if a.edit_at(priv_scope, &owner(priv_scope), idx, &cmd.args, mode) {
+                        mgr.undo_store
+                            .push(history_key(name, priv_scope, &owner(priv_scope)), before)
+                            .await;
+                        mgr.save(&c);
+                        reply_text(event, messages::edited(mode, idx));
+                    } else {
+                        reply_text(event, messages::invalid_index_at(idx));
+                    }
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
+
+            Action::TogglePin(scope) => {
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let priv_scope = matches!(scope, Scope::Private);
+                let changed = a.toggle_pin(priv_scope, &owner(priv_scope), &cmd.indices);
+                if changed.is_empty() {
+                    reply_text(event, Msg::InvalidIndex);
+                    return;
+                }
+                mgr.save(&c);
+                let text = changed
+                    .iter()
+                    .map(|&(i, pinned)| messages::pin_toggled(i, pinned))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                reply_text(event, text);
+            }
+
+            Action::Reorder(scope) => {
+                let [i, j] = cmd.indices[..] else {
+                    reply_text(event, Msg::ReorderUsage);
+                    return;
+                };
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let priv_scope = matches!(scope, Scope::Private);
+                if a.swap_at(priv_scope, &owner(priv_scope), i, j) {
+                    mgr.save(&c);
+                    reply_text(event, messages::swapped(i, j));
+                } else {
+                    reply_text(event, Msg::InvalidIndex);
+                }
+            }
+
+            Action::ReplaceAll {
+                scope,
+                pattern,
+                replacement,
+                regex,
+                ignore_case,
+            } => {
+                if pattern.is_empty() {
+                    reply_text(event, Msg::ReplaceUsage);
+                    return;
+                }
+                let mut c = mgr.config.write().await;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let priv_scope = matches!(scope, Scope::Private);
+                let before = a.history(priv_scope, &owner(priv_scope)).to_vec();
+                match a.replace_all(
+                    priv_scope,
+                    &owner(priv_scope),
+                    &pattern,
+                    &replacement,
+                    regex,
+                    ignore_case,
+                ) {
+                    Ok(0) => reply_text(event, messages::pattern_not_found(&pattern)),
+                    Ok(n) => {
+                        mgr.snapshot(
+                            history_key(name, priv_scope, &owner(priv_scope)),
+                            Msg::SnapReplace.text(),
+                            &before,
+                        )
+                        .await;
+                        mgr.save(&c);
+                        reply_text(event, messages::replaced(n));
+                    }
+                    Err(e) => reply_text(event, messages::invalid_regex(e)),
+                }
+            }
+
+            Action::InsertAt(scope) => {
+                let (role, content) = cmd
+                    .args
+                    .split_once(char::is_whitespace)
+                    .map(|(r, c)| (r.to_lowercase(), c.trim().to_string()))
+                    .unwrap_or_default();
+                if cmd.indices.is_empty()
+                    || !["user", "assistant", "system"].contains(&role.as_str())
+                    || content.is_empty()
+                {
+                    reply_text(event, Msg::InsertUsage);
                     return;
                 }
                 let idx = cmd.indices[0];
                 let mut c = mgr.config.write().await;
                 if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
                     let priv_scope = matches!(scope, Scope::Private);
-                    if a.edit_at(priv_scope, &uid, idx, &cmd.args) {
+                    if a.insert_at(priv_scope, &owner(priv_scope), idx, &role, &content) {
                         mgr.save(&c);
-                        reply_text(event, format!("✏️ 已编辑第 {} 条", idx));
+                        reply_text(event, messages::inserted(idx, &role));
                     } else {
-                        reply_text(event, format!("❌ 索引 {} 无效", idx));
+                        reply_text(event, messages::invalid_index_at(idx));
                     }
                 } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    reply_text(event, messages::not_found(name));
                 }
             }
 
             Action::DeleteAt(scope) => {
-                if cmd.indices.is_empty() {
-                    reply_text(event, "❌ 请指定索引: 智能体-索引 (支持 1,3,5 或 1-5)");
+                if cmd.indices.is_empty() && cmd.args.is_empty() {
+                    reply_text(event, Msg::DeleteUsage);
                     return;
                 }
                 let mut c = mgr.config.write().await;
                 if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
                     let priv_scope = matches!(scope, Scope::Private);
-                    let deleted = a.delete_at(priv_scope, &uid, &cmd.indices);
+                    let indices = if cmd.indices.is_empty() {
+                        parse_indices(&cmd.args, a.history(priv_scope, &owner(priv_scope)).len())
+                    } else {
+                        cmd.indices.clone()
+                    };
+                    if indices.is_empty() {
+                        reply_text(event, Msg::InvalidIndex);
+                        return;
+                    }
+                    if indices.len() > 1 {
+                        mgr.snapshot(
+                            history_key(name, priv_scope, &owner(priv_scope)),
+                            Msg::SnapBatchDelete.text(),
+                            a.history(priv_scope, &owner(priv_scope)),
+                        )
+                        .await;
+                    }
+                    let before = a.history(priv_scope, &owner(priv_scope)).to_vec();
+                    let deleted = a.delete_at(priv_scope, &owner(priv_scope), &indices);
                     if deleted.is_empty() {
-                        reply_text(event, "❌ 索引无效");
+                        reply_text(event, Msg::InvalidIndex);
                     } else {
+                        mgr.undo_store
+                            .push(history_key(name, priv_scope, &owner(priv_scope)), before)
+                            .await;
                         mgr.save(&c);
                         let s = deleted
                             .iter()
                             .map(|i| i.to_string())
                             .collect::<Vec<_>>()
                             .join(", ");
-                        reply_text(
-                            event,
-                            format!("🗑️ 已删除第 {} 条 (共{}条)", s, deleted.len()),
-                        );
+                        reply_text(event, messages::deleted_messages(&s, deleted.len()));
                     }
                 } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    reply_text(event, messages::not_found(name));
                 }
             }
 
@@ -2195,116 +10889,222 @@ mod logic {
                 let is_priv_ctx = cmd.private_reply;
                 {
                     let mut generating = mgr.generating.write().await;
-                    generating.set_generating(name, is_priv_ctx, &uid, false);
+                    generating.set_generating(name, is_priv_ctx, &owner(is_priv_ctx), false);
                 }
                 let mut c = mgr.config.write().await;
                 if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
                     let priv_scope = matches!(scope, Scope::Private);
-                    let s = if priv_scope { "私有" } else { "公有" };
-                    a.clear_history(priv_scope, &uid);
-                    a.generation_id += 1;
+                    mgr.snapshot(
+                        history_key(name, priv_scope, &owner(priv_scope)),
+                        Msg::SnapClear.text(),
+                        a.history(priv_scope, &owner(priv_scope)),
+                    )
+                    .await;
+                    let before = a.history(priv_scope, &owner(priv_scope)).to_vec();
+                    if !before.is_empty() {
+                        mgr.undo_store
+                            .push(history_key(name, priv_scope, &owner(priv_scope)), before)
+                            .await;
+                    }
+                    a.clear_history(priv_scope, &owner(priv_scope));
+                    a.bump_generation(priv_scope, &owner(priv_scope));
+                    let pinned = a.history(priv_scope, &owner(priv_scope)).len();
                     mgr.save(&c);
-                    reply_text(event, format!("🧹 {} {}历史已清空", name, s));
+                    reply_text(event, messages::history_cleared(name, priv_scope, pinned));
                 } else {
-                    reply_text(event, format!("❌ {} 不存在", name));
+                    reply_text(event, messages::not_found(name));
                 }
             }
 
-            Action::ClearAllPublic => {
+            Action::Undo(scope) => {
+                let priv_scope = matches!(scope, Scope::Private);
+                let key = history_key(name, priv_scope, &owner(priv_scope));
+                let Some(prev) = mgr.undo_store.pop(&key).await else {
+                    reply_text(event, messages::nothing_to_undo(name));
+                    return;
+                };
                 {
                     let mut generating = mgr.generating.write().await;
-                    generating.public.clear();
+                    generating.set_generating(name, priv_scope, &owner(priv_scope), false);
                 }
                 let mut c = mgr.config.write().await;
-                let cnt = c.agents.len();
-                for a in c.agents.iter_mut() {
-                    a.public_history.clear();
-                    a.generation_id += 1;
-                }
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let len = prev.len();
+                *a.history_mut(priv_scope, &owner(priv_scope)) = prev;
+                a.bump_generation(priv_scope, &owner(priv_scope));
                 mgr.save(&c);
-                reply_text(event, format!("🧹 已清空 {} 个智能体的公有历史", cnt));
+                reply_text(event, messages::undone(name, len));
             }
 
-            Action::ClearEverything => {
+            Action::ClaimGlobal => {
+                let bucket = public_bucket(event);
                 {
                     let mut generating = mgr.generating.write().await;
-                    generating.public.clear();
-                    generating.private.clear();
+                    generating.set_generating(name, false, &bucket, false);
                 }
                 let mut c = mgr.config.write().await;
-                let cnt = c.agents.len();
-                for a in c.agents.iter_mut() {
-                    a.public_history.clear();
-                    a.private_histories.clear();
-                    a.generation_id += 1;
+                let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) else {
+                    reply_text(event, messages::not_found(name));
+                    return;
+                };
+                let n = a.claim_global(&bucket);
+                if n == 0 {
+                    reply_text(event, messages::no_global_history(name));
+                    return;
                 }
+                a.bump_generation(false, &bucket);
                 mgr.save(&c);
-                reply_text(event, format!("⚠️ 已清空 {} 个智能体的所有历史", cnt));
+                reply_text(event, messages::global_claimed(name, n));
             }
 
-            Action::Help => {
-                let help = r#"## 模式前缀（可组合）
-| 符号 | 含义 |
-|:---:|------|
-| `&` | 私有模式 (独立历史) |
-| `"` | 文本模式 (不转图片) |
-| `~` | 临时模式 (无历史/不阻塞) |
-
-## 智能体管理
-| 指令 | 功能 | 示例 |
-|------|------|------|
-| `##名称 模型 提示词` | 创建/更新 | `##助手 gpt-4o 你是助手` |
-| `##:模型` | 批量生成描述 | `##:gpt-4o` |
-| `智能体~=新名` | 重命名 | `助手~=管家` |
-| `智能体~#新名` | 复制 | `助手~#助手2` |
-| `智能体:描述` | 设置描述 | `助手:通用助手` |
-| `-#名称` | 删除 | `-#助手` |
-| `/#` | 列表 | `/#` |
-
-## 配置修改
-| 指令 | 功能 | 示例 |
-|------|------|------|
-| `智能体%模型` | 修改模型 | `助手%gpt-4` |
-| `智能体$提示词` | 修改提示词 | `助手$你是...` |
-| `智能体$` | 清空提示词 | `助手$` |
-| `智能体/$` | 查看提示词 | `助手/$` |
-| `/%` | 模型列表 | `/%` |
+            Action::ListSnapshots(scope) => {
+                let priv_scope = matches!(scope, Scope::Private);
+                let key = history_key(name, priv_scope, &owner(priv_scope));
+                let snaps = mgr.snapshots.read().await;
+                let list = match snaps.get(&key) {
+                    Some(l) if !l.is_empty() => l,
+                    _ => {
+                        reply_text(event, messages::no_snapshots(name, priv_scope));
+                        return;
+                    }
+                };
+                // 最新的快照编号为 1
+                let content = list
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .map(|(i, snap)| {
+                        let time = chrono::DateTime::from_timestamp(snap.created_at, 0)
+                            .map(|dt| {
+                                use chrono::TimeZone;
+                                chrono::Local
+                                    .from_utc_datetime(&dt.naive_utc())
+                                    .format("%m-%d %H:%M:%S")
+                                    .to_string()
+                            })
+                            .unwrap_or_default();
+                        format!(
+                            "- **#{}** {} · {} · {}",
+                            i + 1,
+                            time,
+                            snap.reason,
+                            messages::message_count(snap.messages.len())
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let content = format!("{}\n\n{}", content, messages::restore_hint(name));
+                reply(
+                    event,
+                    &content,
+                    cmd.text_mode,
+                    &messages::snapshots_title(name, priv_scope, list.len()),
+                )
+                .await;
+            }
 
-## 对话控制
-| 指令 | 功能 |
-|------|------|
-| `智能体 内容` | 正常对话 |
-| `~智能体 内容` | 临时对话 (一次性) |
-| `"智能体 内容` | 文本回复对话 |
-| `&智能体 内容` | 私有历史对话 |
-| `智能体~` | 重新生成上一条 |
-| `智能体!` | 停止生成 |
+            Action::RestoreSnapshot(scope) => {
+                let n = match cmd.indices.first() {
+                    Some(&n) if n > 0 => n,
+                    _ => {
+                        reply_text(event, Msg::RestoreUsage);
+                        return;
+                    }
+                };
+                let priv_scope = matches!(scope, Scope::Private);
+                let key = history_key(name, priv_scope, &owner(priv_scope));
+                let snap = {
+                    let snaps = mgr.snapshots.read().await;
+                    snaps
+                        .get(&key)
+                        .and_then(|l| l.iter().rev().nth(n - 1))
+                        .cloned()
+                };
+                let snap = match snap {
+                    Some(s) => s,
+                    None => {
+                        reply_text(event, messages::no_such_snapshot(n));
+                        return;
+                    }
+                };
 
-## 历史管理
-| 指令 | 功能 |
-|------|------|
-| `智能体/*` | 查看所有 |
-| `智能体/1` | 查看第1条 |
-| `智能体/1-5` | 查看范围 |
-| `智能体_*` | 导出(.txt) |
-| `智能体'1 内容` | 编辑第1条 |
-| `智能体-1` | 删除第1条 |
-| `智能体-1,3` | 删除多条 |
-| `智能体-*` | 清空历史 |
+                {
+                    let mut generating = mgr.generating.write().await;
+                    generating.set_generating(name, priv_scope, &owner(priv_scope), false);
+                }
+                let mut c = mgr.config.write().await;
+                if let Some(a) = c.agents.iter_mut().find(|a| a.name == *name) {
+                    // 回滚前保存当前状态，使回滚本身也可撤回
+                    mgr.snapshot(
+                        key,
+                        Msg::SnapBeforeRestore.text(),
+                        a.history(priv_scope, &owner(priv_scope)),
+                    )
+                    .await;
+                    *a.history_mut(priv_scope, &owner(priv_scope)) = snap.messages.clone();
+                    a.bump_generation(priv_scope, &owner(priv_scope));
+                    mgr.save(&c);
+                    reply_text(event, messages::restored(name, n, snap.messages.len()));
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
 
-> 所有符号支持半角/全角兼容 (如 ～, ＃, ＝)
-> 加 `&` 前缀可操作私有历史: `&智能体/*`
+            Action::ClearAllPublic => {
+                {
+                    let mut generating = mgr.generating.write().await;
+                    generating.public.clear();
+                }
+                let mut c = mgr.config.write().await;
+                let cnt = c.agents.len();
+                let entries: Vec<_> = c
+                    .agents
+                    .iter()
+                    .flat_map(|a| a.all_histories().take(a.public_histories.len()))
+                    .collect();
+                mgr.snapshot_all(Msg::SnapClearAllPublic.text(), entries)
+                    .await;
+                for a in c.agents.iter_mut() {
+                    a.public_histories.clear();
+                    a.topic = None;
+                    a.generation_id = a.generation_id.wrapping_add(1);
+                }
+                mgr.save(&c);
+                reply_text(event, messages::cleared_all(cnt, false));
+            }
 
-## 危险操作
-| 指令 | 功能 |
-|------|------|
-| `-*` | 清空所有智能体公有历史 |
-| `-*!` | 清空数据库所有历史 |
+            Action::ClearEverything => {
+                {
+                    let mut generating = mgr.generating.write().await;
+                    generating.public.clear();
+                    generating.private.clear();
+                }
+                let mut c = mgr.config.write().await;
+                let cnt = c.agents.len();
+                let entries: Vec<_> = c.agents.iter().flat_map(|a| a.all_histories()).collect();
+                mgr.snapshot_all(Msg::SnapClearAll.text(), entries).await;
+                for a in c.agents.iter_mut() {
+                    a.public_histories.clear();
+                    a.topic = None;
+                    a.private_histories.clear();
+                    a.generation_id = a.generation_id.wrapping_add(1);
+                }
+                mgr.save(&c);
+                reply_text(event, messages::cleared_all(cnt, true));
+            }
 
-## API 配置
-更新指令: `oai API地址 API密钥`
-"#;
-                reply(event, help, cmd.text_mode, "🤖 OAI 符号指令帮助").await;
+            Action::Help(lang) => {
+                let lang = lang.unwrap_or_else(messages::lang);
+                reply(
+                    event,
+                    messages::help(lang),
+                    cmd.text_mode,
+                    Msg::HelpTitle.text_in(lang),
+                )
+                .await;
             }
 
             Action::AutoFillDescriptions(model_ref) => {
@@ -2312,11 +11112,10 @@ mod logic {
                     let c = mgr.config.read().await;
 
                     // 1. 确定使用的模型
-                    let models = c.models.clone();
                     let resolved_model = if model_ref.is_empty() {
                         c.default_model.clone()
                     } else {
-                        mgr.resolve_model(&model_ref, &models).unwrap_or(model_ref)
+                        mgr.resolve_model(&model_ref, &c).unwrap_or(model_ref)
                     };
 
                     // 2. 筛选需要生成的智能体 (描述为空 或 仅仅是"新建智能体")
@@ -2335,31 +11134,23 @@ mod logic {
                 };
 
                 if target_agents.is_empty() {
-                    reply_text(event, "✅ 所有智能体均已有描述，无需处理。");
+                    reply_text(event, Msg::AllDescribed);
                     return;
                 }
 
                 if api_config.0.is_empty() || api_config.1.is_empty() {
-                    reply_text(event, "❌ API 未配置");
+                    reply_text(event, Msg::ApiNotConfigured);
                     return;
                 }
 
-                reply_text(
-                    event,
-                    format!(
-                        "🤖 开始使用 [{}] 为 {} 个智能体生成描述，请稍候...",
-                        use_model,
-                        target_agents.len()
-                    ),
-                );
+                reply_text(event, messages::describing(&use_model, target_agents.len()));
 
-                let client = Client::with_config(
-                    OpenAIConfig::new()
-                        .with_api_base(api_config.0)
-                        .with_api_key(api_config.1),
-                );
+                let client = mgr.client(&api_config.0, &api_config.1).await;
 
-                let mut success_count = 0;
+                // 并发生成，信号量限制同时进行的请求数
+                let limit = mgr.config.read().await.auto_fill_concurrency.max(1);
+                let sem = Arc::new(kovi::tokio::sync::Semaphore::new(limit));
+                let mut tasks = kovi::tokio::task::JoinSet::new();
 
                 for (name, prompt) in target_agents {
                     // 这里的 Prompt 专门用于生成简短描述
@@ -2369,42 +11160,203 @@ mod logic {
                                     System Prompt:\n{}",
                         prompt
                     );
-
-                    let req = CreateChatCompletionRequestArgs::default()
-                        .model(&use_model)
-                        .messages(vec![
-                            ChatCompletionRequestUserMessageArgs::default()
-                                .content(gen_prompt)
-                                .build()
-                                .unwrap()
-                                .into(),
-                        ])
-                        .build();
-
-                    if let Ok(req) = req
-                        && let Ok(res) = client.chat().create(req).await
-                        && let Some(choice) = res.choices.first()
-                        && let Some(content) = &choice.message.content
-                    {
+                    let (mgr, client, model, sem) =
+                        (mgr.clone(), client.clone(), use_model.clone(), sem.clone());
+                    tasks.spawn(async move {
+                        let _permit = sem.acquire_owned().await.ok()?;
+                        let content =
+                            complete_once(&mgr, &client, &model, gen_prompt, Priority::Batch, 60)
+                                .await?;
                         let new_desc = content.trim().replace(['"', '“', '”', '。', '.'], ""); // 简单清洗
+                        (!new_desc.is_empty()).then_some((name, new_desc))
+                    });
+                }
+
+                let mut results = Vec::new();
+                while let Some(res) = tasks.join_next().await {
+                    if let Ok(Some(r)) = res {
+                        results.push(r);
+                    }
+                }
 
-                        // 获取写锁更新数据
-                        let mut c = mgr.config.write().await;
+                // 一次性写回
+                let mut success_count = 0;
+                {
+                    let mut c = mgr.config.write().await;
+                    for (name, desc) in results {
                         if let Some(a) = c.agents.iter_mut().find(|a| a.name == name) {
-                            a.description = new_desc.clone();
-                            mgr.save(&c);
+                            a.description = desc;
                             success_count += 1;
                         }
                     }
+                    mgr.save(&c);
+                }
+
+                reply_text(event, messages::described(success_count));
+            }
 
-                    // 小停顿，避免并发过高 (100毫秒)
-                    kovi::tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            Action::SetDefaultModel(input) => {
+                let mut c = mgr.config.write().await;
+                if input.is_empty() {
+                    reply_text(event, messages::default_model(&c.default_model));
+                    return;
+                }
+                if let Some(model) = mgr.resolve_model(&input, &c) {
+                    let old = std::mem::replace(&mut c.default_model, model.clone());
+                    mgr.save(&c);
+                    reply_text(event, messages::default_model_changed(&old, &model));
+                } else {
+                    reply_text(event, Msg::InvalidModel);
                 }
+            }
 
-                reply_text(
-                    event,
-                    format!("✅ 批量处理完成，已更新 {} 个智能体的描述。", success_count),
+            Action::BulkSetModel { model, pattern } => {
+                let mut c = mgr.config.write().await;
+                let Some(model) = mgr.resolve_model(&model, &c) else {
+                    reply_text(event, Msg::InvalidModel);
+                    return;
+                };
+                let mut updated = Vec::new();
+                for a in c
+                    .agents
+                    .iter_mut()
+                    .filter(|a| name_matches(&pattern, &a.name))
+                {
+                    a.model = model.clone();
+                    updated.push(a.name.clone());
+                }
+                if updated.is_empty() {
+                    reply_text(event, messages::no_agents_match(&pattern));
+                    return;
+                }
+                mgr.save(&c);
+                reply_text(event, messages::bulk_model_set(&model, &updated));
+            }
+
+            Action::SetDefaultPrompt(prompt) => {
+                let mut c = mgr.config.write().await;
+                if prompt.is_empty() {
+                    reply_text(event, messages::default_prompt(&c.default_prompt));
+                    return;
+                }
+                c.default_prompt = prompt;
+                mgr.save(&c);
+                reply_text(event, Msg::DefaultPromptUpdated);
+            }
+
+            Action::ExportAgent => {
+                let c = mgr.config.read().await;
+                if let Some(a) = c.agents.iter().find(|a| a.name == *name) {
+                    let json = match kovi::serde_json::to_string_pretty(&AgentDef::from(a)) {
+                        Ok(j) => j,
+                        Err(e) => {
+                            reply_text(event, messages::serialize_failed(e));
+                            return;
+                        }
+                    };
+                    drop(c);
+
+                    let fname = format!("agent_{}.json", name);
+                    let path = bot.get_data_path().join(&fname);
+                    match std::fs::write(&path, json) {
+                        Ok(_) => upload_file(event, bot, &path, &fname).await,
+                        Err(e) => reply_text(event, messages::create_file_failed(e)),
+                    }
+                } else {
+                    reply_text(event, messages::not_found(name));
+                }
+            }
+
+            Action::ImportAgent(new_name) => {
+                let text = match get_reply_file(event, bot).await {
+                    Ok((_, t)) => t,
+                    Err(e) => {
+                        reply_text(event, format!("❌ {}", e));
+                        return;
+                    }
+                };
+                let def: AgentDef = match kovi::serde_json::from_str(&text) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        reply_text(event, messages::invalid_agent_def(e));
+                        return;
+                    }
+                };
+
+                let base = if new_name.is_empty() {
+                    def.name.clone()
+                } else {
+                    new_name
+                };
+                if !valid_agent_name(&base) {
+                    reply_text(event, Msg::InvalidName);
+                    return;
+                }
+
+                let mut c = mgr.config.write().await;
+                // 名称冲突时自动追加数字后缀，并保证不超过 7 字
+                let mut final_name = base.clone();
+                let mut n = 2;
+                while c.agents.iter().any(|a| a.name == final_name) {
+                    let suffix = n.to_string();
+                    let keep = 7 - suffix.chars().count();
+                    final_name = base.chars().take(keep).collect::<String>() + &suffix;
+                    n += 1;
+                }
+
+                let model = def.model.clone();
+                let mut agent = def.into_agent(&final_name);
+                agent.created_by = uid.clone();
+                c.agents.push(agent);
+                mgr.save(&c);
+                reply_text(event, messages::agent_imported(&base, &final_name, &model));
+            }
+
+            Action::BackupConfig => {
+                let fname = format!(
+                    "config_backup_{}.json",
+                    chrono::Local::now().format("%Y%m%d%H%M%S")
                 );
+                let path = bot.get_data_path().join(&fname);
+                match mgr.export_config_json(&path).await {
+                    Ok(_) => upload_file(event, bot, &path, &fname).await,
+                    Err(e) => reply_text(event, messages::backup_failed(e)),
+                }
+            }
+
+            Action::RestoreConfig(fname) => {
+                let dir = bot.get_data_path();
+                let fname = if fname.is_empty() {
+                    // 未指定文件时使用最新的备份
+                    let mut backups: Vec<String> = std::fs::read_dir(&dir)
+                        .map(|rd| {
+                            rd.filter_map(|e| e.ok())
+                                .map(|e| e.file_name().to_string_lossy().to_string())
+                                .filter(|n| n.starts_with("config_backup_") && n.ends_with(".json"))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    backups.sort();
+                    match backups.pop() {
+                        Some(f) => f,
+                        None => {
+                            reply_text(event, Msg::NoBackup);
+                            return;
+                        }
+                    }
+                } else {
+                    fname
+                };
+
+                if fname.contains(['/', '\\']) || fname.contains("..") {
+                    reply_text(event, Msg::InvalidFileName);
+                    return;
+                }
+
+                match mgr.import_config_json(&dir.join(&fname)).await {
+                    Ok(cnt) => reply_text(event, messages::config_restored(&fname, cnt)),
+                    Err(e) => reply_text(event, messages::restore_failed(e)),
+                }
             }
 
             Action::Create => {}
@@ -2420,12 +11372,17 @@ mod logic {
         mgr: &Arc<Manager>,
     ) {
         let mut c = mgr.config.write().await;
-        let models = c.models.clone();
-
         let model = mgr
-            .resolve_model(model, &models)
+            .resolve_model(model, &c)
             .unwrap_or_else(|| model.to_string());
 
+        // 新建时未指定模型则使用默认模型
+        let model = if model.is_empty() && !c.agents.iter().any(|a| a.name == name) {
+            c.default_model.clone()
+        } else {
+            model
+        };
+
         let prompt = if prompt.is_empty() && !c.agents.iter().any(|a| a.name == name) {
             c.default_prompt.clone()
         } else {
@@ -2442,20 +11399,18 @@ mod logic {
             }
             let updated_model = a.model.clone();
             mgr.save(&c);
-            reply_text(
-                event,
-                format!("📝 已更新 {} (模型: {})", name, updated_model),
-            );
+            reply_text(event, messages::agent_saved(name, &updated_model, false));
         } else {
             let description = if desc.is_empty() {
                 "新建智能体".to_string()
             } else {
                 desc.to_string()
             };
-            c.agents
-                .push(Agent::new(name, &model, &prompt, &description));
+            let mut agent = Agent::new(name, &model, &prompt, &description);
+            agent.created_by = event.user_id.to_string();
+            c.agents.push(agent);
             mgr.save(&c);
-            reply_text(event, format!("🤖 已创建 {} (模型: {})", name, model));
+            reply_text(event, messages::agent_saved(name, &model, true));
         }
     }
 }
@@ -2463,16 +11418,47 @@ mod logic {
 // --- 入口 ---
 use cdp_html_shot::Browser;
 use kovi::PluginBuilder;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+
+pub use data::{AgentResponseEvent, Manager};
+
+static MANAGER: OnceLock<Arc<Manager>> = OnceLock::new();
+
+/// 插件加载后可获取管理器，供其他插件注册回复回调:
+/// `kovi_plugin_oai::manager().map(|m| m.set_response_hook(|e| ...))`
+pub fn manager() -> Option<Arc<Manager>> {
+    MANAGER.get().cloned()
+}
 
 #[kovi::plugin]
 async fn main() {
     let bot = PluginBuilder::get_runtime_bot();
     let mgr = Arc::new(data::Manager::new(bot.get_data_path()));
+    let _ = MANAGER.set(mgr.clone());
+
+    let m = mgr.clone();
+    kovi::tokio::spawn(async move {
+        let _ = m.fetch_models_with_refresh().await;
+    });
 
+    // 每小时按各智能体的保留策略维护历史
     let m = mgr.clone();
     kovi::tokio::spawn(async move {
-        let _ = m.fetch_models().await;
+        let mut tick = kovi::tokio::time::interval(std::time::Duration::from_secs(3600));
+        loop {
+            tick.tick().await;
+            m.run_retention().await;
+        }
+    });
+
+    // 定时将未写盘的改动落盘，每轮重新读取间隔以支持热重载
+    let m = mgr.clone();
+    kovi::tokio::spawn(async move {
+        loop {
+            let secs = m.config.read().await.autosave_interval_secs;
+            kovi::tokio::time::sleep(std::time::Duration::from_secs(secs.max(1))).await;
+            m.flush().await;
+        }
     });
 
     let mgr_clone = mgr.clone();
@@ -2480,10 +11466,35 @@ async fn main() {
         let mgr = mgr_clone.clone();
         let bot = bot.clone();
         async move {
-            let raw = match event.borrow_text() {
-                Some(v) => v,
-                None => return,
+            // 黑名单 / 白名单：不满足时静默忽略，仅跳过本插件的处理
+            if !mgr.is_user_allowed(event.user_id).await {
+                return;
+            }
+
+            // 纯语音消息：开启 enable_audio 时转写为文字，按普通消息解析
+            let text = match event.borrow_text() {
+                Some(v) => v.to_string(),
+                None => {
+                    let record = event
+                        .message
+                        .iter()
+                        .find(|s| s.type_ == "record")
+                        .and_then(utils::record_file);
+                    let Some(file) = record else { return };
+                    if !mgr.config.read().await.enable_audio {
+                        return;
+                    }
+                    match mgr.transcribe(&bot, &file).await {
+                        Ok(t) if !t.is_empty() => t,
+                        Ok(_) => return,
+                        Err(e) => {
+                            kovi::log::error!("语音转写失败: {}", e);
+                            return;
+                        }
+                    }
+                }
             };
+            let raw = text.as_str();
 
             if let Some(cmd) = parser::parse_global(raw) {
                 logic::execute(cmd, String::new(), vec![], &event, &mgr, &bot).await;
@@ -2503,12 +11514,26 @@ async fn main() {
             }
 
             if let Some(cmd) = parser::parse_agent_cmd(raw, &agents) {
-                let (quote, imgs) = utils::get_full_content(&event, &bot, Some(&cmd.agent)).await;
+                let (mut quote, imgs, records) =
+                    utils::get_full_content(&event, &bot, Some(&cmd.agent)).await;
+
+                // 引用的语音转写后附在引用内容之后
+                if !records.is_empty() && mgr.config.read().await.enable_audio {
+                    for file in &records {
+                        match mgr.transcribe(&bot, file).await {
+                            Ok(t) if !t.is_empty() => quote.push_str(&format!("[语音] {}\n\n", t)),
+                            Ok(_) => {}
+                            Err(e) => logic::reply_text(&event, messages::transcribe_failed(e)),
+                        }
+                    }
+                }
 
                 // 拼接提示词：引用 + 用户输入参数
                 let prompt = if matches!(
                     cmd.action,
-                    parser::Action::Chat | parser::Action::Regenerate
+                    parser::Action::Chat
+                        | parser::Action::Regenerate { .. }
+                        | parser::Action::Relay(_)
                 ) {
                     format!("{}{}", quote, cmd.args).trim().to_string()
                 } else {
@@ -2525,13 +11550,605 @@ async fn main() {
         move || {
             let mgr = mgr_drop.clone();
             async move {
-                // 保存配置
-                let c = mgr.config.read().await;
-                mgr.save(&c);
-                // 关闭全局浏览器实例
+                // 强制落盘未保存的改动
+                mgr.flush().await;
+                // 关闭标签页池与全局浏览器实例
+                utils::clear_tab_pool().await;
                 // Browser::instance().await.close_async().await.unwrap();
                 Browser::shutdown_global().await;
             }
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parser::{Action, parse_agent_cmd};
+    use super::utils::{eval_expr, normalize, parse_indices, parse_tool_results};
+
+    #[test]
+    fn eval_expr_rejects_deep_or_long_input() {
+        assert_eq!(eval_expr("(1+2)*3"), Ok(9.0));
+        assert_eq!(eval_expr("--1"), Ok(1.0));
+        assert!(eval_expr(&format!("{}1{}", "(".repeat(500), ")".repeat(500))).is_err());
+        assert!(eval_expr(&format!("{}1", "-".repeat(900))).is_err());
+        assert!(eval_expr(&"1+".repeat(600)).is_err());
+    }
+
+    #[test]
+    fn fork_requires_hash_separator() {
+        let agents = vec!["助手".to_string()];
+        let cmd = parse_agent_cmd("助手~1-4#支线", &agents).unwrap();
+        assert_eq!(
+            cmd.action,
+            Action::Copy {
+                with_history: false
+            }
+        );
+        assert_eq!(cmd.args, "支线");
+        assert_eq!(cmd.indices.len(), 4);
+        for prompt in ["助手~3句话总结", "助手~1 更短"] {
+            let cmd = parse_agent_cmd(prompt, &agents).unwrap();
+            assert_eq!(cmd.action, Action::Regenerate { model: None }, "{prompt}");
+        }
+    }
+
+    #[test]
+    fn tool_results_need_explicit_syntax_and_map_per_call() {
+        let agents = vec!["助手".to_string()];
+        let cmd = parse_agent_cmd("助手 =>1 晴", &agents).unwrap();
+        assert_eq!((cmd.action, cmd.args.as_str()), (Action::Chat, "=>1 晴"));
+        assert_eq!(parse_tool_results("晴，25 度", 1), None);
+        assert_eq!(parse_tool_results("=>42", 1), Some(vec![Some("42".into())]));
+        assert_eq!(
+            parse_tool_results("=>2 二号\n=>1 一号\n第二行", 2),
+            Some(vec![Some("一号\n第二行".into()), Some("二号".into())])
+        );
+        assert_eq!(
+            parse_tool_results("=>甲\n=>3 丙", 3),
+            Some(vec![Some("甲".into()), None, Some("丙".into())])
+        );
+    }
+
+    #[test]
+    fn global_settings_require_admin_only_when_changing() {
+        assert!(Action::SetDefaultModel("gpt-4o".into()).requires_admin());
+        assert!(Action::SetDefaultPrompt("你好".into()).requires_admin());
+        assert!(Action::SetTheme("dark".into()).requires_admin());
+        assert!(Action::SetLang("en".into()).requires_admin());
+        assert!(
+            Action::SetAgentOrder {
+                name: "助手".into(),
+                pos: 1
+            }
+            .requires_admin()
+        );
+        assert!(!Action::SetTheme(String::new()).requires_admin());
+        assert!(!Action::SetLang(String::new()).requires_admin());
+    }
+
+    #[test]
+    fn agent_def_round_trips_settings() {
+        use super::types::{Agent, AgentDef, RetentionPolicy};
+        let mut a = Agent::new("助手", "gpt-4o", "你好", "描述");
+        a.system_images = vec!["https://example.com/a.png".into()];
+        a.timeout_secs = Some(30);
+        a.stop = vec!["END".into()];
+        a.seed = Some(7);
+        a.image_size = Some("1024x1024".into());
+        a.image_quality = Some("high".into());
+        a.token_budget = Some(4000);
+        a.max_private_users = Some(5);
+        a.retention_policy = Some(RetentionPolicy {
+            max_days: 3,
+            max_messages: 100,
+            archive: true,
+        });
+        let json = kovi::serde_json::to_string(&AgentDef::from(&a)).unwrap();
+        let def: AgentDef = kovi::serde_json::from_str(&json).unwrap();
+        let b = def.into_agent("副本");
+        assert_eq!(b.name, "副本");
+        assert_eq!(b.system_images, a.system_images);
+        assert_eq!(b.timeout_secs, Some(30));
+        assert_eq!(b.stop, a.stop);
+        assert_eq!(b.seed, Some(7));
+        assert_eq!(b.image_size, a.image_size);
+        assert_eq!(b.image_quality, a.image_quality);
+        assert_eq!(b.token_budget, Some(4000));
+        assert_eq!(b.max_private_users, Some(5));
+        let p = b.retention_policy.unwrap();
+        assert_eq!((p.max_days, p.max_messages, p.archive), (3, 100, true));
+    }
+
+    #[test]
+    fn duplicate_keeps_settings_and_resets_state() {
+        use super::types::{Agent, ChatMessage};
+        let mut a = Agent::new("助手", "gpt-4o", "你好", "描述");
+        a.stop = vec!["END".into()];
+        a.seed = Some(7);
+        a.require_approval = true;
+        a.generation_id = 9;
+        a.session_generations.insert("public:g1".into(), 3);
+        a.call_count = 12;
+        a.last_used_at = 100;
+        a.topic = Some("话题".into());
+        a.history_mut(false, "g1")
+            .push(ChatMessage::new("user", "hi", vec![]));
+        a.history_mut(true, "1")
+            .push(ChatMessage::new("user", "hi", vec![]));
+        let b = a.duplicate("副本");
+        assert_eq!(b.name, "副本");
+        assert_eq!(
+            (b.stop, b.seed, b.require_approval),
+            (a.stop, Some(7), true)
+        );
+        assert!(b.public_histories.is_empty() && b.private_histories.is_empty());
+        assert!(b.session_generations.is_empty() && b.topic.is_none());
+        assert_eq!((b.generation_id, b.call_count, b.last_used_at), (0, 0, 0));
+    }
+
+    /// 在临时目录中创建独立的 Manager，测试之间互不干扰
+    fn temp_manager(tag: &str) -> (super::data::Manager, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("oai-test-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        (super::data::Manager::new(dir.clone()), dir)
+    }
+
+    #[tokio::test]
+    async fn import_bumps_past_live_generation_and_clears_flags_on_success() {
+        use super::types::Agent;
+        let (mgr, dir) = temp_manager("import");
+        let backup = {
+            let mut c = mgr.config.write().await;
+            let mut a = Agent::new("助手", "gpt-4o", "", "");
+            a.generation_id = 1;
+            c.agents = vec![a];
+            let json = kovi::serde_json::to_string(&*c).unwrap();
+            c.agents[0].generation_id = 5;
+            c.agents[0]
+                .session_generations
+                .insert("public:g1".into(), 3);
+            json
+        };
+        std::fs::write(dir.join("backup.json"), backup).unwrap();
+        std::fs::write(dir.join("broken.json"), "{").unwrap();
+        mgr.generating
+            .write()
+            .await
+            .set_generating("助手", false, "g1", true);
+
+        assert!(
+            mgr.import_config_json(&dir.join("broken.json"))
+                .await
+                .is_err()
+        );
+        assert!(
+            mgr.generating
+                .read()
+                .await
+                .is_generating("助手", false, "g1")
+        );
+
+        assert_eq!(
+            mgr.import_config_json(&dir.join("backup.json"))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(
+            !mgr.generating
+                .read()
+                .await
+                .is_generating("助手", false, "g1")
+        );
+        let c = mgr.config.read().await;
+        assert_eq!(c.agents[0].generation_id, 6);
+        assert!(c.agents[0].session_generation(false, "g1") > 5 + 3);
+        drop(c);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn summary_keeps_messages_written_during_summarization() {
+        use super::logic::splice_summary;
+        use super::types::ChatMessage;
+        let msg = |role: &str, text: &str, ts: i64| {
+            let mut m = ChatMessage::new(role, text, vec![]);
+            m.timestamp = ts;
+            m
+        };
+        let prefix = vec![msg("user", "a", 1), msg("assistant", "b", 2)];
+        let mut live = prefix.clone();
+        live[1].pinned = true;
+        live.push(msg("user", "c", 3));
+        live.push(msg("user", "新消息", 4));
+        let (out, cnt) = splice_summary(&live, &prefix, msg("assistant", "[摘要]", 5)).unwrap();
+        let texts: Vec<&str> = out.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(texts, ["[摘要]", "b", "c", "新消息"]);
+        assert_eq!(cnt, 1);
+        // 前缀中的消息在总结期间被删除：放弃写回
+        assert!(splice_summary(&live[1..], &prefix, msg("assistant", "[摘要]", 5)).is_none());
+    }
+
+    #[test]
+    fn parse_indices_step_syntax() {
+        assert_eq!(parse_indices("1-10:2", 0), [1, 3, 5, 7, 9]);
+        assert_eq!(parse_indices("2-10:3", 0), [2, 5, 8]);
+        assert_eq!(parse_indices("1-10：2", 0), [1, 3, 5, 7, 9]);
+        assert_eq!(parse_indices("10-1:4", 0), [10, 6, 2]);
+        // 步长为 0 的区间整体忽略，不影响其他部分
+        assert!(parse_indices("1-10:0", 0).is_empty());
+        assert_eq!(parse_indices("1-10:0,3", 0), [3]);
+        // 步长超过区间长度时只取起点
+        assert_eq!(parse_indices("2-4:10", 0), [2]);
+    }
+
+    #[test]
+    fn parse_indices_reversed_and_negative() {
+        assert_eq!(parse_indices("5-1", 0), [5, 4, 3, 2, 1]);
+        assert_eq!(parse_indices("3,1-2,3", 0), [3, 1, 2]);
+        // total_len = 10：-1 为最后一条
+        assert_eq!(parse_indices("-1", 10), [10]);
+        assert_eq!(parse_indices("-3--1", 10), [8, 9, 10]);
+        assert_eq!(parse_indices("-1--3", 10), [10, 9, 8]);
+        assert_eq!(parse_indices("1,-1", 10), [1, 10]);
+        assert_eq!(parse_indices("-10--9", 10), [1, 2]);
+        // 超出范围的负索引被忽略
+        assert!(parse_indices("-11", 10).is_empty());
+        assert!(parse_indices("-2-1", 1).is_empty());
+        // 不知道总长时无法解析负索引
+        assert!(parse_indices("-1", 0).is_empty());
+    }
+
+    #[test]
+    fn parse_indices_clamps_ranges() {
+        assert_eq!(parse_indices("99999999999-1", 0).len(), 10_000);
+        assert_eq!(parse_indices("1-99999999999:5000", 0), [1, 5001]);
+        assert_eq!(parse_indices("8-20", 10), [8, 9, 10]);
+        assert_eq!(parse_indices("20-9", 10), [10, 9]);
+    }
+
+    #[test]
+    fn negative_delete_index_resolved_at_execute() {
+        let agents = vec!["助手".to_string()];
+        let cmd = parse_agent_cmd("助手--1", &agents).unwrap();
+        assert!(matches!(cmd.action, Action::DeleteAt(_)));
+        assert_eq!(cmd.args, "-1");
+        assert!(cmd.indices.is_empty());
+        let cmd = parse_agent_cmd("助手-1,-2", &agents).unwrap();
+        assert!(matches!(cmd.action, Action::DeleteAt(_)));
+        assert_eq!(parse_indices(&cmd.args, 5), [1, 4]);
+    }
+
+    #[test]
+    fn normalize_fullwidth_table() {
+        let cases = [
+            ("１２３", "123"),
+            ("０９", "09"),
+            ("ＡＢＣｘｙｚ", "ABCxyz"),
+            ("助手～", "助手~"),
+            ("助手〜1", "助手~1"),
+            ("【注意】", "[注意]"),
+            ("『引用』", "\"引用\""),
+            ("助手　你好", "助手 你好"),
+            ("＃＿＊／", "#_*/"),
+            ("中文不变", "中文不变"),
+        ];
+        for (input, want) in cases {
+            assert_eq!(normalize(input), want, "{input}");
+            // 已规范化的文本再处理一次保持不变
+            assert_eq!(normalize(want), want, "{want}");
+        }
+    }
+
+    #[test]
+    fn restart_invalidates_generation_interrupted_by_crash() {
+        use super::types::{Agent, ChatMessage};
+        let (mgr, dir) = temp_manager("crash");
+        // 模拟对话开始：写入用户消息、递增会话 ID 并立即写盘，随后在回复前崩溃
+        let in_flight = {
+            let mut c = mgr.config.blocking_write();
+            c.agents.push(Agent::new("助手", "gpt-4o", "", ""));
+            let a = &mut c.agents[0];
+            a.history_mut(false, "g1")
+                .push(ChatMessage::new("user", "你好", vec![]));
+            let id = a.bump_generation(false, "g1");
+            mgr.save_now(&c);
+            id
+        };
+        drop(mgr);
+
+        let restarted = super::data::Manager::new(dir.clone());
+        let c = restarted.config.blocking_read();
+        let a = &c.agents[0];
+        assert_eq!(a.history(false, "g1").len(), 1);
+        // 崩溃前的生成任务若迟到写回，会因 ID 不符被丢弃
+        assert_ne!(a.session_generation(false, "g1"), in_flight);
+        drop(c);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn dedup_models_keeps_first_occurrence_order() {
+        use super::data::dedup_models;
+        use super::types::Config;
+        let mut c = Config {
+            models: vec!["b".into(), "a".into(), "b".into(), "c".into(), "a".into()],
+            all_models: vec!["x".into(), "x".into()],
+            ..Default::default()
+        };
+        assert!(dedup_models(&mut c));
+        assert_eq!(c.models, ["b", "a", "c"]);
+        assert_eq!(c.all_models, ["x"]);
+        // 无重复时返回 false 且不改动顺序
+        assert!(!dedup_models(&mut c));
+        assert_eq!(c.models, ["b", "a", "c"]);
+    }
+
+    #[test]
+    fn regen_trim_only_pops_the_regenerated_messages() {
+        use super::types::{Agent, ChatMessage};
+        let msg = |role: &str, text: &str, ts: i64| {
+            let mut m = ChatMessage::new(role, text, vec![]);
+            m.timestamp = ts;
+            m
+        };
+        let mut a = Agent::new("助手", "gpt-4o", "", "");
+        *a.history_mut(false, "g1") = vec![msg("user", "问", 1), msg("assistant", "旧答", 2)];
+        let trimmed = vec![msg("assistant", "旧答", 2)];
+        // 重新生成期间旧回复已被删除，末尾换成了别的消息：不能误删
+        a.history_mut(false, "g1").pop();
+        a.history_mut(false, "g1").push(msg("user", "插话", 3));
+        a.apply_turn(false, "g1", &trimmed, vec![msg("assistant", "新答", 4)]);
+        let texts: Vec<&str> = a
+            .history(false, "g1")
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(texts, ["问", "插话", "新答"]);
+
+        // 末尾仍是被重新生成的回复：正常替换
+        a.apply_turn(
+            false,
+            "g1",
+            &[msg("assistant", "新答", 4)],
+            vec![msg("assistant", "再答", 5)],
+        );
+        let texts: Vec<&str> = a
+            .history(false, "g1")
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(texts, ["问", "插话", "再答"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_turns_do_not_lose_messages() {
+        use super::types::{Agent, ChatMessage};
+        use std::sync::Arc;
+        let (mgr, dir) = temp_manager("concurrent");
+        let mgr = Arc::new(mgr);
+        mgr.config
+            .write()
+            .await
+            .agents
+            .push(Agent::new("助手", "gpt-4o", "", ""));
+        let tasks: Vec<_> = (0..32)
+            .map(|i| {
+                let mgr = mgr.clone();
+                tokio::spawn(async move {
+                    // 与对话流程相同：先读快照，锁外"请求模型"，再在写锁内增量写回
+                    let _snapshot = mgr.config.read().await.agents[0]
+                        .history(false, "g1")
+                        .to_vec();
+                    tokio::task::yield_now().await;
+                    let mut c = mgr.config.write().await;
+                    let turn = vec![
+                        ChatMessage::new("user", &format!("问{i}"), vec![]),
+                        ChatMessage::new("assistant", &format!("答{i}"), vec![]),
+                    ];
+                    c.agents[0].apply_turn(false, "g1", &[], turn);
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+        let c = mgr.config.read().await;
+        let hist = c.agents[0].history(false, "g1");
+        assert_eq!(hist.len(), 64);
+        for i in 0..32 {
+            let q = hist
+                .iter()
+                .position(|m| m.content == format!("问{i}"))
+                .unwrap();
+            assert_eq!(hist[q + 1].content, format!("答{i}"));
+        }
+        drop(c);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn edit_then_undo_restores_history() {
+        use super::data::{UNDO_DEPTH, UndoStore};
+        use super::types::{Agent, ChatMessage, EditMode};
+        let store = UndoStore::default();
+        let key = "助手:public:g1".to_string();
+        let mut a = Agent::new("助手", "gpt-4o", "", "");
+        *a.history_mut(false, "g1") = vec![
+            ChatMessage::new("user", "原问题", vec![]),
+            ChatMessage::new("assistant", "原回答", vec![]),
+        ];
+        // 与 EditAt 处理流程相同：修改前压栈，撤销时整体还原
+        let before = a.history(false, "g1").to_vec();
+        assert!(a.edit_at(false, "g1", 1, "新问题", EditMode::Replace));
+        store.push(key.clone(), before).await;
+        assert_eq!(a.history(false, "g1")[0].content, "新问题");
+
+        let prev = store.pop(&key).await.unwrap();
+        *a.history_mut(false, "g1") = prev;
+        let texts: Vec<&str> = a
+            .history(false, "g1")
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(texts, ["原问题", "原回答"]);
+        assert!(store.pop(&key).await.is_none());
+
+        // 只保留最近 UNDO_DEPTH 步
+        for i in 0..UNDO_DEPTH + 2 {
+            store
+                .push(
+                    key.clone(),
+                    vec![ChatMessage::new("user", &i.to_string(), vec![])],
+                )
+                .await;
+        }
+        for i in (2..UNDO_DEPTH + 2).rev() {
+            assert_eq!(store.pop(&key).await.unwrap()[0].content, i.to_string());
+        }
+        assert!(store.pop(&key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_batched_and_removed_with_the_agent() {
+        use super::types::{ChatMessage, history_key};
+        let (mgr, dir) = temp_manager("snapshots");
+        let hist = vec![ChatMessage::new("user", "你好", vec![])];
+        let entries = vec![
+            (history_key("助手", false, "g1"), hist.as_slice()),
+            (history_key("助手", true, "1"), hist.as_slice()),
+            (history_key("助手2", false, "g1"), hist.as_slice()),
+            (history_key("助手", false, "g2"), &[][..]),
+        ];
+        mgr.snapshot_all("测试", entries).await;
+        assert_eq!(mgr.snapshots.read().await.len(), 3);
+        let saved = std::fs::read_to_string(dir.join("snapshots.json")).unwrap();
+        assert!(saved.contains("助手2:public:g1"));
+
+        mgr.undo_store
+            .push(history_key("助手", false, "g1"), hist.clone())
+            .await;
+        mgr.remove_snapshots("助手").await;
+        let keys: Vec<String> = mgr.snapshots.read().await.keys().cloned().collect();
+        assert_eq!(keys, ["助手2:public:g1"]);
+        assert!(
+            mgr.undo_store
+                .pop(&history_key("助手", false, "g1"))
+                .await
+                .is_none()
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn fuzzy_match_picks_closest_agent_deterministically() {
+        let suggest = |input: &str, agents: &[&str]| {
+            let agents: Vec<String> = agents.iter().map(|a| a.to_string()).collect();
+            parse_agent_cmd(input, &agents).map(|c| c.action)
+        };
+        assert_eq!(
+            suggest("助半 你好", &["助手", "助理"]),
+            suggest("助半 你好", &["助理", "助手"])
+        );
+        assert_eq!(
+            suggest("助半 你好", &["助手", "助理"]),
+            Some(Action::DidYouMean("助手".into()))
+        );
+        // 距离相同时按名称排序，而不是取列表中先出现的
+        assert_eq!(
+            suggest("abcd hi", &["abce", "abcd1"]),
+            Some(Action::DidYouMean("abcd1".into()))
+        );
+        assert_eq!(suggest("xyz hi", &["abce", "abcd1"]), None);
+    }
+
+    #[test]
+    fn insert_requires_a_role_token() {
+        use super::parser::Scope;
+        let agents = vec!["助手".to_string()];
+        let cmd = parse_agent_cmd("助手+2 user 你好", &agents).unwrap();
+        assert_eq!(cmd.action, Action::InsertAt(Scope::Public));
+        assert_eq!((cmd.indices, cmd.args.as_str()), (vec![2], "user 你好"));
+        let cmd = parse_agent_cmd("助手+1 System 规则", &agents).unwrap();
+        assert_eq!(cmd.action, Action::InsertAt(Scope::Public));
+        for prompt in ["助手+1 等于几", "助手+1", "助手+1user 你好"] {
+            let cmd = parse_agent_cmd(prompt, &agents).unwrap();
+            assert_eq!(cmd.action, Action::Chat, "{prompt}");
+        }
+    }
+
+    #[test]
+    fn approver_changes_require_admin() {
+        assert!(Action::SetApprovers.requires_admin());
+    }
+
+    #[test]
+    fn retention_requires_admin() {
+        let agents = vec!["助手".to_string()];
+        let cmd = parse_agent_cmd("助手/keep 0 1", &agents).unwrap();
+        assert_eq!(cmd.action, Action::SetRetention);
+        assert!(cmd.action.requires_admin());
+    }
+
+    #[test]
+    fn html_export_escapes_raw_html() {
+        use super::types::ChatMessage;
+        use super::utils::format_export_html;
+        let hist = vec![
+            ChatMessage::new("user", "<script>alert(1)</script>", vec![]),
+            ChatMessage::new(
+                "assistant",
+                "看图 <img src=x onerror=alert(1)> **粗体**",
+                vec![],
+            ),
+        ];
+        let page = format_export_html("助手", "gpt-4o", "公有", &hist);
+        assert!(!page.contains("<script>alert"));
+        assert!(page.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!page.contains("<img src=x"));
+        assert!(page.contains("<strong>粗体</strong>"));
+    }
+
+    #[test]
+    fn agent_names_cannot_escape_the_data_dir() {
+        use super::parser::parse_create;
+        for bad in [
+            "##\"../../x\" gpt-4o",
+            "##\"a\\b\" gpt-4o",
+            "##\"a:b\" gpt-4o",
+            "##\"..\" gpt-4o",
+            "##.. gpt-4o",
+            "##a\\b gpt-4o",
+        ] {
+            assert!(parse_create(bad).is_none(), "{bad}");
+        }
+        assert_eq!(parse_create("##\"C++\" gpt-4o").unwrap().0, "C++");
+        assert_eq!(parse_create("##v1.5 gpt-4o").unwrap().0, "v1.5");
+    }
+
+    #[test]
+    fn global_aliases_need_oai_prefix() {
+        use super::parser::parse_global;
+        for word in ["help", "list", "models", "clearall", "List"] {
+            assert!(parse_global(word).is_none(), "{word}");
+        }
+        assert_eq!(parse_global("oai list").unwrap().action, Action::List);
+        assert_eq!(
+            parse_global("oai clearall").unwrap().action,
+            Action::ClearAllPublic
+        );
+    }
+
+    #[test]
+    fn hash_uid_is_stable_hmac_sha256() {
+        use super::utils::hash_uid;
+        // 常见测试向量 HMAC-SHA256("key", "The quick brown fox ...") 的前 8 字节
+        assert_eq!(
+            hash_uid("The quick brown fox jumps over the lazy dog", "key"),
+            "f7bc83f430538424"
+        );
+        assert_ne!(hash_uid("10001", "a"), hash_uid("10001", "b"));
+    }
+}